@@ -1,48 +1,86 @@
-use std::collections::HashMap;
-use std::error::Error;
-use std::io;
-use std::result::Result;
+use std::env;
+use std::path::Path;
 
 #[macro_use]
 extern crate json;
-use json::Array;
 use json::JsonValue;
+use quarenta::{
+    read_from_console, read_location, Client, Feature, FeatureLayer, FeatureService, Geocoder, Geometry, LoadOptions,
+    ReplicaOptions, ReplicaStore, RowStatus, SpatialReference, SyncDirection,
+};
 
-type BoxResult<T> = Result<T,Box<dyn Error>>;
+const INCIDENTS_SERVICE_URL: &str = "https://services.arcgis.com/V6ZHFr6zdgNZuVG0/ArcGIS/rest/services/IncidentsReport/FeatureServer";
+const INCIDENTS_LAYER_ID: u32 = 0;
+const INCIDENTS_LAYER_URL: &str = "https://services.arcgis.com/V6ZHFr6zdgNZuVG0/ArcGIS/rest/services/IncidentsReport/FeatureServer/0";
 
-const TYPES_DESCRIPTION: &str = r#"Incident types:
-    1. Dead animal
-    2. Graffiti
-    3. Manhole cover
-    4. Pothole
-    5. Street light
-    6. Street sign
-    7. Other"#;
+/// Where `--offline` keeps the replica and its queued incidents.
+const OFFLINE_STORE_PATH: &str = "incidents-replica.json";
+
+const INCIDENT_TYPE_FIELD: &str = "IncidentType";
 
 #[tokio::main]
 async fn main() {
-    let reqwest_client = reqwest::Client::new();
+    let client = Client::new();
     println!("Add Features Demo");
 
-    let lon: f64 = read_from_console("Longitude:").parse().unwrap();
-    let lat: f64 = read_from_console("Latitude:").parse().unwrap();
-    let incident_type: String = read_from_console(format!("{}\n\nIncident type:", TYPES_DESCRIPTION).as_str());
+    // --offline queues the incident in a local replica; --sync uploads the queue; --load adds
+    // every incident in a CSV or GeoJSON file.
+    let mode = env::args().nth(1);
+    if Some("--sync") == mode.as_deref() {
+        sync_offline_incidents(&client).await;
+        read_from_console("Type Enter to exit");
+        return;
+    }
+    if Some("--load") == mode.as_deref() {
+        match env::args().nth(2) {
+            Some(path) => load_incidents(&client, Path::new(&path)).await,
+            None => println!("Usage: add-features --load <incidents.csv|incidents.geojson>"),
+        }
+        read_from_console("Type Enter to exit");
+        return;
+    }
+    let offline = Some("--offline") == mode.as_deref();
+
+    let location = match read_location(&Geocoder::world(&client)).await {
+        Some(location) => location,
+        None => return,
+    };
+    let layer = FeatureLayer::new(&client, INCIDENTS_LAYER_URL);
+    let incident_type = read_incident_type(&layer).await;
     let incident_description: String = read_from_console("Incident description:");
+    let photo_path: String = if offline {
+        String::new()
+    } else {
+        read_from_console("Photo path (optional):")
+    };
 
-    let feature: JsonValue = object!{
-        "attributes" => object!{
-            "IncidentType" => incident_type,
+    let feature = Feature::new(
+        object!{
+            INCIDENT_TYPE_FIELD => incident_type,
             "IncidentDescription" => incident_description
         },
-        "geometry" => object!{
-            "x" => lon,
-            "y" => lat,
-            "spatialReference" => object! { "wkid" => 4326 }
-        }
-    };
-    match add_features(&reqwest_client, [ feature ].to_vec()).await {
-        Ok(response) => {
-            println!("The response: {}", response);
+        Some(Geometry::Point(location))
+    );
+    if offline {
+        queue_offline_incident(&client, feature).await;
+        read_from_console("Type Enter to exit");
+        return;
+    }
+    match layer.add_features(&[ feature ], Some(&SpatialReference::wgs84())).await {
+        Ok(results) => {
+            for result in results {
+                match (result.success, result.object_id) {
+                    (true, Some(object_id)) => {
+                        println!("Added incident {}", object_id);
+                        if !photo_path.is_empty() {
+                            attach_photo(&layer, object_id, Path::new(&photo_path)).await;
+                        }
+                    },
+                    _ => {
+                        println!("Could not add incident: {:?}", result.error);
+                    }
+                }
+            }
         },
         Err(err) => {
             println!("Error: {:?}", err);
@@ -52,33 +90,179 @@ async fn main() {
     read_from_console("Type Enter to exit");
 }
 
-async fn add_features(
-    client: &reqwest::Client,
-    features: Array
-) -> BoxResult<JsonValue> {
-    let features_string = json::stringify(features);
-    let mut params = HashMap::new();
-    params.insert("f", "json");
-    params.insert("features", features_string.as_str());
-
-    match client.post(
-        "https://services.arcgis.com/V6ZHFr6zdgNZuVG0/ArcGIS/rest/services/IncidentsReport/FeatureServer/0/addFeatures"
-    ).form(&params).send().await {
-        Ok(response) => {
-            match response.text().await {
-                Ok(text) => Ok(json::parse(text.as_str()).unwrap()),
-                Err(err) => Err(Box::new(err)),
+async fn attach_photo(
+    layer: &FeatureLayer,
+    object_id: u64,
+    photo_path: &Path
+) {
+    match layer.add_attachment(object_id, photo_path).await {
+        Ok(result) => {
+            if result.success {
+                println!("Attached {} to incident {}", photo_path.display(), object_id);
+            } else {
+                println!("Could not attach photo: {:?}", result.error);
+            }
+        },
+        Err(err) => {
+            println!("Error attaching photo: {:?}", err);
+        }
+    }
+}
+
+/// Lists the incident types from the layer's domain and reads the user's choice. Without the
+/// layer description (e.g. offline), falls back to reading the code itself.
+async fn read_incident_type(layer: &FeatureLayer) -> JsonValue {
+    let coded_values = match layer.describe().await {
+        Ok(info) => info.coded_values(INCIDENT_TYPE_FIELD).unwrap_or_default(),
+        Err(_) => Vec::new(),
+    };
+    if coded_values.is_empty() {
+        let code = read_from_console("Incident type code:");
+        return match code.parse::<i64>() {
+            Ok(code) => code.into(),
+            Err(_) => code.into(),
+        };
+    }
+    println!("Incident types:");
+    for (index, coded_value) in coded_values.iter().enumerate() {
+        println!("    {}. {}", index + 1, coded_value.name);
+    }
+    loop {
+        let choice = read_from_console("\nIncident type:");
+        match choice.parse::<usize>() {
+            Ok(choice) if (1..=coded_values.len()).contains(&choice) => {
+                return coded_values[choice - 1].code.clone();
+            },
+            _ => {
+                println!("Choose a number from 1 to {}", coded_values.len());
+            }
+        }
+    }
+}
+
+/// Opens the offline store, creating the replica first if there isn't one yet. Creating it
+/// needs a connection; everything after that works offline.
+async fn open_offline_store(service: &FeatureService) -> Option<ReplicaStore> {
+    let path = Path::new(OFFLINE_STORE_PATH);
+    let result = if path.exists() {
+        ReplicaStore::open(path).await
+    } else {
+        println!("Creating a replica of the incidents layer in {}", path.display());
+        // Incidents are located in longitude and latitude, so keep the replica in them too.
+        let options = ReplicaOptions::new("add-features", &[ INCIDENTS_LAYER_ID ])
+            .out_sr(SpatialReference::wgs84());
+        ReplicaStore::create(service, &options, path).await
+    };
+    match result {
+        Ok(store) => Some(store),
+        Err(err) => {
+            println!("Could not open the offline store: {:?}", err);
+            None
+        }
+    }
+}
+
+async fn queue_offline_incident(
+    client: &Client,
+    feature: Feature
+) {
+    let service = FeatureService::new(client, INCIDENTS_SERVICE_URL);
+    let mut store = match open_offline_store(&service).await {
+        Some(store) => store,
+        None => return,
+    };
+    // The location is in WGS 1984; a store without a spatial reference was made before
+    // replicas were created in it, so there's no telling what its edits should be in.
+    let feature = match store.spatial_reference() {
+        Some(spatial_reference) => match project_feature(feature, spatial_reference) {
+            Ok(feature) => feature,
+            Err(err) => {
+                println!("Could not queue incident: {:?}", err);
+                return;
+            }
+        },
+        None => {
+            println!("{} has no spatial reference; delete it and run --offline again to recreate it", OFFLINE_STORE_PATH);
+            return;
+        }
+    };
+    let queued = store.add_feature(INCIDENTS_LAYER_ID, feature);
+    match (queued, store.save().await) {
+        (Ok(global_id), Ok(())) => {
+            println!("Queued incident {}; {} edit(s) waiting for --sync", global_id, store.pending_edit_count());
+        },
+        (Err(err), _) | (_, Err(err)) => {
+            println!("Could not queue incident: {:?}", err);
+        }
+    }
+}
+
+fn project_feature(
+    mut feature: Feature,
+    spatial_reference: &SpatialReference
+) -> Result<Feature, Box<dyn std::error::Error>> {
+    if let Some(geometry) = &feature.geometry {
+        feature.geometry = Some(geometry.project(&SpatialReference::wgs84(), spatial_reference)?);
+    }
+    Ok(feature)
+}
+
+async fn sync_offline_incidents(client: &Client) {
+    let service = FeatureService::new(client, INCIDENTS_SERVICE_URL);
+    let mut store = match open_offline_store(&service).await {
+        Some(store) => store,
+        None => return,
+    };
+    let pending = store.pending_edit_count();
+    match store.synchronize(&service, SyncDirection::Bidirectional).await {
+        Ok(result) => {
+            let conflicts = result.conflicts();
+            println!("Uploaded {} edit(s), {} rejected", pending, conflicts.len());
+            for conflict in conflicts {
+                println!("    {:?}: {:?}", conflict.global_id, conflict.error);
             }
+            if 0 < store.pending_edit_count() {
+                println!("{} rejected edit(s) are still queued for the next --sync", store.pending_edit_count());
+            }
+            let downloaded: usize = result.changes
+                .iter()
+                .map(|changes| changes.adds.len() + changes.updates.len())
+                .sum();
+            println!("Downloaded {} new or changed incident(s)", downloaded);
         },
-        Err(err) => Err(Box::new(err)),
+        Err(err) => {
+            println!("Could not sync; edits are still queued: {:?}", err);
+        }
     }
 }
 
-fn read_from_console(prompt: &str) -> String {
-    println!("{}", prompt);
-    let mut value = String::new();
-    io::stdin()
-        .read_line(&mut value)
-        .expect("Failed to read line");
-    String::from(value.trim())
+/// Loads incidents from a file and writes a report of what happened to each row next to it.
+async fn load_incidents(
+    client: &Client,
+    path: &Path
+) {
+    let layer = FeatureLayer::new(client, INCIDENTS_LAYER_URL);
+    let report = match layer.load_file(path, &LoadOptions::new()).await {
+        Ok(report) => report,
+        Err(err) => {
+            println!("Could not load {}: {:?}", path.display(), err);
+            return;
+        }
+    };
+    if !report.unmapped_columns.is_empty() {
+        println!("Skipped columns with no matching field: {}", report.unmapped_columns.join(", "));
+    }
+    println!("Added {} of {} incident(s)", report.added(), report.rows.len());
+    for row in report.rejected() {
+        match &row.status {
+            RowStatus::Invalid(message) => println!("    Row {} is invalid: {}", row.row, message),
+            RowStatus::Failed(message) => println!("    Row {} failed: {}", row.row, message),
+            RowStatus::Added { .. } => {}
+        }
+    }
+    let report_path = path.with_extension("report.csv");
+    match report.write_csv(&report_path) {
+        Ok(()) => println!("Wrote the report to {}", report_path.display()),
+        Err(err) => println!("Could not write the report: {:?}", err),
+    }
 }
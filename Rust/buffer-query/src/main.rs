@@ -1,202 +1,335 @@
-use std::collections::HashMap;
+use std::env;
 use std::error::Error;
-use std::io;
+use std::path::Path;
 
-use geo::Point;
-use geo::algorithm::bearing::Bearing;
-#[macro_use]
-extern crate json;
-use json::Array;
 use json::JsonValue;
-use json::iterators::Members;
-use reqwest::Client;
+use quarenta::{
+    create_writer, read_from_console, read_line_from_console, read_location, Client, Feature, FeatureCache, FeatureLayer,
+    FeatureSet, FeatureWriter, Geocoder, Geometry, GeometryEngine, GeometryService, Point, Query, SpatialIndex,
+    SpatialReference, WhereClause,
+};
 
 type BoxResult<T> = Result<T,Box<dyn Error>>;
 
-const DEFAULT_FEATURE_LAYER_URL: &'static str = "https://services.arcgis.com/P3ePLMYs2RVChkJx/arcgis/rest/services/World_Cities/FeatureServer/0";
-const GEOMETRY_SERVICE_URL: &'static str = "https://tasks.arcgisonline.com/ArcGIS/rest/services/Geometry/GeometryServer";
+const DEFAULT_FEATURE_LAYER_URL: &str = "https://services.arcgis.com/P3ePLMYs2RVChkJx/arcgis/rest/services/World_Cities/FeatureServer/0";
 const DEFAULT_BUFFER_DISTANCE_M: i32 = 500_000;
 
 #[tokio::main]
 async fn main() {
-    let reqwest_client = Client::new();
+    let client = Client::new();
     println!("Buffer and Query Demo");
+    let args: Vec<String> = env::args().skip(1).collect();
+    // --local buffers without calling the geometry service.
+    let geometry_service = if args.iter().any(|arg| "--local" == arg) {
+        GeometryService::local(&client)
+    } else {
+        GeometryService::world(&client)
+    };
+    // --cache <path> queries a local copy of the default layer, so it works offline too.
+    let cache = match args.iter().position(|arg| "--cache" == arg).and_then(|index| args.get(index + 1)) {
+        Some(path) => open_cache(&client, Path::new(path)).await,
+        None => None,
+    };
+    // --geometry <WKT, hex WKB or Esri JSON> buffers that instead of asking for a location.
+    let geometry = match args.iter().position(|arg| "--geometry" == arg).and_then(|index| args.get(index + 1)) {
+        Some(text) => match parse_geometry(text) {
+            Ok(geometry) => Some(geometry),
+            Err(err) => {
+                println!("Error: could not read the geometry: {:?}", err);
+                return;
+            }
+        },
+        None => None,
+    };
     loop {
-        main_loop(&reqwest_client).await;
-        let command = read_from_console("Type 'exit' to exit or Enter to repeat");
-        if "exit" == command {
-            break;
+        main_loop(&client, &geometry_service, cache.as_ref(), geometry.as_ref()).await;
+        match read_line_from_console("Type 'exit' to exit or Enter to repeat") {
+            Some(command) if "exit" != command => {},
+            _ => break,
+        }
+    }
+}
+
+/// Opens the cache at `path` and refreshes it, or creates it from the default layer.
+async fn open_cache(client: &Client, path: &Path) -> Option<FeatureCache> {
+    if !path.exists() {
+        println!("Downloading {} to {}...", DEFAULT_FEATURE_LAYER_URL, path.display());
+        return match FeatureCache::create(path, &FeatureLayer::new(client, DEFAULT_FEATURE_LAYER_URL)).await {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                println!("Error: could not create the cache: {:?}", err);
+                None
+            }
+        };
+    }
+    match FeatureCache::open(path) {
+        Ok(mut cache) => {
+            if let Err(err) = cache.refresh(client).await {
+                println!("Could not refresh the cache, so using it as it is: {:?}", err);
+            }
+            Some(cache)
+        },
+        Err(err) => {
+            println!("Error: could not open the cache: {:?}", err);
+            None
         }
     }
 }
 
-async fn main_loop(reqwest_client: &reqwest::Client) {
-    let lon: f64 = read_from_console("Longitude:").parse().unwrap();
-    let lat: f64 = read_from_console("Latitude:").parse().unwrap();
+async fn main_loop(
+    client: &Client,
+    geometry_service: &GeometryService,
+    cache: Option<&FeatureCache>,
+    geometry: Option<&(Geometry, SpatialReference)>,
+) {
+    let (geometry, spatial_reference) = match geometry {
+        Some((geometry, spatial_reference)) => (geometry.clone(), spatial_reference.clone()),
+        None => match read_location(&Geocoder::world(client)).await {
+            Some(location) => (Geometry::Point(location), SpatialReference::wgs84()),
+            None => return,
+        },
+    };
     let mut url: String = read_from_console(format!("Feature layer URL:\n\t(Default: {} )", DEFAULT_FEATURE_LAYER_URL).as_str());
-    if "" == url {
+    if url.is_empty() {
         url = String::from(DEFAULT_FEATURE_LAYER_URL);
     }
     let buffer_distance: f64 = read_from_console(format!("Buffer distance in meters (default is {}):", DEFAULT_BUFFER_DISTANCE_M).as_str()).parse().unwrap_or(DEFAULT_BUFFER_DISTANCE_M.into());
     let dir: String = read_from_console("Direction: (n | s | e | w; default is all)");
-    let geometry = object!{"x" => lon, "y" => lat};
-    buffer_and_query(
-        &reqwest_client,
-        "esriGeometryPoint",
-        &geometry,
-        buffer_distance,
-        url.as_str(),
-        dir.as_str()
-    ).await;
+    let save_path: String = read_from_console("Save to file (optional: .csv, .ewkb.csv, .geojson or .gpkg):");
+    let options = BufferQueryOptions {
+        geometry: &geometry,
+        spatial_reference: &spatial_reference,
+        cache,
+        buffer_distance_m: buffer_distance,
+        feature_layer_url: url.as_str(),
+        direction: dir.as_str(),
+        save_path: save_path.as_str(),
+    };
+    buffer_and_query(client, geometry_service, &options).await;
 }
 
-async fn buffer_and_query(
-    client: &reqwest::Client,
-    geometry_type: &str,
-    geometry: &JsonValue,
+/// What to query around, and what to do with the features found.
+struct BufferQueryOptions<'a> {
+    geometry: &'a Geometry,
+    spatial_reference: &'a SpatialReference,
+    /// A local copy of the layer to query instead, if it's the layer asked for.
+    cache: Option<&'a FeatureCache>,
     buffer_distance_m: f64,
-    feature_layer_url: &str,
-    direction: &str,
+    feature_layer_url: &'a str,
+    /// `n`, `s`, `e` or `w`, or anything else for all directions.
+    direction: &'a str,
+    /// Where to save the features, or empty not to save them.
+    save_path: &'a str,
+}
+
+async fn buffer_and_query(
+    client: &Client,
+    geometry_service: &GeometryService,
+    options: &BufferQueryOptions<'_>,
 ) {
-    match buffer(client, geometry_type, geometry, &buffer_distance_m).await {
-        Ok(response) => {
-            match query_features(client, Some("0=0"), Some("esriGeometryPolygon"), Some(response), feature_layer_url).await {
-                Ok(query_response) => {
-                    let response_count = query_response.len();
-                    println!("There are {} features inside the buffer.", response_count);
-                    let features_in_direction = filter_by_direction(
-                        query_response.members(),
-                        geometry["x"].to_string().parse().unwrap(),
-                        geometry["y"].to_string().parse().unwrap(),
-                        direction
-                    );
-                    if ["n", "s", "e", "w"].contains(&direction) {
-                        println!("Of those, there are {} features in the right direction.", features_in_direction.len());
-                    }
-                    for feature in features_in_direction {
-                        println!("\t{}\t{}", feature["attributes"]["CITY_NAME"], feature["geometry"]);
-                    }
-                },
+    let BufferQueryOptions { geometry, spatial_reference, cache, buffer_distance_m, feature_layer_url, direction, save_path } =
+        *options;
+    // Directions are measured from the middle of the geometry.
+    let from_point = match geometry.envelope() {
+        Some(extent) => {
+            let center = Point::new((extent.xmin + extent.xmax) / 2.0, (extent.ymin + extent.ymax) / 2.0);
+            match center.project(spatial_reference, &SpatialReference::wgs84()) {
+                Ok(center) => center,
                 Err(err) => {
                     println!("Error: {:?}", err);
+                    return;
                 }
             }
         },
+        None => {
+            println!("Error: the geometry is empty");
+            return;
+        }
+    };
+    let buffer = geometry_service.buffer(
+        geometry,
+        spatial_reference,
+        buffer_distance_m,
+        GeometryEngine::Auto
+    ).await;
+    let query = match buffer {
+        Ok(buffer) => Query::new()
+            .filter(WhereClause::all())
+            .geometry(Geometry::Polygon(buffer), spatial_reference.clone()),
         Err(err) => {
             println!("Error: {:?}", err);
+            return;
         }
+    };
+    let mut results = Results {
+        from_point: &from_point,
+        direction,
+        save_path,
+        response_count: 0,
+        direction_count: 0,
+        writer: None,
+        spatial_reference: None,
+        held: Vec::new(),
+    };
+    match cache {
+        Some(cache) if cache.url() == feature_layer_url.trim_end_matches('/') => match cache.query(&query) {
+            Ok(feature_set) => {
+                if !feature_set.features.is_empty() && results.start(&feature_set) {
+                    for feature in &feature_set.features {
+                        if !results.add(feature) {
+                            break;
+                        }
+                    }
+                }
+            },
+            Err(err) => {
+                println!("Error: {:?}", err);
+                return;
+            }
+        },
+        // Features are printed as they arrive, so the counts come at the end.
+        _ => match FeatureLayer::new(client, feature_layer_url).query_stream(&query).await {
+            Ok(mut features) => {
+                while let Some(feature) = features.next().await {
+                    match feature {
+                        Ok(feature) => {
+                            // The schema is known once the first feature has arrived.
+                            if results.spatial_reference.is_none() && !results.start(&features.metadata()) {
+                                break;
+                            }
+                            if !results.add(&feature) {
+                                break;
+                            }
+                        },
+                        Err(err) => {
+                            println!("Error: {:?}", err);
+                            break;
+                        }
+                    }
+                }
+            },
+            Err(err) => {
+                println!("Error: {:?}", err);
+                return;
+            }
+        },
     }
+    results.finish();
 }
 
-async fn buffer(
-    client: &reqwest::Client,
-    geometry_type: &str,
-    geometry: &JsonValue,
-    buffer_distance_m: &f64
-) -> BoxResult<JsonValue> {
-    let geometries: String = json::stringify(object!{
-        "geometryType" => geometry_type,
-        "geometries" => array![ json::parse(geometry.to_string().as_str()).unwrap() ]
-    });
-    let distance_string = buffer_distance_m.to_string();
-    let mut params = HashMap::new();
-    params.insert("f", "json");
-    params.insert("geometries", geometries.as_str());
-    params.insert("inSR", "4326");
-    params.insert("outSR", "4326");
-    params.insert("distances", distance_string.as_str());
-    params.insert("unit", "9001"); // meters
-    params.insert("geodesic", "true");
+/// Counts, prints and saves the features a query finds. Without a direction they're handled
+/// one at a time; with one, they're held until the query ends and then sorted nearest first.
+struct Results<'a> {
+    from_point: &'a Point,
+    direction: &'a str,
+    save_path: &'a str,
+    response_count: u32,
+    direction_count: u32,
+    writer: Option<Box<dyn FeatureWriter>>,
+    // The layer's spatial reference, once the schema is known.
+    spatial_reference: Option<SpatialReference>,
+    held: Vec<Feature>,
+}
 
-    match client.get(format!("{}/buffer", GEOMETRY_SERVICE_URL).as_str()).query(&params).send().await {
-        Ok(response) => {
-            match response.text().await {
-                Ok(text) => Ok(json::parse(text.as_str()).unwrap().take()["geometries"].take()[0].take()),
-                Err(err) => Err(Box::new(err)),
+impl Results<'_> {
+    /// Gets ready for features with the given schema. Returns `false` if that failed.
+    fn start(&mut self, metadata: &FeatureSet) -> bool {
+        self.spatial_reference = Some(metadata.spatial_reference.clone().unwrap_or_else(SpatialReference::wgs84));
+        if !self.save_path.is_empty() {
+            match create_writer(Path::new(self.save_path), metadata) {
+                Ok(created) => self.writer = Some(created),
+                Err(err) => {
+                    println!("Error: could not save to {}: {:?}", self.save_path, err);
+                    return false;
+                }
             }
-        },
-        Err(err) => Err(Box::new(err)),
+        }
+        true
     }
-}
 
-async fn query_features(
-    client: &reqwest::Client,
-    where_clause: Option<&str>,
-    geometry_type: Option<&str>,
-    geometry: Option<JsonValue>,
-    feature_layer_url: &str
-) -> BoxResult<JsonValue> {
-    let mut params = HashMap::new();
-    params.insert("f", "json");
-    params.insert("outFields", "*");
-    params.insert("outSR", "4326");
-    params.insert("where", match where_clause {
-        Some(_where_clause) => _where_clause,
-        _ => "0=0"
-    });
-    let stringified_geometry;
-    match geometry_type {
-        Some(_geometry_type) => {
-            match geometry {
-                Some(_geometry) => {
-                    stringified_geometry = json::stringify(_geometry);
-                    params.insert("geometry", stringified_geometry.as_str());
-                    params.insert("geometryType", _geometry_type);
-                    params.insert("inSR", "4326");
-                },
-                _ => {}
-            }
-        },
-        _ => {}
+    /// Prints and saves a feature, or holds it back if there's a direction to sort by. Returns
+    /// `false` if saving it failed.
+    fn add(&mut self, feature: &Feature) -> bool {
+        self.response_count += 1;
+        if sector(self.direction).is_some() {
+            self.held.push(feature.clone());
+            return true;
+        }
+        self.output(feature)
     }
 
-    // Semantically, GET makes more sense than POST. However, the buffer string might be
-    // too long for a GET.
-    match client.post(format!("{}/query", feature_layer_url).as_str()).form(&params).send().await {
-        Ok(response) => {
-            match response.text().await {
-                Ok(text) => Ok(json::parse(text.as_str()).unwrap().take()["features"].take()),
-                Err(err) => Err(Box::new(err)),
+    fn output(&mut self, feature: &Feature) -> bool {
+        self.direction_count += 1;
+        if let Some(writer) = self.writer.as_mut() {
+            if let Err(err) = writer.write_feature(feature) {
+                println!("Error: could not save to {}: {:?}", self.save_path, err);
+                return false;
             }
-        },
-        Err(err) => Err(Box::new(err)),
+        }
+        println!("\t{}\t{}", feature.attributes["CITY_NAME"], feature.geometry.as_ref().map(Geometry::to_json).unwrap_or(JsonValue::Null));
+        true
     }
-}
 
-fn filter_by_direction(
-    input_features: Members,
-    from_lon: f64,
-    from_lat: f64,
-    direction: &str,
-) -> Array {
-
-    let from_point = Point::new(from_lon, from_lat);
-    input_features.filter(|feature| {
-        let geometry = &feature["geometry"];
-        let geometry_x = geometry["x"].as_f64().unwrap();
-        let geometry_y = geometry["y"].as_f64().unwrap();
-        let mut bearing = from_point.bearing(Point::new(geometry_x, geometry_y));
-        while bearing < 0.0 {
-            bearing += 360.0;
+    fn finish(mut self) {
+        if let Some((start, end)) = sector(self.direction) {
+            let feature_set = FeatureSet {
+                spatial_reference: self.spatial_reference.clone(),
+                features: std::mem::take(&mut self.held),
+                ..FeatureSet::default()
+            };
+            match SpatialIndex::new(feature_set) {
+                Ok(index) => {
+                    for (feature, _) in index.in_sector(self.from_point, start, end, None) {
+                        if !self.output(feature) {
+                            break;
+                        }
+                    }
+                },
+                Err(err) => println!("Error: {:?}", err),
+            }
         }
-        while bearing > 360.0 {
-            bearing -= 360.0;
+        if let Some(mut writer) = self.writer {
+            match writer.finish() {
+                Ok(()) => println!("Saved {} features to {}", self.direction_count, self.save_path),
+                Err(err) => println!("Error: could not save to {}: {:?}", self.save_path, err),
+            }
         }
-        match direction {
-            "n" => bearing <= 45.0 || bearing >= 315.0,
-            "e" => bearing >= 45.0 && bearing <= 135.0,
-            "s" => bearing >= 135.0 && bearing <= 225.0,
-            "w" => bearing >= 225.0 && bearing <= 315.0,
-            _ => true
+        println!("There are {} features inside the buffer.", self.response_count);
+        if sector(self.direction).is_some() {
+            println!("Of those, there are {} features in the right direction.", self.direction_count);
         }
-    }).cloned().collect()
+    }
+}
+
+/// The range of bearings, clockwise from north, for a compass direction.
+fn sector(direction: &str) -> Option<(f64, f64)> {
+    match direction {
+        "n" => Some((315.0, 45.0)),
+        "e" => Some((45.0, 135.0)),
+        "s" => Some((135.0, 225.0)),
+        "w" => Some((225.0, 315.0)),
+        _ => None,
+    }
 }
 
-fn read_from_console(prompt: &str) -> String {
-    println!("{}", prompt);
-    let mut value = String::new();
-    io::stdin()
-        .read_line(&mut value)
-        .expect("Failed to read line");
-    String::from(value.trim())
+/// Reads a geometry given as WKT or EWKT, hex WKB or EWKB, or Esri JSON, whose curves are
+/// densified. The spatial reference is WGS 1984 unless the geometry says otherwise.
+fn parse_geometry(text: &str) -> BoxResult<(Geometry, SpatialReference)> {
+    let text = text.trim();
+    let (geometry, spatial_reference) = if text.starts_with('{') {
+        let value = json::parse(text)?;
+        let spatial_reference = SpatialReference::from_json(&value["spatialReference"]);
+        // About a meter, in degrees or in the units of a projected coordinate system.
+        let max_deviation = match spatial_reference.as_ref().map_or(Some(4326), SpatialReference::effective_wkid) {
+            Some(4326) => 0.00001,
+            _ => 1.0,
+        };
+        let geometry = Geometry::from_json_densified(&value, max_deviation).ok_or("not an Esri JSON geometry")?;
+        (geometry, spatial_reference)
+    } else if text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        Geometry::from_hex_ewkb(text)?
+    } else {
+        Geometry::from_ewkt(text)?
+    };
+    Ok((geometry, spatial_reference.unwrap_or_else(SpatialReference::wgs84)))
 }
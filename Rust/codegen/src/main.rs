@@ -0,0 +1,57 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::process;
+use std::result::Result;
+
+use quarenta::{Client, FeatureLayer, LayerInfo};
+
+type BoxResult<T> = Result<T,Box<dyn Error>>;
+
+const USAGE: &str = r#"Usage: codegen <layer URL or JSON file> <struct name> [output file]
+
+Writes a Rust struct for the layer's attributes, with serde renames, Options for nullable
+fields and enums for coded-value domains. The layer can be a REST URL such as
+https://.../FeatureServer/0 or a file holding the layer's JSON description."#;
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    let layer = &args[1];
+    let struct_name = &args[2];
+
+    let info = match read_layer_info(layer).await {
+        Ok(info) => info,
+        Err(err) => {
+            eprintln!("Could not read the layer description: {:?}", err);
+            process::exit(1);
+        }
+    };
+    let code = format!(
+        "// Generated by codegen from {}. Edits will be lost if it's regenerated.\n\n{}",
+        layer,
+        quarenta::rust_struct(&info, struct_name)
+    );
+    match args.get(3) {
+        Some(output) => {
+            if let Err(err) = fs::write(output, code) {
+                eprintln!("Could not write {}: {:?}", output, err);
+                process::exit(1);
+            }
+        },
+        None => print!("{}", code),
+    }
+}
+
+async fn read_layer_info(layer: &str) -> BoxResult<LayerInfo> {
+    if layer.starts_with("http://") || layer.starts_with("https://") {
+        let info = FeatureLayer::new(&Client::new(), layer).describe().await?;
+        Ok((*info).clone())
+    } else {
+        Ok(LayerInfo::from_json(json::parse(&fs::read_to_string(layer)?)?))
+    }
+}
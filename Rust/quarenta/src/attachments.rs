@@ -0,0 +1,170 @@
+//! Feature attachments: listing, uploading, replacing, deleting and downloading them.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use json::JsonValue;
+use reqwest::multipart::{Form, Part};
+use tokio::io::AsyncWriteExt;
+
+use crate::edit::{edit_results, EditResult};
+use crate::feature_layer::FeatureLayer;
+use crate::request::{ArcGisError, LimitedResponse, RequestOptions};
+use crate::BoxResult;
+
+/// The description of one attachment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttachmentInfo {
+    pub id: u64,
+    pub global_id: Option<String>,
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    pub keywords: Option<String>,
+}
+
+impl AttachmentInfo {
+    pub fn from_json(value: &JsonValue) -> Option<AttachmentInfo> {
+        Some(AttachmentInfo {
+            id: value["id"].as_u64()?,
+            global_id: value["globalId"].as_str().map(String::from),
+            name: value["name"].as_str().unwrap_or("").to_string(),
+            content_type: value["contentType"].as_str().unwrap_or("").to_string(),
+            size: value["size"].as_u64().unwrap_or(0),
+            keywords: value["keywords"].as_str().map(String::from),
+        })
+    }
+}
+
+impl FeatureLayer {
+    /// Lists the attachments of the given features, grouped by the object ID of the feature
+    /// they belong to. `definition_expression` further limits which features are considered.
+    pub async fn query_attachments(
+        &self,
+        object_ids: &[u64],
+        definition_expression: Option<&str>,
+    ) -> BoxResult<HashMap<u64, Vec<AttachmentInfo>>> {
+        let object_ids: Vec<String> = object_ids.iter().map(ToString::to_string).collect();
+        let mut params = vec![("objectIds", object_ids.join(","))];
+        if let Some(definition_expression) = definition_expression {
+            params.push(("definitionExpression", definition_expression.to_string()));
+        }
+        let response = self
+            .client()
+            .get_json(&format!("{}/queryAttachments", self.url()), &params, &RequestOptions::default())
+            .await?;
+        let mut groups = HashMap::new();
+        for group in response["attachmentGroups"].members() {
+            if let Some(parent_object_id) = group["parentObjectId"].as_u64() {
+                let infos = group["attachmentInfos"]
+                    .members()
+                    .filter_map(AttachmentInfo::from_json)
+                    .collect();
+                groups.insert(parent_object_id, infos);
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Uploads a file as a new attachment of a feature.
+    pub async fn add_attachment(&self, object_id: u64, path: &Path) -> BoxResult<EditResult> {
+        let form = self.attachment_form(path).await?;
+        let response = self
+            .send_multipart(&format!("{}/{}/addAttachment", self.url(), object_id), form)
+            .await?;
+        Ok(EditResult::from_json(&response["addAttachmentResult"]))
+    }
+
+    /// Replaces the file of an existing attachment.
+    pub async fn update_attachment(&self, object_id: u64, attachment_id: u64, path: &Path) -> BoxResult<EditResult> {
+        let form = self
+            .attachment_form(path)
+            .await?
+            .text("attachmentId", attachment_id.to_string());
+        let response = self
+            .send_multipart(&format!("{}/{}/updateAttachment", self.url(), object_id), form)
+            .await?;
+        Ok(EditResult::from_json(&response["updateAttachmentResult"]))
+    }
+
+    /// Deletes attachments of a feature and returns one result per attachment.
+    pub async fn delete_attachments(&self, object_id: u64, attachment_ids: &[u64]) -> BoxResult<Vec<EditResult>> {
+        let attachment_ids: Vec<String> = attachment_ids.iter().map(ToString::to_string).collect();
+        let response = self
+            .client()
+            .post_json(
+                &format!("{}/{}/deleteAttachments", self.url(), object_id),
+                &[("attachmentIds", attachment_ids.join(","))],
+                &RequestOptions::default(),
+            )
+            .await?;
+        Ok(edit_results(&response["deleteAttachmentResults"]))
+    }
+
+    /// Starts downloading an attachment. Read the body with `chunk` to stream it
+    /// rather than holding it all in memory, or use [`FeatureLayer::save_attachment`].
+    pub async fn download_attachment(&self, object_id: u64, attachment_id: u64) -> BoxResult<LimitedResponse> {
+        let client = self.client();
+        let url = format!("{}/{}/attachments/{}", self.url(), object_id, attachment_id);
+        let request = client.http().get(&url).query(&client.auth_params());
+        let response = client.send(request, &RequestOptions::default()).await?;
+        if !response.status().is_success() {
+            return Err(format!("attachment download failed with HTTP status {}", response.status()).into());
+        }
+        Ok(response)
+    }
+
+    /// Downloads an attachment to a file, a chunk at a time.
+    pub async fn save_attachment(&self, object_id: u64, attachment_id: u64, path: &Path) -> BoxResult<u64> {
+        let mut response = self.download_attachment(object_id, attachment_id).await?;
+        let mut file = tokio::fs::File::create(path).await?;
+        let mut written = 0;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        file.flush().await?;
+        Ok(written)
+    }
+
+    async fn attachment_form(&self, path: &Path) -> BoxResult<Form> {
+        let bytes = tokio::fs::read(path).await?;
+        let file_name = path
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from("attachment"));
+        let part = Part::bytes(bytes)
+            .file_name(file_name)
+            .mime_str(content_type_for(path))?;
+        Ok(Form::new().part("attachment", part))
+    }
+
+    /// Sends a multipart upload once, since a file body can't be replayed for a retry.
+    async fn send_multipart(&self, url: &str, mut form: Form) -> BoxResult<JsonValue> {
+        let client = self.client();
+        for (name, value) in client.params_with_auth(&[], "json") {
+            form = form.text(name.to_string(), value);
+        }
+        let request = client.http().post(url).multipart(form);
+        ArcGisError::check(client.send_json(request, &RequestOptions::no_retry()).await?)
+    }
+}
+
+/// Guesses a MIME type from a file extension, for the common attachment types.
+fn content_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "tif" | "tiff" => "image/tiff",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        "zip" => "application/zip",
+        "mp4" => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,209 @@
+//! Change tracking: `extractChanges` and a cursor that remembers where the last pull stopped.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use json::JsonValue;
+use serde::de::DeserializeOwned;
+
+use crate::feature_service::FeatureService;
+use crate::geometry::Geometry;
+use crate::replica::{layer_server_gens, LayerChanges};
+use crate::request::RequestOptions;
+use crate::typed::GeometryValue;
+use crate::util::write_replacing;
+use crate::BoxResult;
+
+/// The result of `extractChanges`: each layer's changes and the generations to ask from next
+/// time.
+#[derive(Clone, Debug, Default)]
+pub struct ExtractedChanges {
+    pub changes: Vec<LayerChanges>,
+    pub layer_server_gens: BTreeMap<u32, i64>,
+}
+
+impl FeatureService {
+    /// The current server generation of each layer with change tracking enabled, from the
+    /// service's `changeTrackingInfo`.
+    pub async fn layer_server_gens(&self) -> BoxResult<BTreeMap<u32, i64>> {
+        let info = self.describe().await?;
+        if !info["changeTrackingInfo"].is_object() {
+            return Err("the service does not have change tracking enabled".into());
+        }
+        Ok(layer_server_gens(&info["changeTrackingInfo"]["layerServerGens"]))
+    }
+
+    /// Fetches the inserts, updates and deletes made to each layer since the generation given
+    /// for it in `since`.
+    pub async fn extract_changes(&self, since: &BTreeMap<u32, i64>) -> BoxResult<ExtractedChanges> {
+        let layers: Vec<String> = since.keys().map(ToString::to_string).collect();
+        let server_gens: Vec<JsonValue> = since
+            .iter()
+            .map(|(id, server_gen)| json::object! { "id" => *id, "serverGen" => *server_gen })
+            .collect();
+        let params = [
+            ("layers", format!("[{}]", layers.join(","))),
+            ("layerServerGens", JsonValue::Array(server_gens).dump()),
+            ("returnInserts", String::from("true")),
+            ("returnUpdates", String::from("true")),
+            ("returnDeletes", String::from("true")),
+            ("returnIdsOnly", String::from("false")),
+            ("returnExtentOnly", String::from("false")),
+            ("returnAttachments", String::from("false")),
+            ("dataFormat", String::from("json")),
+            ("transportType", String::from("esriTransportTypeEmbedded")),
+        ];
+        let response = self
+            .client()
+            .post_json(&format!("{}/extractChanges", self.url()), &params, &RequestOptions::default())
+            .await?;
+        let mut next_gens = since.clone();
+        next_gens.extend(layer_server_gens(&response["layerServerGens"]));
+        Ok(ExtractedChanges {
+            changes: response["edits"]
+                .members()
+                .filter_map(LayerChanges::from_json)
+                .filter(|changes| !changes.is_empty())
+                .collect(),
+            layer_server_gens: next_gens,
+        })
+    }
+}
+
+/// The server generation each layer was last pulled at, saved in a JSON file so incremental
+/// pulls carry on from the previous run.
+///
+/// A pull doesn't move the cursor: apply the changes, then [`PendingChanges::commit`] them. If
+/// the program stops in between, the next run pulls the same changes again rather than losing
+/// them.
+///
+/// # Examples
+///
+/// ```no_run
+/// # async fn example(service: quarenta::FeatureService) -> Result<(), Box<dyn std::error::Error>> {
+/// let mut cursor = quarenta::ChangeCursor::open(std::path::Path::new("incidents.cursor.json"), &[0]).await?;
+/// let pending = cursor.pull(&service).await?;
+/// for changes in &pending.changes {
+///     println!("layer {}: {} inserts, {} updates, {} deletes",
+///         changes.layer_id, changes.adds.len(), changes.updates.len(), changes.delete_ids.len());
+/// }
+/// pending.commit().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ChangeCursor {
+    path: PathBuf,
+    layers: Vec<u32>,
+    layer_server_gens: BTreeMap<u32, i64>,
+}
+
+impl ChangeCursor {
+    /// Opens the cursor saved at `path` for the given layers, or starts a new one if there's no
+    /// file yet.
+    pub async fn open(path: &Path, layers: &[u32]) -> BoxResult<ChangeCursor> {
+        let layer_server_gens = match tokio::fs::read_to_string(path).await {
+            Ok(text) => layer_server_gens(&json::parse(&text)?["layerServerGens"]),
+            Err(err) if std::io::ErrorKind::NotFound == err.kind() => BTreeMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(ChangeCursor {
+            path: path.to_path_buf(),
+            layers: layers.to_vec(),
+            layer_server_gens,
+        })
+    }
+
+    /// The generation each layer was last pulled at. Layers that haven't been pulled yet are
+    /// missing.
+    pub fn layer_server_gens(&self) -> &BTreeMap<u32, i64> {
+        &self.layer_server_gens
+    }
+
+    /// Fetches what changed since the last committed pull.
+    ///
+    /// A layer's first pull only records its current generation and returns no changes for
+    /// it, since `extractChanges` can't return a layer's whole history. Do a full download
+    /// alongside the first pull, and later pulls pick up from there.
+    pub async fn pull(&mut self, service: &FeatureService) -> BoxResult<PendingChanges<'_>> {
+        if self.layers.iter().any(|id| !self.layer_server_gens.contains_key(id)) {
+            let current = service.layer_server_gens().await?;
+            let mut next_gens = self.layer_server_gens.clone();
+            for id in &self.layers {
+                if !next_gens.contains_key(id) {
+                    let server_gen = current
+                        .get(id)
+                        .ok_or_else(|| format!("layer {} does not have change tracking enabled", id))?;
+                    next_gens.insert(*id, *server_gen);
+                }
+            }
+            return Ok(PendingChanges {
+                changes: Vec::new(),
+                cursor: self,
+                layer_server_gens: next_gens,
+            });
+        }
+        let since: BTreeMap<u32, i64> = self
+            .layers
+            .iter()
+            .filter_map(|id| Some((*id, *self.layer_server_gens.get(id)?)))
+            .collect();
+        let extracted = service.extract_changes(&since).await?;
+        let mut next_gens = self.layer_server_gens.clone();
+        next_gens.extend(extracted.layer_server_gens);
+        Ok(PendingChanges {
+            changes: extracted.changes,
+            cursor: self,
+            layer_server_gens: next_gens,
+        })
+    }
+
+    /// Like [`ChangeCursor::pull`], with the adds and updates as `Feature<A, G>`.
+    pub async fn pull_as<A: DeserializeOwned, G: GeometryValue>(
+        &mut self,
+        service: &FeatureService,
+    ) -> BoxResult<PendingChanges<'_, A, G>> {
+        self.pull(service).await?.into_typed()
+    }
+
+    async fn save(&self) -> BoxResult<()> {
+        let server_gens: Vec<JsonValue> = self
+            .layer_server_gens
+            .iter()
+            .map(|(id, server_gen)| json::object! { "id" => *id, "serverGen" => *server_gen })
+            .collect();
+        let value = json::object! { "layerServerGens" => JsonValue::Array(server_gens) };
+        write_replacing(&self.path, value.dump().as_bytes()).await
+    }
+}
+
+/// Changes from [`ChangeCursor::pull`] that haven't been committed yet. Dropping this without
+/// committing leaves the cursor where it was.
+pub struct PendingChanges<'a, A = JsonValue, G = Geometry> {
+    pub changes: Vec<LayerChanges<A, G>>,
+    cursor: &'a mut ChangeCursor,
+    layer_server_gens: BTreeMap<u32, i64>,
+}
+
+impl<'a> PendingChanges<'a> {
+    /// Converts the changes with [`LayerChanges::into_typed`].
+    pub fn into_typed<A: DeserializeOwned, G: GeometryValue>(self) -> BoxResult<PendingChanges<'a, A, G>> {
+        Ok(PendingChanges {
+            changes: self.changes.into_iter().map(LayerChanges::into_typed).collect::<BoxResult<_>>()?,
+            cursor: self.cursor,
+            layer_server_gens: self.layer_server_gens,
+        })
+    }
+}
+
+impl<A, G> PendingChanges<'_, A, G> {
+    /// Advances the cursor past these changes and saves it. Call this once they've been
+    /// applied.
+    pub async fn commit(self) -> BoxResult<()> {
+        let previous = std::mem::replace(&mut self.cursor.layer_server_gens, self.layer_server_gens);
+        if let Err(err) = self.cursor.save().await {
+            self.cursor.layer_server_gens = previous;
+            return Err(err);
+        }
+        Ok(())
+    }
+}
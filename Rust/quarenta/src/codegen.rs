@@ -0,0 +1,421 @@
+//! Generating Rust attribute structs from layer descriptions.
+//!
+//! The generated code derives `serde::Deserialize` and `serde::Serialize`, so it works with
+//! [`FeatureLayer::query_as`] and [`FeatureLayer::add_features_as`]. The crate using it needs
+//! `serde` with the `derive` feature, and `chrono` and `uuid` if the layer has date or GUID
+//! fields, which are read and written with [`crate::as_attribute`].
+//!
+//! [`FeatureLayer::query_as`]: crate::FeatureLayer::query_as
+//! [`FeatureLayer::add_features_as`]: crate::FeatureLayer::add_features_as
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use json::JsonValue;
+
+use crate::domain::{CodedValue, Domain};
+use crate::feature_layer::LayerInfo;
+use crate::field::{Field, FieldType};
+
+/// Keywords that a member name is written as a raw identifier in place of.
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "box", "break", "const", "continue", "dyn", "else", "enum", "extern", "false", "fn", "for",
+    "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "priv", "pub", "ref", "return", "static",
+    "struct", "trait", "true", "try", "type", "unsafe", "use", "where", "while", "yield",
+];
+
+/// Keywords that can't be raw identifiers, so a member or type name gets an underscore after it
+/// instead.
+const RESERVED: &[&str] = &["crate", "self", "super", "Self"];
+
+/// Generates a Rust struct named `name` for a layer's attributes, plus an enum for each field
+/// with a coded-value domain and for the subtype field.
+///
+/// Nullable fields become `Option`s, field names that aren't snake case get a
+/// `#[serde(rename)]`, and geometry, blob and raster fields are left out. Date fields become
+/// `chrono` types and GUID fields `uuid::Uuid`s.
+///
+/// # Examples
+///
+/// ```
+/// let info = quarenta::LayerInfo::from_json(json::parse(r#"{
+///     "name": "Cities",
+///     "fields": [
+///         { "name": "FID", "type": "esriFieldTypeOID", "nullable": false },
+///         { "name": "population", "type": "esriFieldTypeInteger", "nullable": true }
+///     ]
+/// }"#).unwrap());
+/// let code = quarenta::rust_struct(&info, "City");
+/// assert!(code.contains("pub population: Option<i32>,"));
+/// ```
+pub fn rust_struct(info: &LayerInfo, name: &str) -> String {
+    let mut code = String::new();
+    let mut enums = String::new();
+    let mut enum_names = HashSet::new();
+    enum_names.insert(name.to_string());
+
+    writeln!(code, "/// The attributes of the {} layer.", info.name).unwrap();
+    writeln!(code, "#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]").unwrap();
+    writeln!(code, "pub struct {} {{", name).unwrap();
+    let mut member_names = HashSet::new();
+    for field in &info.fields {
+        let attribute_type = attribute_type(&field.field_type);
+        let rust_type = match attribute_type.or_else(|| scalar_type(&field.field_type)) {
+            Some(rust_type) => rust_type.to_string(),
+            None => continue,
+        };
+        let coded_values = info.coded_values(&field.name).filter(|_| attribute_type.is_none());
+        let rust_type = match coded_values {
+            Some(coded_values) if !coded_values.is_empty() => {
+                let enum_name = unique(type_name(&enum_source_name(field)), &mut enum_names);
+                enums.push('\n');
+                enums.push_str(&coded_value_enum(&enum_name, field, &rust_type, &coded_values));
+                enum_name
+            }
+            _ => rust_type,
+        };
+        let member = unique(member_name(&field.name), &mut member_names);
+        if let Some(alias) = field.alias.as_ref().filter(|alias| *alias != &field.name) {
+            writeln!(code, "    /// {}", alias).unwrap();
+        }
+        let optional = field.nullable && FieldType::Oid != field.field_type;
+        if member.trim_start_matches("r#") != field.name {
+            writeln!(code, "    #[serde(rename = {})]", JsonValue::from(field.name.as_str()).dump()).unwrap();
+        }
+        if attribute_type.is_some() {
+            if optional {
+                writeln!(code, "    #[serde(default, with = \"quarenta::as_attribute::option\")]").unwrap();
+            } else {
+                writeln!(code, "    #[serde(with = \"quarenta::as_attribute\")]").unwrap();
+            }
+        }
+        if optional {
+            writeln!(code, "    pub {}: Option<{}>,", member, rust_type).unwrap();
+        } else {
+            writeln!(code, "    pub {}: {},", member, rust_type).unwrap();
+        }
+    }
+    code.push_str("}\n");
+    code.push_str(&enums);
+    code
+}
+
+/// The Rust type of a field's values that serde can take as they are, or `None` for fields
+/// that need [`attribute_type`] or aren't attributes.
+fn scalar_type(field_type: &FieldType) -> Option<&'static str> {
+    match field_type {
+        FieldType::SmallInteger => Some("i16"),
+        FieldType::Integer => Some("i32"),
+        FieldType::BigInteger | FieldType::Oid => Some("i64"),
+        FieldType::Single => Some("f32"),
+        FieldType::Double => Some("f64"),
+        FieldType::String | FieldType::Xml => Some("String"),
+        _ => None,
+    }
+}
+
+/// The [`crate::AttributeValue`] type of a field's values, for fields that are read and
+/// written with [`crate::as_attribute`].
+fn attribute_type(field_type: &FieldType) -> Option<&'static str> {
+    match field_type {
+        FieldType::Date => Some("chrono::DateTime<chrono::Utc>"),
+        FieldType::DateOnly => Some("chrono::NaiveDate"),
+        FieldType::TimeOnly => Some("chrono::NaiveTime"),
+        FieldType::TimestampOffset => Some("chrono::DateTime<chrono::FixedOffset>"),
+        FieldType::Guid | FieldType::GlobalId => Some("uuid::Uuid"),
+        _ => None,
+    }
+}
+
+/// Generates an enum for coded values. Numeric codes are (de)serialized through the field's
+/// numeric type, since serde's derive would otherwise expect the variant names.
+fn coded_value_enum(name: &str, field: &Field, rust_type: &str, coded_values: &[CodedValue]) -> String {
+    let mut code = String::new();
+    let mut variants = HashSet::new();
+    let variants: Vec<(String, &CodedValue)> = coded_values
+        .iter()
+        .map(|coded_value| {
+            let variant = if coded_value.name.is_empty() {
+                format!("Code{}", coded_value.code.to_string().replace('-', "Minus"))
+            } else {
+                type_name(&coded_value.name)
+            };
+            (unique(variant, &mut variants), coded_value)
+        })
+        .collect();
+    let numeric = "String" != rust_type;
+
+    writeln!(code, "/// The coded values of {}.", field.name).unwrap();
+    writeln!(code, "#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]").unwrap();
+    if numeric {
+        writeln!(code, "#[serde(try_from = \"{0}\", into = \"{0}\")]", rust_type).unwrap();
+    }
+    writeln!(code, "pub enum {} {{", name).unwrap();
+    for (variant, coded_value) in &variants {
+        if !coded_value.name.is_empty() && coded_value.name != *variant {
+            writeln!(code, "    /// {}", coded_value.name).unwrap();
+        }
+        if !numeric {
+            writeln!(code, "    #[serde(rename = {})]", coded_value.code.dump()).unwrap();
+        }
+        writeln!(code, "    {},", variant).unwrap();
+    }
+    code.push_str("}\n");
+    if numeric {
+        writeln!(code, "\nimpl std::convert::TryFrom<{}> for {} {{", rust_type, name).unwrap();
+        writeln!(code, "    type Error = String;\n").unwrap();
+        writeln!(code, "    fn try_from(code: {}) -> Result<Self, Self::Error> {{", rust_type).unwrap();
+        writeln!(code, "        match code {{").unwrap();
+        for (variant, coded_value) in &variants {
+            writeln!(code, "            {} => Ok({}::{}),", numeric_pattern(&coded_value.code, rust_type), name, variant).unwrap();
+        }
+        writeln!(code, "            _ => Err(format!(\"{{}} is not a {} code\", code)),", name).unwrap();
+        writeln!(code, "        }}\n    }}\n}}").unwrap();
+        writeln!(code, "\nimpl From<{}> for {} {{", name, rust_type).unwrap();
+        writeln!(code, "    fn from(value: {}) -> Self {{", name).unwrap();
+        writeln!(code, "        match value {{").unwrap();
+        for (variant, coded_value) in &variants {
+            writeln!(code, "            {}::{} => {},", name, variant, numeric_literal(&coded_value.code, rust_type)).unwrap();
+        }
+        writeln!(code, "        }}\n    }}\n}}").unwrap();
+    }
+    code
+}
+
+/// A code as a literal of the field's type.
+fn numeric_literal(code: &JsonValue, rust_type: &str) -> String {
+    // `as_i64` doesn't fail for fractions, so only trust it for whole numbers.
+    let text = match (code.as_i64(), code.as_f64()) {
+        (Some(integer), Some(number)) if 0.0 == number.fract() => integer.to_string(),
+        (_, Some(number)) => number.to_string(),
+        _ => code.to_string(),
+    };
+    if rust_type.starts_with('f') {
+        format!("{}{}", text, rust_type)
+    } else {
+        text
+    }
+}
+
+/// A code as a match pattern. Float literals can't be patterns, so those use a guard.
+fn numeric_pattern(code: &JsonValue, rust_type: &str) -> String {
+    if rust_type.starts_with('f') {
+        format!("x if x == {}", numeric_literal(code, rust_type))
+    } else {
+        numeric_literal(code, rust_type)
+    }
+}
+
+fn enum_source_name(field: &Field) -> String {
+    match &field.domain {
+        Some(domain) if !domain.name().is_empty() && matches!(domain, Domain::CodedValue { .. }) => {
+            domain.name().to_string()
+        }
+        _ => field.name.clone(),
+    }
+}
+
+/// Splits a name into words at non-alphanumerics and lower-to-upper case changes.
+fn words(name: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut previous_lower = false;
+    for c in name.chars() {
+        if !c.is_ascii_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            previous_lower = false;
+            continue;
+        }
+        if c.is_ascii_uppercase() && previous_lower && !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+        previous_lower = c.is_ascii_lowercase() || c.is_ascii_digit();
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn member_name(name: &str) -> String {
+    let mut member = words(name)
+        .iter()
+        .map(|word| word.to_ascii_lowercase())
+        .collect::<Vec<_>>()
+        .join("_");
+    if member.is_empty() || member.starts_with(|c: char| c.is_ascii_digit()) {
+        member.insert_str(0, "field_");
+    }
+    if RESERVED.contains(&member.as_str()) {
+        member.push('_');
+    } else if KEYWORDS.contains(&member.as_str()) {
+        member.insert_str(0, "r#");
+    }
+    member
+}
+
+fn type_name(name: &str) -> String {
+    let mut type_name: String = words(name)
+        .iter()
+        .map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().map(|c| c.to_ascii_uppercase()).unwrap_or_default();
+            let rest: String = if word.chars().all(|c| !c.is_ascii_lowercase()) {
+                chars.as_str().to_ascii_lowercase()
+            } else {
+                chars.as_str().to_string()
+            };
+            format!("{}{}", first, rest)
+        })
+        .collect();
+    if type_name.is_empty() || type_name.starts_with(|c: char| c.is_ascii_digit()) {
+        type_name.insert(0, 'V');
+    }
+    if RESERVED.contains(&type_name.as_str()) {
+        type_name.push('_');
+    }
+    type_name
+}
+
+/// Appends a number to a name until it's unused.
+fn unique(name: String, used: &mut HashSet<String>) -> String {
+    let mut candidate = name.clone();
+    let mut suffix = 2;
+    while !used.insert(candidate.clone()) {
+        candidate = format!("{}{}", name, suffix);
+        suffix += 1;
+    }
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer_info() -> LayerInfo {
+        LayerInfo::from_json(json::object! {
+            "name" => "Inspections",
+            "fields" => json::array![
+                json::object! { "name" => "OBJECTID", "type" => "esriFieldTypeOID", "nullable" => false },
+                json::object! { "name" => "InspectorName", "type" => "esriFieldTypeString", "alias" => "Inspector" },
+                json::object! { "name" => "visits", "type" => "esriFieldTypeInteger", "nullable" => false },
+                json::object! { "name" => "type", "type" => "esriFieldTypeString" },
+                json::object! { "name" => "self", "type" => "esriFieldTypeString" },
+                json::object! { "name" => "2nd", "type" => "esriFieldTypeDouble" },
+                json::object! {
+                    "name" => "STATUS",
+                    "type" => "esriFieldTypeSmallInteger",
+                    "domain" => json::object! {
+                        "type" => "codedValue",
+                        "name" => "InspectionStatus",
+                        "codedValues" => json::array![
+                            json::object! { "name" => "Passed", "code" => 1 },
+                            json::object! { "name" => "Failed inspection", "code" => -2 }
+                        ]
+                    }
+                },
+                json::object! {
+                    "name" => "kind",
+                    "type" => "esriFieldTypeString",
+                    "nullable" => false,
+                    "domain" => json::object! {
+                        "type" => "codedValue",
+                        "name" => "",
+                        "codedValues" => json::array![
+                            json::object! { "name" => "Hydrant", "code" => "HYD" },
+                            json::object! { "name" => "Valve", "code" => "VLV" }
+                        ]
+                    }
+                },
+                json::object! { "name" => "Inspected", "type" => "esriFieldTypeDate" },
+                json::object! { "name" => "DueDate", "type" => "esriFieldTypeDateOnly", "nullable" => false },
+                json::object! { "name" => "GlobalID", "type" => "esriFieldTypeGlobalID", "nullable" => false },
+                json::object! { "name" => "Shape", "type" => "esriFieldTypeGeometry" }
+            ]
+        })
+    }
+
+    #[test]
+    fn generates_a_struct() {
+        let code = rust_struct(&layer_info(), "Inspection");
+        assert!(code.starts_with("/// The attributes of the Inspections layer.\n"));
+        assert!(code.contains("pub struct Inspection {\n"));
+        assert!(code.contains("    #[serde(rename = \"OBJECTID\")]\n    pub objectid: i64,\n"));
+        assert!(code.contains("    /// Inspector\n    #[serde(rename = \"InspectorName\")]\n    pub inspector_name: Option<String>,\n"));
+        assert!(code.contains("    pub visits: i32,\n"));
+        assert!(code.contains("    #[serde(rename = \"2nd\")]\n    pub field_2nd: Option<f64>,\n"));
+        assert!(code.contains("    #[serde(rename = \"STATUS\")]\n    pub status: Option<InspectionStatus>,\n"));
+        assert!(code.contains("    pub kind: Kind,\n"));
+        assert!(!code.contains("Shape"));
+    }
+
+    #[test]
+    fn escapes_keywords() {
+        let code = rust_struct(&layer_info(), "Inspection");
+        assert!(code.contains("    pub r#type: Option<String>,\n"));
+        assert!(code.contains("    #[serde(rename = \"self\")]\n    pub self_: Option<String>,\n"));
+        assert!(!code.contains("r#self"));
+        assert_eq!(member_name("super"), "super_");
+        assert_eq!(member_name("Crate"), "crate_");
+        assert_eq!(member_name("Match"), "r#match");
+        assert_eq!(type_name("self"), "Self_");
+    }
+
+    #[test]
+    fn uses_chrono_and_uuid_types() {
+        let code = rust_struct(&layer_info(), "Inspection");
+        assert!(code.contains(
+            "    #[serde(rename = \"Inspected\")]\n    #[serde(default, with = \"quarenta::as_attribute::option\")]\n    pub inspected: Option<chrono::DateTime<chrono::Utc>>,\n"
+        ));
+        assert!(code.contains(
+            "    #[serde(rename = \"DueDate\")]\n    #[serde(with = \"quarenta::as_attribute\")]\n    pub due_date: chrono::NaiveDate,\n"
+        ));
+        assert!(code.contains("    pub global_id: uuid::Uuid,\n"));
+    }
+
+    #[test]
+    fn generates_numeric_coded_value_enums() {
+        let code = rust_struct(&layer_info(), "Inspection");
+        assert!(code.contains("#[serde(try_from = \"i16\", into = \"i16\")]\npub enum InspectionStatus {\n"));
+        assert!(code.contains("    Passed,\n    /// Failed inspection\n    FailedInspection,\n}\n"));
+        assert!(code.contains("impl std::convert::TryFrom<i16> for InspectionStatus {"));
+        assert!(code.contains("            1 => Ok(InspectionStatus::Passed),\n            -2 => Ok(InspectionStatus::FailedInspection),\n"));
+        assert!(code.contains("            _ => Err(format!(\"{} is not a InspectionStatus code\", code)),"));
+        assert!(code.contains("impl From<InspectionStatus> for i16 {"));
+        assert!(code.contains("            InspectionStatus::FailedInspection => -2,\n"));
+
+        let rating = Field::new("rating", FieldType::Double);
+        let coded_values = vec![
+            CodedValue { code: 0.5.into(), name: String::from("Half") },
+            CodedValue { code: (-1).into(), name: String::new() },
+        ];
+        let code = coded_value_enum("Rating", &rating, "f64", &coded_values);
+        assert!(code.contains("            x if x == 0.5f64 => Ok(Rating::Half),\n"));
+        assert!(code.contains("            Rating::CodeMinus1 => -1f64,\n"));
+    }
+
+    #[test]
+    fn generates_string_coded_value_enums() {
+        let code = rust_struct(&layer_info(), "Inspection");
+        assert!(code.contains("pub enum Kind {\n    #[serde(rename = \"HYD\")]\n    Hydrant,\n    #[serde(rename = \"VLV\")]\n    Valve,\n}\n"));
+        assert!(!code.contains("TryFrom<String>"));
+    }
+
+    #[test]
+    fn names_types_and_members() {
+        assert_eq!(words("parcelID_2020"), vec!["parcel", "ID", "2020"]);
+        assert_eq!(member_name("Parcel ID"), "parcel_id");
+        assert_eq!(member_name("__"), "field_");
+        assert_eq!(type_name("ROAD_CLASS"), "RoadClass");
+        assert_eq!(type_name("roadClass"), "RoadClass");
+        assert_eq!(type_name("4wd"), "V4wd");
+
+        let mut used = HashSet::new();
+        assert_eq!(unique(String::from("name"), &mut used), "name");
+        assert_eq!(unique(String::from("name"), &mut used), "name2");
+        assert_eq!(unique(String::from("name"), &mut used), "name3");
+    }
+}
@@ -0,0 +1,41 @@
+//! Prompts for the command-line samples: lines typed at the console, and locations typed as
+//! "longitude, latitude" or an address.
+
+use std::io;
+
+use crate::geocode::Geocoder;
+use crate::geometry::Point;
+
+/// Prints `prompt` and reads a line, trimmed. Returns an empty string at the end of input.
+pub fn read_from_console(prompt: &str) -> String {
+    read_line_from_console(prompt).unwrap_or_default()
+}
+
+/// Like [`read_from_console`], but returns `None` at the end of input.
+pub fn read_line_from_console(prompt: &str) -> Option<String> {
+    println!("{}", prompt);
+    let mut value = String::new();
+    match io::stdin()
+        .read_line(&mut value)
+        .expect("Failed to read line")
+    {
+        0 => None,
+        _ => Some(String::from(value.trim())),
+    }
+}
+
+/// Reads a location typed as "longitude, latitude", or as an address or place name to
+/// geocode, asking again until one is found. Returns `None` at the end of input.
+pub async fn read_location(geocoder: &Geocoder) -> Option<Point> {
+    loop {
+        let text = read_line_from_console("Location (address, place name, or longitude, latitude):")?;
+        if text.is_empty() {
+            continue;
+        }
+        match geocoder.locate(&text).await {
+            Ok(Some(location)) => return Some(location),
+            Ok(None) => println!("Could not find {}", text),
+            Err(err) => println!("Could not geocode {}: {:?}", text, err),
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Esri JSON curves: `curvePaths` and `curveRings` with circular arcs, elliptic arcs and cubic
+//! Bézier curves, densified into the straight segments every other format uses.
+
+use std::f64::consts::TAU;
+
+use json::JsonValue;
+
+use crate::geometry::{vertex_from_json, Geometry, Point, Polygon, Polyline};
+
+/// The most segments a curve becomes, however small the allowed deviation.
+const MAX_CURVE_STEPS: usize = 1024;
+
+impl Geometry {
+    /// Parses an Esri JSON geometry like [`Geometry::from_json`], which ignores curves, but
+    /// also reads `curvePaths` and `curveRings`, replacing each curve with enough straight
+    /// segments that none strays more than `max_deviation` from it, in the geometry's units.
+    /// Z and M values are interpolated along each curve.
+    ///
+    /// ```
+    /// # use quarenta::Geometry;
+    /// let arc = json::parse(r#"{"curvePaths": [[[0, 0], {"c": [[2, 0], [1, 1]]}]]}"#).unwrap();
+    /// if let Some(Geometry::Polyline(polyline)) = Geometry::from_json_densified(&arc, 0.01) {
+    ///     println!("The arc became {} vertices", polyline.paths[0].len());
+    /// }
+    /// ```
+    pub fn from_json_densified(value: &JsonValue, max_deviation: f64) -> Option<Geometry> {
+        let has_z = value["hasZ"].as_bool().unwrap_or(false);
+        let has_m = value["hasM"].as_bool().unwrap_or(false);
+        let parts = |key: &str| -> Option<Vec<Vec<Point>>> {
+            value[key]
+                .members()
+                .map(|part| densify_part(part, has_z, has_m, max_deviation))
+                .collect()
+        };
+        if value["curvePaths"].is_array() {
+            return Some(Geometry::Polyline(Polyline {
+                paths: parts("curvePaths")?,
+            }));
+        }
+        if value["curveRings"].is_array() {
+            return Some(Geometry::Polygon(Polygon {
+                rings: parts("curveRings")?,
+            }));
+        }
+        Geometry::from_json(value)
+    }
+}
+
+/// A curve path or ring as vertices. Each element is either a vertex or a curve from the
+/// previous vertex: `{"c": [end, interior]}`, `{"a": [end, center, minor, clockwise,
+/// rotation, semiMajor, ratio]}` or `{"b": [end, control1, control2]}`.
+fn densify_part(part: &JsonValue, has_z: bool, has_m: bool, max_deviation: f64) -> Option<Vec<Point>> {
+    let mut points: Vec<Point> = Vec::new();
+    for element in part.members() {
+        if element.is_array() {
+            points.push(vertex_from_json(element, has_z, has_m)?);
+            continue;
+        }
+        let start = *points.last()?;
+        let segment = if element["c"].is_array() {
+            let end = vertex_from_json(&element["c"][0], has_z, has_m)?;
+            circular_arc(&start, &xy(&element["c"][1])?, &end, max_deviation)
+        } else if element["a"].is_array() {
+            elliptic_arc(&start, &element["a"], has_z, has_m, max_deviation)?
+        } else if element["b"].is_array() {
+            let b = &element["b"];
+            let end = vertex_from_json(&b[0], has_z, has_m)?;
+            cubic_bezier(&start, &xy(&b[1])?, &xy(&b[2])?, &end, max_deviation)
+        } else {
+            return None;
+        };
+        points.extend(segment);
+    }
+    Some(points)
+}
+
+fn xy(value: &JsonValue) -> Option<Point> {
+    Some(Point::new(value[0].as_f64()?, value[1].as_f64()?))
+}
+
+/// The circular arc from `start` through `interior` to `end`, without `start`.
+fn circular_arc(start: &Point, interior: &Point, end: &Point, max_deviation: f64) -> Vec<Point> {
+    let (a, b, c) = (start, interior, end);
+    let center = if a.x == c.x && a.y == c.y {
+        // A full circle, with the interior point opposite the start.
+        Point::new((a.x + b.x) / 2.0, (a.y + b.y) / 2.0)
+    } else {
+        let d = 2.0 * (a.x * (b.y - c.y) + b.x * (c.y - a.y) + c.x * (a.y - b.y));
+        if 0.0 == d || !d.is_finite() {
+            // The three points are in a line.
+            return vec![*end];
+        }
+        let (a2, b2, c2) = (a.x * a.x + a.y * a.y, b.x * b.x + b.y * b.y, c.x * c.x + c.y * c.y);
+        Point::new(
+            (a2 * (b.y - c.y) + b2 * (c.y - a.y) + c2 * (a.y - b.y)) / d,
+            (a2 * (c.x - b.x) + b2 * (a.x - c.x) + c2 * (b.x - a.x)) / d,
+        )
+    };
+    let radius = (a.x - center.x).hypot(a.y - center.y);
+    let angle = |point: &Point| (point.y - center.y).atan2(point.x - center.x);
+    let start_angle = angle(start);
+    let counterclockwise = (angle(end) - start_angle).rem_euclid(TAU);
+    // The arc goes whichever way passes the interior point. A closed arc, with the interior
+    // point opposite, goes clockwise as Esri rings do.
+    let sweep = if (angle(interior) - start_angle).rem_euclid(TAU) < counterclockwise {
+        counterclockwise
+    } else {
+        counterclockwise - TAU
+    };
+    let circle = Ellipse {
+        center,
+        semi_major: radius,
+        semi_minor: radius,
+        rotation: 0.0,
+    };
+    circle.arc(start_angle, sweep, start, end, max_deviation)
+}
+
+/// An `"a"` arc: around `center`, clockwise or not, on a circle through `start` or, with a
+/// rotation, semi-major axis and ratio, on that ellipse. The minor flag is implied by the
+/// center and direction, so it's not needed.
+fn elliptic_arc(start: &Point, a: &JsonValue, has_z: bool, has_m: bool, max_deviation: f64) -> Option<Vec<Point>> {
+    let end = vertex_from_json(&a[0], has_z, has_m)?;
+    let center = xy(&a[1])?;
+    let clockwise = a[3].as_f64().is_some_and(|clockwise| 0.0 != clockwise) || Some(true) == a[3].as_bool();
+    let rotation = a[4].as_f64().unwrap_or(0.0);
+    let semi_major = a[5]
+        .as_f64()
+        .unwrap_or_else(|| (start.x - center.x).hypot(start.y - center.y));
+    let semi_minor = semi_major * a[6].as_f64().unwrap_or(1.0);
+    if 0.0 == semi_major || 0.0 == semi_minor {
+        return Some(vec![end]);
+    }
+    // Each end's angle on the ellipse before it's stretched and rotated.
+    let angle = |point: &Point| {
+        let (sin, cos) = (-rotation).sin_cos();
+        let (x, y) = (point.x - center.x, point.y - center.y);
+        ((x * sin + y * cos) / semi_minor).atan2((x * cos - y * sin) / semi_major)
+    };
+    let start_angle = angle(start);
+    let counterclockwise = (angle(&end) - start_angle).rem_euclid(TAU);
+    let counterclockwise = if 0.0 == counterclockwise { TAU } else { counterclockwise };
+    let sweep = if clockwise { counterclockwise - TAU } else { counterclockwise };
+    let sweep = if 0.0 == sweep { -TAU } else { sweep };
+    let ellipse = Ellipse {
+        center,
+        semi_major,
+        semi_minor,
+        rotation,
+    };
+    Some(ellipse.arc(start_angle, sweep, start, &end, max_deviation))
+}
+
+/// An ellipse, or with equal axes a circle, rotated counterclockwise by `rotation` radians.
+struct Ellipse {
+    center: Point,
+    semi_major: f64,
+    semi_minor: f64,
+    rotation: f64,
+}
+
+impl Ellipse {
+    /// Points along the ellipse from `start_angle`, turning `sweep` radians, counterclockwise
+    /// if positive. The last point is `end` exactly.
+    fn arc(&self, start_angle: f64, sweep: f64, start: &Point, end: &Point, max_deviation: f64) -> Vec<Point> {
+        // A chord across an angle θ of a circle strays r(1 - cos(θ/2)) from it; the semi-major
+        // axis is the largest radius an ellipse has.
+        let steps = if max_deviation.is_nan() || max_deviation <= 0.0 {
+            MAX_CURVE_STEPS
+        } else {
+            let max_angle = 2.0 * (1.0 - (max_deviation / self.semi_major).min(1.0)).acos();
+            ((sweep.abs() / max_angle).ceil() as usize).clamp(1, MAX_CURVE_STEPS)
+        };
+        let (sin, cos) = self.rotation.sin_cos();
+        (1..=steps)
+            .map(|step| {
+                if step == steps {
+                    return *end;
+                }
+                let t = step as f64 / steps as f64;
+                let angle = start_angle + sweep * t;
+                let (x, y) = (self.semi_major * angle.cos(), self.semi_minor * angle.sin());
+                Point {
+                    x: self.center.x + x * cos - y * sin,
+                    y: self.center.y + x * sin + y * cos,
+                    z: interpolate(start.z, end.z, t),
+                    m: interpolate(start.m, end.m, t),
+                }
+            })
+            .collect()
+    }
+}
+
+/// The cubic Bézier curve from `start` to `end`, without `start`.
+fn cubic_bezier(start: &Point, control1: &Point, control2: &Point, end: &Point, max_deviation: f64) -> Vec<Point> {
+    // Chords across 1/n of the curve stray at most 3/4 of the control points' largest
+    // second difference over n².
+    let second_difference = |a: &Point, b: &Point, c: &Point| (a.x - 2.0 * b.x + c.x).hypot(a.y - 2.0 * b.y + c.y);
+    let bend = second_difference(start, control1, control2).max(second_difference(control1, control2, end));
+    let steps = if max_deviation.is_nan() || max_deviation <= 0.0 {
+        MAX_CURVE_STEPS
+    } else {
+        ((0.75 * bend / max_deviation).sqrt().ceil() as usize).clamp(1, MAX_CURVE_STEPS)
+    };
+    (1..=steps)
+        .map(|step| {
+            if step == steps {
+                return *end;
+            }
+            let t = step as f64 / steps as f64;
+            let u = 1.0 - t;
+            let (w0, w1, w2, w3) = (u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t);
+            Point {
+                x: w0 * start.x + w1 * control1.x + w2 * control2.x + w3 * end.x,
+                y: w0 * start.y + w1 * control1.y + w2 * control2.y + w3 * end.y,
+                z: interpolate(start.z, end.z, t),
+                m: interpolate(start.m, end.m, t),
+            }
+        })
+        .collect()
+}
+
+fn interpolate(from: Option<f64>, to: Option<f64>, t: f64) -> Option<f64> {
+    Some(from? + (to? - from?) * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(value: &str, max_deviation: f64) -> Vec<Point> {
+        match Geometry::from_json_densified(&json::parse(value).unwrap(), max_deviation) {
+            Some(Geometry::Polyline(mut polyline)) => polyline.paths.remove(0),
+            other => panic!("expected a polyline, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn densifies_a_circular_arc_through_its_interior_point() {
+        // The upper half of the unit circle around (1, 0), from (0, 0) over (1, 1) to (2, 0).
+        let points = path(r#"{"curvePaths": [[[0, 0], {"c": [[2, 0], [1, 1]]}]]}"#, 0.001);
+        assert_eq!(Point::new(0.0, 0.0), points[0]);
+        assert_eq!(Point::new(2.0, 0.0), *points.last().unwrap());
+        assert!(points.len() > 10);
+        for point in &points {
+            assert!(((point.x - 1.0).hypot(point.y) - 1.0).abs() < 1e-9);
+            assert!(point.y >= -1e-9);
+        }
+        // Chords stray no further than asked.
+        for pair in points.windows(2) {
+            let middle = Point::new((pair[0].x + pair[1].x) / 2.0, (pair[0].y + pair[1].y) / 2.0);
+            assert!(1.0 - (middle.x - 1.0).hypot(middle.y) <= 0.001);
+        }
+        // Through (1, -1) instead, it's the lower half.
+        let points = path(r#"{"curvePaths": [[[0, 0], {"c": [[2, 0], [1, -1]]}]]}"#, 0.001);
+        assert!(points.iter().all(|point| point.y <= 1e-9));
+    }
+
+    #[test]
+    fn densifies_elliptic_arcs() {
+        // A quarter circle counterclockwise around the origin, from (1, 0) to (0, 1).
+        let points = path(r#"{"curvePaths": [[[1, 0], {"a": [[0, 1], [0, 0], 0, 0]}]]}"#, 0.01);
+        assert!(points.iter().all(|point| (point.x.hypot(point.y) - 1.0).abs() < 1e-9 && point.x >= -1e-9 && point.y >= -1e-9));
+        // Clockwise, it goes the long way round.
+        let points = path(r#"{"curvePaths": [[[1, 0], {"a": [[0, 1], [0, 0], 0, 1]}]]}"#, 0.01);
+        assert!(points.iter().any(|point| point.x < -0.9));
+        // An ellipse twice as wide as it is high.
+        let points = path(r#"{"curvePaths": [[[2, 0], {"a": [[-2, 0], [0, 0], 0, 0, 0, 2, 0.5]}]]}"#, 0.01);
+        for point in &points {
+            assert!(((point.x / 2.0).powi(2) + point.y.powi(2) - 1.0).abs() < 1e-9);
+        }
+        assert!(points.iter().any(|point| (point.y - 1.0).abs() < 0.01));
+    }
+
+    #[test]
+    fn densifies_a_bezier_curve_with_z() {
+        let value = r#"{"hasZ": true, "curvePaths": [[[0, 0, 10], {"b": [[3, 0, 40], [1, 2], [2, 2]]}]]}"#;
+        let points = path(value, 0.01);
+        assert_eq!(Some(10.0), points[0].z);
+        assert_eq!(Point { x: 3.0, y: 0.0, z: Some(40.0), m: None }, *points.last().unwrap());
+        // Symmetric controls put the top of the curve at 1.5, 1.5.
+        let top = points.iter().map(|point| point.y).fold(0.0, f64::max);
+        assert!(top <= 1.5 && 1.5 - top < 0.01, "{}", top);
+        // Z goes evenly from one end to the other.
+        for pair in points.windows(2) {
+            assert!(pair[1].z.unwrap() > pair[0].z.unwrap());
+        }
+    }
+
+    #[test]
+    fn densifies_curve_rings() {
+        // A full circle: the interior point is opposite the start, which is also the end.
+        let value = r#"{"curveRings": [[[0, 0], {"c": [[0, 0], [2, 0]]}]]}"#;
+        match Geometry::from_json_densified(&json::parse(value).unwrap(), 0.01) {
+            Some(Geometry::Polygon(polygon)) => {
+                let ring = &polygon.rings[0];
+                assert_eq!(ring[0], *ring.last().unwrap());
+                assert!(ring.iter().any(|point| (point.x - 2.0).abs() < 0.01));
+                // Clockwise, as Esri outer rings are.
+                assert!(ring[1].y > 0.0);
+            }
+            other => panic!("expected a polygon, got {:?}", other),
+        }
+        // Without curves it's the same as from_json.
+        let plain = json::parse(r#"{"rings": [[[0, 0], [0, 1], [1, 1], [0, 0]]]}"#).unwrap();
+        assert_eq!(Geometry::from_json(&plain), Geometry::from_json_densified(&plain, 0.01));
+    }
+}
@@ -0,0 +1,502 @@
+//! Attribute domains and subtypes: decoding coded values and validating edits.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use json::JsonValue;
+
+use crate::feature::Feature;
+use crate::feature_layer::LayerInfo;
+
+/// One code of a coded-value domain and its label.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodedValue {
+    pub code: JsonValue,
+    pub name: String,
+}
+
+/// The values a field is allowed to take.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Domain {
+    /// A fixed list of codes, each with a label.
+    CodedValue { name: String, coded_values: Vec<CodedValue> },
+    /// An inclusive numeric range.
+    Range { name: String, min: f64, max: f64 },
+}
+
+impl Domain {
+    /// Parses a domain. Returns `None` for `inherited` and anything else that doesn't restrict
+    /// values by itself.
+    pub fn from_json(value: &JsonValue) -> Option<Domain> {
+        let name = value["name"].as_str().unwrap_or("").to_string();
+        match value["type"].as_str()? {
+            "codedValue" => Some(Domain::CodedValue {
+                name,
+                coded_values: value["codedValues"]
+                    .members()
+                    .map(|coded_value| CodedValue {
+                        code: coded_value["code"].clone(),
+                        name: coded_value["name"].as_str().unwrap_or("").to_string(),
+                    })
+                    .collect(),
+            }),
+            "range" => Some(Domain::Range {
+                name,
+                min: value["range"][0].as_f64()?,
+                max: value["range"][1].as_f64()?,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            Domain::CodedValue { name, coded_values } => json::object! {
+                "type" => "codedValue",
+                "name" => name.as_str(),
+                "codedValues" => JsonValue::Array(
+                    coded_values
+                        .iter()
+                        .map(|coded_value| json::object! {
+                            "name" => coded_value.name.as_str(),
+                            "code" => coded_value.code.clone()
+                        })
+                        .collect()
+                )
+            },
+            Domain::Range { name, min, max } => json::object! {
+                "type" => "range",
+                "name" => name.as_str(),
+                "range" => json::array![*min, *max]
+            },
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Domain::CodedValue { name, .. } | Domain::Range { name, .. } => name,
+        }
+    }
+
+    /// The label of a code, for a coded-value domain.
+    pub fn label(&self, code: &JsonValue) -> Option<&str> {
+        match self {
+            Domain::CodedValue { coded_values, .. } => coded_values
+                .iter()
+                .find(|coded_value| same_value(&coded_value.code, code))
+                .map(|coded_value| coded_value.name.as_str()),
+            Domain::Range { .. } => None,
+        }
+    }
+
+    /// The code with a label, ignoring case, for a coded-value domain.
+    pub fn code(&self, label: &str) -> Option<&JsonValue> {
+        match self {
+            Domain::CodedValue { coded_values, .. } => coded_values
+                .iter()
+                .find(|coded_value| coded_value.name.eq_ignore_ascii_case(label))
+                .map(|coded_value| &coded_value.code),
+            Domain::Range { .. } => None,
+        }
+    }
+
+    /// Whether the domain allows a value. Nulls are left to the field's `nullable`.
+    pub fn allows(&self, value: &JsonValue) -> bool {
+        if value.is_null() {
+            return true;
+        }
+        match self {
+            Domain::CodedValue { .. } => self.label(value).is_some(),
+            Domain::Range { min, max, .. } => value.as_f64().is_some_and(|value| *min <= value && value <= *max),
+        }
+    }
+}
+
+/// Whether two attribute values are the same, treating `1` and `1.0` as equal.
+fn same_value(a: &JsonValue, b: &JsonValue) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// A subtype (or feature type) of a layer: a value of the subtype field with its own name,
+/// defaults and domains.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subtype {
+    pub code: JsonValue,
+    pub name: String,
+    /// Domains that replace the field's own domain for features of this subtype.
+    pub domains: HashMap<String, Domain>,
+    pub default_values: JsonValue,
+}
+
+impl Subtype {
+    /// Parses an element of either `subtypes` (with `code`) or the older `types` (with `id`).
+    pub fn from_json(value: &JsonValue) -> Option<Subtype> {
+        let code = if value.has_key("code") { &value["code"] } else { &value["id"] };
+        if code.is_null() {
+            return None;
+        }
+        Some(Subtype {
+            code: code.clone(),
+            name: value["name"].as_str().unwrap_or("").to_string(),
+            domains: value["domains"]
+                .entries()
+                .filter_map(|(field, domain)| Some((field.to_string(), Domain::from_json(domain)?)))
+                .collect(),
+            default_values: if value["defaultValues"].is_object() {
+                value["defaultValues"].clone()
+            } else {
+                value["templates"][0]["prototype"]["attributes"].clone()
+            },
+        })
+    }
+}
+
+/// The subtype field and subtypes of a layer description, from `subtypeField` and `subtypes`
+/// or else `typeIdField` and `types`.
+pub(crate) fn subtypes_from_json(value: &JsonValue) -> (Option<String>, Vec<Subtype>) {
+    let (field, subtypes) = if value["subtypes"].is_array() {
+        (&value["subtypeField"], &value["subtypes"])
+    } else {
+        (&value["typeIdField"], &value["types"])
+    };
+    (
+        field.as_str().filter(|field| !field.is_empty()).map(String::from),
+        subtypes.members().filter_map(Subtype::from_json).collect(),
+    )
+}
+
+/// An attribute value that a layer's domains don't allow.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DomainViolation {
+    /// The index of the feature in the edit.
+    pub feature: usize,
+    pub field: String,
+    pub value: JsonValue,
+    /// The name of the domain, or the subtype field if the value isn't a known subtype.
+    pub domain: String,
+}
+
+/// The error returned when features fail validation before an edit is sent.
+#[derive(Clone, Debug)]
+pub struct ValidationError {
+    pub violations: Vec<DomainViolation>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} attribute value(s) not allowed by the layer's domains", self.violations.len())?;
+        for violation in &self.violations {
+            write!(
+                f,
+                "; feature {} field {} value {} ({})",
+                violation.feature, violation.field, violation.value, violation.domain
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ValidationError {}
+
+impl LayerInfo {
+    /// Finds a subtype by its code.
+    pub fn subtype(&self, code: &JsonValue) -> Option<&Subtype> {
+        self.subtypes.iter().find(|subtype| same_value(&subtype.code, code))
+    }
+
+    /// The domain that applies to a field of a feature with the given attributes: the
+    /// feature's subtype's domain for the field if it has one, otherwise the field's own.
+    pub fn domain(&self, field: &str, attributes: &JsonValue) -> Option<&Domain> {
+        let subtype_domain = self
+            .subtype_field
+            .as_ref()
+            .and_then(|subtype_field| self.subtype(attribute(attributes, subtype_field)?))
+            .and_then(|subtype| {
+                subtype
+                    .domains
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(field))
+                    .map(|(_, domain)| domain)
+            });
+        subtype_domain.or_else(|| self.field(field)?.domain.as_ref())
+    }
+
+    /// The allowed codes and labels of a field, for a coded-value domain or the subtype field.
+    pub fn coded_values(&self, field: &str) -> Option<Vec<CodedValue>> {
+        if self.is_subtype_field(field) && !self.subtypes.is_empty() {
+            return Some(
+                self.subtypes
+                    .iter()
+                    .map(|subtype| CodedValue {
+                        code: subtype.code.clone(),
+                        name: subtype.name.clone(),
+                    })
+                    .collect(),
+            );
+        }
+        match self.field(field)?.domain.as_ref()? {
+            Domain::CodedValue { coded_values, .. } => Some(coded_values.clone()),
+            Domain::Range { .. } => None,
+        }
+    }
+
+    /// Replaces coded values in a feature's attributes with their labels, including the
+    /// subtype field's value with the subtype's name. Other values are left alone.
+    pub fn decode(&self, feature: &mut Feature) {
+        let mut decoded = feature.attributes.clone();
+        for (field, value) in feature.attributes.entries() {
+            let label = if self.is_subtype_field(field) {
+                self.subtype(value).map(|subtype| subtype.name.as_str())
+            } else {
+                self.domain(field, &feature.attributes)
+                    .and_then(|domain| domain.label(value))
+            };
+            if let Some(label) = label {
+                decoded[field] = label.into();
+            }
+        }
+        feature.attributes = decoded;
+    }
+
+    /// Checks features' attributes against the layer's domains and subtypes. Attributes the
+    /// features don't have aren't checked, so partial updates validate too.
+    pub fn validate(&self, features: &[Feature]) -> Result<(), ValidationError> {
+        let mut violations = Vec::new();
+        for (index, feature) in features.iter().enumerate() {
+            for (field, value) in feature.attributes.entries() {
+                let domain = if self.is_subtype_field(field) && !self.subtypes.is_empty() {
+                    if value.is_null() || self.subtype(value).is_some() {
+                        continue;
+                    }
+                    field.to_string()
+                } else {
+                    match self.domain(field, &feature.attributes) {
+                        Some(domain) if !domain.allows(value) => domain.name().to_string(),
+                        _ => continue,
+                    }
+                };
+                violations.push(DomainViolation {
+                    feature: index,
+                    field: field.to_string(),
+                    value: value.clone(),
+                    domain,
+                });
+            }
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationError { violations })
+        }
+    }
+
+    fn is_subtype_field(&self, field: &str) -> bool {
+        self.subtype_field
+            .as_ref()
+            .is_some_and(|subtype_field| subtype_field.eq_ignore_ascii_case(field))
+    }
+}
+
+/// Looks up an attribute ignoring case, as ArcGIS does with field names.
+fn attribute<'a>(attributes: &'a JsonValue, field: &str) -> Option<&'a JsonValue> {
+    attributes
+        .entries()
+        .find(|(name, _)| name.eq_ignore_ascii_case(field))
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Water valves: a coded-value `STATUS`, a range `DIAMETER`, and subtypes on `VALVE_TYPE`
+    /// where gate valves have their own `STATUS` codes.
+    fn layer_info() -> LayerInfo {
+        LayerInfo::from_json(json::object! {
+            "name" => "Valves",
+            "subtypeField" => "VALVE_TYPE",
+            "fields" => json::array![
+                json::object! { "name" => "VALVE_TYPE", "type" => "esriFieldTypeSmallInteger" },
+                json::object! {
+                    "name" => "STATUS",
+                    "type" => "esriFieldTypeString",
+                    "domain" => json::object! {
+                        "type" => "codedValue",
+                        "name" => "ValveStatus",
+                        "codedValues" => json::array![
+                            json::object! { "name" => "Open", "code" => "O" },
+                            json::object! { "name" => "Closed", "code" => "C" }
+                        ]
+                    }
+                },
+                json::object! {
+                    "name" => "DIAMETER",
+                    "type" => "esriFieldTypeDouble",
+                    "domain" => json::object! { "type" => "range", "name" => "Diameter", "range" => json::array![2, 48] }
+                }
+            ],
+            "subtypes" => json::array![
+                json::object! {
+                    "code" => 1,
+                    "name" => "Gate",
+                    "defaultValues" => json::object! { "STATUS" => "O" },
+                    "domains" => json::object! {
+                        "status" => json::object! {
+                            "type" => "codedValue",
+                            "name" => "GateStatus",
+                            "codedValues" => json::array![
+                                json::object! { "name" => "Open", "code" => "O" },
+                                json::object! { "name" => "Partly open", "code" => "P" }
+                            ]
+                        },
+                        "DIAMETER" => json::object! { "type" => "inherited" }
+                    }
+                },
+                json::object! { "code" => 2, "name" => "Butterfly", "domains" => json::object! {} }
+            ]
+        })
+    }
+
+    fn feature(attributes: JsonValue) -> Feature {
+        Feature::new(attributes, None)
+    }
+
+    #[test]
+    fn parses_domains() {
+        let info = layer_info();
+        let status = info.field("STATUS").and_then(|field| field.domain.as_ref()).unwrap();
+        assert_eq!(status.name(), "ValveStatus");
+        assert_eq!(status.label(&"C".into()), Some("Closed"));
+        assert_eq!(status.code("closed"), Some(&JsonValue::from("C")));
+        assert_eq!(status.code("Ajar"), None);
+        assert_eq!(Domain::from_json(&status.to_json()).as_ref(), Some(status));
+
+        let diameter = info.field("DIAMETER").and_then(|field| field.domain.as_ref()).unwrap();
+        assert_eq!(diameter, &Domain::Range { name: String::from("Diameter"), min: 2.0, max: 48.0 });
+        assert_eq!(diameter.label(&2.into()), None);
+        assert_eq!(Domain::from_json(&diameter.to_json()).as_ref(), Some(diameter));
+
+        assert_eq!(Domain::from_json(&json::object! { "type" => "inherited" }), None);
+        assert_eq!(Domain::from_json(&JsonValue::Null), None);
+    }
+
+    #[test]
+    fn allows_values_in_domains() {
+        let coded = Domain::from_json(&json::object! {
+            "type" => "codedValue",
+            "name" => "Lanes",
+            "codedValues" => json::array![json::object! { "name" => "One", "code" => 1 }]
+        })
+        .unwrap();
+        assert!(coded.allows(&1.into()));
+        assert!(coded.allows(&1.0.into()));
+        assert!(!coded.allows(&2.into()));
+        assert!(coded.allows(&JsonValue::Null));
+
+        let range = Domain::Range { name: String::from("Diameter"), min: 2.0, max: 48.0 };
+        assert!(range.allows(&2.into()));
+        assert!(range.allows(&48.0.into()));
+        assert!(!range.allows(&48.5.into()));
+        assert!(!range.allows(&"10".into()));
+    }
+
+    #[test]
+    fn parses_subtypes() {
+        let info = layer_info();
+        assert_eq!(info.subtype_field.as_deref(), Some("VALVE_TYPE"));
+        assert_eq!(info.subtypes.len(), 2);
+        let gate = info.subtype(&1.0.into()).unwrap();
+        assert_eq!(gate.name, "Gate");
+        assert_eq!(gate.default_values["STATUS"], "O");
+        // Inherited domains aren't kept, so the field's own domain applies.
+        assert_eq!(gate.domains.len(), 1);
+
+        let (field, types) = subtypes_from_json(&json::object! {
+            "typeIdField" => "KIND",
+            "types" => json::array![
+                json::object! {
+                    "id" => "A",
+                    "name" => "Arterial",
+                    "templates" => json::array![json::object! { "prototype" => json::object! { "attributes" => json::object! { "LANES" => 4 } } }]
+                },
+                json::object! { "name" => "No code" }
+            ]
+        });
+        assert_eq!(field.as_deref(), Some("KIND"));
+        assert_eq!(types.len(), 1);
+        assert_eq!(types[0].code, "A");
+        assert_eq!(types[0].default_values["LANES"], 4);
+    }
+
+    #[test]
+    fn picks_the_subtypes_domain() {
+        let info = layer_info();
+        let gate = json::object! { "valve_type" => 1 };
+        let butterfly = json::object! { "VALVE_TYPE" => 2 };
+        assert_eq!(info.domain("STATUS", &gate).map(Domain::name), Some("GateStatus"));
+        assert_eq!(info.domain("STATUS", &butterfly).map(Domain::name), Some("ValveStatus"));
+        assert_eq!(info.domain("STATUS", &JsonValue::new_object()).map(Domain::name), Some("ValveStatus"));
+        assert_eq!(info.domain("DIAMETER", &gate).map(Domain::name), Some("Diameter"));
+        assert_eq!(info.domain("VALVE_TYPE", &gate), None);
+    }
+
+    #[test]
+    fn lists_coded_values() {
+        let info = layer_info();
+        let subtypes = info.coded_values("valve_type").unwrap();
+        assert_eq!(
+            subtypes,
+            vec![
+                CodedValue { code: 1.into(), name: String::from("Gate") },
+                CodedValue { code: 2.into(), name: String::from("Butterfly") },
+            ]
+        );
+        let statuses = info.coded_values("STATUS").unwrap();
+        assert_eq!(statuses.iter().map(|value| value.name.as_str()).collect::<Vec<_>>(), vec!["Open", "Closed"]);
+        assert_eq!(info.coded_values("DIAMETER"), None);
+        assert_eq!(info.coded_values("MISSING"), None);
+    }
+
+    #[test]
+    fn decodes_coded_values() {
+        let info = layer_info();
+        let mut gate = feature(json::object! { "VALVE_TYPE" => 1, "STATUS" => "P", "DIAMETER" => 12 });
+        info.decode(&mut gate);
+        assert_eq!(gate.attributes, json::object! { "VALVE_TYPE" => "Gate", "STATUS" => "Partly open", "DIAMETER" => 12 });
+
+        let mut butterfly = feature(json::object! { "VALVE_TYPE" => 2, "STATUS" => "P" });
+        info.decode(&mut butterfly);
+        assert_eq!(butterfly.attributes, json::object! { "VALVE_TYPE" => "Butterfly", "STATUS" => "P" });
+    }
+
+    #[test]
+    fn validates_features() {
+        let info = layer_info();
+        let valid = vec![
+            feature(json::object! { "VALVE_TYPE" => 1, "STATUS" => "P", "DIAMETER" => 12 }),
+            feature(json::object! { "VALVE_TYPE" => 2, "STATUS" => "C" }),
+            feature(json::object! { "STATUS" => JsonValue::Null }),
+        ];
+        assert!(info.validate(&valid).is_ok());
+
+        let invalid = vec![
+            feature(json::object! { "VALVE_TYPE" => 1, "STATUS" => "C" }),
+            feature(json::object! { "VALVE_TYPE" => 3, "DIAMETER" => 60 }),
+        ];
+        let err = info.validate(&invalid).unwrap_err();
+        let violations: Vec<(usize, &str, &str)> = err
+            .violations
+            .iter()
+            .map(|violation| (violation.feature, violation.field.as_str(), violation.domain.as_str()))
+            .collect();
+        assert_eq!(
+            violations,
+            vec![(0, "STATUS", "GateStatus"), (1, "VALVE_TYPE", "VALVE_TYPE"), (1, "DIAMETER", "Diameter")]
+        );
+        assert!(err.to_string().starts_with("3 attribute value(s) not allowed by the layer's domains; feature 0 field STATUS value C (GateStatus)"));
+    }
+}
@@ -0,0 +1,192 @@
+//! Editing features: `addFeatures`, `updateFeatures`, `deleteFeatures` and `applyEdits`.
+
+use std::sync::Arc;
+
+use json::JsonValue;
+
+use crate::feature::Feature;
+use crate::feature_layer::{FeatureLayer, LayerInfo};
+use crate::geometry::SpatialReference;
+use crate::replica::LayerEditResults;
+use crate::request::{ArcGisError, RequestOptions};
+use crate::BoxResult;
+
+/// The result of one edit, as returned in `addResults`, `updateResults`, `deleteResults`,
+/// `addAttachmentResult` and the like.
+#[derive(Clone, Debug)]
+pub struct EditResult {
+    pub object_id: Option<u64>,
+    pub global_id: Option<String>,
+    pub success: bool,
+    pub error: Option<ArcGisError>,
+}
+
+impl EditResult {
+    pub fn from_json(value: &JsonValue) -> EditResult {
+        let error = if value["error"].is_object() {
+            Some(ArcGisError {
+                code: value["error"]["code"].as_i32().unwrap_or(0),
+                message: value["error"]["description"]
+                    .as_str()
+                    .or_else(|| value["error"]["message"].as_str())
+                    .unwrap_or("")
+                    .to_string(),
+                details: Vec::new(),
+            })
+        } else {
+            None
+        };
+        EditResult {
+            object_id: value["objectId"].as_u64(),
+            global_id: value["globalId"].as_str().map(String::from),
+            success: value["success"].as_bool().unwrap_or(false),
+            error,
+        }
+    }
+}
+
+pub(crate) fn edit_results(value: &JsonValue) -> Vec<EditResult> {
+    value.members().map(EditResult::from_json).collect()
+}
+
+impl FeatureLayer {
+    /// Adds features and returns one result per feature, in order. Geometries are in
+    /// `spatial_reference`, or the layer's spatial reference if that's `None`.
+    ///
+    /// The features are checked against the layer's domains first, and nothing is sent if any
+    /// value isn't allowed; the error is a [`ValidationError`]. If the layer can't be described,
+    /// the features are sent unchecked and the server has the last word.
+    ///
+    /// This is never retried, since a retry after a lost response would add the features
+    /// twice.
+    ///
+    /// [`ValidationError`]: crate::ValidationError
+    pub async fn add_features(
+        &self,
+        features: &[Feature],
+        spatial_reference: Option<&SpatialReference>,
+    ) -> BoxResult<Vec<EditResult>> {
+        if let Some(info) = self.validation_info().await {
+            info.validate(features)?;
+        }
+        let response = self
+            .client()
+            .post_json(
+                &format!("{}/addFeatures", self.url()),
+                &[("features", features_to_json(features, spatial_reference).dump())],
+                &RequestOptions::no_retry(),
+            )
+            .await?;
+        Ok(edit_results(&response["addResults"]))
+    }
+
+    /// Updates features, matched by the object ID in their attributes. Geometries are in
+    /// `spatial_reference`, or the layer's spatial reference if that's `None`. Like
+    /// [`FeatureLayer::add_features`], the attributes being changed are validated first.
+    pub async fn update_features(
+        &self,
+        features: &[Feature],
+        spatial_reference: Option<&SpatialReference>,
+    ) -> BoxResult<Vec<EditResult>> {
+        if let Some(info) = self.validation_info().await {
+            info.validate(features)?;
+        }
+        let response = self
+            .client()
+            .post_json(
+                &format!("{}/updateFeatures", self.url()),
+                &[("features", features_to_json(features, spatial_reference).dump())],
+                &RequestOptions::default(),
+            )
+            .await?;
+        Ok(edit_results(&response["updateResults"]))
+    }
+
+    /// Deletes features by object ID.
+    pub async fn delete_features(&self, object_ids: &[u64]) -> BoxResult<Vec<EditResult>> {
+        let object_ids: Vec<String> = object_ids.iter().map(ToString::to_string).collect();
+        let response = self
+            .client()
+            .post_json(
+                &format!("{}/deleteFeatures", self.url()),
+                &[("objectIds", object_ids.join(","))],
+                &RequestOptions::default(),
+            )
+            .await?;
+        Ok(edit_results(&response["deleteResults"]))
+    }
+
+    /// Adds, updates and deletes features in one request. Adds and updates are validated like
+    /// [`FeatureLayer::add_features`].
+    ///
+    /// With `rollback_on_failure`, nothing is applied unless every edit succeeds; without it,
+    /// the edits that can be applied are, and the results say which weren't. Like adding, this
+    /// is never retried.
+    pub async fn apply_edits(
+        &self,
+        adds: &[Feature],
+        updates: &[Feature],
+        deletes: &[u64],
+        spatial_reference: Option<&SpatialReference>,
+        rollback_on_failure: bool,
+    ) -> BoxResult<LayerEditResults> {
+        let info = self.validation_info().await;
+        if let Some(info) = &info {
+            info.validate(adds)?;
+            info.validate(updates)?;
+        }
+        let mut params = vec![("rollbackOnFailure", rollback_on_failure.to_string())];
+        if !adds.is_empty() {
+            params.push(("adds", features_to_json(adds, spatial_reference).dump()));
+        }
+        if !updates.is_empty() {
+            params.push(("updates", features_to_json(updates, spatial_reference).dump()));
+        }
+        if !deletes.is_empty() {
+            let deletes: Vec<String> = deletes.iter().map(ToString::to_string).collect();
+            params.push(("deletes", deletes.join(",")));
+        }
+        let response = self
+            .client()
+            .post_json(&format!("{}/applyEdits", self.url()), &params, &RequestOptions::no_retry())
+            .await?;
+        Ok(LayerEditResults {
+            layer_id: info
+                .and_then(|info| info.id)
+                .or_else(|| self.url().rsplit('/').next().and_then(|id| id.parse().ok()))
+                .unwrap_or(0),
+            add_results: edit_results(&response["addResults"]),
+            update_results: edit_results(&response["updateResults"]),
+            delete_results: edit_results(&response["deleteResults"]),
+        })
+    }
+
+    /// The layer's description for checking edits, or `None` if it can't be fetched. Validation
+    /// is a convenience, so a failure here shouldn't stop an edit the server would accept.
+    async fn validation_info(&self) -> Option<Arc<LayerInfo>> {
+        match self.describe().await {
+            Ok(info) => Some(info),
+            Err(err) => {
+                tracing::debug!(error = %err, "couldn't describe the layer; sending edits unvalidated");
+                None
+            }
+        }
+    }
+}
+
+pub(crate) fn features_to_json(features: &[Feature], spatial_reference: Option<&SpatialReference>) -> JsonValue {
+    JsonValue::Array(
+        features
+            .iter()
+            .map(|feature| {
+                let mut value = feature.to_json();
+                if let Some(spatial_reference) = spatial_reference {
+                    if value["geometry"].is_object() {
+                        value["geometry"]["spatialReference"] = spatial_reference.to_json();
+                    }
+                }
+                value
+            })
+            .collect(),
+    )
+}
@@ -0,0 +1,753 @@
+//! Writing features to files GIS tools open without a connection: CSV, GeoJSON and
+//! GeoPackage.
+//!
+//! Each writer takes features one at a time, so a [`FeatureStream`] can be saved as it
+//! downloads. The schema comes from a feature set's metadata, such as
+//! [`FeatureStream::metadata`] once the first feature has arrived.
+//!
+//! [`FeatureStream`]: crate::FeatureStream
+//! [`FeatureStream::metadata`]: crate::FeatureStream::metadata
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use json::JsonValue;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection};
+
+use crate::feature::{Feature, FeatureSet};
+use crate::field::{attribute_fields, Field, FieldType};
+use crate::geometry::{Envelope, Geometry, GeometryType, Point, SpatialReference};
+use crate::projection::{Projection, WEB_MERCATOR_WKIDS};
+use crate::sqlite::{quote_identifier, sqlite_type, sqlite_value};
+use crate::values::date_text;
+use crate::wkt::{to_ewkb, to_hex, to_wkb, to_wkt};
+use crate::BoxResult;
+
+/// Something features can be written to.
+pub trait FeatureWriter {
+    fn write_feature(&mut self, feature: &Feature) -> BoxResult<()>;
+
+    /// Completes the file. Features written after this may be lost.
+    fn finish(&mut self) -> BoxResult<()>;
+}
+
+impl FeatureSet {
+    /// Writes all of the features and finishes the writer.
+    pub fn write_to(&self, writer: &mut dyn FeatureWriter) -> BoxResult<()> {
+        for feature in &self.features {
+            writer.write_feature(feature)?;
+        }
+        writer.finish()
+    }
+}
+
+/// Creates a writer for a path by its extension: `.csv`, `.geojson` or `.json`, or `.gpkg`.
+/// CSV files get X and Y columns for points and WKT for other geometries, except that a name
+/// ending in `.ewkb.csv` gets hex EWKB for any geometry, ready for PostGIS.
+pub fn create_writer(path: &Path, metadata: &FeatureSet) -> BoxResult<Box<dyn FeatureWriter>> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "csv" => {
+            let is_ewkb = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|stem| stem.to_ascii_lowercase().ends_with(".ewkb"));
+            let geometry = if is_ewkb {
+                CsvGeometry::Ewkb
+            } else if Some(GeometryType::Point) == metadata.geometry_type {
+                CsvGeometry::XY
+            } else {
+                CsvGeometry::Wkt
+            };
+            Ok(Box::new(CsvWriter::create(path, metadata, geometry)?))
+        }
+        "geojson" | "json" => Ok(Box::new(GeoJsonWriter::create(path, metadata)?)),
+        "gpkg" => {
+            let table_name = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or("features");
+            Ok(Box::new(GeoPackageWriter::create(path, table_name, metadata)?))
+        }
+        _ => Err(format!("don't know how to write {}; use .csv, .geojson or .gpkg", path.display()).into()),
+    }
+}
+
+/// The fields of a feature set, or if it doesn't list any, the attributes of its first
+/// feature.
+fn fields_or_attributes(fields: &[Field], feature: &Feature) -> Vec<Field> {
+    if fields.is_empty() {
+        feature
+            .attributes
+            .entries()
+            .map(|(name, _)| Field::new(name, FieldType::Other(String::new())))
+            .collect()
+    } else {
+        fields.to_vec()
+    }
+}
+
+/// How a [`CsvWriter`] writes geometries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsvGeometry {
+    /// A `WKT` column.
+    Wkt,
+    /// An `EWKB` column of hex EWKB with the features' SRID, which PostGIS reads as a
+    /// geometry when the file is loaded with `COPY`.
+    Ewkb,
+    /// `X` and `Y` columns, left empty for geometries other than points.
+    XY,
+    /// No geometry columns.
+    None,
+}
+
+/// Writes features as CSV, one row per feature, with dates as ISO 8601 timestamps.
+pub struct CsvWriter<W: Write> {
+    writer: csv::Writer<W>,
+    fields: Vec<Field>,
+    geometry: CsvGeometry,
+    srid: Option<u32>,
+    wrote_header: bool,
+}
+
+impl CsvWriter<BufWriter<File>> {
+    pub fn create(path: &Path, metadata: &FeatureSet, geometry: CsvGeometry) -> BoxResult<CsvWriter<BufWriter<File>>> {
+        Ok(CsvWriter::new(BufWriter::new(File::create(path)?), metadata, geometry))
+    }
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(writer: W, metadata: &FeatureSet, geometry: CsvGeometry) -> CsvWriter<W> {
+        CsvWriter {
+            writer: csv::Writer::from_writer(writer),
+            fields: attribute_fields(&metadata.fields),
+            geometry,
+            srid: metadata.spatial_reference.as_ref().and_then(SpatialReference::effective_wkid),
+            wrote_header: false,
+        }
+    }
+
+    fn write_header(&mut self, feature: &Feature) -> BoxResult<()> {
+        self.fields = fields_or_attributes(&self.fields, feature);
+        let mut header: Vec<&str> = self.fields.iter().map(|field| field.name.as_str()).collect();
+        match self.geometry {
+            CsvGeometry::Wkt => header.push("WKT"),
+            CsvGeometry::Ewkb => header.push("EWKB"),
+            CsvGeometry::XY => header.extend(&["X", "Y"]),
+            CsvGeometry::None => {}
+        }
+        self.writer.write_record(&header)?;
+        self.wrote_header = true;
+        Ok(())
+    }
+}
+
+impl<W: Write> FeatureWriter for CsvWriter<W> {
+    fn write_feature(&mut self, feature: &Feature) -> BoxResult<()> {
+        if !self.wrote_header {
+            self.write_header(feature)?;
+        }
+        let mut record: Vec<String> = self
+            .fields
+            .iter()
+            .map(|field| {
+                let value = &feature.attributes[field.name.as_str()];
+                match (value, &field.field_type) {
+                    (JsonValue::Null, _) => String::new(),
+                    (_, FieldType::Date) => date_text(value).unwrap_or_else(|| value.to_string()),
+                    _ => value.as_str().map(String::from).unwrap_or_else(|| value.dump()),
+                }
+            })
+            .collect();
+        match (self.geometry, &feature.geometry) {
+            (CsvGeometry::Wkt, geometry) => record.push(geometry.as_ref().map(to_wkt).unwrap_or_default()),
+            (CsvGeometry::Ewkb, geometry) => record.push(
+                geometry
+                    .as_ref()
+                    .map(|geometry| to_hex(&to_ewkb(geometry, self.srid)))
+                    .unwrap_or_default(),
+            ),
+            (CsvGeometry::XY, Some(Geometry::Point(point))) => {
+                record.push(point.x.to_string());
+                record.push(point.y.to_string());
+            }
+            (CsvGeometry::XY, _) => record.extend(vec![String::new(), String::new()]),
+            (CsvGeometry::None, _) => {}
+        }
+        self.writer.write_record(&record)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> BoxResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes features as a GeoJSON feature collection.
+///
+/// GeoJSON coordinates are WGS 1984 longitude and latitude. Features in any other spatial
+/// reference are projected locally, and refused if their spatial reference can't be; querying
+/// with `out_sr(SpatialReference::wgs84())` avoids that.
+pub struct GeoJsonWriter<W: Write> {
+    writer: W,
+    projection: Option<Projection>,
+    fields: Vec<Field>,
+    object_id_field: Option<String>,
+    count: usize,
+}
+
+impl GeoJsonWriter<BufWriter<File>> {
+    pub fn create(path: &Path, metadata: &FeatureSet) -> BoxResult<GeoJsonWriter<BufWriter<File>>> {
+        GeoJsonWriter::new(BufWriter::new(File::create(path)?), metadata)
+    }
+}
+
+impl<W: Write> GeoJsonWriter<W> {
+    pub fn new(mut writer: W, metadata: &FeatureSet) -> BoxResult<GeoJsonWriter<W>> {
+        let projection = match &metadata.spatial_reference {
+            Some(spatial_reference) if Some(4326) != spatial_reference.effective_wkid() => Some(
+                Projection::new(spatial_reference, &SpatialReference::wgs84())
+                    .map_err(|err| format!("GeoJSON needs WGS 1984 coordinates: {}", err))?,
+            ),
+            _ => None,
+        };
+        writer.write_all(br#"{"type":"FeatureCollection","features":["#)?;
+        Ok(GeoJsonWriter {
+            writer,
+            projection,
+            fields: attribute_fields(&metadata.fields),
+            object_id_field: metadata.object_id_field_name.clone().or_else(|| {
+                metadata
+                    .fields
+                    .iter()
+                    .find(|field| FieldType::Oid == field.field_type)
+                    .map(|field| field.name.clone())
+            }),
+            count: 0,
+        })
+    }
+}
+
+impl<W: Write> FeatureWriter for GeoJsonWriter<W> {
+    fn write_feature(&mut self, feature: &Feature) -> BoxResult<()> {
+        let mut properties = JsonValue::new_object();
+        for field in fields_or_attributes(&self.fields, feature) {
+            let value = &feature.attributes[field.name.as_str()];
+            properties[field.name.as_str()] = match (&field.field_type, date_text(value)) {
+                (FieldType::Date, Some(date)) => date.into(),
+                _ => value.clone(),
+            };
+        }
+        let geometry = match (&feature.geometry, &self.projection) {
+            (Some(geometry), Some(projection)) => Some(projection.geometry(geometry)?),
+            (geometry, None) => geometry.clone(),
+            (None, _) => None,
+        };
+        let mut value = json::object! {
+            "type" => "Feature",
+            "geometry" => geometry.as_ref().map(geojson_geometry).unwrap_or(JsonValue::Null),
+            "properties" => properties
+        };
+        if let Some(object_id_field) = &self.object_id_field {
+            let id = &feature.attributes[object_id_field.as_str()];
+            if !id.is_null() {
+                value["id"] = id.clone();
+            }
+        }
+        if 0 < self.count {
+            self.writer.write_all(b",")?;
+        }
+        self.writer.write_all(value.dump().as_bytes())?;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> BoxResult<()> {
+        self.writer.write_all(b"]}")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+fn geojson_position(point: &Point) -> JsonValue {
+    match point.z {
+        Some(z) => json::array![point.x, point.y, z],
+        None => json::array![point.x, point.y],
+    }
+}
+
+fn geojson_positions<'a>(points: impl Iterator<Item = &'a Point>) -> JsonValue {
+    JsonValue::Array(points.map(geojson_position).collect())
+}
+
+/// Converts a geometry to GeoJSON. Rings are reversed, since GeoJSON wants outer rings
+/// counterclockwise and holes clockwise, the opposite of Esri JSON.
+fn geojson_geometry(geometry: &Geometry) -> JsonValue {
+    match geometry {
+        Geometry::Point(point) => json::object! { "type" => "Point", "coordinates" => geojson_position(point) },
+        Geometry::Multipoint(multipoint) => json::object! {
+            "type" => "MultiPoint",
+            "coordinates" => geojson_positions(multipoint.points.iter())
+        },
+        Geometry::Polyline(polyline) if 1 == polyline.paths.len() => json::object! {
+            "type" => "LineString",
+            "coordinates" => geojson_positions(polyline.paths[0].iter())
+        },
+        Geometry::Polyline(polyline) => json::object! {
+            "type" => "MultiLineString",
+            "coordinates" => JsonValue::Array(polyline.paths.iter().map(|path| geojson_positions(path.iter())).collect())
+        },
+        Geometry::Polygon(polygon) => {
+            let parts: Vec<JsonValue> = polygon
+                .parts()
+                .iter()
+                .map(|rings| JsonValue::Array(rings.iter().map(|ring| geojson_positions(ring.iter().rev())).collect()))
+                .collect();
+            if 1 == parts.len() {
+                json::object! { "type" => "Polygon", "coordinates" => parts[0].clone() }
+            } else {
+                json::object! { "type" => "MultiPolygon", "coordinates" => JsonValue::Array(parts) }
+            }
+        }
+        Geometry::Envelope(envelope) => geojson_geometry(&Geometry::Polygon(envelope.to_polygon())),
+    }
+}
+
+/// The WKT of WGS 1984, which every GeoPackage has to define.
+const WGS84_WKT: &str = r#"GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,298.257223563]],PRIMEM["Greenwich",0],UNIT["degree",0.0174532925199433]]"#;
+
+/// The WKT of Web Mercator, which most ArcGIS Online layers and basemaps use.
+const WEB_MERCATOR_WKT: &str = r#"PROJCS["WGS 84 / Pseudo-Mercator",GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,298.257223563]],PRIMEM["Greenwich",0],UNIT["degree",0.0174532925199433]],PROJECTION["Mercator_1SP"],PARAMETER["central_meridian",0],PARAMETER["scale_factor",1],PARAMETER["false_easting",0],PARAMETER["false_northing",0],UNIT["metre",1],EXTENSION["PROJ4","+proj=merc +a=6378137 +b=6378137 +lat_ts=0 +lon_0=0 +x_0=0 +y_0=0 +k=1 +units=m +nadgrids=@null +wktext +no_defs"],AUTHORITY["EPSG","3857"]]"#;
+
+/// Writes features to a table in a new GeoPackage, with a column of the matching SQLite type
+/// for each field. The layer's object ID field, if it has one, becomes the primary key.
+///
+/// Features are written in one transaction, committed by [`FeatureWriter::finish`].
+///
+/// A GeoPackage needs the WKT of its spatial reference. WGS 1984 and Web Mercator are built
+/// in, and other spatial references use the WKT the layer gave; failing that, the features
+/// are projected to WGS 1984.
+pub struct GeoPackageWriter {
+    connection: Connection,
+    table_name: String,
+    fields: Vec<Field>,
+    object_id_field: Option<String>,
+    srs_id: i32,
+    /// Set when the features have to be projected to WGS 1984 as they're written.
+    to_wgs84: Option<Projection>,
+    extent: Option<Envelope>,
+    insert: String,
+}
+
+impl GeoPackageWriter {
+    /// Creates a GeoPackage at `path`, replacing any file already there, with one feature
+    /// table.
+    pub fn create(path: &Path, table_name: &str, metadata: &FeatureSet) -> BoxResult<GeoPackageWriter> {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "PRAGMA application_id = 1196444487;
+             PRAGMA user_version = 10300;
+             CREATE TABLE gpkg_spatial_ref_sys (
+                 srs_name TEXT NOT NULL, srs_id INTEGER PRIMARY KEY, organization TEXT NOT NULL,
+                 organization_coordsys_id INTEGER NOT NULL, definition TEXT NOT NULL, description TEXT);
+             CREATE TABLE gpkg_contents (
+                 table_name TEXT NOT NULL PRIMARY KEY, data_type TEXT NOT NULL, identifier TEXT UNIQUE,
+                 description TEXT DEFAULT '', last_change DATETIME NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ','now')),
+                 min_x DOUBLE, min_y DOUBLE, max_x DOUBLE, max_y DOUBLE,
+                 srs_id INTEGER, CONSTRAINT fk_gc_r_srs_id FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys(srs_id));
+             CREATE TABLE gpkg_geometry_columns (
+                 table_name TEXT NOT NULL, column_name TEXT NOT NULL, geometry_type_name TEXT NOT NULL,
+                 srs_id INTEGER NOT NULL, z TINYINT NOT NULL, m TINYINT NOT NULL,
+                 CONSTRAINT pk_geom_cols PRIMARY KEY (table_name, column_name),
+                 CONSTRAINT fk_gc_tn FOREIGN KEY (table_name) REFERENCES gpkg_contents(table_name),
+                 CONSTRAINT fk_gc_srs FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys (srs_id));",
+        )?;
+        let define_srs = "INSERT INTO gpkg_spatial_ref_sys VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+        connection.execute(define_srs, rusqlite::params!["Undefined cartesian SRS", -1, "NONE", -1, "undefined", ""])?;
+        connection.execute(define_srs, rusqlite::params!["Undefined geographic SRS", 0, "NONE", 0, "undefined", ""])?;
+        connection.execute(define_srs, rusqlite::params!["WGS 84", 4326, "EPSG", 4326, WGS84_WKT, ""])?;
+        let mut to_wgs84 = None;
+        let srs_id = match &metadata.spatial_reference {
+            Some(spatial_reference) => match (spatial_reference.effective_wkid(), &spatial_reference.wkt) {
+                (Some(4326), _) => 4326,
+                (Some(wkid), _) if WEB_MERCATOR_WKIDS.contains(&wkid) => {
+                    connection.execute(
+                        define_srs,
+                        rusqlite::params!["WGS 84 / Pseudo-Mercator", 3857, "EPSG", 3857, WEB_MERCATOR_WKT, ""],
+                    )?;
+                    3857
+                }
+                (Some(wkid), Some(wkt)) => {
+                    let organization = if wkid < 100_000 { "EPSG" } else { "ESRI" };
+                    connection.execute(
+                        define_srs,
+                        rusqlite::params![format!("{} {}", organization, wkid), wkid, organization, wkid, wkt, ""],
+                    )?;
+                    wkid as i32
+                }
+                _ => {
+                    to_wgs84 = Some(Projection::new(spatial_reference, &SpatialReference::wgs84()).map_err(|err| {
+                        format!("the GeoPackage needs the spatial reference's WKT or a projection to WGS 1984: {}", err)
+                    })?);
+                    4326
+                }
+            },
+            None => -1,
+        };
+
+        let object_id_field = metadata.object_id_field_name.clone().or_else(|| {
+            metadata
+                .fields
+                .iter()
+                .find(|field| FieldType::Oid == field.field_type)
+                .map(|field| field.name.clone())
+        });
+        let fields: Vec<Field> = attribute_fields(&metadata.fields)
+            .into_iter()
+            .filter(|field| Some(&field.name) != object_id_field.as_ref())
+            .collect();
+        let geometry_type_name = match metadata.geometry_type {
+            Some(GeometryType::Point) => "POINT",
+            Some(GeometryType::Multipoint) => "MULTIPOINT",
+            Some(GeometryType::Polyline) => "MULTILINESTRING",
+            Some(GeometryType::Polygon) | Some(GeometryType::Envelope) => "MULTIPOLYGON",
+            None => "GEOMETRY",
+        };
+        let primary_key = object_id_field.clone().unwrap_or_else(|| String::from("fid"));
+        let mut columns = vec![
+            format!("{} INTEGER PRIMARY KEY AUTOINCREMENT", quote_identifier(&primary_key)),
+            format!("geom {}", geometry_type_name),
+        ];
+        columns.extend(
+            fields
+                .iter()
+                .map(|field| format!("{} {}", quote_identifier(&field.name), sqlite_type(field))),
+        );
+        connection.execute_batch(&format!("CREATE TABLE {} ({});", quote_identifier(table_name), columns.join(", ")))?;
+        connection.execute(
+            "INSERT INTO gpkg_contents (table_name, data_type, identifier, srs_id) VALUES (?1, 'features', ?1, ?2)",
+            rusqlite::params![table_name, srs_id],
+        )?;
+        connection.execute(
+            "INSERT INTO gpkg_geometry_columns VALUES (?1, 'geom', ?2, ?3, ?4, ?5)",
+            rusqlite::params![table_name, geometry_type_name, srs_id, metadata.has_z as i32, metadata.has_m as i32],
+        )?;
+
+        let mut insert_columns = vec![quote_identifier(&primary_key), String::from("geom")];
+        insert_columns.extend(fields.iter().map(|field| quote_identifier(&field.name)));
+        let placeholders: Vec<String> = (1..=insert_columns.len()).map(|index| format!("?{}", index)).collect();
+        let insert = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            quote_identifier(table_name),
+            insert_columns.join(", "),
+            placeholders.join(", ")
+        );
+        connection.execute_batch("BEGIN")?;
+        Ok(GeoPackageWriter {
+            connection,
+            table_name: table_name.to_string(),
+            fields,
+            object_id_field,
+            srs_id,
+            to_wgs84,
+            extent: None,
+            insert,
+        })
+    }
+
+    /// A geometry as a GeoPackage geometry blob: a `GP` header with the SRS ID and envelope,
+    /// then the WKB.
+    fn geometry_blob(&self, geometry: &Geometry) -> Vec<u8> {
+        let envelope = geometry.envelope();
+        let mut blob = vec![b'G', b'P', 0];
+        // Little-endian, with an XY envelope unless the geometry is a point.
+        let with_envelope = !matches!(geometry, Geometry::Point(_)) && envelope.is_some();
+        blob.push(if with_envelope { 0b0000_0011 } else { 0b0000_0001 });
+        blob.extend_from_slice(&self.srs_id.to_le_bytes());
+        if let (true, Some(envelope)) = (with_envelope, envelope) {
+            for value in &[envelope.xmin, envelope.xmax, envelope.ymin, envelope.ymax] {
+                blob.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        blob.extend(to_wkb(geometry, !matches!(geometry, Geometry::Point(_) | Geometry::Multipoint(_))));
+        blob
+    }
+}
+
+impl FeatureWriter for GeoPackageWriter {
+    fn write_feature(&mut self, feature: &Feature) -> BoxResult<()> {
+        let mut values = Vec::with_capacity(self.fields.len() + 2);
+        values.push(match &self.object_id_field {
+            Some(object_id_field) => sqlite_value(&feature.attributes[object_id_field.as_str()], &FieldType::Oid),
+            None => Value::Null,
+        });
+        let geometry = match (&feature.geometry, &self.to_wgs84) {
+            (Some(geometry), Some(to_wgs84)) => Some(to_wgs84.geometry(geometry)?),
+            (geometry, _) => geometry.clone(),
+        };
+        values.push(match &geometry {
+            Some(geometry) => {
+                if let Some(envelope) = geometry.envelope() {
+                    self.extent = Some(match self.extent {
+                        Some(extent) => Envelope {
+                            xmin: extent.xmin.min(envelope.xmin),
+                            ymin: extent.ymin.min(envelope.ymin),
+                            xmax: extent.xmax.max(envelope.xmax),
+                            ymax: extent.ymax.max(envelope.ymax),
+                        },
+                        None => envelope,
+                    });
+                }
+                Value::Blob(self.geometry_blob(geometry))
+            }
+            None => Value::Null,
+        });
+        for field in &self.fields {
+            values.push(sqlite_value(&feature.attributes[field.name.as_str()], &field.field_type));
+        }
+        self.connection.execute(&self.insert, params_from_iter(values))?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> BoxResult<()> {
+        if let Some(extent) = self.extent {
+            self.connection.execute(
+                "UPDATE gpkg_contents SET min_x = ?1, min_y = ?2, max_x = ?3, max_y = ?4 WHERE table_name = ?5",
+                rusqlite::params![extent.xmin, extent.ymin, extent.xmax, extent.ymax, self.table_name],
+            )?;
+        }
+        if !self.connection.is_autocommit() {
+            self.connection.execute_batch("COMMIT")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::geometry::Polygon;
+
+    fn metadata(spatial_reference: Option<SpatialReference>) -> FeatureSet {
+        FeatureSet {
+            object_id_field_name: Some(String::from("OBJECTID")),
+            geometry_type: Some(GeometryType::Point),
+            spatial_reference,
+            fields: vec![
+                Field::new("OBJECTID", FieldType::Oid),
+                Field::new("NAME", FieldType::String),
+                Field::new("FOUNDED", FieldType::Date),
+                Field::new("POP", FieldType::Integer),
+                Field::new("Shape", FieldType::Geometry),
+            ],
+            ..FeatureSet::default()
+        }
+    }
+
+    fn features() -> Vec<Feature> {
+        vec![
+            Feature::new(
+                json::object! { "OBJECTID" => 1, "NAME" => "Lisbon", "FOUNDED" => 1_577_836_800_000i64, "POP" => 545_000 },
+                Some(Geometry::Point(Point::new(-9.14, 38.72))),
+            ),
+            Feature::new(
+                json::object! { "OBJECTID" => 2, "NAME" => "Porto, Norte", "FOUNDED" => JsonValue::Null, "POP" => 232_000 },
+                None,
+            ),
+        ]
+    }
+
+    fn csv(geometry: CsvGeometry) -> String {
+        let mut writer = CsvWriter::new(Vec::new(), &metadata(Some(SpatialReference::wgs84())), geometry);
+        for feature in features() {
+            writer.write_feature(&feature).unwrap();
+        }
+        writer.finish().unwrap();
+        String::from_utf8(writer.writer.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn writes_csv() {
+        assert_eq!(
+            "OBJECTID,NAME,FOUNDED,POP,X,Y\n\
+             1,Lisbon,2020-01-01T00:00:00.000Z,545000,-9.14,38.72\n\
+             2,\"Porto, Norte\",,232000,,\n",
+            csv(CsvGeometry::XY)
+        );
+        assert_eq!(
+            "OBJECTID,NAME,FOUNDED,POP,WKT\n\
+             1,Lisbon,2020-01-01T00:00:00.000Z,545000,POINT (-9.14 38.72)\n\
+             2,\"Porto, Norte\",,232000,\n",
+            csv(CsvGeometry::Wkt)
+        );
+        assert_eq!(
+            "OBJECTID,NAME,FOUNDED,POP,EWKB\n\
+             1,Lisbon,2020-01-01T00:00:00.000Z,545000,0101000020E610000048E17A14AE4722C05C8FC2F5285C4340\n\
+             2,\"Porto, Norte\",,232000,\n",
+            csv(CsvGeometry::Ewkb)
+        );
+    }
+
+    #[test]
+    fn writes_geojson() {
+        let mut writer = GeoJsonWriter::new(Vec::new(), &metadata(None)).unwrap();
+        for feature in features() {
+            writer.write_feature(&feature).unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(
+            concat!(
+                r#"{"type":"FeatureCollection","features":["#,
+                r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[-9.14,38.72]},"#,
+                r#""properties":{"OBJECTID":1,"NAME":"Lisbon","FOUNDED":"2020-01-01T00:00:00.000Z","POP":545000},"id":1},"#,
+                r#"{"type":"Feature","geometry":null,"#,
+                r#""properties":{"OBJECTID":2,"NAME":"Porto, Norte","FOUNDED":null,"POP":232000},"id":2}"#,
+                r#"]}"#
+            ),
+            String::from_utf8(writer.writer).unwrap()
+        );
+    }
+
+    #[test]
+    fn reverses_rings_and_projects_for_geojson() {
+        // A clockwise Esri ring becomes a counterclockwise GeoJSON ring.
+        let square = Geometry::Polygon(Polygon {
+            rings: vec![vec![
+                Point::new(0.0, 0.0),
+                Point::new(0.0, 1.0),
+                Point::new(1.0, 1.0),
+                Point::new(1.0, 0.0),
+                Point::new(0.0, 0.0),
+            ]],
+        });
+        assert_eq!(
+            json::parse(r#"{"type":"Polygon","coordinates":[[[0,0],[1,0],[1,1],[0,1],[0,0]]]}"#).unwrap(),
+            geojson_geometry(&square)
+        );
+
+        let mut writer = GeoJsonWriter::new(Vec::new(), &metadata(Some(SpatialReference::from_wkid(3857)))).unwrap();
+        let feature = Feature::new(json::object! { "OBJECTID" => 1 }, Some(Geometry::Point(Point::new(0.0, 0.0))));
+        writer.write_feature(&feature).unwrap();
+        writer.finish().unwrap();
+        let value = json::parse(&String::from_utf8(writer.writer).unwrap()).unwrap();
+        assert_eq!(json::array![0.0, 0.0], value["features"][0]["geometry"]["coordinates"]);
+    }
+
+    /// A GeoPackage at a unique path in the temp directory, removed when dropped.
+    struct TempFile(std::path::PathBuf);
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn geopackage(spatial_reference: SpatialReference, features: &[Feature]) -> (TempFile, Connection) {
+        let path = TempFile(std::env::temp_dir().join(format!("quarenta-export-{}.gpkg", uuid::Uuid::new_v4())));
+        let mut writer = GeoPackageWriter::create(&path.0, "cities", &metadata(Some(spatial_reference))).unwrap();
+        for feature in features {
+            writer.write_feature(feature).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+        let connection = Connection::open(&path.0).unwrap();
+        (path, connection)
+    }
+
+    fn srs_definition(connection: &Connection, srs_id: i32) -> String {
+        connection
+            .query_row("SELECT definition FROM gpkg_spatial_ref_sys WHERE srs_id = ?1", [srs_id], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn writes_a_geopackage() {
+        let (_path, connection) = geopackage(SpatialReference::wgs84(), &features());
+        let srs_ids: Vec<i32> = connection
+            .prepare("SELECT srs_id FROM gpkg_spatial_ref_sys ORDER BY srs_id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(vec![-1, 0, 4326], srs_ids);
+        assert_eq!(WGS84_WKT, srs_definition(&connection, 4326));
+
+        let (column, geometry_type, srs_id): (String, String, i32) = connection
+            .query_row(
+                "SELECT column_name, geometry_type_name, srs_id FROM gpkg_geometry_columns WHERE table_name = 'cities'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(("geom", "POINT", 4326), (column.as_str(), geometry_type.as_str(), srs_id));
+
+        let (name, founded, blob): (String, String, Vec<u8>) = connection
+            .query_row("SELECT NAME, FOUNDED, geom FROM cities WHERE OBJECTID = 1", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert_eq!(("Lisbon", "2020-01-01T00:00:00.000Z"), (name.as_str(), founded.as_str()));
+        // "GP", version 0, little-endian with no envelope, then the SRS ID.
+        assert_eq!(&[b'G', b'P', 0, 1], &blob[..4]);
+        assert_eq!(4326, i32::from_le_bytes(blob[4..8].try_into().unwrap()));
+        assert_eq!(Geometry::Point(Point::new(-9.14, 38.72)), Geometry::from_wkb(&blob[8..]).unwrap());
+        let geometry: Option<Vec<u8>> = connection
+            .query_row("SELECT geom FROM cities WHERE OBJECTID = 2", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(None, geometry);
+    }
+
+    #[test]
+    fn defines_the_geopackage_spatial_reference() {
+        let (_path, connection) = geopackage(SpatialReference::from_wkid(102100), &[]);
+        assert_eq!(WEB_MERCATOR_WKT, srs_definition(&connection, 3857));
+
+        let with_wkt = SpatialReference {
+            wkid: Some(2193),
+            latest_wkid: None,
+            wkt: Some(String::from("PROJCS[\"NZGD2000 / New Zealand Transverse Mercator 2000\"]")),
+        };
+        let (_path, connection) = geopackage(with_wkt, &[]);
+        assert_eq!("PROJCS[\"NZGD2000 / New Zealand Transverse Mercator 2000\"]", srs_definition(&connection, 2193));
+
+        // Without a WKT, the features are projected to WGS 1984.
+        let utm = Feature::new(
+            json::object! { "OBJECTID" => 1 },
+            Some(Geometry::Point(Point::new(500_000.0, 0.0))),
+        );
+        let (_path, connection) = geopackage(SpatialReference::from_wkid(32629), &[utm]);
+        let srs_id: i32 = connection
+            .query_row("SELECT srs_id FROM gpkg_geometry_columns", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(4326, srs_id);
+        let blob: Vec<u8> = connection.query_row("SELECT geom FROM cities", [], |row| row.get(0)).unwrap();
+        match Geometry::from_wkb(&blob[8..]).unwrap() {
+            Geometry::Point(point) => assert!((point.x + 9.0).abs() < 1e-6 && point.y.abs() < 1e-6, "{:?}", point),
+            other => panic!("expected a point, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn picks_a_writer_by_extension() {
+        let path = TempFile(std::env::temp_dir().join(format!("quarenta-export-{}.EWKB.csv", uuid::Uuid::new_v4())));
+        let mut writer = create_writer(&path.0, &metadata(Some(SpatialReference::wgs84()))).unwrap();
+        writer.write_feature(&features()[0]).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        assert!(std::fs::read_to_string(&path.0).unwrap().starts_with("OBJECTID,NAME,FOUNDED,POP,EWKB\n"));
+        assert!(create_writer(Path::new("features.shp"), &metadata(None)).is_err());
+    }
+}
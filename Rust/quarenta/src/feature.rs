@@ -0,0 +1,191 @@
+//! Features and feature sets, as returned by queries and sent in edits.
+
+use json::JsonValue;
+
+use crate::field::{fields_from_json, Field};
+use crate::geometry::{Geometry, GeometryType, SpatialReference};
+
+/// A feature: attribute values and an optional geometry.
+///
+/// By default the attributes are a `JsonValue` object and the geometry is any [`Geometry`].
+/// Use your own `serde` struct for `A`, and a specific geometry type such as [`Point`] for
+/// `G`, to work with a layer's features as Rust types; see [`Feature::into_typed`].
+///
+/// [`Point`]: crate::Point
+#[derive(Clone, Debug, PartialEq)]
+pub struct Feature<A = JsonValue, G = Geometry> {
+    pub attributes: A,
+    pub geometry: Option<G>,
+}
+
+impl Feature {
+    pub fn new(attributes: JsonValue, geometry: Option<Geometry>) -> Feature {
+        Feature { attributes, geometry }
+    }
+
+    pub fn from_json(value: &JsonValue) -> Feature {
+        Feature {
+            attributes: if value["attributes"].is_object() {
+                value["attributes"].clone()
+            } else {
+                JsonValue::new_object()
+            },
+            geometry: Geometry::from_json(&value["geometry"]),
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut value = JsonValue::new_object();
+        value["attributes"] = self.attributes.clone();
+        if let Some(geometry) = &self.geometry {
+            value["geometry"] = geometry.to_json();
+        }
+        value
+    }
+}
+
+/// A set of features with the metadata that came with them.
+///
+/// JSON and PBF queries both produce a `FeatureSet`, so callers don't need to care which
+/// format the server used. Like [`Feature`], it can be typed with an attribute struct and a
+/// geometry type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeatureSet<A = JsonValue, G = Geometry> {
+    pub object_id_field_name: Option<String>,
+    pub global_id_field_name: Option<String>,
+    pub geometry_type: Option<GeometryType>,
+    pub spatial_reference: Option<SpatialReference>,
+    pub has_z: bool,
+    pub has_m: bool,
+    pub fields: Vec<Field>,
+    pub features: Vec<Feature<A, G>>,
+    /// Whether the server stopped before returning every matching feature.
+    pub exceeded_transfer_limit: bool,
+}
+
+impl<A, G> Default for FeatureSet<A, G> {
+    fn default() -> Self {
+        FeatureSet {
+            object_id_field_name: None,
+            global_id_field_name: None,
+            geometry_type: None,
+            spatial_reference: None,
+            has_z: false,
+            has_m: false,
+            fields: Vec::new(),
+            features: Vec::new(),
+            exceeded_transfer_limit: false,
+        }
+    }
+}
+
+impl FeatureSet {
+    pub fn from_json(value: &JsonValue) -> FeatureSet {
+        let mut feature_set = FeatureSet {
+            object_id_field_name: value["objectIdFieldName"].as_str().map(String::from),
+            global_id_field_name: value["globalIdFieldName"].as_str().map(String::from),
+            geometry_type: value["geometryType"].as_str().and_then(GeometryType::from_name),
+            spatial_reference: SpatialReference::from_json(&value["spatialReference"]),
+            has_z: value["hasZ"].as_bool().unwrap_or(false),
+            has_m: value["hasM"].as_bool().unwrap_or(false),
+            fields: fields_from_json(&value["fields"]),
+            features: Vec::new(),
+            exceeded_transfer_limit: value["exceededTransferLimit"].as_bool().unwrap_or(false),
+        };
+        feature_set.features = value["features"]
+            .members()
+            .map(|feature| feature_set.feature_from_json(feature))
+            .collect();
+        feature_set
+    }
+
+    /// Parses a feature that belongs to this set. Vertex arrays in a query response rely on
+    /// the set's `hasZ` and `hasM` rather than repeating them on every geometry.
+    pub fn feature_from_json(&self, value: &JsonValue) -> Feature {
+        if (self.has_z || self.has_m) && value["geometry"].is_object() {
+            let mut geometry = value["geometry"].clone();
+            if self.has_z && !geometry.has_key("hasZ") {
+                geometry["hasZ"] = true.into();
+            }
+            if self.has_m && !geometry.has_key("hasM") {
+                geometry["hasM"] = true.into();
+            }
+            let mut feature = Feature::from_json(value);
+            feature.geometry = Geometry::from_json(&geometry);
+            return feature;
+        }
+        Feature::from_json(value)
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut value = JsonValue::new_object();
+        if let Some(object_id_field_name) = &self.object_id_field_name {
+            value["objectIdFieldName"] = object_id_field_name.as_str().into();
+        }
+        if let Some(global_id_field_name) = &self.global_id_field_name {
+            value["globalIdFieldName"] = global_id_field_name.as_str().into();
+        }
+        if let Some(geometry_type) = self.geometry_type {
+            value["geometryType"] = geometry_type.as_str().into();
+        }
+        if let Some(spatial_reference) = &self.spatial_reference {
+            value["spatialReference"] = spatial_reference.to_json();
+        }
+        if self.has_z {
+            value["hasZ"] = true.into();
+        }
+        if self.has_m {
+            value["hasM"] = true.into();
+        }
+        value["fields"] = JsonValue::Array(self.fields.iter().map(Field::to_json).collect());
+        value["features"] = JsonValue::Array(self.features.iter().map(Feature::to_json).collect());
+        if self.exceeded_transfer_limit {
+            value["exceededTransferLimit"] = true.into();
+        }
+        value
+    }
+}
+
+impl<A, G> FeatureSet<A, G> {
+    /// Finds a field by name, ignoring case as ArcGIS does.
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|field| field.name.eq_ignore_ascii_case(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_vertices_with_the_sets_z_and_m() {
+        let feature_set = FeatureSet::from_json(&json::parse(r#"{
+            "objectIdFieldName": "OBJECTID",
+            "geometryType": "esriGeometryPolyline",
+            "hasZ": true,
+            "features": [
+                { "attributes": { "OBJECTID": 1 }, "geometry": { "paths": [[[1, 2, 3], [4, 5, 6]]] } },
+                { "attributes": null, "geometry": { "paths": [[[1, 2, 3], [4, 5, 6]]] } },
+                { "geometry": { "paths": [[[1, 2, 3], [4, 5, 6]]] } }
+            ]
+        }"#).unwrap());
+        assert_eq!(feature_set.features.len(), 3);
+        match &feature_set.features[0].geometry {
+            Some(Geometry::Polyline(polyline)) => assert_eq!(polyline.paths[0][1].z, Some(6.0)),
+            other => panic!("expected a polyline, got {:?}", other),
+        }
+        assert_eq!(feature_set.features[0].attributes["OBJECTID"], 1);
+        // Attributes that aren't an object are read as none, with or without the set's hasZ.
+        assert!(feature_set.features[1].attributes.is_object());
+        assert!(feature_set.features[2].attributes.is_object());
+        assert_eq!(feature_set.features[2].attributes.len(), 0);
+    }
+
+    #[test]
+    fn reads_features_without_attributes() {
+        let feature = Feature::from_json(&json::parse(r#"{ "attributes": [1, 2], "geometry": { "x": 1, "y": 2 } }"#).unwrap());
+        assert!(feature.attributes.is_object());
+        assert!(matches!(feature.geometry, Some(Geometry::Point(_))));
+        assert_eq!(Feature::from_json(&feature.to_json()), feature);
+    }
+}
@@ -0,0 +1,796 @@
+//! A copy of a layer's features in a local SQLite file, for querying offline.
+//!
+//! Each field gets a column, so where clauses and `orderByFields` run as SQLite SQL, and an
+//! R-tree over the features' extents answers spatial filters. Most simple where clauses mean
+//! the same in SQLite as on the server; dates are stored as ISO 8601 text, so compare them
+//! with strings such as `'2024-01-31'` rather than `DATE` or `TIMESTAMP` literals.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use geo::Intersects;
+use json::JsonValue;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+
+use crate::feature::{Feature, FeatureSet};
+use crate::feature_layer::{FeatureLayer, LayerInfo, Query};
+use crate::feature_service::FeatureService;
+use crate::field::{attribute_fields, Field};
+use crate::geodesic::to_geo_polygons;
+use crate::geometry::{Envelope, Geometry, Point, SpatialReference};
+use crate::projection::Projection;
+use crate::request::Client;
+use crate::sqlite::{quote_identifier, sqlite_type, sqlite_value};
+use crate::typed::GeometryValue;
+use crate::BoxResult;
+
+/// The column holding each feature's attributes as JSON, exactly as they were downloaded.
+const ATTRIBUTES_COLUMN: &str = "_attributes";
+
+/// The column holding each feature's geometry as Esri JSON.
+const GEOMETRY_COLUMN: &str = "_geometry";
+
+/// What [`FeatureCache::refresh`] did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheRefresh {
+    /// The changes since the last refresh were applied.
+    Changes { upserted: usize, deleted: usize },
+    /// Every feature was downloaded again, because the layer doesn't track changes.
+    Reloaded { features: usize },
+}
+
+/// A layer's features in a SQLite file, answering queries without the server.
+///
+/// [`FeatureCache::query`] takes the same [`Query`] as [`FeatureLayer::query`] and supports
+/// its where clause, object IDs, `esriSpatialRelIntersects` and
+/// `esriSpatialRelEnvelopeIntersects` geometry filters, out fields, out spatial reference,
+/// ordering and paging. Time filters aren't supported.
+///
+/// ```no_run
+/// # use quarenta::{Client, FeatureCache, FeatureLayer, Query};
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let client = Client::new();
+/// let path = std::path::Path::new("cities.sqlite");
+/// let mut cache = if path.exists() {
+///     FeatureCache::open(path)?
+/// } else {
+///     let layer = FeatureLayer::new(&client, "https://example.com/arcgis/rest/services/Cities/FeatureServer/0");
+///     FeatureCache::create(path, &layer).await?
+/// };
+/// if let Err(err) = cache.refresh(&client).await {
+///     println!("Using the cached cities, since they couldn't be refreshed: {}", err);
+/// }
+/// let largest = cache.query(&Query::new().order_by("population DESC").result_record_count(10))?;
+/// println!("{} cities", largest.features.len());
+/// # Ok(())
+/// # }
+/// ```
+pub struct FeatureCache {
+    connection: Connection,
+    url: String,
+    info: LayerInfo,
+    metadata: FeatureSet,
+    object_id_field: String,
+    /// The fields with columns of their own, which is all of them but the object ID field.
+    fields: Vec<Field>,
+}
+
+impl FeatureCache {
+    /// Creates a cache of a layer at `path`, replacing any file already there, and downloads
+    /// all of the layer's features into it. The layer must have an object ID field.
+    pub async fn create(path: &Path, layer: &FeatureLayer) -> BoxResult<FeatureCache> {
+        let info = layer.describe().await?;
+        let spatial_reference = SpatialReference::from_json(&info.raw["extent"]["spatialReference"]);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let mut cache = FeatureCache::create_tables(
+            Connection::open(path)?,
+            layer.url(),
+            LayerInfo::from_json(info.raw.clone()),
+            spatial_reference,
+        )?;
+        cache.reload(layer.client()).await?;
+        Ok(cache)
+    }
+
+    /// Sets up an empty cache's tables.
+    fn create_tables(
+        connection: Connection,
+        url: &str,
+        info: LayerInfo,
+        spatial_reference: Option<SpatialReference>,
+    ) -> BoxResult<FeatureCache> {
+        connection.execute_batch(
+            "CREATE TABLE cache_info (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             CREATE VIRTUAL TABLE features_rtree USING rtree(id, xmin, xmax, ymin, ymax);",
+        )?;
+        connection.execute(
+            "INSERT INTO cache_info VALUES ('url', ?1), ('layerInfo', ?2)",
+            rusqlite::params![url, info.raw.dump()],
+        )?;
+        if let Some(spatial_reference) = &spatial_reference {
+            set_cache_info(&connection, "spatialReference", &spatial_reference.to_json().dump())?;
+        }
+        let cache = FeatureCache::from_info(connection, url, info, spatial_reference)?;
+        let mut columns = vec![format!("{} INTEGER PRIMARY KEY", quote_identifier(&cache.object_id_field))];
+        columns.extend(
+            cache
+                .fields
+                .iter()
+                .map(|field| format!("{} {}", quote_identifier(&field.name), sqlite_type(field))),
+        );
+        columns.push(format!("{} TEXT", GEOMETRY_COLUMN));
+        columns.push(format!("{} TEXT NOT NULL", ATTRIBUTES_COLUMN));
+        cache
+            .connection
+            .execute_batch(&format!("CREATE TABLE features ({});", columns.join(", ")))?;
+        Ok(cache)
+    }
+
+    /// Opens a cache made by [`FeatureCache::create`].
+    pub fn open(path: &Path) -> BoxResult<FeatureCache> {
+        if !path.exists() {
+            return Err(format!("there's no feature cache at {}", path.display()).into());
+        }
+        let connection = Connection::open(path)?;
+        let url = cache_info(&connection, "url")?.ok_or("the feature cache has no layer URL")?;
+        let info = LayerInfo::from_json(json::parse(
+            &cache_info(&connection, "layerInfo")?.ok_or("the feature cache has no layer description")?,
+        )?);
+        let spatial_reference = match cache_info(&connection, "spatialReference")? {
+            Some(text) => SpatialReference::from_json(&json::parse(&text)?),
+            None => SpatialReference::from_json(&info.raw["extent"]["spatialReference"]),
+        };
+        FeatureCache::from_info(connection, &url, info, spatial_reference)
+    }
+
+    fn from_info(
+        connection: Connection,
+        url: &str,
+        info: LayerInfo,
+        spatial_reference: Option<SpatialReference>,
+    ) -> BoxResult<FeatureCache> {
+        let object_id_field = info
+            .object_id_field
+            .clone()
+            .ok_or("can't cache a layer without an object ID field")?;
+        let metadata = FeatureSet {
+            object_id_field_name: Some(object_id_field.clone()),
+            global_id_field_name: info.global_id_field.clone(),
+            geometry_type: info.geometry_type,
+            spatial_reference,
+            has_z: info.raw["hasZ"].as_bool().unwrap_or(false),
+            has_m: info.raw["hasM"].as_bool().unwrap_or(false),
+            fields: info.fields.clone(),
+            ..FeatureSet::default()
+        };
+        let fields = attribute_fields(&metadata.fields)
+            .into_iter()
+            .filter(|field| !field.name.eq_ignore_ascii_case(&object_id_field))
+            .collect();
+        Ok(FeatureCache {
+            connection,
+            url: url.to_string(),
+            info,
+            metadata,
+            object_id_field,
+            fields,
+        })
+    }
+
+    /// The URL of the cached layer.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The layer's description, as it was when the cache was created.
+    pub fn info(&self) -> &LayerInfo {
+        &self.info
+    }
+
+    /// The schema of the cached features: a feature set with no features.
+    pub fn metadata(&self) -> &FeatureSet {
+        &self.metadata
+    }
+
+    /// The number of cached features.
+    pub fn count(&self) -> BoxResult<usize> {
+        let count: i64 = self.connection.query_row("SELECT COUNT(*) FROM features", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// The server generation the cache is up to date with, if the layer tracks changes.
+    pub fn server_gen(&self) -> BoxResult<Option<i64>> {
+        Ok(cache_info(&self.connection, "serverGen")?.and_then(|text| text.parse().ok()))
+    }
+
+    /// Brings the cache up to date: with the changes since the last refresh if the layer's
+    /// service tracks changes, or otherwise by downloading every feature again.
+    pub async fn refresh(&mut self, client: &Client) -> BoxResult<CacheRefresh> {
+        let server_gen = match self.server_gen()? {
+            Some(server_gen) => server_gen,
+            None => return Ok(CacheRefresh::Reloaded { features: self.reload(client).await? }),
+        };
+        let (service, layer_id) = self.service(client)?;
+        let mut since = BTreeMap::new();
+        since.insert(layer_id, server_gen);
+        let extracted = service.extract_changes(&since).await?;
+        let (mut upserted, mut deleted) = (0, 0);
+        let transaction = self.connection.transaction()?;
+        for changes in extracted.changes.iter().filter(|changes| layer_id == changes.layer_id) {
+            for feature in changes.adds.iter().chain(&changes.updates) {
+                write_feature(&transaction, &self.object_id_field, &self.fields, feature)?;
+                upserted += 1;
+            }
+            for object_id in &changes.delete_ids {
+                deleted += delete_feature(&transaction, &self.object_id_field, *object_id as i64)?;
+            }
+            if let Some(global_id_field) = &self.metadata.global_id_field_name {
+                for global_id in &changes.delete_global_ids {
+                    let object_id: Option<i64> = transaction
+                        .query_row(
+                            &format!(
+                                "SELECT {} FROM features WHERE {} = ?1",
+                                quote_identifier(&self.object_id_field),
+                                quote_identifier(global_id_field)
+                            ),
+                            [global_id],
+                            |row| row.get(0),
+                        )
+                        .optional()?;
+                    if let Some(object_id) = object_id {
+                        deleted += delete_feature(&transaction, &self.object_id_field, object_id)?;
+                    }
+                }
+            }
+        }
+        if let Some(server_gen) = extracted.layer_server_gens.get(&layer_id) {
+            set_cache_info(&transaction, "serverGen", &server_gen.to_string())?;
+        }
+        transaction.commit()?;
+        Ok(CacheRefresh::Changes { upserted, deleted })
+    }
+
+    /// Replaces the cached features with a fresh download of all of them, and returns how
+    /// many there are.
+    pub async fn reload(&mut self, client: &Client) -> BoxResult<usize> {
+        let layer = FeatureLayer::new(client, &self.url);
+        // The generation comes first, so changes made during the download are picked up by
+        // the next refresh rather than lost.
+        let server_gen = match self.service(client) {
+            Ok((service, layer_id)) => match service.layer_server_gens().await {
+                Ok(server_gens) => server_gens.get(&layer_id).copied(),
+                Err(_) => None,
+            },
+            Err(_) => None,
+        };
+        let mut features = Vec::new();
+        let mut spatial_reference = None;
+        let mut last_object_id = None;
+        loop {
+            let where_clause = match last_object_id {
+                Some(object_id) => format!("{} > {}", self.object_id_field, object_id),
+                None => String::from("1=1"),
+            };
+            let page = layer
+                .query(&Query::new().where_clause(&where_clause).order_by(&self.object_id_field))
+                .await?;
+            if spatial_reference.is_none() {
+                spatial_reference = page.spatial_reference.clone();
+            }
+            let next_object_id = page
+                .features
+                .last()
+                .and_then(|feature| feature.attributes[self.object_id_field.as_str()].as_i64());
+            let exceeded_transfer_limit = page.exceeded_transfer_limit;
+            features.extend(page.features);
+            match next_object_id {
+                Some(object_id) if exceeded_transfer_limit => last_object_id = Some(object_id),
+                _ => break,
+            }
+        }
+
+        let transaction = self.connection.transaction()?;
+        transaction.execute_batch("DELETE FROM features; DELETE FROM features_rtree;")?;
+        for feature in &features {
+            write_feature(&transaction, &self.object_id_field, &self.fields, feature)?;
+        }
+        match server_gen {
+            Some(server_gen) => set_cache_info(&transaction, "serverGen", &server_gen.to_string())?,
+            None => {
+                transaction.execute("DELETE FROM cache_info WHERE key = 'serverGen'", [])?;
+            }
+        }
+        if let Some(spatial_reference) = &spatial_reference {
+            set_cache_info(&transaction, "spatialReference", &spatial_reference.to_json().dump())?;
+        }
+        transaction.commit()?;
+        if spatial_reference.is_some() {
+            self.metadata.spatial_reference = spatial_reference;
+        }
+        Ok(features.len())
+    }
+
+    /// Queries the cached features, as [`FeatureLayer::query`] would query the layer.
+    ///
+    /// A where clause set with [`Query::where_clause`] is run as SQLite SQL as it is, so it
+    /// must come from a trusted source; build one with [`Query::filter`] to have values
+    /// escaped. `order_by` must be a list of field names, each optionally followed by `ASC` or
+    /// `DESC`.
+    pub fn query(&self, query: &Query) -> BoxResult<FeatureSet> {
+        if query.time.is_some() {
+            return Err("the feature cache can't filter by time".into());
+        }
+        let filter_geometry = match &query.geometry {
+            Some(geometry) => Some(self.filter_geometry(geometry, query.in_sr.as_ref(), query.spatial_rel.as_deref())?),
+            None => None,
+        };
+        let filter = filter_geometry.as_ref().and_then(|(filter, _)| filter.as_ref());
+
+        let object_id_column = quote_identifier(&self.object_id_field);
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        if let Some((_, extent)) = &filter_geometry {
+            conditions.push(format!(
+                "{} IN (SELECT id FROM features_rtree WHERE xmax >= ?1 AND xmin <= ?2 AND ymax >= ?3 AND ymin <= ?4)",
+                object_id_column
+            ));
+            values.extend([extent.xmin, extent.xmax, extent.ymin, extent.ymax].iter().map(|value| Value::Real(*value)));
+        }
+        if let Some(where_clause) = &query.where_clause {
+            conditions.push(format!("({})", where_clause));
+        }
+        if !query.object_ids.is_empty() {
+            let object_ids: Vec<String> = query.object_ids.iter().map(ToString::to_string).collect();
+            conditions.push(format!("{} IN ({})", object_id_column, object_ids.join(",")));
+        }
+        let mut sql = format!("SELECT {}, {} FROM features", ATTRIBUTES_COLUMN, GEOMETRY_COLUMN);
+        if !conditions.is_empty() {
+            sql.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+        }
+        let order_by = match &query.order_by {
+            Some(order_by) => order_by_sql(order_by)?,
+            None => object_id_column.clone(),
+        };
+        sql.push_str(&format!(" ORDER BY {}", order_by));
+
+        // Paging runs in SQLite, asking for one more feature than the page holds to tell
+        // whether there are more. Only the extents are indexed, though, so when features have
+        // to be tested against the filter geometry, the page is counted as the rows come.
+        let offset = query.result_offset.unwrap_or(0) as usize;
+        let count = query.result_record_count.map(|count| count as usize);
+        let mut skip = offset;
+        if filter.is_none() && (count.is_some() || 0 < offset) {
+            sql.push_str(&format!(" LIMIT ?{} OFFSET ?{}", values.len() + 1, values.len() + 2));
+            values.push(Value::Integer(count.map_or(-1, |count| count as i64 + 1)));
+            values.push(Value::Integer(offset as i64));
+            skip = 0;
+        }
+
+        let mut statement = self.connection.prepare(&sql)?;
+        let mut rows = statement.query(params_from_iter(values))?;
+        let mut features = Vec::new();
+        let mut exceeded_transfer_limit = false;
+        while let Some(row) = rows.next()? {
+            let geometry: Option<String> = row.get(1)?;
+            let geometry = match geometry {
+                Some(geometry) => Geometry::from_json(&json::parse(&geometry)?),
+                None => None,
+            };
+            if let Some(filter) = filter {
+                if !geometry.as_ref().is_some_and(|geometry| to_geo_geometry(geometry).intersects(filter)) {
+                    continue;
+                }
+            }
+            if 0 < skip {
+                skip -= 1;
+                continue;
+            }
+            if Some(features.len()) == count {
+                exceeded_transfer_limit = true;
+                break;
+            }
+            let attributes: String = row.get(0)?;
+            features.push(Feature {
+                attributes: json::parse(&attributes)?,
+                geometry,
+            });
+        }
+
+        let mut feature_set = FeatureSet {
+            exceeded_transfer_limit,
+            ..self.metadata.clone()
+        };
+        if !query.out_fields.is_empty() && !query.out_fields.iter().any(|field| "*" == field) {
+            feature_set
+                .fields
+                .retain(|field| query.out_fields.iter().any(|name| name.eq_ignore_ascii_case(&field.name)));
+            for feature in &mut features {
+                feature.attributes = out_attributes(&feature.attributes, &query.out_fields);
+            }
+        }
+        if Some(false) == query.return_geometry {
+            for feature in &mut features {
+                feature.geometry = None;
+            }
+        } else if let (Some(out_sr), Some(spatial_reference)) = (&query.out_sr, &self.metadata.spatial_reference) {
+            if out_sr.effective_wkid() != spatial_reference.effective_wkid() {
+                let projection = Projection::new(spatial_reference, out_sr)?;
+                for feature in &mut features {
+                    if let Some(geometry) = &feature.geometry {
+                        feature.geometry = Some(projection.geometry(geometry)?);
+                    }
+                }
+                feature_set.spatial_reference = Some(out_sr.clone());
+            }
+        }
+        if query.decode_domains {
+            for feature in &mut features {
+                self.info.decode(feature);
+            }
+        }
+        feature_set.features = features;
+        Ok(feature_set)
+    }
+
+    /// Like [`FeatureCache::query`], with the features as `Feature<A, G>`.
+    pub fn query_as<A: DeserializeOwned, G: GeometryValue>(&self, query: &Query) -> BoxResult<FeatureSet<A, G>> {
+        self.query(query)?.into_typed()
+    }
+
+    /// A query's filter geometry in the cache's spatial reference: the geometry to test
+    /// features against, if the spatial relationship needs more than the extent, and its
+    /// extent for the R-tree.
+    fn filter_geometry(
+        &self,
+        geometry: &Geometry,
+        in_sr: Option<&SpatialReference>,
+        spatial_rel: Option<&str>,
+    ) -> BoxResult<(Option<geo::Geometry>, Envelope)> {
+        let geometry = match (in_sr, &self.metadata.spatial_reference) {
+            (Some(in_sr), Some(spatial_reference)) if in_sr.effective_wkid() != spatial_reference.effective_wkid() => {
+                Projection::new(in_sr, spatial_reference)?.geometry(geometry)?
+            }
+            _ => geometry.clone(),
+        };
+        let extent = geometry.envelope().ok_or("the query geometry is empty")?;
+        match spatial_rel.unwrap_or("esriSpatialRelIntersects") {
+            "esriSpatialRelIntersects" => Ok((Some(to_geo_geometry(&geometry)), extent)),
+            "esriSpatialRelEnvelopeIntersects" => Ok((None, extent)),
+            spatial_rel => Err(format!("the feature cache can't filter by {}", spatial_rel).into()),
+        }
+    }
+
+    /// The layer's feature service and the layer's ID in it, from the layer's URL.
+    fn service(&self, client: &Client) -> BoxResult<(FeatureService, u32)> {
+        let (service_url, layer_id) = self
+            .url
+            .rsplit_once('/')
+            .ok_or_else(|| format!("{} isn't a feature layer URL", self.url))?;
+        let layer_id = layer_id
+            .parse()
+            .map_err(|_| format!("{} doesn't end with a layer ID", self.url))?;
+        Ok((FeatureService::new(client, service_url), layer_id))
+    }
+}
+
+fn cache_info(connection: &Connection, key: &str) -> BoxResult<Option<String>> {
+    Ok(connection
+        .query_row("SELECT value FROM cache_info WHERE key = ?1", [key], |row| row.get(0))
+        .optional()?)
+}
+
+fn set_cache_info(connection: &Connection, key: &str, value: &str) -> BoxResult<()> {
+    connection.execute("INSERT OR REPLACE INTO cache_info VALUES (?1, ?2)", [key, value])?;
+    Ok(())
+}
+
+/// Inserts a feature, or replaces the one with the same object ID, and indexes its extent.
+fn write_feature(connection: &Connection, object_id_field: &str, fields: &[Field], feature: &Feature) -> BoxResult<()> {
+    let object_id = feature.attributes[object_id_field]
+        .as_i64()
+        .ok_or_else(|| format!("a feature has no {}", object_id_field))?;
+    let mut columns = vec![quote_identifier(object_id_field)];
+    let mut values = vec![Value::Integer(object_id)];
+    for field in fields {
+        columns.push(quote_identifier(&field.name));
+        values.push(sqlite_value(&feature.attributes[field.name.as_str()], &field.field_type));
+    }
+    columns.push(String::from(GEOMETRY_COLUMN));
+    values.push(match &feature.geometry {
+        Some(geometry) => Value::Text(geometry.to_json().dump()),
+        None => Value::Null,
+    });
+    columns.push(String::from(ATTRIBUTES_COLUMN));
+    values.push(Value::Text(feature.attributes.dump()));
+    let placeholders: Vec<String> = (1..=columns.len()).map(|index| format!("?{}", index)).collect();
+    connection.execute(
+        &format!(
+            "INSERT OR REPLACE INTO features ({}) VALUES ({})",
+            columns.join(", "),
+            placeholders.join(", ")
+        ),
+        params_from_iter(values),
+    )?;
+    connection.execute("DELETE FROM features_rtree WHERE id = ?1", [object_id])?;
+    if let Some(extent) = feature.geometry.as_ref().and_then(Geometry::envelope) {
+        connection.execute(
+            "INSERT INTO features_rtree VALUES (?1, ?2, ?3, ?4, ?5)",
+            rusqlite::params![object_id, extent.xmin, extent.xmax, extent.ymin, extent.ymax],
+        )?;
+    }
+    Ok(())
+}
+
+/// Deletes a feature and returns how many were deleted: one, or zero if it wasn't cached.
+fn delete_feature(connection: &Connection, object_id_field: &str, object_id: i64) -> BoxResult<usize> {
+    connection.execute("DELETE FROM features_rtree WHERE id = ?1", [object_id])?;
+    Ok(connection.execute(
+        &format!("DELETE FROM features WHERE {} = ?1", quote_identifier(object_id_field)),
+        [object_id],
+    )?)
+}
+
+/// An `orderByFields` list as SQL, with each field name quoted. Anything but field names and
+/// `ASC` or `DESC` is an error, so the list can't change the rest of the statement.
+fn order_by_sql(order_by: &str) -> BoxResult<String> {
+    order_by
+        .split(',')
+        .map(|item| {
+            let mut words = item.split_whitespace();
+            let field = words.next().ok_or_else(|| format!("{:?} has an empty order by field", order_by))?;
+            if !field.chars().all(|c| c.is_alphanumeric() || '_' == c) {
+                return Err(format!("{:?} isn't a field name to order by", field).into());
+            }
+            let direction = match words.next() {
+                None => "",
+                Some(direction) if direction.eq_ignore_ascii_case("ASC") => " ASC",
+                Some(direction) if direction.eq_ignore_ascii_case("DESC") => " DESC",
+                Some(other) => return Err(format!("{:?} isn't ASC or DESC", other).into()),
+            };
+            match words.next() {
+                None => Ok(format!("{}{}", quote_identifier(field), direction)),
+                Some(other) => Err(format!("unexpected {:?} in the order by fields", other).into()),
+            }
+        })
+        .collect::<BoxResult<Vec<String>>>()
+        .map(|items| items.join(", "))
+}
+
+/// The attributes named in `out_fields`, matched ignoring case as the server does.
+fn out_attributes(attributes: &JsonValue, out_fields: &[String]) -> JsonValue {
+    let mut selected = JsonValue::new_object();
+    for (name, value) in attributes.entries() {
+        if out_fields.iter().any(|field| field.eq_ignore_ascii_case(name)) {
+            selected[name] = value.clone();
+        }
+    }
+    selected
+}
+
+fn to_geo_geometry(geometry: &Geometry) -> geo::Geometry {
+    let line_string = |points: &[Point]| {
+        geo::LineString::from(points.iter().map(|point| (point.x, point.y)).collect::<Vec<_>>())
+    };
+    match geometry {
+        Geometry::Point(point) => geo::Point::new(point.x, point.y).into(),
+        Geometry::Multipoint(multipoint) => {
+            geo::MultiPoint::from(multipoint.points.iter().map(|point| (point.x, point.y)).collect::<Vec<_>>()).into()
+        }
+        Geometry::Polyline(polyline) => {
+            geo::MultiLineString::new(polyline.paths.iter().map(|path| line_string(path)).collect()).into()
+        }
+        Geometry::Polygon(polygon) => geo::MultiPolygon::new(to_geo_polygons(polygon)).into(),
+        Geometry::Envelope(envelope) => {
+            geo::Rect::new((envelope.xmin, envelope.ymin), (envelope.xmax, envelope.ymax)).into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Polygon;
+
+    /// A cache at a unique path in the temp directory, removed when dropped.
+    struct TempCache {
+        path: std::path::PathBuf,
+    }
+
+    impl Drop for TempCache {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn layer_info() -> LayerInfo {
+        LayerInfo::from_json(
+            json::parse(
+                r#"{
+                    "name": "Cities",
+                    "geometryType": "esriGeometryPoint",
+                    "objectIdField": "OBJECTID",
+                    "extent": { "spatialReference": { "wkid": 4326 } },
+                    "fields": [
+                        { "name": "OBJECTID", "type": "esriFieldTypeOID" },
+                        { "name": "NAME", "type": "esriFieldTypeString", "length": 50 },
+                        { "name": "POP", "type": "esriFieldTypeInteger" },
+                        { "name": "Shape", "type": "esriFieldTypeGeometry" }
+                    ]
+                }"#,
+            )
+            .unwrap(),
+        )
+    }
+
+    fn city(object_id: u64, name: &str, pop: i64, x: f64, y: f64) -> Feature {
+        Feature::new(
+            json::object! { "OBJECTID" => object_id, "NAME" => name, "POP" => pop },
+            Some(Geometry::Point(Point::new(x, y))),
+        )
+    }
+
+    fn cities() -> Vec<Feature> {
+        vec![
+            city(1, "Lisbon", 545_000, -9.14, 38.72),
+            city(2, "Porto", 232_000, -8.61, 41.15),
+            city(3, "Madrid", 3_300_000, -3.70, 40.42),
+            city(4, "Paris", 2_100_000, 2.35, 48.86),
+            city(5, "Faro", 64_000, -7.93, 37.02),
+        ]
+    }
+
+    /// Writes the cities to a new cache, then opens it again as a later run would.
+    fn cache() -> (TempCache, FeatureCache) {
+        let temp = TempCache {
+            path: std::env::temp_dir().join(format!("quarenta-cache-{}.sqlite", uuid::Uuid::new_v4())),
+        };
+        let cache = FeatureCache::create_tables(
+            Connection::open(&temp.path).unwrap(),
+            "https://example.com/arcgis/rest/services/Cities/FeatureServer/0",
+            layer_info(),
+            Some(SpatialReference::wgs84()),
+        )
+        .unwrap();
+        for feature in cities() {
+            write_feature(&cache.connection, &cache.object_id_field, &cache.fields, &feature).unwrap();
+        }
+        drop(cache);
+        let cache = FeatureCache::open(&temp.path).unwrap();
+        (temp, cache)
+    }
+
+    fn names(feature_set: &FeatureSet) -> Vec<&str> {
+        feature_set
+            .features
+            .iter()
+            .map(|feature| feature.attributes["NAME"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let (_temp, cache) = cache();
+        assert_eq!(5, cache.count().unwrap());
+        assert_eq!("https://example.com/arcgis/rest/services/Cities/FeatureServer/0", cache.url());
+        let feature_set = cache.query(&Query::new()).unwrap();
+        assert_eq!(cities(), feature_set.features);
+        assert_eq!(Some(4326), feature_set.spatial_reference.and_then(|sr| sr.effective_wkid()));
+        assert!(!feature_set.exceeded_transfer_limit);
+        // Writing a feature again replaces it.
+        write_feature(&cache.connection, "OBJECTID", &cache.fields, &city(5, "Faro", 65_000, -7.93, 37.02)).unwrap();
+        assert_eq!(5, cache.count().unwrap());
+        assert_eq!(65_000, cache.query(&Query::new().object_ids(&[5])).unwrap().features[0].attributes["POP"]);
+    }
+
+    #[test]
+    fn filters_by_where_clause_and_object_ids() {
+        let (_temp, cache) = cache();
+        assert_eq!(vec!["Madrid", "Paris"], names(&cache.query(&Query::new().where_clause("POP > 1000000")).unwrap()));
+        assert_eq!(vec!["Porto", "Faro"], names(&cache.query(&Query::new().object_ids(&[2, 5])).unwrap()));
+        let both = Query::new().where_clause("NAME LIKE 'P%'").object_ids(&[2, 3]);
+        assert_eq!(vec!["Porto"], names(&cache.query(&both).unwrap()));
+    }
+
+    #[test]
+    fn filters_by_geometry() {
+        let (_temp, cache) = cache();
+        // Portugal, roughly.
+        let envelope = Envelope { xmin: -9.6, ymin: 36.9, xmax: -6.1, ymax: 42.2 };
+        let query = Query::new()
+            .geometry(Geometry::Envelope(envelope), SpatialReference::wgs84())
+            .spatial_rel("esriSpatialRelEnvelopeIntersects");
+        assert_eq!(vec!["Lisbon", "Porto", "Faro"], names(&cache.query(&query).unwrap()));
+        // A triangle whose extent covers Portugal but not Porto.
+        let triangle = Polygon {
+            rings: vec![vec![
+                Point::new(-9.6, 36.9),
+                Point::new(-9.6, 42.2),
+                Point::new(-6.1, 36.9),
+                Point::new(-9.6, 36.9),
+            ]],
+        };
+        let query = Query::new().geometry(Geometry::Polygon(triangle), SpatialReference::wgs84());
+        assert_eq!(vec!["Lisbon", "Faro"], names(&cache.query(&query).unwrap()));
+    }
+
+    #[test]
+    fn orders_by_fields() {
+        let (_temp, cache) = cache();
+        assert_eq!(
+            vec!["Madrid", "Paris", "Lisbon", "Porto", "Faro"],
+            names(&cache.query(&Query::new().order_by("POP DESC")).unwrap())
+        );
+        assert_eq!(
+            vec!["Faro", "Lisbon", "Madrid", "Paris", "Porto"],
+            names(&cache.query(&Query::new().order_by("name asc, POP")).unwrap())
+        );
+        assert!(cache.query(&Query::new().order_by("POP; DROP TABLE features")).is_err());
+        assert!(cache.query(&Query::new().order_by("POP DESC NULLS")).is_err());
+        assert!(cache.query(&Query::new().order_by("(SELECT 1)")).is_err());
+    }
+
+    #[test]
+    fn pages_through_features() {
+        let (_temp, cache) = cache();
+        let page = |offset, count| cache.query(&Query::new().result_offset(offset).result_record_count(count)).unwrap();
+        let first = page(0, 2);
+        assert_eq!(vec!["Lisbon", "Porto"], names(&first));
+        assert!(first.exceeded_transfer_limit);
+        let last = page(4, 2);
+        assert_eq!(vec!["Faro"], names(&last));
+        assert!(!last.exceeded_transfer_limit);
+        let exact = page(3, 2);
+        assert_eq!(vec!["Paris", "Faro"], names(&exact));
+        assert!(!exact.exceeded_transfer_limit);
+        assert_eq!(vec!["Paris", "Faro"], names(&cache.query(&Query::new().result_offset(3)).unwrap()));
+
+        // Pages count only the features that pass the geometry test.
+        let triangle = Polygon {
+            rings: vec![vec![
+                Point::new(-9.6, 36.9),
+                Point::new(-9.6, 42.2),
+                Point::new(-6.1, 36.9),
+                Point::new(-9.6, 36.9),
+            ]],
+        };
+        let query = Query::new()
+            .geometry(Geometry::Polygon(triangle), SpatialReference::wgs84())
+            .result_offset(1)
+            .result_record_count(1);
+        let feature_set = cache.query(&query).unwrap();
+        assert_eq!(vec!["Faro"], names(&feature_set));
+        assert!(!feature_set.exceeded_transfer_limit);
+    }
+
+    #[test]
+    fn returns_only_the_out_fields() {
+        let (_temp, cache) = cache();
+        let feature_set = cache
+            .query(&Query::new().out_fields(&["name"]).return_geometry(false).object_ids(&[1]))
+            .unwrap();
+        assert_eq!(vec!["NAME"], feature_set.fields.iter().map(|field| field.name.as_str()).collect::<Vec<_>>());
+        assert_eq!(json::object! { "NAME" => "Lisbon" }, feature_set.features[0].attributes);
+        assert_eq!(None, feature_set.features[0].geometry);
+    }
+
+    #[test]
+    fn queries_typed_features() {
+        #[derive(serde::Deserialize)]
+        struct City {
+            #[serde(rename = "NAME")]
+            name: String,
+            #[serde(rename = "POP")]
+            pop: u32,
+        }
+
+        let (_temp, cache) = cache();
+        let feature_set = cache.query_as::<City, Point>(&Query::new().object_ids(&[4])).unwrap();
+        let paris = &feature_set.features[0];
+        assert_eq!(("Paris", 2_100_000), (paris.attributes.name.as_str(), paris.attributes.pop));
+        assert_eq!(Some(Point::new(2.35, 48.86)), paris.geometry);
+    }
+}
@@ -0,0 +1,440 @@
+//! Feature layers and tables in feature services and map services.
+
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, TimeZone, Utc};
+use json::JsonValue;
+
+use crate::domain::{subtypes_from_json, Subtype};
+use crate::feature::FeatureSet;
+use crate::field::{fields_from_json, Field};
+use crate::geometry::{Geometry, GeometryType, SpatialReference};
+use crate::pbf;
+use crate::request::{has_json_body, ArcGisError, Client, RequestOptions};
+use crate::stream::FeatureStream;
+use crate::values::time_param;
+use crate::where_clause::WhereClause;
+use crate::BoxResult;
+
+/// The description of a layer, from its REST endpoint.
+#[derive(Clone, Debug)]
+pub struct LayerInfo {
+    pub id: Option<u32>,
+    pub name: String,
+    pub layer_type: Option<String>,
+    pub geometry_type: Option<GeometryType>,
+    pub object_id_field: Option<String>,
+    pub global_id_field: Option<String>,
+    pub fields: Vec<Field>,
+    pub max_record_count: Option<u32>,
+    pub capabilities: Vec<String>,
+    pub supported_query_formats: Vec<String>,
+    /// The field that holds each feature's subtype (or feature type), if the layer has them.
+    pub subtype_field: Option<String>,
+    pub subtypes: Vec<Subtype>,
+    /// The layer's time settings, if it's time-aware.
+    pub time_info: Option<TimeInfo>,
+    /// The full response, for properties quarenta doesn't model.
+    pub raw: JsonValue,
+}
+
+impl LayerInfo {
+    pub fn from_json(value: JsonValue) -> LayerInfo {
+        let (subtype_field, subtypes) = subtypes_from_json(&value);
+        LayerInfo {
+            id: value["id"].as_u32(),
+            name: value["name"].as_str().unwrap_or("").to_string(),
+            layer_type: value["type"].as_str().map(String::from),
+            geometry_type: value["geometryType"].as_str().and_then(GeometryType::from_name),
+            object_id_field: value["objectIdField"].as_str().map(String::from),
+            global_id_field: value["globalIdField"].as_str().map(String::from),
+            fields: fields_from_json(&value["fields"]),
+            max_record_count: value["maxRecordCount"].as_u32(),
+            capabilities: split_list(&value["capabilities"]),
+            supported_query_formats: split_list(&value["supportedQueryFormats"]),
+            subtype_field,
+            subtypes,
+            time_info: TimeInfo::from_json(&value["timeInfo"]),
+            raw: value,
+        }
+    }
+
+    /// Whether the layer can return query results as protocol buffers.
+    pub fn supports_pbf(&self) -> bool {
+        self.supported_query_formats
+            .iter()
+            .any(|format| format.eq_ignore_ascii_case("pbf"))
+    }
+
+    /// Finds a field by name, ignoring case as ArcGIS does.
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|field| field.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// The `timeInfo` of a time-aware layer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeInfo {
+    pub start_time_field: Option<String>,
+    pub end_time_field: Option<String>,
+    pub track_id_field: Option<String>,
+    /// The earliest and latest times of the layer's features.
+    pub time_extent: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    /// The time zone the layer's dates are in, if they aren't UTC.
+    pub time_zone: Option<String>,
+    pub time_interval: Option<f64>,
+    /// e.g. `esriTimeUnitsHours`.
+    pub time_interval_units: Option<String>,
+}
+
+impl TimeInfo {
+    pub fn from_json(value: &JsonValue) -> Option<TimeInfo> {
+        if !value.is_object() {
+            return None;
+        }
+        let time = |value: &JsonValue| value.as_i64().and_then(|millis| Utc.timestamp_millis_opt(millis).single());
+        Some(TimeInfo {
+            start_time_field: value["startTimeField"].as_str().map(String::from),
+            end_time_field: value["endTimeField"].as_str().map(String::from),
+            track_id_field: value["trackIdField"].as_str().map(String::from),
+            time_extent: time(&value["timeExtent"][0]).zip(time(&value["timeExtent"][1])),
+            time_zone: value["timeReference"]["timeZone"].as_str().map(String::from),
+            time_interval: value["timeInterval"].as_f64(),
+            time_interval_units: value["timeIntervalUnits"].as_str().map(String::from),
+        })
+    }
+}
+
+/// Splits a comma-separated list such as `"Query,Create,Update"`.
+fn split_list(value: &JsonValue) -> Vec<String> {
+    value
+        .as_str()
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// The response format to ask for when querying features.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueryFormat {
+    /// PBF if the layer supports it, otherwise JSON.
+    #[default]
+    Pbf,
+    Json,
+}
+
+/// The parameters of a feature query.
+///
+/// # Examples
+///
+/// ```
+/// use quarenta::Query;
+///
+/// let query = Query::new()
+///     .where_clause("population IS NOT NULL")
+///     .out_fields(&["city", "population"])
+///     .order_by("population DESC")
+///     .result_record_count(10);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    pub(crate) where_clause: Option<String>,
+    filter: Option<WhereClause>,
+    pub(crate) object_ids: Vec<u64>,
+    pub(crate) out_fields: Vec<String>,
+    pub(crate) return_geometry: Option<bool>,
+    pub(crate) geometry: Option<Geometry>,
+    pub(crate) in_sr: Option<SpatialReference>,
+    pub(crate) spatial_rel: Option<String>,
+    pub(crate) out_sr: Option<SpatialReference>,
+    pub(crate) order_by: Option<String>,
+    pub(crate) result_offset: Option<u64>,
+    pub(crate) result_record_count: Option<u64>,
+    pub(crate) time: Option<String>,
+    format: QueryFormat,
+    pub(crate) decode_domains: bool,
+}
+
+impl Query {
+    pub fn new() -> Query {
+        Query::default()
+    }
+
+    pub fn where_clause(mut self, where_clause: &str) -> Query {
+        self.where_clause = Some(where_clause.to_string());
+        self.filter = None;
+        self
+    }
+
+    /// Sets the where clause from a [`WhereClause`], whose fields are checked against the
+    /// layer before the query is sent.
+    pub fn filter(mut self, filter: WhereClause) -> Query {
+        self.where_clause = Some(filter.to_sql());
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn object_ids(mut self, object_ids: &[u64]) -> Query {
+        self.object_ids = object_ids.to_vec();
+        self
+    }
+
+    /// The fields to return. All fields are returned if this isn't called.
+    pub fn out_fields(mut self, out_fields: &[&str]) -> Query {
+        self.out_fields = out_fields.iter().map(|field| field.to_string()).collect();
+        self
+    }
+
+    pub fn return_geometry(mut self, return_geometry: bool) -> Query {
+        self.return_geometry = Some(return_geometry);
+        self
+    }
+
+    /// Filters by a geometry in the given spatial reference, using `esriSpatialRelIntersects`
+    /// unless [`Query::spatial_rel`] says otherwise.
+    pub fn geometry(mut self, geometry: Geometry, in_sr: SpatialReference) -> Query {
+        self.geometry = Some(geometry);
+        self.in_sr = Some(in_sr);
+        self
+    }
+
+    pub fn spatial_rel(mut self, spatial_rel: &str) -> Query {
+        self.spatial_rel = Some(spatial_rel.to_string());
+        self
+    }
+
+    pub fn out_sr(mut self, out_sr: SpatialReference) -> Query {
+        self.out_sr = Some(out_sr);
+        self
+    }
+
+    pub fn order_by(mut self, order_by: &str) -> Query {
+        self.order_by = Some(order_by.to_string());
+        self
+    }
+
+    pub fn result_offset(mut self, result_offset: u64) -> Query {
+        self.result_offset = Some(result_offset);
+        self
+    }
+
+    pub fn result_record_count(mut self, result_record_count: u64) -> Query {
+        self.result_record_count = Some(result_record_count);
+        self
+    }
+
+    /// Limits a time-aware layer's features to those within a time extent. Either end can be
+    /// left open.
+    pub fn time_extent(mut self, start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) -> Query {
+        self.time = Some(time_param(start.as_ref(), end.as_ref()));
+        self
+    }
+
+    /// Limits a time-aware layer's features to those at an instant.
+    pub fn time_instant(mut self, instant: DateTime<Utc>) -> Query {
+        self.time = Some(instant.timestamp_millis().to_string());
+        self
+    }
+
+    pub fn format(mut self, format: QueryFormat) -> Query {
+        self.format = format;
+        self
+    }
+
+    /// Whether [`FeatureLayer::query`] and [`FeatureLayer::query_stream`] replace coded values
+    /// with their labels, using the layer's domains and subtypes.
+    pub fn decode_domains(mut self, decode_domains: bool) -> Query {
+        self.decode_domains = decode_domains;
+        self
+    }
+
+    pub(crate) fn to_params(&self) -> Vec<(&'static str, String)> {
+        let mut params = vec![(
+            "where",
+            self.where_clause.clone().unwrap_or_else(|| String::from("1=1")),
+        )];
+        if !self.object_ids.is_empty() {
+            let object_ids: Vec<String> = self.object_ids.iter().map(ToString::to_string).collect();
+            params.push(("objectIds", object_ids.join(",")));
+        }
+        params.push((
+            "outFields",
+            if self.out_fields.is_empty() { String::from("*") } else { self.out_fields.join(",") },
+        ));
+        if let Some(return_geometry) = self.return_geometry {
+            params.push(("returnGeometry", return_geometry.to_string()));
+        }
+        if let Some(geometry) = &self.geometry {
+            params.push(("geometry", geometry.to_json().dump()));
+            params.push(("geometryType", geometry.geometry_type().as_str().to_string()));
+            params.push((
+                "spatialRel",
+                self.spatial_rel.clone().unwrap_or_else(|| String::from("esriSpatialRelIntersects")),
+            ));
+        }
+        if let Some(in_sr) = &self.in_sr {
+            params.push(("inSR", in_sr.to_param()));
+        }
+        if let Some(out_sr) = &self.out_sr {
+            params.push(("outSR", out_sr.to_param()));
+        }
+        if let Some(order_by) = &self.order_by {
+            params.push(("orderByFields", order_by.clone()));
+        }
+        if let Some(time) = &self.time {
+            params.push(("time", time.clone()));
+        }
+        if let Some(result_offset) = self.result_offset {
+            params.push(("resultOffset", result_offset.to_string()));
+        }
+        if let Some(result_record_count) = self.result_record_count {
+            params.push(("resultRecordCount", result_record_count.to_string()));
+        }
+        params
+    }
+}
+
+/// A feature layer or table, identified by its REST URL, e.g.
+/// `https://services.arcgis.com/.../FeatureServer/0`.
+#[derive(Clone)]
+pub struct FeatureLayer {
+    client: Client,
+    url: String,
+    info: Arc<Mutex<Option<Arc<LayerInfo>>>>,
+}
+
+impl FeatureLayer {
+    pub fn new(client: &Client, url: &str) -> FeatureLayer {
+        FeatureLayer {
+            client: client.clone(),
+            url: url.trim_end_matches('/').to_string(),
+            info: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Fetches the layer's description. The result is cached, so later calls, including the
+    /// ones `query` makes to pick a format, don't go back to the server.
+    pub async fn describe(&self) -> BoxResult<Arc<LayerInfo>> {
+        if let Some(info) = self.info.lock().unwrap().as_ref() {
+            return Ok(info.clone());
+        }
+        let value = self.client.get_json(&self.url, &[], &RequestOptions::default()).await?;
+        let info = Arc::new(LayerInfo::from_json(value));
+        *self.info.lock().unwrap() = Some(info.clone());
+        Ok(info)
+    }
+
+    /// Queries the layer's features.
+    ///
+    /// By default this asks for `f=pbf` when the layer lists PBF in `supportedQueryFormats`,
+    /// and uses `f=json` otherwise, if the layer can't be described, or if the PBF request fails.
+    /// Either way, the result is the same `FeatureSet`.
+    pub async fn query(&self, query: &Query) -> BoxResult<FeatureSet> {
+        self.validate_filter(query).await?;
+        let mut feature_set = None;
+        if QueryFormat::Json != query.format && self.supports_pbf().await {
+            match self.query_pbf(query).await {
+                Ok(pbf_feature_set) => feature_set = Some(pbf_feature_set),
+                Err(err) => {
+                    tracing::debug!(error = %err, "PBF query failed; falling back to JSON");
+                }
+            }
+        }
+        let mut feature_set = match feature_set {
+            Some(feature_set) => feature_set,
+            None => self.query_json(query).await?,
+        };
+        if query.decode_domains {
+            let info = self.describe().await?;
+            for feature in &mut feature_set.features {
+                info.decode(feature);
+            }
+        }
+        Ok(feature_set)
+    }
+
+    /// Queries the layer's features as JSON and returns them one at a time as the response
+    /// downloads, so the caller can start on the first features before the last ones arrive.
+    ///
+    /// The request is retried like any other, but only until the response starts; an error
+    /// partway through the body ends the stream with an `Err`.
+    pub async fn query_stream(&self, query: &Query) -> BoxResult<FeatureStream> {
+        self.validate_filter(query).await?;
+        let params = self.client.params_with_auth(&query.to_params(), "json");
+        let request = self.client.http().post(&self.query_url()).form(&params);
+        let response = self.client.send(request, &RequestOptions::default()).await?;
+        let mut stream = FeatureStream::new(response);
+        if query.decode_domains {
+            stream.decode_with(self.describe().await?);
+        }
+        Ok(stream)
+    }
+
+    /// Counts the features that match the query.
+    pub async fn query_count(&self, query: &Query) -> BoxResult<u64> {
+        self.validate_filter(query).await?;
+        let mut params = query.to_params();
+        params.push(("returnCountOnly", String::from("true")));
+        let response = self.client.post_json(&self.query_url(), &params, &RequestOptions::default()).await?;
+        response["count"]
+            .as_u64()
+            .ok_or_else(|| "query response has no count".into())
+    }
+
+    async fn query_json(&self, query: &Query) -> BoxResult<FeatureSet> {
+        let response = self
+            .client
+            .post_json(&self.query_url(), &query.to_params(), &RequestOptions::default())
+            .await?;
+        Ok(FeatureSet::from_json(&response))
+    }
+
+    async fn supports_pbf(&self) -> bool {
+        match self.describe().await {
+            Ok(info) => info.supports_pbf(),
+            Err(err) => {
+                tracing::debug!(error = %err, "couldn't describe the layer; querying as JSON");
+                false
+            }
+        }
+    }
+
+    async fn query_pbf(&self, query: &Query) -> BoxResult<FeatureSet> {
+        let params = self.client.params_with_auth(&query.to_params(), "pbf");
+        let request = self.client.http().post(&self.query_url()).form(&params);
+        let response = self.client.send(request, &RequestOptions::default()).await?;
+        let is_json = has_json_body(response.headers());
+        let bytes = response.bytes().await?;
+        if is_json {
+            // Errors come back as JSON even when PBF was requested.
+            let value = json::parse(&String::from_utf8_lossy(&bytes))?;
+            ArcGisError::check(value)?;
+            return Err("expected PBF but the server returned JSON".into());
+        }
+        pbf::decode_feature_set(&bytes)
+    }
+
+    /// Checks a [`Query::filter`] against the layer's fields. Plain string where clauses are
+    /// sent as they are.
+    async fn validate_filter(&self, query: &Query) -> BoxResult<()> {
+        match &query.filter {
+            Some(filter) => filter.validate(&*self.describe().await?),
+            None => Ok(()),
+        }
+    }
+
+    fn query_url(&self) -> String {
+        format!("{}/query", self.url)
+    }
+}
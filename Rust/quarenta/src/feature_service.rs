@@ -0,0 +1,42 @@
+//! Feature services, the parent of feature layers and tables.
+
+use json::JsonValue;
+
+use crate::feature_layer::FeatureLayer;
+use crate::request::{Client, RequestOptions};
+use crate::BoxResult;
+
+/// A feature service, identified by its REST URL, e.g.
+/// `https://services.arcgis.com/.../FeatureServer`.
+#[derive(Clone)]
+pub struct FeatureService {
+    client: Client,
+    url: String,
+}
+
+impl FeatureService {
+    pub fn new(client: &Client, url: &str) -> FeatureService {
+        FeatureService {
+            client: client.clone(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Returns one of the service's layers or tables by ID.
+    pub fn layer(&self, id: u32) -> FeatureLayer {
+        FeatureLayer::new(&self.client, &format!("{}/{}", self.url, id))
+    }
+
+    /// Fetches the service's description.
+    pub async fn describe(&self) -> BoxResult<JsonValue> {
+        self.client.get_json(&self.url, &[], &RequestOptions::default()).await
+    }
+}
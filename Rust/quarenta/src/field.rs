@@ -0,0 +1,150 @@
+//! Field definitions from layer metadata and query responses.
+
+use json::JsonValue;
+
+use crate::domain::Domain;
+
+/// The `esriFieldType*` field types.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FieldType {
+    SmallInteger,
+    Integer,
+    BigInteger,
+    Single,
+    Double,
+    String,
+    Date,
+    DateOnly,
+    TimeOnly,
+    TimestampOffset,
+    Oid,
+    Geometry,
+    Blob,
+    Raster,
+    Guid,
+    GlobalId,
+    Xml,
+    /// A field type this version of quarenta doesn't know about.
+    Other(String),
+}
+
+impl FieldType {
+    pub fn from_name(name: &str) -> FieldType {
+        match name {
+            "esriFieldTypeSmallInteger" => FieldType::SmallInteger,
+            "esriFieldTypeInteger" => FieldType::Integer,
+            "esriFieldTypeBigInteger" => FieldType::BigInteger,
+            "esriFieldTypeSingle" => FieldType::Single,
+            "esriFieldTypeDouble" => FieldType::Double,
+            "esriFieldTypeString" => FieldType::String,
+            "esriFieldTypeDate" => FieldType::Date,
+            "esriFieldTypeDateOnly" => FieldType::DateOnly,
+            "esriFieldTypeTimeOnly" => FieldType::TimeOnly,
+            "esriFieldTypeTimestampOffset" => FieldType::TimestampOffset,
+            "esriFieldTypeOID" => FieldType::Oid,
+            "esriFieldTypeGeometry" => FieldType::Geometry,
+            "esriFieldTypeBlob" => FieldType::Blob,
+            "esriFieldTypeRaster" => FieldType::Raster,
+            "esriFieldTypeGUID" => FieldType::Guid,
+            "esriFieldTypeGlobalID" => FieldType::GlobalId,
+            "esriFieldTypeXML" => FieldType::Xml,
+            other => FieldType::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            FieldType::SmallInteger => "esriFieldTypeSmallInteger",
+            FieldType::Integer => "esriFieldTypeInteger",
+            FieldType::BigInteger => "esriFieldTypeBigInteger",
+            FieldType::Single => "esriFieldTypeSingle",
+            FieldType::Double => "esriFieldTypeDouble",
+            FieldType::String => "esriFieldTypeString",
+            FieldType::Date => "esriFieldTypeDate",
+            FieldType::DateOnly => "esriFieldTypeDateOnly",
+            FieldType::TimeOnly => "esriFieldTypeTimeOnly",
+            FieldType::TimestampOffset => "esriFieldTypeTimestampOffset",
+            FieldType::Oid => "esriFieldTypeOID",
+            FieldType::Geometry => "esriFieldTypeGeometry",
+            FieldType::Blob => "esriFieldTypeBlob",
+            FieldType::Raster => "esriFieldTypeRaster",
+            FieldType::Guid => "esriFieldTypeGUID",
+            FieldType::GlobalId => "esriFieldTypeGlobalID",
+            FieldType::Xml => "esriFieldTypeXML",
+            FieldType::Other(name) => name.as_str(),
+        }
+    }
+}
+
+/// A field of a layer or feature set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub field_type: FieldType,
+    pub alias: Option<String>,
+    pub length: Option<u32>,
+    /// Whether the field accepts nulls. Services that don't say are assumed to.
+    pub nullable: bool,
+    pub editable: bool,
+    /// The field's domain, if any. Subtypes can override it; see [`LayerInfo::domain`].
+    ///
+    /// [`LayerInfo::domain`]: crate::LayerInfo::domain
+    pub domain: Option<Domain>,
+}
+
+impl Field {
+    pub fn new(name: &str, field_type: FieldType) -> Field {
+        Field {
+            name: name.to_string(),
+            field_type,
+            alias: None,
+            length: None,
+            nullable: true,
+            editable: true,
+            domain: None,
+        }
+    }
+
+    pub fn from_json(value: &JsonValue) -> Option<Field> {
+        Some(Field {
+            name: value["name"].as_str()?.to_string(),
+            field_type: FieldType::from_name(value["type"].as_str().unwrap_or("")),
+            alias: value["alias"].as_str().map(String::from),
+            length: value["length"].as_u32(),
+            nullable: value["nullable"].as_bool().unwrap_or(true),
+            editable: value["editable"].as_bool().unwrap_or(true),
+            domain: Domain::from_json(&value["domain"]),
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut value = JsonValue::new_object();
+        value["name"] = self.name.as_str().into();
+        value["type"] = self.field_type.as_str().into();
+        if let Some(alias) = &self.alias {
+            value["alias"] = alias.as_str().into();
+        }
+        if let Some(length) = self.length {
+            value["length"] = length.into();
+        }
+        value["nullable"] = self.nullable.into();
+        value["editable"] = self.editable.into();
+        if let Some(domain) = &self.domain {
+            value["domain"] = domain.to_json();
+        }
+        value
+    }
+}
+
+pub(crate) fn fields_from_json(value: &JsonValue) -> Vec<Field> {
+    value.members().filter_map(Field::from_json).collect()
+}
+
+/// The fields worth exporting or caching: everything except geometry, blob and raster fields.
+pub(crate) fn attribute_fields(fields: &[Field]) -> Vec<Field> {
+    fields
+        .iter()
+        .filter(|field| !matches!(field.field_type, FieldType::Geometry | FieldType::Blob | FieldType::Raster))
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,486 @@
+//! Geocoding services: finding addresses and places, suggestions, reverse geocoding and batch
+//! geocoding.
+
+use json::JsonValue;
+
+use crate::geometry::{Envelope, Geometry, Point, SpatialReference};
+use crate::request::{ArcGisError, Client, RequestOptions};
+use crate::BoxResult;
+
+/// The ArcGIS World Geocoding Service.
+pub const WORLD_GEOCODER_URL: &str = "https://geocode.arcgis.com/arcgis/rest/services/World/GeocodeServer";
+
+/// The number of addresses per `geocodeAddresses` request if the locator doesn't suggest one.
+const DEFAULT_BATCH_SIZE: usize = 150;
+
+/// A match for an address or place.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AddressCandidate {
+    pub address: String,
+    pub location: Point,
+    /// How well the candidate matches, from 0 to 100.
+    pub score: f64,
+    /// The output fields asked for, such as `Match_addr`, `Addr_type` or `Country`.
+    pub attributes: JsonValue,
+    /// The area to show the candidate at, if the locator gives one.
+    pub extent: Option<Envelope>,
+}
+
+impl AddressCandidate {
+    pub fn from_json(value: &JsonValue) -> Option<AddressCandidate> {
+        let extent = match Geometry::from_json(&value["extent"]) {
+            Some(Geometry::Envelope(extent)) => Some(extent),
+            _ => None,
+        };
+        Some(AddressCandidate {
+            address: value["address"].as_str().unwrap_or("").to_string(),
+            location: Point::new(value["location"]["x"].as_f64()?, value["location"]["y"].as_f64()?),
+            score: value["score"].as_f64().unwrap_or(0.0),
+            attributes: value["attributes"].clone(),
+            extent,
+        })
+    }
+}
+
+/// A completion of partial text, from [`Geocoder::suggest`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Suggestion {
+    pub text: String,
+    /// Identifies the suggestion to [`FindAddressOptions::magic_key`], which makes finding it
+    /// faster and exact.
+    pub magic_key: String,
+    /// Whether the suggestion is a category or a group of places rather than one place.
+    pub is_collection: bool,
+}
+
+impl Suggestion {
+    pub fn from_json(value: &JsonValue) -> Option<Suggestion> {
+        Some(Suggestion {
+            text: value["text"].as_str()?.to_string(),
+            magic_key: value["magicKey"].as_str().unwrap_or("").to_string(),
+            is_collection: value["isCollection"].as_bool().unwrap_or(false),
+        })
+    }
+}
+
+/// The address at a location, from [`Geocoder::reverse_geocode`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReverseGeocodeResult {
+    /// The address as one line, from `Match_addr` or else `LongLabel`.
+    pub address: String,
+    /// The location of the address, which may be a little way from the one asked about.
+    pub location: Point,
+    /// The address's parts, such as `City`, `Region` and `Postal`.
+    pub attributes: JsonValue,
+}
+
+impl ReverseGeocodeResult {
+    pub fn from_json(value: &JsonValue) -> Option<ReverseGeocodeResult> {
+        let attributes = &value["address"];
+        let address = attributes["Match_addr"]
+            .as_str()
+            .or_else(|| attributes["LongLabel"].as_str())
+            .unwrap_or("");
+        Some(ReverseGeocodeResult {
+            address: address.to_string(),
+            location: Point::new(value["location"]["x"].as_f64()?, value["location"]["y"].as_f64()?),
+            attributes: attributes.clone(),
+        })
+    }
+}
+
+/// The parameters of a `findAddressCandidates` request.
+#[derive(Clone, Debug)]
+pub struct FindAddressOptions {
+    single_line: String,
+    magic_key: Option<String>,
+    max_locations: Option<u32>,
+    out_fields: Vec<String>,
+    out_sr: Option<SpatialReference>,
+    location: Option<Point>,
+    category: Option<String>,
+    country_code: Option<String>,
+    for_storage: bool,
+}
+
+impl FindAddressOptions {
+    /// Finds an address or place written on one line, e.g. `380 New York St, Redlands, CA`
+    /// or `Lisbon`.
+    pub fn new(single_line: &str) -> FindAddressOptions {
+        FindAddressOptions {
+            single_line: single_line.to_string(),
+            magic_key: None,
+            max_locations: None,
+            out_fields: Vec::new(),
+            out_sr: None,
+            location: None,
+            category: None,
+            country_code: None,
+            for_storage: false,
+        }
+    }
+
+    /// Finds the place a [`Suggestion`] stands for.
+    pub fn from_suggestion(suggestion: &Suggestion) -> FindAddressOptions {
+        FindAddressOptions::new(&suggestion.text).magic_key(&suggestion.magic_key)
+    }
+
+    pub fn magic_key(mut self, magic_key: &str) -> FindAddressOptions {
+        self.magic_key = Some(magic_key.to_string());
+        self
+    }
+
+    pub fn max_locations(mut self, max_locations: u32) -> FindAddressOptions {
+        self.max_locations = Some(max_locations);
+        self
+    }
+
+    /// The attributes to return with each candidate, e.g. `["*"]` for all of them.
+    pub fn out_fields(mut self, out_fields: &[&str]) -> FindAddressOptions {
+        self.out_fields = out_fields.iter().map(|field| field.to_string()).collect();
+        self
+    }
+
+    pub fn out_sr(mut self, out_sr: SpatialReference) -> FindAddressOptions {
+        self.out_sr = Some(out_sr);
+        self
+    }
+
+    /// Prefers candidates near a longitude and latitude.
+    pub fn location(mut self, location: Point) -> FindAddressOptions {
+        self.location = Some(location);
+        self
+    }
+
+    /// Limits the candidates to a category, e.g. `Address`, `City` or `Coffee Shop`.
+    pub fn category(mut self, category: &str) -> FindAddressOptions {
+        self.category = Some(category.to_string());
+        self
+    }
+
+    /// Limits the candidates to a country, by ISO 3166 code such as `USA` or `PRT`.
+    pub fn country_code(mut self, country_code: &str) -> FindAddressOptions {
+        self.country_code = Some(country_code.to_string());
+        self
+    }
+
+    /// Whether the results will be stored, which the World Geocoding Service requires a
+    /// token for.
+    pub fn for_storage(mut self, for_storage: bool) -> FindAddressOptions {
+        self.for_storage = for_storage;
+        self
+    }
+
+    fn to_params(&self) -> Vec<(&'static str, String)> {
+        let mut params = vec![("SingleLine", self.single_line.clone())];
+        if let Some(magic_key) = &self.magic_key {
+            params.push(("magicKey", magic_key.clone()));
+        }
+        if let Some(max_locations) = self.max_locations {
+            params.push(("maxLocations", max_locations.to_string()));
+        }
+        if !self.out_fields.is_empty() {
+            params.push(("outFields", self.out_fields.join(",")));
+        }
+        if let Some(out_sr) = &self.out_sr {
+            params.push(("outSR", out_sr.to_param()));
+        }
+        if let Some(location) = &self.location {
+            params.push(("location", format!("{},{}", location.x, location.y)));
+        }
+        if let Some(category) = &self.category {
+            params.push(("category", category.clone()));
+        }
+        if let Some(country_code) = &self.country_code {
+            params.push(("countryCode", country_code.clone()));
+        }
+        if self.for_storage {
+            params.push(("forStorage", String::from("true")));
+        }
+        params
+    }
+}
+
+/// A geocoding service, identified by its REST URL, e.g. [`WORLD_GEOCODER_URL`] or a custom
+/// locator's `.../GeocodeServer`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// use quarenta::{Client, FindAddressOptions, Geocoder};
+///
+/// let geocoder = Geocoder::world(&Client::new());
+/// let candidates = geocoder
+///     .find_address_candidates(&FindAddressOptions::new("380 New York St, Redlands").max_locations(1))
+///     .await?;
+/// if let Some(candidate) = candidates.first() {
+///     println!("{} is at {}, {}", candidate.address, candidate.location.x, candidate.location.y);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Geocoder {
+    client: Client,
+    url: String,
+}
+
+impl Geocoder {
+    pub fn new(client: &Client, url: &str) -> Geocoder {
+        Geocoder {
+            client: client.clone(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// The ArcGIS World Geocoding Service. Batch geocoding and storing results need a token.
+    pub fn world(client: &Client) -> Geocoder {
+        Geocoder::new(client, WORLD_GEOCODER_URL)
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Fetches the locator's description.
+    pub async fn describe(&self) -> BoxResult<JsonValue> {
+        self.client.get_json(&self.url, &[], &RequestOptions::default()).await
+    }
+
+    /// Finds the candidates for an address or place, best first.
+    pub async fn find_address_candidates(&self, options: &FindAddressOptions) -> BoxResult<Vec<AddressCandidate>> {
+        let response = self
+            .client
+            .get_json(
+                &format!("{}/findAddressCandidates", self.url),
+                &options.to_params(),
+                &RequestOptions::default(),
+            )
+            .await?;
+        Ok(response["candidates"].members().filter_map(AddressCandidate::from_json).collect())
+    }
+
+    /// Finds a location typed by a user: "longitude, latitude" as read by [`Point::parse`], or
+    /// else an address or place name, geocoded to its best candidate in WGS 1984. Returns
+    /// `None` if nothing matches, and `Err` without geocoding if `text` is blank.
+    pub async fn locate(&self, text: &str) -> BoxResult<Option<Point>> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err("no location given".into());
+        }
+        if let Some(point) = Point::parse(text) {
+            return Ok(Some(point));
+        }
+        let options = FindAddressOptions::new(text)
+            .max_locations(1)
+            .out_sr(SpatialReference::wgs84());
+        let candidate = self.find_address_candidates(&options).await?.into_iter().next();
+        if let Some(candidate) = &candidate {
+            tracing::debug!(address = %candidate.address, score = candidate.score, "located {}", text);
+        }
+        Ok(candidate.map(|candidate| candidate.location))
+    }
+
+    /// Suggests completions of partial text as the user types, preferring places near
+    /// `location` if it's given.
+    pub async fn suggest(&self, text: &str, location: Option<&Point>, max_suggestions: u32) -> BoxResult<Vec<Suggestion>> {
+        let mut params = vec![("text", text.to_string()), ("maxSuggestions", max_suggestions.to_string())];
+        if let Some(location) = location {
+            params.push(("location", format!("{},{}", location.x, location.y)));
+        }
+        let response = self
+            .client
+            .get_json(&format!("{}/suggest", self.url), &params, &RequestOptions::default())
+            .await?;
+        Ok(response["suggestions"].members().filter_map(Suggestion::from_json).collect())
+    }
+
+    /// Finds the address at a location. Returns `None` if there's no address nearby.
+    pub async fn reverse_geocode(
+        &self,
+        location: &Point,
+        spatial_reference: &SpatialReference,
+    ) -> BoxResult<Option<ReverseGeocodeResult>> {
+        let params = [
+            (
+                "location",
+                Geometry::Point(*location)
+                    .to_json_with_spatial_reference(spatial_reference)
+                    .dump(),
+            ),
+            ("outSR", spatial_reference.to_param()),
+        ];
+        let request = self
+            .client
+            .http()
+            .get(&format!("{}/reverseGeocode", self.url))
+            .query(&self.client.params_with_auth(&params, "json"));
+        let response = self.client.send_json(request, &RequestOptions::default()).await?;
+        match ArcGisError::from_json(&response) {
+            // "Unable to find address for the specified location."
+            Some(error) if 400 == error.code => Ok(None),
+            Some(error) => Err(error.into()),
+            None => Ok(ReverseGeocodeResult::from_json(&response)),
+        }
+    }
+
+    /// Geocodes many addresses, in batches of the locator's suggested size. The results are in
+    /// the same order as `addresses`, with `None` for addresses that didn't match.
+    pub async fn geocode_addresses(
+        &self,
+        addresses: &[&str],
+        out_sr: Option<&SpatialReference>,
+    ) -> BoxResult<Vec<Option<AddressCandidate>>> {
+        let description = self.describe().await?;
+        let batch_size = description["locatorProperties"]["SuggestedBatchSize"]
+            .as_usize()
+            .filter(|batch_size| *batch_size > 0)
+            .unwrap_or(DEFAULT_BATCH_SIZE);
+        let mut results = vec![None; addresses.len()];
+        for (batch_index, batch) in addresses.chunks(batch_size).enumerate() {
+            let offset = batch_index * batch_size;
+            let records: Vec<JsonValue> = batch
+                .iter()
+                .enumerate()
+                .map(|(index, address)| {
+                    json::object! {
+                        "attributes" => json::object! {
+                            "OBJECTID" => offset + index,
+                            "SingleLine" => *address
+                        }
+                    }
+                })
+                .collect();
+            let mut params = vec![("addresses", json::object! { "records" => records }.dump())];
+            if let Some(out_sr) = out_sr {
+                params.push(("outSR", out_sr.to_param()));
+            }
+            let response = self
+                .client
+                .post_json(&format!("{}/geocodeAddresses", self.url), &params, &RequestOptions::default())
+                .await?;
+            for location in response["locations"].members() {
+                let matched = location["attributes"]["Status"].as_str() != Some("U");
+                match (location["attributes"]["ResultID"].as_usize(), matched) {
+                    (Some(id), true) if id < results.len() => results[id] = AddressCandidate::from_json(location),
+                    _ => {}
+                }
+            }
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_address_candidates() {
+        let response = json::parse(r#"{
+            "spatialReference": { "wkid": 4326, "latestWkid": 4326 },
+            "candidates": [
+                {
+                    "address": "380 New York St, Redlands, California, 92373",
+                    "location": { "x": -117.19566, "y": 34.05723 },
+                    "score": 100,
+                    "attributes": { "Addr_type": "PointAddress", "Country": "USA" },
+                    "extent": { "xmin": -117.19666, "ymin": 34.05623, "xmax": -117.19466, "ymax": 34.05823 }
+                },
+                { "address": "New York St, Redlands", "location": { "x": -117.195, "y": 34.058 } },
+                { "address": "No location", "score": 80 }
+            ]
+        }"#).unwrap();
+        let candidates: Vec<AddressCandidate> =
+            response["candidates"].members().filter_map(AddressCandidate::from_json).collect();
+        assert_eq!(candidates.len(), 2);
+        let best = &candidates[0];
+        assert_eq!(best.address, "380 New York St, Redlands, California, 92373");
+        assert_eq!(best.location, Point::new(-117.19566, 34.05723));
+        assert_eq!(best.score, 100.0);
+        assert_eq!(best.attributes["Addr_type"], "PointAddress");
+        let extent = best.extent.as_ref().unwrap();
+        assert_eq!((extent.xmin, extent.ymax), (-117.19666, 34.05823));
+        assert_eq!(candidates[1].score, 0.0);
+        assert_eq!(candidates[1].extent, None);
+        assert!(candidates[1].attributes.is_null());
+    }
+
+    #[test]
+    fn parses_suggestions() {
+        let suggestion = Suggestion::from_json(&json::object! {
+            "text" => "Lisboa, PRT",
+            "magicKey" => "dHA9MCNsb2M9MjY5NzI=",
+            "isCollection" => false
+        })
+        .unwrap();
+        assert_eq!(suggestion.text, "Lisboa, PRT");
+        assert_eq!(suggestion.magic_key, "dHA9MCNsb2M9MjY5NzI=");
+        assert!(!suggestion.is_collection);
+        let collection = Suggestion::from_json(&json::object! { "text" => "Coffee Shop", "isCollection" => true }).unwrap();
+        assert!(collection.is_collection);
+        assert_eq!(collection.magic_key, "");
+        assert_eq!(Suggestion::from_json(&json::object! { "magicKey" => "abc" }), None);
+    }
+
+    #[test]
+    fn parses_reverse_geocode_results() {
+        let result = ReverseGeocodeResult::from_json(&json::parse(r#"{
+            "address": { "Match_addr": "Praça do Comércio, Lisboa", "LongLabel": "Praça do Comércio, 1100-148, Lisboa, PRT", "City": "Lisboa" },
+            "location": { "x": -9.13647, "y": 38.70758, "spatialReference": { "wkid": 4326 } }
+        }"#).unwrap())
+        .unwrap();
+        assert_eq!(result.address, "Praça do Comércio, Lisboa");
+        assert_eq!(result.location, Point::new(-9.13647, 38.70758));
+        assert_eq!(result.attributes["City"], "Lisboa");
+
+        let labelled = ReverseGeocodeResult::from_json(&json::parse(r#"{
+            "address": { "LongLabel": "Atlantic Ocean" },
+            "location": { "x": -30.0, "y": 30.0 }
+        }"#).unwrap())
+        .unwrap();
+        assert_eq!(labelled.address, "Atlantic Ocean");
+        assert_eq!(ReverseGeocodeResult::from_json(&json::object! { "address" => json::object! {} }), None);
+    }
+
+    #[test]
+    fn builds_find_address_parameters() {
+        assert_eq!(FindAddressOptions::new("Lisbon").to_params(), vec![("SingleLine", String::from("Lisbon"))]);
+        let params = FindAddressOptions::new("Starbucks")
+            .max_locations(5)
+            .out_fields(&["Match_addr", "Addr_type"])
+            .out_sr(SpatialReference::wgs84())
+            .location(Point::new(-9.14, 38.71))
+            .category("Coffee Shop")
+            .country_code("PRT")
+            .for_storage(true)
+            .to_params();
+        assert_eq!(
+            params,
+            vec![
+                ("SingleLine", String::from("Starbucks")),
+                ("maxLocations", String::from("5")),
+                ("outFields", String::from("Match_addr,Addr_type")),
+                ("outSR", String::from("4326")),
+                ("location", String::from("-9.14,38.71")),
+                ("category", String::from("Coffee Shop")),
+                ("countryCode", String::from("PRT")),
+                ("forStorage", String::from("true")),
+            ]
+        );
+        let suggestion = Suggestion {
+            text: String::from("Lisboa, PRT"),
+            magic_key: String::from("abc"),
+            is_collection: false,
+        };
+        assert_eq!(
+            FindAddressOptions::from_suggestion(&suggestion).to_params(),
+            vec![("SingleLine", String::from("Lisboa, PRT")), ("magicKey", String::from("abc"))]
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! Geodesic distances, bearings and buffers on the WGS 1984 ellipsoid, computed locally.
+//!
+//! These take longitude and latitude in degrees and distances in meters. They're what
+//! [`GeometryService`](crate::GeometryService) uses when there's no geometry service to ask,
+//! and can be called directly when a round trip isn't worth it.
+
+use geo::{BooleanOps, GeodesicBearing, GeodesicDestination, GeodesicDistance};
+
+use crate::geometry::{signed_area, Geometry, Point, Polygon};
+use crate::BoxResult;
+
+/// The number of vertices in the circle around each buffered point.
+const CIRCLE_VERTICES: usize = 72;
+
+/// The most pieces a buffered segment is cut into, however long it is.
+const MAX_SEGMENT_STEPS: usize = 256;
+
+/// The geodesic distance in meters between two points.
+pub fn geodesic_distance(from: &Point, to: &Point) -> f64 {
+    to_geo(from).geodesic_distance(&to_geo(to))
+}
+
+/// The initial bearing from one point to another along the geodesic, in degrees clockwise
+/// from north, from 0 up to 360.
+pub fn geodesic_bearing(from: &Point, to: &Point) -> f64 {
+    to_geo(from).geodesic_bearing(to_geo(to)).rem_euclid(360.0)
+}
+
+/// The point reached by going `distance` meters from a point at a bearing in degrees.
+pub fn geodesic_destination(from: &Point, bearing: f64, distance: f64) -> Point {
+    let destination = to_geo(from).geodesic_destination(bearing, distance);
+    Point::new(destination.x(), destination.y())
+}
+
+/// Buffers a geometry by `distance` meters along geodesics, like the geometry service's
+/// `buffer` with `geodesic=true`.
+///
+/// Points become circles of 72 vertices, and lines and ring boundaries get a circle at each
+/// vertex joined by bands along the segments, so the result is a close approximation rather
+/// than exact. It isn't split at the antimeridian and goes wrong near the poles.
+pub fn geodesic_buffer(geometry: &Geometry, distance: f64) -> BoxResult<Polygon> {
+    if distance.is_nan() || distance <= 0.0 {
+        return Err(format!("can't buffer by {} meters locally; the distance must be positive", distance).into());
+    }
+    let mut pieces: Vec<geo::Polygon> = Vec::new();
+    match geometry {
+        Geometry::Point(point) => pieces.push(circle(point, distance)),
+        Geometry::Multipoint(multipoint) => pieces.extend(multipoint.points.iter().map(|point| circle(point, distance))),
+        Geometry::Polyline(polyline) => {
+            for path in &polyline.paths {
+                pieces.extend(path_pieces(path, distance, false));
+            }
+        }
+        Geometry::Polygon(polygon) => {
+            pieces.extend(to_geo_polygons(polygon));
+            for ring in &polygon.rings {
+                pieces.extend(path_pieces(ring, distance, true));
+            }
+        }
+        Geometry::Envelope(envelope) => {
+            let polygon = envelope.to_polygon();
+            pieces.extend(to_geo_polygons(&polygon));
+            for ring in &polygon.rings {
+                pieces.extend(path_pieces(ring, distance, true));
+            }
+        }
+    }
+    Ok(from_geo(&cascaded_union(pieces)))
+}
+
+/// Unions polygons in pairs, then those unions in pairs and so on. Each union then only
+/// involves pieces near each other, where folding them in one at a time would union every piece
+/// with an ever larger polygon.
+fn cascaded_union(pieces: Vec<geo::Polygon>) -> geo::MultiPolygon {
+    let mut unions: Vec<geo::MultiPolygon> = pieces
+        .into_iter()
+        .map(|piece| geo::MultiPolygon::new(vec![piece]))
+        .collect();
+    while unions.len() > 1 {
+        let mut pairs = unions.into_iter();
+        let mut next = Vec::new();
+        while let Some(first) = pairs.next() {
+            next.push(match pairs.next() {
+                Some(second) => first.union(&second),
+                None => first,
+            });
+        }
+        unions = next;
+    }
+    unions.pop().unwrap_or_else(|| geo::MultiPolygon::new(Vec::new()))
+}
+
+fn to_geo(point: &Point) -> geo::Point {
+    geo::Point::new(point.x, point.y)
+}
+
+/// A circle around a point, counterclockwise as `geo` prefers.
+fn circle(center: &Point, distance: f64) -> geo::Polygon {
+    let center = to_geo(center);
+    let vertices = (0..CIRCLE_VERTICES)
+        .rev()
+        .map(|index| {
+            let bearing = 360.0 * index as f64 / CIRCLE_VERTICES as f64;
+            center.geodesic_destination(bearing, distance).0
+        })
+        .collect::<Vec<_>>();
+    geo::Polygon::new(geo::LineString::new(vertices), Vec::new())
+}
+
+/// The pieces whose union is a path's buffer: a circle at each vertex, and a band along each
+/// segment as wide as the buffer on either side.
+fn path_pieces(path: &[Point], distance: f64, closed: bool) -> Vec<geo::Polygon> {
+    let mut pieces: Vec<geo::Polygon> = path.iter().map(|point| circle(point, distance)).collect();
+    let closing = if closed { path.first().zip(path.last()) } else { None };
+    let segments = path.windows(2).map(|pair| (&pair[0], &pair[1])).chain(closing.map(|(first, last)| (last, first)));
+    for (from, to) in segments {
+        let (from, to) = (to_geo(from), to_geo(to));
+        let length = from.geodesic_distance(&to);
+        if length == 0.0 {
+            continue;
+        }
+        let steps = ((length / distance).ceil() as usize).clamp(1, MAX_SEGMENT_STEPS);
+        let bearing = from.geodesic_bearing(to);
+        let mut left = Vec::with_capacity(steps + 1);
+        let mut right = Vec::with_capacity(steps + 1);
+        for step in 0..=steps {
+            let along = from.geodesic_destination(bearing, length * step as f64 / steps as f64);
+            // The heading changes along a geodesic, so take it again at each step: the reverse of
+            // the bearing back to the start.
+            let heading = if step == 0 { bearing } else { along.geodesic_bearing(from) + 180.0 };
+            left.push(along.geodesic_destination(heading - 90.0, distance).0);
+            right.push(along.geodesic_destination(heading + 90.0, distance).0);
+        }
+        right.reverse();
+        left.extend(right);
+        pieces.push(geo::Polygon::new(geo::LineString::new(left), Vec::new()));
+    }
+    pieces
+}
+
+pub(crate) fn to_geo_polygons(polygon: &Polygon) -> Vec<geo::Polygon> {
+    polygon
+        .parts()
+        .into_iter()
+        .map(|part| {
+            let ring = |ring: &&[Point]| geo::LineString::from(ring.iter().map(|point| (point.x, point.y)).collect::<Vec<_>>());
+            let exterior = ring(&part[0]);
+            let holes = part[1..].iter().map(ring).collect();
+            geo::Polygon::new(exterior, holes)
+        })
+        .collect()
+}
+
+/// Converts polygons to Esri JSON rings: outer rings clockwise and holes counterclockwise.
+fn from_geo(multipolygon: &geo::MultiPolygon) -> Polygon {
+    let mut rings = Vec::new();
+    for polygon in &multipolygon.0 {
+        let outer = std::iter::once(polygon.exterior()).map(|ring| (ring, true));
+        let holes = polygon.interiors().iter().map(|ring| (ring, false));
+        for (ring, is_outer) in outer.chain(holes) {
+            let mut ring: Vec<Point> = ring.points().map(|point| Point::new(point.x(), point.y())).collect();
+            let clockwise = signed_area(&ring) < 0.0;
+            if clockwise != is_outer {
+                ring.reverse();
+            }
+            rings.push(ring);
+        }
+    }
+    Polygon { rings }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use geo::Contains;
+
+    /// Flinders Peak and Buninyong, Vincenty's (1975) worked example.
+    fn flinders_peak() -> Point {
+        Point::new(144.0 + 25.0 / 60.0 + 29.5244 / 3600.0, -(37.0 + 57.0 / 60.0 + 3.7203 / 3600.0))
+    }
+
+    fn buninyong() -> Point {
+        Point::new(143.0 + 55.0 / 60.0 + 35.3839 / 3600.0, -(37.0 + 39.0 / 60.0 + 10.1561 / 3600.0))
+    }
+
+    fn contains(polygon: &Polygon, point: &Point) -> bool {
+        to_geo_polygons(polygon).iter().any(|part| part.contains(&to_geo(point)))
+    }
+
+    #[test]
+    fn measures_distances() {
+        assert!((geodesic_distance(&flinders_peak(), &buninyong()) - 54_972.271).abs() < 0.001);
+        // A quarter meridian.
+        let quarter = geodesic_distance(&Point::new(0.0, 0.0), &Point::new(0.0, 90.0));
+        assert!((quarter - 10_001_965.729).abs() < 0.001);
+        assert_eq!(geodesic_distance(&buninyong(), &buninyong()), 0.0);
+    }
+
+    #[test]
+    fn measures_bearings() {
+        let forward = 306.0 + 52.0 / 60.0 + 5.37 / 3600.0;
+        assert!((geodesic_bearing(&flinders_peak(), &buninyong()) - forward).abs() < 1e-5);
+        // The reverse azimuth, from Buninyong back to Flinders Peak.
+        let back = 127.0 + 10.0 / 60.0 + 25.07 / 3600.0;
+        assert!((geodesic_bearing(&buninyong(), &flinders_peak()) - back).abs() < 1e-5);
+        assert!((geodesic_bearing(&Point::new(0.0, 0.0), &Point::new(1.0, 0.0)) - 90.0).abs() < 1e-9);
+        let west = geodesic_bearing(&Point::new(0.0, 0.0), &Point::new(-1.0, 0.0));
+        assert!((west - 270.0).abs() < 1e-9, "bearings are from 0 up to 360, not {}", west);
+    }
+
+    #[test]
+    fn finds_destinations() {
+        let forward = 306.0 + 52.0 / 60.0 + 5.37 / 3600.0;
+        let destination = geodesic_destination(&flinders_peak(), forward, 54_972.271);
+        let expected = buninyong();
+        assert!((destination.x - expected.x).abs() < 1e-7);
+        assert!((destination.y - expected.y).abs() < 1e-7);
+        let pole = geodesic_destination(&Point::new(0.0, 0.0), 0.0, 10_001_965.729);
+        assert!((pole.y - 90.0).abs() < 1e-7);
+    }
+
+    #[test]
+    fn buffers_points_into_circles() {
+        let center = Point::new(-9.14, 38.72);
+        let buffer = geodesic_buffer(&Geometry::Point(center), 1000.0).unwrap();
+        assert_eq!(buffer.rings.len(), 1);
+        assert!(signed_area(&buffer.rings[0]) < 0.0, "the outer ring should be clockwise");
+        for vertex in &buffer.rings[0] {
+            assert!((geodesic_distance(&center, vertex) - 1000.0).abs() < 0.01);
+        }
+
+        let near = Geometry::Multipoint(crate::geometry::Multipoint {
+            points: vec![center, geodesic_destination(&center, 90.0, 1500.0)],
+        });
+        assert_eq!(geodesic_buffer(&near, 1000.0).unwrap().rings.len(), 1);
+        let far = Geometry::Multipoint(crate::geometry::Multipoint {
+            points: vec![center, geodesic_destination(&center, 90.0, 5000.0)],
+        });
+        assert_eq!(geodesic_buffer(&far, 1000.0).unwrap().rings.len(), 2);
+    }
+
+    #[test]
+    fn buffers_lines_along_their_length() {
+        let start = Point::new(-9.14, 38.72);
+        let end = geodesic_destination(&start, 60.0, 20_000.0);
+        let line = Geometry::Polyline(crate::geometry::Polyline {
+            paths: vec![vec![start, end]],
+        });
+        let buffer = geodesic_buffer(&line, 500.0).unwrap();
+        assert_eq!(buffer.rings.len(), 1);
+        let middle = geodesic_destination(&start, 60.0, 10_000.0);
+        let heading = geodesic_bearing(&middle, &start) + 180.0;
+        assert!(contains(&buffer, &geodesic_destination(&middle, heading - 90.0, 490.0)));
+        assert!(contains(&buffer, &geodesic_destination(&middle, heading + 90.0, 490.0)));
+        assert!(!contains(&buffer, &geodesic_destination(&middle, heading + 90.0, 510.0)));
+        assert!(contains(&buffer, &geodesic_destination(&start, 240.0, 490.0)));
+        assert!(!contains(&buffer, &geodesic_destination(&start, 240.0, 510.0)));
+    }
+
+    #[test]
+    fn buffers_polygons_outward() {
+        let polygon = Polygon {
+            rings: vec![vec![
+                Point::new(0.0, 0.0),
+                Point::new(0.0, 0.1),
+                Point::new(0.1, 0.1),
+                Point::new(0.1, 0.0),
+                Point::new(0.0, 0.0),
+            ]],
+        };
+        let buffer = geodesic_buffer(&Geometry::Polygon(polygon), 1000.0).unwrap();
+        assert_eq!(buffer.rings.len(), 1);
+        assert!(contains(&buffer, &Point::new(0.05, 0.05)));
+        assert!(contains(&buffer, &geodesic_destination(&Point::new(0.0, 0.05), 270.0, 990.0)));
+        assert!(!contains(&buffer, &geodesic_destination(&Point::new(0.0, 0.05), 270.0, 1010.0)));
+    }
+
+    #[test]
+    fn refuses_distances_that_are_not_positive() {
+        let point = Geometry::Point(Point::new(0.0, 0.0));
+        assert!(geodesic_buffer(&point, 0.0).is_err());
+        assert!(geodesic_buffer(&point, -1.0).is_err());
+        assert!(geodesic_buffer(&point, f64::NAN).is_err());
+    }
+}
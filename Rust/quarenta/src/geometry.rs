@@ -0,0 +1,417 @@
+//! Esri JSON geometries and spatial references.
+
+use json::JsonValue;
+
+/// A spatial reference, given by well-known ID or well-known text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpatialReference {
+    pub wkid: Option<u32>,
+    pub latest_wkid: Option<u32>,
+    pub wkt: Option<String>,
+}
+
+impl SpatialReference {
+    /// A spatial reference with the given well-known ID.
+    pub fn from_wkid(wkid: u32) -> SpatialReference {
+        SpatialReference {
+            wkid: Some(wkid),
+            latest_wkid: None,
+            wkt: None,
+        }
+    }
+
+    /// WGS 1984 (4326), the default for the demos.
+    pub fn wgs84() -> SpatialReference {
+        SpatialReference::from_wkid(4326)
+    }
+
+    /// The well-known ID to use when comparing spatial references: the latest WKID if the
+    /// server gave one, otherwise the WKID.
+    pub fn effective_wkid(&self) -> Option<u32> {
+        self.latest_wkid.or(self.wkid)
+    }
+
+    pub fn from_json(value: &JsonValue) -> Option<SpatialReference> {
+        if !value.is_object() {
+            return None;
+        }
+        let spatial_reference = SpatialReference {
+            wkid: value["wkid"].as_u32(),
+            latest_wkid: value["latestWkid"].as_u32(),
+            wkt: value["wkt"].as_str().map(String::from),
+        };
+        if spatial_reference.wkid.is_none() && spatial_reference.latest_wkid.is_none() && spatial_reference.wkt.is_none() {
+            None
+        } else {
+            Some(spatial_reference)
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut value = JsonValue::new_object();
+        if let Some(wkid) = self.wkid {
+            value["wkid"] = wkid.into();
+        }
+        if let Some(latest_wkid) = self.latest_wkid {
+            value["latestWkid"] = latest_wkid.into();
+        }
+        if let Some(wkt) = &self.wkt {
+            value["wkt"] = wkt.as_str().into();
+        }
+        value
+    }
+
+    /// The value to use for `inSR`, `outSR` and similar parameters.
+    pub fn to_param(&self) -> String {
+        match self.wkid {
+            Some(wkid) => wkid.to_string(),
+            None => self.to_json().dump(),
+        }
+    }
+}
+
+/// The `esriGeometry*` geometry types.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GeometryType {
+    Point,
+    Multipoint,
+    Polyline,
+    Polygon,
+    Envelope,
+}
+
+impl GeometryType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GeometryType::Point => "esriGeometryPoint",
+            GeometryType::Multipoint => "esriGeometryMultipoint",
+            GeometryType::Polyline => "esriGeometryPolyline",
+            GeometryType::Polygon => "esriGeometryPolygon",
+            GeometryType::Envelope => "esriGeometryEnvelope",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<GeometryType> {
+        match name {
+            "esriGeometryPoint" => Some(GeometryType::Point),
+            "esriGeometryMultipoint" => Some(GeometryType::Multipoint),
+            "esriGeometryPolyline" => Some(GeometryType::Polyline),
+            "esriGeometryPolygon" => Some(GeometryType::Polygon),
+            "esriGeometryEnvelope" => Some(GeometryType::Envelope),
+            _ => None,
+        }
+    }
+}
+
+/// A point, which is also the vertex type of the other geometries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+    pub z: Option<f64>,
+    pub m: Option<f64>,
+}
+
+impl Point {
+    pub fn new(x: f64, y: f64) -> Point {
+        Point { x, y, z: None, m: None }
+    }
+
+    /// Reads a point typed as "x, y", such as "-9.14, 38.71" for a longitude and latitude.
+    /// Returns `None` for anything else.
+    pub fn parse(text: &str) -> Option<Point> {
+        let mut parts = text.split(',').map(|part| part.trim().parse::<f64>());
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(x)), Some(Ok(y)), None) if x.is_finite() && y.is_finite() => Some(Point::new(x, y)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Multipoint {
+    pub points: Vec<Point>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Polyline {
+    pub paths: Vec<Vec<Point>>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Polygon {
+    pub rings: Vec<Vec<Point>>,
+}
+
+impl Polygon {
+    /// Groups the rings into polygons, each an outer ring followed by its holes.
+    ///
+    /// Esri JSON lists outer rings clockwise and holes counterclockwise, in any order. Each
+    /// hole goes with the first outer ring that contains it; a hole that isn't inside any outer
+    /// ring is treated as an outer ring itself.
+    pub fn parts(&self) -> Vec<Vec<&[Point]>> {
+        let (outer, holes): (Vec<&Vec<Point>>, Vec<&Vec<Point>>) =
+            self.rings.iter().partition(|ring| signed_area(ring) <= 0.0);
+        let mut parts: Vec<Vec<&[Point]>> = outer.into_iter().map(|ring| vec![ring.as_slice()]).collect();
+        for hole in holes {
+            let container = hole
+                .first()
+                .and_then(|point| parts.iter().position(|part| ring_contains(part[0], point)));
+            match container {
+                Some(index) => parts[index].push(hole.as_slice()),
+                None => parts.push(vec![hole.as_slice()]),
+            }
+        }
+        parts
+    }
+}
+
+/// Twice the signed area of a ring: positive if counterclockwise, negative if clockwise.
+pub(crate) fn signed_area(ring: &[Point]) -> f64 {
+    ring.iter()
+        .zip(ring.iter().skip(1).chain(ring.first()))
+        .map(|(a, b)| a.x * b.y - b.x * a.y)
+        .sum()
+}
+
+/// Whether a point is inside a ring, by ray casting.
+fn ring_contains(ring: &[Point], point: &Point) -> bool {
+    let mut inside = false;
+    for (a, b) in ring.iter().zip(ring.iter().skip(1).chain(ring.first())) {
+        if (a.y > point.y) != (b.y > point.y) && point.x < (b.x - a.x) * (point.y - a.y) / (b.y - a.y) + a.x {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Envelope {
+    pub xmin: f64,
+    pub ymin: f64,
+    pub xmax: f64,
+    pub ymax: f64,
+}
+
+impl Envelope {
+    /// The envelope as a polygon with one clockwise ring.
+    pub fn to_polygon(&self) -> Polygon {
+        Polygon {
+            rings: vec![vec![
+                Point::new(self.xmin, self.ymin),
+                Point::new(self.xmin, self.ymax),
+                Point::new(self.xmax, self.ymax),
+                Point::new(self.xmax, self.ymin),
+                Point::new(self.xmin, self.ymin),
+            ]],
+        }
+    }
+}
+
+/// Any of the Esri JSON geometries.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Geometry {
+    Point(Point),
+    Multipoint(Multipoint),
+    Polyline(Polyline),
+    Polygon(Polygon),
+    Envelope(Envelope),
+}
+
+impl Geometry {
+    pub fn geometry_type(&self) -> GeometryType {
+        match self {
+            Geometry::Point(_) => GeometryType::Point,
+            Geometry::Multipoint(_) => GeometryType::Multipoint,
+            Geometry::Polyline(_) => GeometryType::Polyline,
+            Geometry::Polygon(_) => GeometryType::Polygon,
+            Geometry::Envelope(_) => GeometryType::Envelope,
+        }
+    }
+
+    /// Parses an Esri JSON geometry, working out its type from its keys.
+    ///
+    /// `hasZ` and `hasM` on the geometry decide how a third coordinate in a vertex array is
+    /// read, as they do on the server.
+    pub fn from_json(value: &JsonValue) -> Option<Geometry> {
+        let has_z = value["hasZ"].as_bool().unwrap_or(false);
+        let has_m = value["hasM"].as_bool().unwrap_or(false);
+        if let Some(x) = value["x"].as_f64() {
+            return Some(Geometry::Point(Point {
+                x,
+                y: value["y"].as_f64()?,
+                z: value["z"].as_f64(),
+                m: value["m"].as_f64(),
+            }));
+        }
+        if value["points"].is_array() {
+            return Some(Geometry::Multipoint(Multipoint {
+                points: vertices_from_json(&value["points"], has_z, has_m)?,
+            }));
+        }
+        if value["paths"].is_array() {
+            return Some(Geometry::Polyline(Polyline {
+                paths: parts_from_json(&value["paths"], has_z, has_m)?,
+            }));
+        }
+        if value["rings"].is_array() {
+            return Some(Geometry::Polygon(Polygon {
+                rings: parts_from_json(&value["rings"], has_z, has_m)?,
+            }));
+        }
+        if let Some(xmin) = value["xmin"].as_f64() {
+            return Some(Geometry::Envelope(Envelope {
+                xmin,
+                ymin: value["ymin"].as_f64()?,
+                xmax: value["xmax"].as_f64()?,
+                ymax: value["ymax"].as_f64()?,
+            }));
+        }
+        None
+    }
+
+    /// The smallest envelope containing the geometry, or `None` if it has no vertices.
+    pub fn envelope(&self) -> Option<Envelope> {
+        let points: Vec<&Point> = match self {
+            Geometry::Point(point) => vec![point],
+            Geometry::Multipoint(multipoint) => multipoint.points.iter().collect(),
+            Geometry::Polyline(polyline) => polyline.paths.iter().flatten().collect(),
+            Geometry::Polygon(polygon) => polygon.rings.iter().flatten().collect(),
+            Geometry::Envelope(envelope) => return Some(*envelope),
+        };
+        let first = points.first()?;
+        Some(points.iter().fold(
+            Envelope {
+                xmin: first.x,
+                ymin: first.y,
+                xmax: first.x,
+                ymax: first.y,
+            },
+            |envelope, point| Envelope {
+                xmin: envelope.xmin.min(point.x),
+                ymin: envelope.ymin.min(point.y),
+                xmax: envelope.xmax.max(point.x),
+                ymax: envelope.ymax.max(point.y),
+            },
+        ))
+    }
+
+    /// Writes the geometry as Esri JSON, without a spatial reference.
+    pub fn to_json(&self) -> JsonValue {
+        let mut value = JsonValue::new_object();
+        match self {
+            Geometry::Point(point) => {
+                value["x"] = point.x.into();
+                value["y"] = point.y.into();
+                if let Some(z) = point.z {
+                    value["z"] = z.into();
+                }
+                if let Some(m) = point.m {
+                    value["m"] = m.into();
+                }
+            }
+            Geometry::Multipoint(multipoint) => {
+                value["points"] = vertices_to_json(&multipoint.points);
+                set_zm_flags(&mut value, multipoint.points.iter());
+            }
+            Geometry::Polyline(polyline) => {
+                value["paths"] = parts_to_json(&polyline.paths);
+                set_zm_flags(&mut value, polyline.paths.iter().flatten());
+            }
+            Geometry::Polygon(polygon) => {
+                value["rings"] = parts_to_json(&polygon.rings);
+                set_zm_flags(&mut value, polygon.rings.iter().flatten());
+            }
+            Geometry::Envelope(envelope) => {
+                value["xmin"] = envelope.xmin.into();
+                value["ymin"] = envelope.ymin.into();
+                value["xmax"] = envelope.xmax.into();
+                value["ymax"] = envelope.ymax.into();
+            }
+        }
+        value
+    }
+
+    /// Writes the geometry as Esri JSON with the given spatial reference.
+    pub fn to_json_with_spatial_reference(&self, spatial_reference: &SpatialReference) -> JsonValue {
+        let mut value = self.to_json();
+        value["spatialReference"] = spatial_reference.to_json();
+        value
+    }
+}
+
+pub(crate) fn vertex_from_json(value: &JsonValue, has_z: bool, has_m: bool) -> Option<Point> {
+    let mut point = Point::new(value[0].as_f64()?, value[1].as_f64()?);
+    let mut next = 2;
+    if has_z || (!has_m && value.len() > 2) {
+        point.z = value[next].as_f64();
+        next += 1;
+    }
+    if has_m {
+        point.m = value[next].as_f64();
+    }
+    Some(point)
+}
+
+fn vertices_from_json(value: &JsonValue, has_z: bool, has_m: bool) -> Option<Vec<Point>> {
+    value.members().map(|vertex| vertex_from_json(vertex, has_z, has_m)).collect()
+}
+
+fn parts_from_json(value: &JsonValue, has_z: bool, has_m: bool) -> Option<Vec<Vec<Point>>> {
+    value.members().map(|part| vertices_from_json(part, has_z, has_m)).collect()
+}
+
+fn vertices_to_json(points: &[Point]) -> JsonValue {
+    let has_z = points.iter().any(|point| point.z.is_some());
+    let has_m = points.iter().any(|point| point.m.is_some());
+    let mut array = JsonValue::new_array();
+    for point in points {
+        let mut vertex = json::array![point.x, point.y];
+        if has_z {
+            vertex.push(point.z.map(JsonValue::from).unwrap_or(JsonValue::Null)).unwrap();
+        }
+        if has_m {
+            vertex.push(point.m.map(JsonValue::from).unwrap_or(JsonValue::Null)).unwrap();
+        }
+        array.push(vertex).unwrap();
+    }
+    array
+}
+
+fn parts_to_json(parts: &[Vec<Point>]) -> JsonValue {
+    let mut array = JsonValue::new_array();
+    for part in parts {
+        array.push(vertices_to_json(part)).unwrap();
+    }
+    array
+}
+
+fn set_zm_flags<'a>(value: &mut JsonValue, mut points: impl Iterator<Item = &'a Point> + Clone) {
+    if points.clone().any(|point| point.z.is_some()) {
+        value["hasZ"] = true.into();
+    }
+    if points.any(|point| point.m.is_some()) {
+        value["hasM"] = true.into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_longitude_latitude() {
+        assert_eq!(Point::parse("-9.14, 38.71"), Some(Point::new(-9.14, 38.71)));
+        assert_eq!(Point::parse("  -117.195,34.057 "), Some(Point::new(-117.195, 34.057)));
+        assert_eq!(Point::parse("0,0"), Some(Point::new(0.0, 0.0)));
+        assert_eq!(Point::parse("1e2, -3"), Some(Point::new(100.0, -3.0)));
+        assert_eq!(Point::parse("Lisbon"), None);
+        assert_eq!(Point::parse("380 New York St, Redlands"), None);
+        assert_eq!(Point::parse("1, 2, 3"), None);
+        assert_eq!(Point::parse("1,"), None);
+        assert_eq!(Point::parse("-9.14"), None);
+        assert_eq!(Point::parse("NaN, 38.71"), None);
+        assert_eq!(Point::parse("inf, 0"), None);
+        assert_eq!(Point::parse(""), None);
+    }
+}
@@ -0,0 +1,181 @@
+//! Geometry services: geodesic buffers and distances, from a `GeometryServer` or computed
+//! locally.
+
+use json::JsonValue;
+
+use crate::geodesic::{geodesic_buffer, geodesic_distance};
+use crate::geometry::{Geometry, Polygon, SpatialReference};
+use crate::projection::Projection;
+use crate::request::{Client, RequestOptions};
+use crate::BoxResult;
+
+/// The geometry service on ArcGIS Online that anyone can use.
+pub const WORLD_GEOMETRY_SERVICE_URL: &str = "https://tasks.arcgisonline.com/ArcGIS/rest/services/Geometry/GeometryServer";
+
+/// The `esriSRUnit_Meter` linear unit.
+const METERS: &str = "9001";
+
+/// Where a [`GeometryService`] operation is computed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GeometryEngine {
+    /// The geometry service if there is one, otherwise locally.
+    #[default]
+    Auto,
+    /// Locally, with the functions in [`geodesic_distance`] and friends. Geometries not in
+    /// WGS 1984 are projected to it and back with a [`Projection`].
+    Local,
+    /// The geometry service. It's an error if there isn't one.
+    Service,
+}
+
+/// A geometry service, or the local stand-in for one.
+///
+/// ```no_run
+/// # use quarenta::{Client, Geometry, GeometryEngine, GeometryService, Point, SpatialReference};
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let service = GeometryService::local(&Client::new());
+/// let lisbon = Geometry::Point(Point::new(-9.14, 38.74));
+/// let buffer = service
+///     .buffer(&lisbon, &SpatialReference::wgs84(), 50_000.0, GeometryEngine::Auto)
+///     .await?;
+/// println!("{} vertices", buffer.rings[0].len());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct GeometryService {
+    client: Client,
+    url: Option<String>,
+}
+
+impl GeometryService {
+    pub fn new(client: &Client, url: &str) -> GeometryService {
+        GeometryService {
+            client: client.clone(),
+            url: Some(url.trim_end_matches('/').to_string()),
+        }
+    }
+
+    /// A geometry service with no server, which computes everything locally.
+    pub fn local(client: &Client) -> GeometryService {
+        GeometryService {
+            client: client.clone(),
+            url: None,
+        }
+    }
+
+    /// The geometry service at `tasks.arcgisonline.com`.
+    pub fn world(client: &Client) -> GeometryService {
+        GeometryService::new(client, WORLD_GEOMETRY_SERVICE_URL)
+    }
+
+    /// The service's URL, or `None` if it's local.
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Buffers a geometry by `distance` meters along geodesics and returns the buffer in the
+    /// geometry's spatial reference.
+    pub async fn buffer(
+        &self,
+        geometry: &Geometry,
+        spatial_reference: &SpatialReference,
+        distance: f64,
+        engine: GeometryEngine,
+    ) -> BoxResult<Polygon> {
+        let url = match self.service_url(engine)? {
+            Some(url) => url,
+            None => {
+                let wgs84 = SpatialReference::wgs84();
+                let geometry = Projection::new(spatial_reference, &wgs84)?.geometry(geometry)?;
+                let buffer = geodesic_buffer(&geometry, distance)?;
+                return Ok(Polygon {
+                    rings: Projection::new(&wgs84, spatial_reference)?.parts(&buffer.rings)?,
+                });
+            }
+        };
+        let geometries = json::object! {
+            "geometryType" => geometry.geometry_type().as_str(),
+            "geometries" => json::array![ geometry.to_json() ]
+        };
+        let params = [
+            ("geometries", geometries.dump()),
+            ("inSR", spatial_reference.to_param()),
+            ("outSR", spatial_reference.to_param()),
+            ("distances", distance.to_string()),
+            ("unit", METERS.to_string()),
+            ("geodesic", String::from("true")),
+        ];
+        let response = self
+            .client
+            .post_json(&format!("{}/buffer", url), &params, &RequestOptions::default())
+            .await?;
+        match Geometry::from_json(&response["geometries"][0]) {
+            Some(Geometry::Polygon(polygon)) => Ok(polygon),
+            _ => Err(format!("the buffer response has no polygon: {}", response).into()),
+        }
+    }
+
+    /// The geodesic distance in meters between two geometries. Locally, only the distance
+    /// between two points can be measured.
+    pub async fn distance(
+        &self,
+        from: &Geometry,
+        to: &Geometry,
+        spatial_reference: &SpatialReference,
+        engine: GeometryEngine,
+    ) -> BoxResult<f64> {
+        let url = match self.service_url(engine)? {
+            Some(url) => url,
+            None => {
+                return match (from, to) {
+                    (Geometry::Point(from), Geometry::Point(to)) => {
+                        let projection = Projection::new(spatial_reference, &SpatialReference::wgs84())?;
+                        Ok(geodesic_distance(&projection.point(from)?, &projection.point(to)?))
+                    }
+                    _ => Err(format!(
+                        "can't measure from {} to {} locally; use a geometry service",
+                        from.geometry_type().as_str(),
+                        to.geometry_type().as_str()
+                    )
+                    .into()),
+                };
+            }
+        };
+        let params = [
+            ("geometry1", typed_geometry(from).dump()),
+            ("geometry2", typed_geometry(to).dump()),
+            ("sr", spatial_reference.to_param()),
+            ("distanceUnit", METERS.to_string()),
+            ("geodesic", String::from("true")),
+        ];
+        let response = self
+            .client
+            .post_json(&format!("{}/distance", url), &params, &RequestOptions::default())
+            .await?;
+        response["distance"]
+            .as_f64()
+            .ok_or_else(|| format!("the distance response has no distance: {}", response).into())
+    }
+
+    /// The URL to send an operation to, or `None` to compute it locally.
+    fn service_url(&self, engine: GeometryEngine) -> BoxResult<Option<&str>> {
+        match (engine, self.url()) {
+            (GeometryEngine::Local, _) | (GeometryEngine::Auto, None) => Ok(None),
+            (_, Some(url)) => Ok(Some(url)),
+            (GeometryEngine::Service, None) => Err("no geometry service is configured".into()),
+        }
+    }
+}
+
+/// A geometry with its type, as the `distance` operation takes it.
+fn typed_geometry(geometry: &Geometry) -> JsonValue {
+    json::object! {
+        "geometryType" => geometry.geometry_type().as_str(),
+        "geometry" => geometry.to_json()
+    }
+}
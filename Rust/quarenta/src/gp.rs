@@ -0,0 +1,288 @@
+//! Geoprocessing tasks, run synchronously with `execute` or as jobs with `submitJob`.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use json::JsonValue;
+use reqwest::header::CACHE_CONTROL;
+
+use crate::request::{ArcGisError, Client, RequestOptions};
+use crate::BoxResult;
+
+/// The status of a geoprocessing job.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    New,
+    Submitted,
+    Waiting,
+    Executing,
+    Succeeded,
+    Failed,
+    TimedOut,
+    Cancelling,
+    Cancelled,
+    Deleting,
+    Deleted,
+    Other(String),
+}
+
+impl JobStatus {
+    pub fn from_name(name: &str) -> JobStatus {
+        match name {
+            "esriJobNew" => JobStatus::New,
+            "esriJobSubmitted" => JobStatus::Submitted,
+            "esriJobWaiting" => JobStatus::Waiting,
+            "esriJobExecuting" => JobStatus::Executing,
+            "esriJobSucceeded" => JobStatus::Succeeded,
+            "esriJobFailed" => JobStatus::Failed,
+            "esriJobTimedOut" => JobStatus::TimedOut,
+            "esriJobCancelling" => JobStatus::Cancelling,
+            "esriJobCancelled" => JobStatus::Cancelled,
+            "esriJobDeleting" => JobStatus::Deleting,
+            "esriJobDeleted" => JobStatus::Deleted,
+            other => JobStatus::Other(other.to_string()),
+        }
+    }
+
+    /// Whether the job has stopped, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Succeeded | JobStatus::Failed | JobStatus::TimedOut | JobStatus::Cancelled | JobStatus::Deleted
+        )
+    }
+}
+
+/// A geoprocessing job, as of its last status check.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GpJob {
+    pub id: String,
+    pub status: JobStatus,
+    /// The names of the output parameters, once the job has succeeded.
+    pub result_names: Vec<String>,
+    pub messages: Vec<String>,
+}
+
+impl GpJob {
+    pub fn from_json(value: &JsonValue) -> Option<GpJob> {
+        Some(GpJob {
+            id: value["jobId"].as_str()?.to_string(),
+            status: JobStatus::from_name(value["jobStatus"].as_str().unwrap_or("")),
+            result_names: value["results"].entries().map(|(name, _)| name.to_string()).collect(),
+            messages: value["messages"]
+                .members()
+                .filter_map(|message| message["description"].as_str())
+                .map(String::from)
+                .collect(),
+        })
+    }
+}
+
+/// What a task returned.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GpResults {
+    /// The output parameters' values, by name.
+    pub values: HashMap<String, JsonValue>,
+    /// The messages the task logged, such as warnings about inputs it skipped.
+    pub messages: Vec<String>,
+}
+
+impl GpResults {
+    /// Parses an `execute` response, which has `results` with each parameter's `paramName`
+    /// and `value`.
+    pub fn from_json(value: &JsonValue) -> GpResults {
+        GpResults {
+            values: value["results"]
+                .members()
+                .filter_map(|result| Some((result["paramName"].as_str()?.to_string(), result["value"].clone())))
+                .collect(),
+            messages: value["messages"]
+                .members()
+                .filter_map(|message| message["description"].as_str())
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+/// A geoprocessing task, identified by its REST URL, e.g. `.../GPServer/FindRoutes`.
+///
+/// Parameters and results are JSON values, keyed by parameter name; feature sets are sent and
+/// returned in the usual feature set JSON.
+#[derive(Clone)]
+pub struct GpTask {
+    client: Client,
+    url: String,
+}
+
+impl GpTask {
+    pub fn new(client: &Client, url: &str) -> GpTask {
+        GpTask {
+            client: client.clone(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Fetches the task's description.
+    pub async fn describe(&self) -> BoxResult<JsonValue> {
+        self.client.get_json(&self.url, &[], &RequestOptions::default()).await
+    }
+
+    /// Whether the task runs with `submitJob` rather than `execute`.
+    pub async fn is_asynchronous(&self) -> BoxResult<bool> {
+        Ok(Some("esriExecutionTypeSynchronous") != self.describe().await?["executionType"].as_str())
+    }
+
+    /// Runs a synchronous task and returns its output parameters and messages.
+    pub async fn execute(&self, params: &[(&str, String)]) -> BoxResult<GpResults> {
+        let response = self
+            .client
+            .post_json(&format!("{}/execute", self.url), params, &RequestOptions::no_retry())
+            .await?;
+        Ok(GpResults::from_json(&response))
+    }
+
+    /// Starts a job on an asynchronous task.
+    pub async fn submit_job(&self, params: &[(&str, String)]) -> BoxResult<GpJob> {
+        let response = self
+            .client
+            .post_json(&format!("{}/submitJob", self.url), params, &RequestOptions::no_retry())
+            .await?;
+        GpJob::from_json(&response).ok_or_else(|| "submitJob response has no job ID".into())
+    }
+
+    /// Checks on a job.
+    pub async fn job_status(&self, job_id: &str) -> BoxResult<GpJob> {
+        let params = self.client.params_with_auth(&[], "json");
+        let request = self
+            .client
+            .http()
+            .get(&format!("{}/jobs/{}", self.url, job_id))
+            .header(CACHE_CONTROL, "no-cache")
+            .query(&params);
+        let response = ArcGisError::check(self.client.send_json(request, &RequestOptions::default()).await?)?;
+        GpJob::from_json(&response).ok_or_else(|| "job status response has no job ID".into())
+    }
+
+    /// Checks on a job every `poll_interval` until it finishes. Returns `Err` with the job's
+    /// messages if it doesn't succeed, or if it's still going after `timeout`; the job isn't
+    /// cancelled then, so it can be checked on again later.
+    pub async fn wait_for_job(&self, job_id: &str, poll_interval: Duration, timeout: Duration) -> BoxResult<GpJob> {
+        let started = Instant::now();
+        loop {
+            let job = self.job_status(job_id).await?;
+            if JobStatus::Succeeded == job.status {
+                return Ok(job);
+            }
+            if job.status.is_finished() {
+                return Err(format!("job {} ended with {:?}: {}", job.id, job.status, job.messages.join("; ")).into());
+            }
+            if started.elapsed() + poll_interval > timeout {
+                return Err(format!("job {} is still {:?} after {:?}", job.id, job.status, started.elapsed()).into());
+            }
+            tracing::debug!(job_id, status = ?job.status, "waiting for geoprocessing job");
+            tokio::time::delay_for(poll_interval).await;
+        }
+    }
+
+    /// Fetches one output parameter of a job that has succeeded.
+    pub async fn job_result(&self, job_id: &str, param_name: &str) -> BoxResult<JsonValue> {
+        let response = self
+            .client
+            .get_json(
+                &format!("{}/jobs/{}/results/{}", self.url, job_id, param_name),
+                &[],
+                &RequestOptions::default(),
+            )
+            .await?;
+        Ok(response["value"].clone())
+    }
+
+    /// Runs the task whichever way it runs: with `execute`, or by submitting a job, waiting
+    /// up to `timeout` for it and fetching all of its outputs.
+    pub async fn run(&self, params: &[(&str, String)], poll_interval: Duration, timeout: Duration) -> BoxResult<GpResults> {
+        if !self.is_asynchronous().await? {
+            return self.execute(params).await;
+        }
+        let job = self.submit_job(params).await?;
+        let job = self.wait_for_job(&job.id, poll_interval, timeout).await?;
+        let mut values = HashMap::new();
+        for name in &job.result_names {
+            values.insert(name.clone(), self.job_result(&job.id, name).await?);
+        }
+        Ok(GpResults {
+            values,
+            messages: job.messages,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_job_statuses() {
+        assert_eq!(JobStatus::from_name("esriJobExecuting"), JobStatus::Executing);
+        assert_eq!(JobStatus::from_name("esriJobTimedOut"), JobStatus::TimedOut);
+        assert_eq!(JobStatus::from_name("esriJobPaused"), JobStatus::Other(String::from("esriJobPaused")));
+        assert!(JobStatus::Succeeded.is_finished());
+        assert!(JobStatus::Failed.is_finished());
+        assert!(JobStatus::Cancelled.is_finished());
+        assert!(!JobStatus::Executing.is_finished());
+        assert!(!JobStatus::Cancelling.is_finished());
+        assert!(!JobStatus::Other(String::new()).is_finished());
+    }
+
+    #[test]
+    fn parses_jobs() {
+        let job = GpJob::from_json(&json::parse(r#"{
+            "jobId": "j1a2b3c4d5e6f",
+            "jobStatus": "esriJobSucceeded",
+            "results": {
+                "output_routes": { "paramUrl": "results/output_routes" },
+                "output_directions": { "paramUrl": "results/output_directions" }
+            },
+            "messages": [
+                { "type": "esriJobMessageTypeInformative", "description": "Submitted." },
+                { "type": "esriJobMessageTypeWarning", "description": "Network elements with avoid-restrictions are traversed." },
+                { "type": "esriJobMessageTypeInformative" }
+            ]
+        }"#).unwrap())
+        .unwrap();
+        assert_eq!(job.id, "j1a2b3c4d5e6f");
+        assert_eq!(job.status, JobStatus::Succeeded);
+        assert_eq!(job.result_names, vec!["output_routes", "output_directions"]);
+        assert_eq!(job.messages, vec!["Submitted.", "Network elements with avoid-restrictions are traversed."]);
+
+        let submitted = GpJob::from_json(&json::object! { "jobId" => "j2", "jobStatus" => "esriJobSubmitted" }).unwrap();
+        assert_eq!(submitted.status, JobStatus::Submitted);
+        assert!(submitted.result_names.is_empty());
+        assert_eq!(GpJob::from_json(&json::object! { "jobStatus" => "esriJobFailed" }), None);
+    }
+
+    #[test]
+    fn parses_execute_results() {
+        let results = GpResults::from_json(&json::parse(r#"{
+            "results": [
+                { "paramName": "Output_Polygons", "dataType": "GPFeatureRecordSetLayer", "value": { "features": [] } },
+                { "paramName": "Solve_Succeeded", "dataType": "GPBoolean", "value": true },
+                { "dataType": "GPString", "value": "no name" }
+            ],
+            "messages": [{ "type": "esriJobMessageTypeInformative", "description": "Done." }]
+        }"#).unwrap());
+        assert_eq!(results.values.len(), 2);
+        assert_eq!(results.values["Solve_Succeeded"], true);
+        assert!(results.values["Output_Polygons"]["features"].is_array());
+        assert_eq!(results.messages, vec!["Done."]);
+        assert_eq!(GpResults::from_json(&JsonValue::Null), GpResults::default());
+    }
+}
@@ -3,11 +3,91 @@
 //! `quarenta` helps Rust developers access ArcGIS RESTful services.
 #![crate_name = "quarenta"]
 
-use std::collections::HashMap;
 use std::error::Error;
 
 use json::JsonValue;
 
+mod attachments;
+mod changes;
+mod codegen;
+mod console;
+mod curve;
+mod domain;
+mod edit;
+mod export;
+mod feature;
+mod feature_cache;
+mod feature_layer;
+mod feature_service;
+mod field;
+mod geocode;
+mod geodesic;
+mod geometry;
+mod geometry_service;
+mod gp;
+mod load;
+mod map_service;
+mod network;
+mod pbf;
+mod portal;
+mod projection;
+mod related;
+mod replica;
+mod replica_store;
+mod request;
+mod spatial_index;
+mod sqlite;
+mod stream;
+mod tiles;
+mod trace;
+mod typed;
+mod util;
+mod values;
+mod where_clause;
+mod wkt;
+
+pub use attachments::AttachmentInfo;
+pub use changes::{ChangeCursor, ExtractedChanges, PendingChanges};
+pub use codegen::rust_struct;
+pub use console::{read_from_console, read_line_from_console, read_location};
+pub use domain::{CodedValue, Domain, DomainViolation, Subtype, ValidationError};
+pub use edit::EditResult;
+pub use export::{create_writer, CsvGeometry, CsvWriter, FeatureWriter, GeoJsonWriter, GeoPackageWriter};
+pub use feature::{Feature, FeatureSet};
+pub use feature_cache::{CacheRefresh, FeatureCache};
+pub use feature_layer::{FeatureLayer, LayerInfo, Query, QueryFormat, TimeInfo};
+pub use feature_service::FeatureService;
+pub use field::{Field, FieldType};
+pub use geocode::{AddressCandidate, FindAddressOptions, Geocoder, ReverseGeocodeResult, Suggestion, WORLD_GEOCODER_URL};
+pub use geodesic::{geodesic_bearing, geodesic_buffer, geodesic_destination, geodesic_distance};
+pub use geometry::{Envelope, Geometry, GeometryType, Multipoint, Point, Polygon, Polyline, SpatialReference};
+pub use geometry_service::{GeometryEngine, GeometryService, WORLD_GEOMETRY_SERVICE_URL};
+pub use gp::{GpJob, GpResults, GpTask, JobStatus};
+pub use load::{LoadOptions, LoadReport, RowResult, RowStatus};
+pub use map_service::{ExportOptions, FindOptions, IdentifyOptions, LegendLayer, LegendSymbol, MapFeature, MapService};
+pub use network::{
+    ClosestFacilityOptions, ClosestFacilityResult, DirectionStep, Directions, NetworkService, Route, RouteOptions, RouteResult,
+    ServiceArea, ServiceAreaOptions, ServiceAreaResult, Stop, TravelDirection, TravelMode, WORLD_CLOSEST_FACILITY_URL,
+    WORLD_ROUTE_URL, WORLD_SERVICE_AREA_URL,
+};
+pub use portal::{
+    Basemap, BasemapLayer, GeocodeService, HelperService, HelperServices, Portal, PortalSelf, PortalUser, SubscriptionInfo,
+    ARCGIS_ONLINE_URL,
+};
+pub use projection::Projection;
+pub use related::{RelatedRecordsQuery, Relationship};
+pub use replica::{
+    LayerChanges, LayerEditResults, LayerEdits, Replica, ReplicaOptions, SyncConflict, SyncDirection, SyncModel, SyncResult,
+};
+pub use replica_store::ReplicaStore;
+pub use request::{ArcGisError, Client, LimitedResponse, RequestOptions, RetryPolicy};
+pub use spatial_index::SpatialIndex;
+pub use stream::FeatureStream;
+pub use tiles::{CacheSummary, Lod, TileCache, TileInfo, TileKey, TileLayout, TileRange, TileService};
+pub use typed::GeometryValue;
+pub use values::{as_attribute, AttributeValue};
+pub use where_clause::{FieldCondition, SqlValue, WhereClause};
+
 type BoxResult<T> = Result<T,Box<dyn Error>>;
 
 /// Attempts to login to ArcGIS Online.
@@ -29,12 +109,15 @@ type BoxResult<T> = Result<T,Box<dyn Error>>;
 /// 
 /// The function's result will be either `Ok` or `Err`. If `Err`, it's probably because the login
 /// request went wrong (e.g. no network connectivity), not because of a bad username or password.
+/// Transient failures are retried first with the default [`RetryPolicy`]; use [`Client::login`]
+/// to control that.
 /// 
 /// # Examples
 /// 
-/// ```
+/// ```no_run
+/// # async fn example(username: String, password: String, referrer: String) {
 /// let reqwest_client = reqwest::Client::new();
-/// let login_result: Result<T,Box<dyn Error>> = quarenta::login(&reqwest_client, &username, &password, &referrer).await;
+/// let login_result = quarenta::login(&reqwest_client, &username, &password, &referrer).await;
 /// match login_result {
 ///    Ok(token_response) => {
 ///        match token_response["token"].as_str() {
@@ -52,27 +135,13 @@ type BoxResult<T> = Result<T,Box<dyn Error>>;
 ///        println!("Something went wrong while calling login: {:?}", err);
 ///    },
 /// }
+/// # }
 /// ```
 pub async fn login(
     client: &reqwest::Client,
-    username: &String,
-    password: &String,
-    referrer: &String,
+    username: &str,
+    password: &str,
+    referrer: &str,
 ) -> BoxResult<JsonValue> {
-    let mut params = HashMap::new();
-    let f_json = String::from("json");
-    params.insert("username", username);
-    params.insert("password", password);
-    params.insert("referer", referrer);
-    params.insert("f", &f_json);
-
-    match client.post("https://www.arcgis.com/sharing/rest/generateToken").form(&params).send().await {
-        Ok(response) => {
-            match response.text().await {
-                Ok(text) => Ok(json::parse(text.as_str()).unwrap()),
-                Err(err) => Err(Box::new(err)),
-            }
-        },
-        Err(err) => Err(Box::new(err)),
-    }
+    Client::from(client.clone()).login(username, password, referrer).await
 }
@@ -0,0 +1,822 @@
+//! Bulk loading features from CSV and GeoJSON files into a layer.
+//!
+//! Each row is checked locally first: its columns are matched to the layer's fields, its
+//! values converted to the fields' types and checked against their domains. The rows that
+//! pass are sent in batches with `applyEdits`, several batches at a time, and every row gets a
+//! result in the [`LoadReport`], whether it was added, rejected locally or refused by the
+//! server.
+
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use futures::stream::{self, StreamExt};
+use json::JsonValue;
+
+use crate::feature::Feature;
+use crate::feature_layer::{FeatureLayer, LayerInfo};
+use crate::field::{Field, FieldType};
+use crate::geometry::{signed_area, Geometry, Multipoint, Point, Polygon, Polyline, SpatialReference};
+use crate::replica::LayerEditResults;
+use crate::values::AttributeValue;
+use crate::BoxResult;
+
+/// Column names taken as X and Y when [`LoadOptions::xy_columns`] isn't given, ignoring case.
+const X_COLUMNS: &[&str] = &["x", "lon", "long", "longitude", "lng"];
+const Y_COLUMNS: &[&str] = &["y", "lat", "latitude"];
+
+/// How to load a file into a layer.
+#[derive(Clone, Debug)]
+pub struct LoadOptions {
+    batch_size: usize,
+    concurrency: usize,
+    field_map: Vec<(String, String)>,
+    xy_columns: Option<(String, String)>,
+    spatial_reference: SpatialReference,
+}
+
+impl LoadOptions {
+    /// Batches of 250 features, 4 batches at a time, with CSV coordinates in WGS 1984.
+    pub fn new() -> LoadOptions {
+        LoadOptions {
+            batch_size: 250,
+            concurrency: 4,
+            field_map: Vec::new(),
+            xy_columns: None,
+            spatial_reference: SpatialReference::wgs84(),
+        }
+    }
+
+    /// The most features to send in one `applyEdits` request.
+    pub fn batch_size(mut self, batch_size: usize) -> LoadOptions {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// The most `applyEdits` requests to have in flight at once. The client's per-host limit
+    /// applies as well.
+    pub fn concurrency(mut self, concurrency: usize) -> LoadOptions {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Loads a column into a field with a different name. Other columns go into the field
+    /// with the same name or alias, ignoring case.
+    pub fn map_field(mut self, column: &str, field: &str) -> LoadOptions {
+        self.field_map.push((column.to_string(), field.to_string()));
+        self
+    }
+
+    /// The CSV columns with the points' X and Y. Without this, columns named e.g. `x`,
+    /// `longitude` or `lon` and `y`, `latitude` or `lat` are used if there are any.
+    pub fn xy_columns(mut self, x: &str, y: &str) -> LoadOptions {
+        self.xy_columns = Some((x.to_string(), y.to_string()));
+        self
+    }
+
+    /// The spatial reference of CSV coordinates. GeoJSON is always WGS 1984.
+    pub fn spatial_reference(mut self, spatial_reference: SpatialReference) -> LoadOptions {
+        self.spatial_reference = spatial_reference;
+        self
+    }
+}
+
+impl Default for LoadOptions {
+    fn default() -> LoadOptions {
+        LoadOptions::new()
+    }
+}
+
+/// What happened to one row.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RowStatus {
+    /// The feature was added, with the object ID the server gave it.
+    Added { object_id: Option<u64> },
+    /// The row wasn't sent, because a value couldn't be converted or isn't allowed.
+    Invalid(String),
+    /// The server refused the feature, or the request for its batch failed.
+    Failed(String),
+}
+
+/// The result of loading one row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RowResult {
+    /// The row's number: its line among the CSV records, not counting the header, or its
+    /// place in the GeoJSON features, counting from 1.
+    pub row: usize,
+    pub status: RowStatus,
+}
+
+/// The result of loading a file, with one [`RowResult`] per row, in order.
+#[derive(Clone, Debug, Default)]
+pub struct LoadReport {
+    pub rows: Vec<RowResult>,
+    /// The columns that didn't match an editable field and weren't loaded.
+    pub unmapped_columns: Vec<String>,
+}
+
+impl LoadReport {
+    /// The number of rows added.
+    pub fn added(&self) -> usize {
+        self.rows
+            .iter()
+            .filter(|row| matches!(row.status, RowStatus::Added { .. }))
+            .count()
+    }
+
+    /// The rows that weren't added.
+    pub fn rejected(&self) -> impl Iterator<Item = &RowResult> {
+        self.rows
+            .iter()
+            .filter(|row| !matches!(row.status, RowStatus::Added { .. }))
+    }
+
+    /// Writes the report as CSV, with the columns `row`, `status`, `object_id` and `message`.
+    pub fn write_csv(&self, path: &Path) -> BoxResult<()> {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record(["row", "status", "object_id", "message"])?;
+        for row in &self.rows {
+            let (status, object_id, message) = match &row.status {
+                RowStatus::Added { object_id } => ("added", object_id.map(|id| id.to_string()), ""),
+                RowStatus::Invalid(message) => ("invalid", None, message.as_str()),
+                RowStatus::Failed(message) => ("failed", None, message.as_str()),
+            };
+            writer.write_record(&[
+                row.row.to_string(),
+                status.to_string(),
+                object_id.unwrap_or_default(),
+                message.to_string(),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// A row's values by column, and its geometry.
+type RowValues = (Vec<(String, JsonValue)>, Option<Geometry>);
+
+/// A row read from a file, or why it couldn't be read.
+struct SourceRow {
+    row: usize,
+    values: Result<RowValues, String>,
+}
+
+impl FeatureLayer {
+    /// Loads a `.csv`, `.geojson` or `.json` file, by its extension.
+    pub async fn load_file(&self, path: &Path, options: &LoadOptions) -> BoxResult<LoadReport> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        match extension.as_str() {
+            "csv" => self.load_csv(path, options).await,
+            "geojson" | "json" => self.load_geojson(path, options).await,
+            _ => Err(format!("don't know how to load {}; use .csv or .geojson", path.display()).into()),
+        }
+    }
+
+    /// Loads the rows of a CSV file with a header, as points if it has X and Y columns. Empty
+    /// values are loaded as nulls.
+    pub async fn load_csv(&self, path: &Path, options: &LoadOptions) -> BoxResult<LoadReport> {
+        let bytes = tokio::fs::read(path).await?;
+        let mut reader = csv::Reader::from_reader(bytes.as_slice());
+        let headers: Vec<String> = reader.headers()?.iter().map(String::from).collect();
+        let position = |names: &[&str]| {
+            headers
+                .iter()
+                .position(|header| names.iter().any(|name| header.trim().eq_ignore_ascii_case(name)))
+        };
+        let (x_column, y_column) = match &options.xy_columns {
+            Some((x, y)) => match (position(&[x.as_str()]), position(&[y.as_str()])) {
+                (Some(x_column), Some(y_column)) => (Some(x_column), Some(y_column)),
+                _ => return Err(format!("{} has no {} and {} columns", path.display(), x, y).into()),
+            },
+            None => match (position(X_COLUMNS), position(Y_COLUMNS)) {
+                (Some(x_column), Some(y_column)) => (Some(x_column), Some(y_column)),
+                _ => (None, None),
+            },
+        };
+        let rows = reader
+            .records()
+            .enumerate()
+            .map(|(index, record)| SourceRow {
+                row: index + 1,
+                values: record.map_err(|err| err.to_string()).and_then(|record| {
+                    let mut values = Vec::new();
+                    let (mut x, mut y) = (None, None);
+                    for (column, text) in record.iter().enumerate() {
+                        if Some(column) == x_column || Some(column) == y_column {
+                            let coordinate = text
+                                .trim()
+                                .parse::<f64>()
+                                .map_err(|_| format!("{} isn't a number: {:?}", headers[column], text))?;
+                            if Some(column) == x_column {
+                                x = Some(coordinate);
+                            } else {
+                                y = Some(coordinate);
+                            }
+                        } else if let Some(header) = headers.get(column) {
+                            let value = if text.is_empty() { JsonValue::Null } else { text.into() };
+                            values.push((header.clone(), value));
+                        }
+                    }
+                    let geometry = match (x, y) {
+                        (Some(x), Some(y)) => Some(Geometry::Point(Point::new(x, y))),
+                        _ => None,
+                    };
+                    Ok((values, geometry))
+                }),
+            })
+            .collect();
+        self.load_rows(rows, options, &options.spatial_reference).await
+    }
+
+    /// Loads the features of a GeoJSON feature collection, or a single feature.
+    pub async fn load_geojson(&self, path: &Path, options: &LoadOptions) -> BoxResult<LoadReport> {
+        let document = json::parse(&tokio::fs::read_to_string(path).await?)?;
+        let features: Vec<&JsonValue> = match document["type"].as_str() {
+            Some("FeatureCollection") => document["features"].members().collect(),
+            Some("Feature") => vec![&document],
+            _ => return Err(format!("{} isn't a GeoJSON feature collection", path.display()).into()),
+        };
+        let rows = features
+            .into_iter()
+            .enumerate()
+            .map(|(index, feature)| SourceRow {
+                row: index + 1,
+                values: geometry_from_geojson(&feature["geometry"]).map(|geometry| {
+                    let values = feature["properties"]
+                        .entries()
+                        .map(|(name, value)| (name.to_string(), value.clone()))
+                        .collect();
+                    (values, geometry)
+                }),
+            })
+            .collect();
+        self.load_rows(rows, options, &SpatialReference::wgs84()).await
+    }
+
+    async fn load_rows(
+        &self,
+        rows: Vec<SourceRow>,
+        options: &LoadOptions,
+        spatial_reference: &SpatialReference,
+    ) -> BoxResult<LoadReport> {
+        let info = self.describe().await?;
+        let mut report = LoadReport::default();
+        let mut valid: Vec<(usize, Feature)> = Vec::new();
+        for source in rows {
+            let (values, geometry) = match source.values {
+                Ok(values) => values,
+                Err(message) => {
+                    report.rows.push(RowResult {
+                        row: source.row,
+                        status: RowStatus::Invalid(message),
+                    });
+                    continue;
+                }
+            };
+            match to_feature(&info, options, values, geometry, &mut report.unmapped_columns) {
+                Ok(feature) => valid.push((source.row, feature)),
+                Err(message) => report.rows.push(RowResult {
+                    row: source.row,
+                    status: RowStatus::Invalid(message),
+                }),
+            }
+        }
+
+        let results: Vec<Vec<RowResult>> = stream::iter(batches(valid, options.batch_size))
+            .map(|batch| async move {
+                let (row_numbers, features): (Vec<usize>, Vec<Feature>) = batch.into_iter().unzip();
+                let results = self.apply_edits(&features, &[], &[], Some(spatial_reference), false).await;
+                tracing::debug!(features = features.len(), ok = results.is_ok(), "loaded batch");
+                batch_results(&row_numbers, &results)
+            })
+            .buffer_unordered(options.concurrency)
+            .collect()
+            .await;
+        report.rows.extend(results.into_iter().flatten());
+        report.rows.sort_by_key(|row| row.row);
+        Ok(report)
+    }
+}
+
+/// Splits the rows that passed the local checks into batches of at most `batch_size`, in order.
+fn batches(rows: Vec<(usize, Feature)>, batch_size: usize) -> Vec<Vec<(usize, Feature)>> {
+    rows.chunks(batch_size.max(1)).map(|batch| batch.to_vec()).collect()
+}
+
+/// The results for a batch's rows, from the server's add results in the same order, or the
+/// error for all of them if the request failed.
+fn batch_results(row_numbers: &[usize], results: &BoxResult<LayerEditResults>) -> Vec<RowResult> {
+    match results {
+        Ok(results) => row_numbers
+            .iter()
+            .enumerate()
+            .map(|(index, row)| {
+                let status = match results.add_results.get(index) {
+                    Some(result) if result.success => RowStatus::Added {
+                        object_id: result.object_id,
+                    },
+                    Some(result) => RowStatus::Failed(match &result.error {
+                        Some(error) => error.to_string(),
+                        None => String::from("not added"),
+                    }),
+                    None => RowStatus::Failed(String::from("no result from the server")),
+                };
+                RowResult { row: *row, status }
+            })
+            .collect(),
+        Err(err) => row_numbers
+            .iter()
+            .map(|row| RowResult {
+                row: *row,
+                status: RowStatus::Failed(err.to_string()),
+            })
+            .collect(),
+    }
+}
+
+/// Makes a feature of a row's values, converted to the layer's field types and checked
+/// against its domains. Columns with no editable field are added to `unmapped`.
+fn to_feature(
+    info: &LayerInfo,
+    options: &LoadOptions,
+    values: Vec<(String, JsonValue)>,
+    geometry: Option<Geometry>,
+    unmapped: &mut Vec<String>,
+) -> Result<Feature, String> {
+    let mut attributes = JsonValue::new_object();
+    let mut unconverted = Vec::new();
+    for (column, value) in values {
+        let field = match target_field(info, options, &column) {
+            Some(field) => field,
+            None => {
+                if !unmapped.contains(&column) {
+                    unmapped.push(column);
+                }
+                continue;
+            }
+        };
+        match convert_value(&value, field) {
+            Ok(value) => attributes[field.name.as_str()] = value,
+            // Text that isn't a value of the field's type may still be a code's label.
+            Err(message) if value.is_string() => {
+                attributes[field.name.as_str()] = value;
+                unconverted.push((field.name.clone(), message));
+            }
+            Err(message) => return Err(format!("{}: {}", field.name, message)),
+        }
+    }
+    // A coded-value field also takes a code's label.
+    let mut labelled = Vec::new();
+    for (name, value) in attributes.entries() {
+        if let Some(domain) = info.domain(name, &attributes) {
+            if let (false, Some(label)) = (domain.allows(value), value.as_str()) {
+                if let Some(code) = domain.code(label) {
+                    labelled.push((name.to_string(), code.clone()));
+                }
+            }
+        }
+    }
+    if let Some((name, message)) = unconverted
+        .into_iter()
+        .find(|(name, _)| !labelled.iter().any(|(labelled, _)| labelled == name))
+    {
+        return Err(format!("{}: {}", name, message));
+    }
+    for (name, code) in labelled {
+        attributes[name.as_str()] = code;
+    }
+    if let (Some(geometry), Some(geometry_type)) = (&geometry, info.geometry_type) {
+        if geometry.geometry_type() != geometry_type {
+            return Err(format!(
+                "the layer has {} geometries, not {}",
+                geometry_type.as_str(),
+                geometry.geometry_type().as_str()
+            ));
+        }
+    }
+    let feature = Feature::new(attributes, geometry);
+    info.validate(std::slice::from_ref(&feature)).map_err(|err| err.to_string())?;
+    Ok(feature)
+}
+
+/// The editable field a column goes into: the one it's mapped to, or else the one with its
+/// name or alias.
+fn target_field<'a>(info: &'a LayerInfo, options: &LoadOptions, column: &str) -> Option<&'a Field> {
+    let column = column.trim();
+    let field = match options
+        .field_map
+        .iter()
+        .find(|(mapped, _)| mapped.eq_ignore_ascii_case(column))
+    {
+        Some((_, field)) => info.field(field),
+        None => info.field(column).or_else(|| {
+            info.fields.iter().find(|field| {
+                field
+                    .alias
+                    .as_ref()
+                    .is_some_and(|alias| alias.eq_ignore_ascii_case(column))
+            })
+        }),
+    }?;
+    let loadable = field.editable
+        && !matches!(
+            field.field_type,
+            FieldType::Oid | FieldType::GlobalId | FieldType::Geometry | FieldType::Blob | FieldType::Raster
+        );
+    if loadable {
+        Some(field)
+    } else {
+        None
+    }
+}
+
+/// Converts a value read from a file, often text, to the JSON a field of its type takes.
+fn convert_value(value: &JsonValue, field: &Field) -> Result<JsonValue, String> {
+    if value.is_null() {
+        return if field.nullable {
+            Ok(JsonValue::Null)
+        } else {
+            Err(String::from("a value is required"))
+        };
+    }
+    let text = value.as_str().map(str::trim);
+    match field.field_type {
+        FieldType::SmallInteger | FieldType::Integer | FieldType::BigInteger => {
+            let integer = match text {
+                Some(text) => text.parse::<i64>().ok(),
+                None => value.as_f64().filter(|number| number.fract() == 0.0).map(|number| number as i64),
+            }
+            .ok_or_else(|| format!("{} isn't a whole number", value))?;
+            let (min, max) = match field.field_type {
+                FieldType::SmallInteger => (i16::MIN as i64, i16::MAX as i64),
+                FieldType::Integer => (i32::MIN as i64, i32::MAX as i64),
+                _ => (i64::MIN, i64::MAX),
+            };
+            if integer < min || integer > max {
+                return Err(format!("{} is out of range for {}", integer, field.field_type.as_str()));
+            }
+            Ok(integer.into())
+        }
+        FieldType::Single | FieldType::Double => match text {
+            Some(text) => text.parse::<f64>().ok(),
+            None => value.as_f64(),
+        }
+        .map(JsonValue::from)
+        .ok_or_else(|| format!("{} isn't a number", value)),
+        FieldType::Date => match text {
+            Some(text) => parse_date(text).ok_or_else(|| format!("{:?} isn't a date", text)),
+            None => value.as_i64().map(JsonValue::from).ok_or_else(|| format!("{} isn't a date", value)),
+        },
+        FieldType::DateOnly => {
+            let text = text.ok_or_else(|| format!("{} isn't a date", value))?;
+            NaiveDate::parse_from_str(text, "%Y-%m-%d").map_err(|_| format!("{:?} isn't a date like 2020-12-31", text))?;
+            Ok(text.into())
+        }
+        FieldType::String => {
+            let text = match text {
+                Some(_) => value.as_str().unwrap_or("").to_string(),
+                None => value.dump(),
+            };
+            match field.length {
+                Some(length) if 0 < length && text.chars().count() > length as usize => {
+                    Err(format!("{:?} is longer than {} characters", text, length))
+                }
+                _ => Ok(text.into()),
+            }
+        }
+        _ => Ok(value.clone()),
+    }
+}
+
+/// Parses a date as an RFC 3339 timestamp, `YYYY-MM-DD HH:MM:SS` in UTC or `YYYY-MM-DD`, and
+/// returns it as an attribute. Only numeric JSON values are taken as epoch milliseconds, so
+/// text like `20240101` isn't a date.
+fn parse_date(text: &str) -> Option<JsonValue> {
+    let date = DateTime::parse_from_rfc3339(text)
+        .map(|date| date.with_timezone(&Utc))
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S").map(|date| DateTime::from_naive_utc_and_offset(date, Utc))
+        })
+        .or_else(|_| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .map(|date| DateTime::from_naive_utc_and_offset(date.and_hms_opt(0, 0, 0).unwrap_or_default(), Utc))
+        })
+        .ok()?;
+    Some(date.to_attribute())
+}
+
+/// Converts a GeoJSON geometry to a geometry, turning rings to Esri JSON's orientation:
+/// outer rings clockwise and holes counterclockwise.
+fn geometry_from_geojson(value: &JsonValue) -> Result<Option<Geometry>, String> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    let coordinates = &value["coordinates"];
+    let geometry = match value["type"].as_str() {
+        Some("Point") => Geometry::Point(position(coordinates)?),
+        Some("MultiPoint") => Geometry::Multipoint(Multipoint {
+            points: positions(coordinates)?,
+        }),
+        Some("LineString") => Geometry::Polyline(Polyline {
+            paths: vec![positions(coordinates)?],
+        }),
+        Some("MultiLineString") => Geometry::Polyline(Polyline {
+            paths: coordinates.members().map(positions).collect::<Result<_, _>>()?,
+        }),
+        Some("Polygon") => Geometry::Polygon(Polygon {
+            rings: polygon_rings(coordinates)?,
+        }),
+        Some("MultiPolygon") => {
+            let mut rings = Vec::new();
+            for polygon in coordinates.members() {
+                rings.extend(polygon_rings(polygon)?);
+            }
+            Geometry::Polygon(Polygon { rings })
+        }
+        Some(other) => return Err(format!("can't load {} geometries", other)),
+        None => return Err(String::from("the geometry has no type")),
+    };
+    Ok(Some(geometry))
+}
+
+fn position(value: &JsonValue) -> Result<Point, String> {
+    match (value[0].as_f64(), value[1].as_f64()) {
+        (Some(x), Some(y)) => {
+            let mut point = Point::new(x, y);
+            point.z = value[2].as_f64();
+            Ok(point)
+        }
+        _ => Err(format!("{} isn't a position", value)),
+    }
+}
+
+fn positions(value: &JsonValue) -> Result<Vec<Point>, String> {
+    value.members().map(position).collect()
+}
+
+/// The rings of a GeoJSON polygon, the first outer and the rest holes, oriented for Esri JSON.
+fn polygon_rings(value: &JsonValue) -> Result<Vec<Vec<Point>>, String> {
+    let mut rings = Vec::new();
+    for (index, ring) in value.members().enumerate() {
+        let mut ring = positions(ring)?;
+        let clockwise = signed_area(&ring) < 0.0;
+        if clockwise != (0 == index) {
+            ring.reverse();
+        }
+        rings.push(ring);
+    }
+    Ok(rings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::edit::EditResult;
+
+    fn layer_info() -> LayerInfo {
+        LayerInfo::from_json(json::object! {
+            "name" => "Sites",
+            "geometryType" => "esriGeometryPoint",
+            "objectIdField" => "OBJECTID",
+            "fields" => json::array![
+                json::object! { "name" => "OBJECTID", "type" => "esriFieldTypeOID", "editable" => false },
+                json::object! { "name" => "NAME", "type" => "esriFieldTypeString", "alias" => "Site name", "length" => 10 },
+                json::object! { "name" => "VISITORS", "type" => "esriFieldTypeInteger" },
+                json::object! {
+                    "name" => "STATUS",
+                    "type" => "esriFieldTypeSmallInteger",
+                    "domain" => json::object! {
+                        "type" => "codedValue",
+                        "name" => "Status",
+                        "codedValues" => json::array![
+                            json::object! { "name" => "Open", "code" => 1 },
+                            json::object! { "name" => "Closed", "code" => 2 }
+                        ]
+                    }
+                },
+                json::object! { "name" => "AREA", "type" => "esriFieldTypeDouble", "nullable" => false },
+                json::object! { "name" => "SURVEYED", "type" => "esriFieldTypeDate" },
+                json::object! { "name" => "OPENED", "type" => "esriFieldTypeDateOnly" },
+                json::object! { "name" => "NOTES", "type" => "esriFieldTypeString", "editable" => false }
+            ]
+        })
+    }
+
+    fn field(field_type: FieldType) -> Field {
+        Field::new("FIELD", field_type)
+    }
+
+    #[test]
+    fn converts_integers_in_range() {
+        let integer = field(FieldType::Integer);
+        assert_eq!(convert_value(&" 42 ".into(), &integer), Ok(42.into()));
+        assert_eq!(convert_value(&42.0.into(), &integer), Ok(42.into()));
+        assert!(convert_value(&"4.2".into(), &integer).is_err());
+        assert!(convert_value(&4.2.into(), &integer).is_err());
+        assert!(convert_value(&"3000000000".into(), &integer).is_err());
+        assert!(convert_value(&"40000".into(), &field(FieldType::SmallInteger)).is_err());
+        assert_eq!(
+            convert_value(&"3000000000".into(), &field(FieldType::BigInteger)),
+            Ok(3_000_000_000i64.into())
+        );
+    }
+
+    #[test]
+    fn converts_numbers_strings_and_nulls() {
+        assert_eq!(convert_value(&"2.5".into(), &field(FieldType::Double)), Ok(2.5.into()));
+        assert!(convert_value(&"two".into(), &field(FieldType::Double)).is_err());
+        assert_eq!(convert_value(&7.into(), &field(FieldType::String)), Ok("7".into()));
+
+        let mut name = field(FieldType::String);
+        name.length = Some(3);
+        assert_eq!(convert_value(&"abc".into(), &name), Ok("abc".into()));
+        assert!(convert_value(&"abcd".into(), &name).is_err());
+
+        assert_eq!(convert_value(&JsonValue::Null, &name), Ok(JsonValue::Null));
+        name.nullable = false;
+        assert!(convert_value(&JsonValue::Null, &name).is_err());
+    }
+
+    #[test]
+    fn converts_dates() {
+        let date = field(FieldType::Date);
+        assert_eq!(convert_value(&1_700_000_000_000i64.into(), &date), Ok(1_700_000_000_000i64.into()));
+        assert_eq!(convert_value(&"2024-01-01".into(), &date), Ok(1_704_067_200_000i64.into()));
+        assert!(convert_value(&"20240101".into(), &date).is_err());
+
+        let date_only = field(FieldType::DateOnly);
+        assert_eq!(convert_value(&"2024-02-29".into(), &date_only), Ok("2024-02-29".into()));
+        assert!(convert_value(&"2023-02-29".into(), &date_only).is_err());
+        assert!(convert_value(&20240101.into(), &date_only).is_err());
+    }
+
+    #[test]
+    fn parses_date_text() {
+        assert_eq!(parse_date("2024-01-01T12:00:00Z"), Some(1_704_110_400_000i64.into()));
+        assert_eq!(parse_date("2024-01-01T13:00:00+01:00"), Some(1_704_110_400_000i64.into()));
+        assert_eq!(parse_date("2024-01-01 12:00:00"), Some(1_704_110_400_000i64.into()));
+        assert_eq!(parse_date("2024-01-01"), Some(1_704_067_200_000i64.into()));
+        // All digits isn't taken as epoch milliseconds, which would make this 1970.
+        assert_eq!(parse_date("20240101"), None);
+        assert_eq!(parse_date("1704067200000"), None);
+        assert_eq!(parse_date("January 1st"), None);
+    }
+
+    #[test]
+    fn reads_geojson_geometries() {
+        assert_eq!(geometry_from_geojson(&JsonValue::Null), Ok(None));
+
+        let point = json::object! { "type" => "Point", "coordinates" => json::array![-9.14, 38.72, 12.0] };
+        match geometry_from_geojson(&point) {
+            Ok(Some(Geometry::Point(point))) => {
+                assert_eq!((point.x, point.y, point.z), (-9.14, 38.72, Some(12.0)));
+            }
+            other => panic!("expected a point, got {:?}", other),
+        }
+
+        let lines = json::object! {
+            "type" => "MultiLineString",
+            "coordinates" => json::array![json::array![json::array![0, 0], json::array![1, 1]], json::array![json::array![2, 2], json::array![3, 3]]]
+        };
+        match geometry_from_geojson(&lines) {
+            Ok(Some(Geometry::Polyline(polyline))) => assert_eq!(polyline.paths.len(), 2),
+            other => panic!("expected a polyline, got {:?}", other),
+        }
+
+        let points = json::object! { "type" => "MultiPoint", "coordinates" => json::array![json::array![0, 0], json::array![1, 1]] };
+        match geometry_from_geojson(&points) {
+            Ok(Some(Geometry::Multipoint(multipoint))) => assert_eq!(multipoint.points.len(), 2),
+            other => panic!("expected a multipoint, got {:?}", other),
+        }
+
+        assert!(geometry_from_geojson(&json::object! { "type" => "GeometryCollection", "geometries" => json::array![] }).is_err());
+        assert!(geometry_from_geojson(&json::object! { "coordinates" => json::array![0, 0] }).is_err());
+        assert!(geometry_from_geojson(&json::object! { "type" => "Point", "coordinates" => json::array!["a", 0] }).is_err());
+    }
+
+    #[test]
+    fn orients_rings_for_esri_json() {
+        // GeoJSON's right-hand rule: the outer ring counterclockwise and the hole clockwise.
+        let polygon = json::object! {
+            "type" => "Polygon",
+            "coordinates" => json::array![
+                json::array![json::array![0, 0], json::array![10, 0], json::array![10, 10], json::array![0, 10], json::array![0, 0]],
+                json::array![json::array![2, 2], json::array![2, 8], json::array![8, 8], json::array![8, 2], json::array![2, 2]]
+            ]
+        };
+        let rings = match geometry_from_geojson(&polygon) {
+            Ok(Some(Geometry::Polygon(polygon))) => polygon.rings,
+            other => panic!("expected a polygon, got {:?}", other),
+        };
+        assert!(signed_area(&rings[0]) < 0.0, "the outer ring should be clockwise");
+        assert!(signed_area(&rings[1]) > 0.0, "the hole should be counterclockwise");
+
+        // Rings already in Esri JSON's orientation are left alone.
+        let reversed = json::array![
+            json::array![json::array![0, 0], json::array![0, 10], json::array![10, 10], json::array![10, 0], json::array![0, 0]]
+        ];
+        let rings = polygon_rings(&reversed).unwrap();
+        assert_eq!((rings[0][1].x, rings[0][1].y), (0.0, 10.0));
+
+        let multipolygon = json::object! {
+            "type" => "MultiPolygon",
+            "coordinates" => json::array![polygon["coordinates"].clone(), reversed]
+        };
+        match geometry_from_geojson(&multipolygon) {
+            Ok(Some(Geometry::Polygon(polygon))) => {
+                assert_eq!(polygon.rings.len(), 3);
+                assert!(signed_area(&polygon.rings[2]) < 0.0);
+            }
+            other => panic!("expected a polygon, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn matches_columns_to_fields() {
+        let info = layer_info();
+        let options = LoadOptions::new().map_field("Guests", "visitors");
+        let target = |column| target_field(&info, &options, column).map(|field| field.name.as_str());
+        assert_eq!(target("name"), Some("NAME"));
+        assert_eq!(target(" Site Name "), Some("NAME"));
+        assert_eq!(target("GUESTS"), Some("VISITORS"));
+        assert_eq!(target("OBJECTID"), None);
+        assert_eq!(target("NOTES"), None);
+        assert_eq!(target("COLOUR"), None);
+    }
+
+    #[test]
+    fn makes_features_of_rows() {
+        let info = layer_info();
+        let options = LoadOptions::new();
+        let mut unmapped = Vec::new();
+        let values = vec![
+            (String::from("Site name"), "Belém".into()),
+            (String::from("status"), "Closed".into()),
+            (String::from("AREA"), "12.5".into()),
+            (String::from("colour"), "red".into()),
+        ];
+        let feature = to_feature(&info, &options, values, Some(Geometry::Point(Point::new(-9.2, 38.7))), &mut unmapped).unwrap();
+        assert_eq!(feature.attributes["NAME"], "Belém");
+        assert_eq!(feature.attributes["STATUS"], 2);
+        assert_eq!(feature.attributes["AREA"], 12.5);
+        assert_eq!(unmapped, vec![String::from("colour")]);
+
+        let values = vec![(String::from("STATUS"), "Ajar".into()), (String::from("AREA"), "1".into())];
+        assert!(to_feature(&info, &options, values, None, &mut unmapped).is_err());
+
+        let values = vec![(String::from("AREA"), JsonValue::Null)];
+        assert!(to_feature(&info, &options, values, None, &mut unmapped).is_err());
+
+        let line = Geometry::Polyline(Polyline {
+            paths: vec![vec![Point::new(0.0, 0.0), Point::new(1.0, 1.0)]],
+        });
+        let values = vec![(String::from("AREA"), "1".into())];
+        assert!(to_feature(&info, &options, values, Some(line), &mut unmapped).is_err());
+    }
+
+    #[test]
+    fn batches_rows_in_order() {
+        let rows: Vec<(usize, Feature)> = (1..=7).map(|row| (row, Feature::new(JsonValue::new_object(), None))).collect();
+        let batches = batches(rows, 3);
+        let row_numbers: Vec<Vec<usize>> = batches
+            .iter()
+            .map(|batch| batch.iter().map(|(row, _)| *row).collect())
+            .collect();
+        assert_eq!(row_numbers, vec![vec![1, 2, 3], vec![4, 5, 6], vec![7]]);
+    }
+
+    #[test]
+    fn reports_batch_results_by_row() {
+        let results: BoxResult<LayerEditResults> = Ok(LayerEditResults {
+            add_results: vec![
+                EditResult::from_json(&json::object! { "objectId" => 11, "success" => true }),
+                EditResult::from_json(&json::object! {
+                    "success" => false,
+                    "error" => json::object! { "code" => 1000, "description" => "bad value" }
+                }),
+            ],
+            ..LayerEditResults::default()
+        });
+        let rows = batch_results(&[4, 5, 6], &results);
+        assert_eq!(rows[0], RowResult { row: 4, status: RowStatus::Added { object_id: Some(11) } });
+        assert!(matches!(&rows[1].status, RowStatus::Failed(message) if message.contains("bad value")));
+        assert_eq!(rows[2].status, RowStatus::Failed(String::from("no result from the server")));
+
+        let failed: BoxResult<LayerEditResults> = Err("timed out".into());
+        let rows = batch_results(&[1, 2], &failed);
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(|row| row.status == RowStatus::Failed(String::from("timed out"))));
+
+        let report = LoadReport {
+            rows: batch_results(&[1, 2], &results),
+            unmapped_columns: Vec::new(),
+        };
+        assert_eq!(report.added(), 1);
+        assert_eq!(report.rejected().map(|row| row.row).collect::<Vec<_>>(), vec![2]);
+    }
+}
@@ -0,0 +1,449 @@
+//! Map services: exported images, identify, find, the legend and the layer list.
+
+use json::JsonValue;
+
+use crate::feature::Feature;
+use crate::feature_layer::{FeatureLayer, LayerInfo};
+use crate::geometry::{Envelope, Geometry, SpatialReference};
+use crate::request::{has_json_body, ArcGisError, Client, RequestOptions};
+use crate::BoxResult;
+
+/// A map service, identified by its REST URL, e.g.
+/// `https://sampleserver6.arcgisonline.com/arcgis/rest/services/USA/MapServer`.
+#[derive(Clone)]
+pub struct MapService {
+    client: Client,
+    url: String,
+}
+
+/// The parameters of a map export.
+#[derive(Clone, Debug)]
+pub struct ExportOptions {
+    bbox: Envelope,
+    width: u32,
+    height: u32,
+    bbox_sr: Option<SpatialReference>,
+    image_sr: Option<SpatialReference>,
+    layers: Option<Vec<u32>>,
+    dpi: Option<u32>,
+    format: Option<String>,
+    transparent: bool,
+}
+
+impl ExportOptions {
+    /// Exports the area `bbox` as an image `width` by `height` pixels.
+    pub fn new(bbox: Envelope, width: u32, height: u32) -> ExportOptions {
+        ExportOptions {
+            bbox,
+            width,
+            height,
+            bbox_sr: None,
+            image_sr: None,
+            layers: None,
+            dpi: None,
+            format: None,
+            transparent: false,
+        }
+    }
+
+    /// The spatial reference of the bounding box. The service's is used if this isn't called.
+    pub fn bbox_sr(mut self, bbox_sr: SpatialReference) -> ExportOptions {
+        self.bbox_sr = Some(bbox_sr);
+        self
+    }
+
+    pub fn image_sr(mut self, image_sr: SpatialReference) -> ExportOptions {
+        self.image_sr = Some(image_sr);
+        self
+    }
+
+    /// Draws only these layers. The service's default layers are drawn if this isn't called.
+    pub fn layers(mut self, layer_ids: &[u32]) -> ExportOptions {
+        self.layers = Some(layer_ids.to_vec());
+        self
+    }
+
+    pub fn dpi(mut self, dpi: u32) -> ExportOptions {
+        self.dpi = Some(dpi);
+        self
+    }
+
+    /// The image format, e.g. `png32`, `jpg` or `pdf`. The server's default is `png`.
+    pub fn format(mut self, format: &str) -> ExportOptions {
+        self.format = Some(format.to_string());
+        self
+    }
+
+    pub fn transparent(mut self, transparent: bool) -> ExportOptions {
+        self.transparent = transparent;
+        self
+    }
+
+    fn to_params(&self) -> Vec<(&'static str, String)> {
+        let mut params = vec![
+            ("bbox", bbox_param(&self.bbox)),
+            ("size", format!("{},{}", self.width, self.height)),
+            ("transparent", self.transparent.to_string()),
+        ];
+        if let Some(bbox_sr) = &self.bbox_sr {
+            params.push(("bboxSR", bbox_sr.to_param()));
+        }
+        if let Some(image_sr) = &self.image_sr {
+            params.push(("imageSR", image_sr.to_param()));
+        }
+        if let Some(layers) = &self.layers {
+            params.push(("layers", format!("show:{}", id_list(layers))));
+        }
+        if let Some(dpi) = self.dpi {
+            params.push(("dpi", dpi.to_string()));
+        }
+        if let Some(format) = &self.format {
+            params.push(("format", format.clone()));
+        }
+        params
+    }
+}
+
+/// The parameters of an identify: where the user clicked, and the map they clicked on.
+#[derive(Clone, Debug)]
+pub struct IdentifyOptions {
+    geometry: Geometry,
+    map_extent: Envelope,
+    width: u32,
+    height: u32,
+    dpi: u32,
+    tolerance: u32,
+    spatial_reference: Option<SpatialReference>,
+    layers: String,
+    return_geometry: bool,
+}
+
+impl IdentifyOptions {
+    /// Identifies features at `geometry` on a map of `map_extent` displayed `width` by
+    /// `height` pixels. The tolerance defaults to 3 pixels, and all layers are searched.
+    pub fn new(geometry: Geometry, map_extent: Envelope, width: u32, height: u32) -> IdentifyOptions {
+        IdentifyOptions {
+            geometry,
+            map_extent,
+            width,
+            height,
+            dpi: 96,
+            tolerance: 3,
+            spatial_reference: None,
+            layers: String::from("all"),
+            return_geometry: true,
+        }
+    }
+
+    pub fn dpi(mut self, dpi: u32) -> IdentifyOptions {
+        self.dpi = dpi;
+        self
+    }
+
+    /// How far from the geometry, in screen pixels, to look for features.
+    pub fn tolerance(mut self, tolerance: u32) -> IdentifyOptions {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// The spatial reference of the geometry and map extent, and of the returned geometries.
+    /// The service's is used if this isn't called.
+    pub fn spatial_reference(mut self, spatial_reference: SpatialReference) -> IdentifyOptions {
+        self.spatial_reference = Some(spatial_reference);
+        self
+    }
+
+    /// Searches only these layers.
+    pub fn layers(mut self, layer_ids: &[u32]) -> IdentifyOptions {
+        self.layers = format!("all:{}", id_list(layer_ids));
+        self
+    }
+
+    /// Searches only the topmost layer with a feature at the geometry.
+    pub fn top_only(mut self) -> IdentifyOptions {
+        self.layers = String::from("top");
+        self
+    }
+
+    pub fn return_geometry(mut self, return_geometry: bool) -> IdentifyOptions {
+        self.return_geometry = return_geometry;
+        self
+    }
+
+    fn to_params(&self) -> Vec<(&'static str, String)> {
+        let mut params = vec![
+            ("geometry", self.geometry.to_json().dump()),
+            ("geometryType", self.geometry.geometry_type().as_str().to_string()),
+            ("mapExtent", bbox_param(&self.map_extent)),
+            ("imageDisplay", format!("{},{},{}", self.width, self.height, self.dpi)),
+            ("tolerance", self.tolerance.to_string()),
+            ("layers", self.layers.clone()),
+            ("returnGeometry", self.return_geometry.to_string()),
+        ];
+        if let Some(spatial_reference) = &self.spatial_reference {
+            params.push(("sr", spatial_reference.to_param()));
+        }
+        params
+    }
+}
+
+/// The parameters of a find: text to search for in some layers' fields.
+#[derive(Clone, Debug)]
+pub struct FindOptions {
+    search_text: String,
+    layers: Vec<u32>,
+    search_fields: Vec<String>,
+    contains: bool,
+    spatial_reference: Option<SpatialReference>,
+    return_geometry: bool,
+}
+
+impl FindOptions {
+    /// Finds features in `layers` whose fields contain `search_text`.
+    pub fn new(search_text: &str, layer_ids: &[u32]) -> FindOptions {
+        FindOptions {
+            search_text: search_text.to_string(),
+            layers: layer_ids.to_vec(),
+            search_fields: Vec::new(),
+            contains: true,
+            spatial_reference: None,
+            return_geometry: true,
+        }
+    }
+
+    /// The fields to search. All string fields are searched if this isn't called.
+    pub fn search_fields(mut self, search_fields: &[&str]) -> FindOptions {
+        self.search_fields = search_fields.iter().map(|field| field.to_string()).collect();
+        self
+    }
+
+    /// Whether a field only has to contain the text, rather than match it exactly.
+    pub fn contains(mut self, contains: bool) -> FindOptions {
+        self.contains = contains;
+        self
+    }
+
+    pub fn spatial_reference(mut self, spatial_reference: SpatialReference) -> FindOptions {
+        self.spatial_reference = Some(spatial_reference);
+        self
+    }
+
+    pub fn return_geometry(mut self, return_geometry: bool) -> FindOptions {
+        self.return_geometry = return_geometry;
+        self
+    }
+
+    fn to_params(&self) -> Vec<(&'static str, String)> {
+        let mut params = vec![
+            ("searchText", self.search_text.clone()),
+            ("layers", id_list(&self.layers)),
+            ("contains", self.contains.to_string()),
+            ("returnGeometry", self.return_geometry.to_string()),
+        ];
+        if !self.search_fields.is_empty() {
+            params.push(("searchFields", self.search_fields.join(",")));
+        }
+        if let Some(spatial_reference) = &self.spatial_reference {
+            params.push(("sr", spatial_reference.to_param()));
+        }
+        params
+    }
+}
+
+/// A feature found by [`MapService::identify`] or [`MapService::find`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapFeature {
+    pub layer_id: u32,
+    pub layer_name: String,
+    pub display_field_name: String,
+    /// The field that matched, for [`MapService::find`].
+    pub found_field_name: Option<String>,
+    /// The value of the display field, or of the matching field for a find.
+    pub value: String,
+    pub feature: Feature,
+}
+
+impl MapFeature {
+    pub fn from_json(value: &JsonValue) -> Option<MapFeature> {
+        Some(MapFeature {
+            layer_id: value["layerId"].as_u32()?,
+            layer_name: value["layerName"].as_str().unwrap_or("").to_string(),
+            display_field_name: value["displayFieldName"].as_str().unwrap_or("").to_string(),
+            found_field_name: value["foundFieldName"].as_str().map(String::from),
+            value: match value["value"].as_str() {
+                Some(text) => text.to_string(),
+                None => value["value"].dump(),
+            },
+            feature: Feature::from_json(value),
+        })
+    }
+}
+
+/// One symbol in a layer's legend.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LegendSymbol {
+    pub label: String,
+    /// The swatch's URL, relative to the layer's legend.
+    pub url: String,
+    /// The swatch as base64.
+    pub image_data: String,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+    /// The attribute values the symbol stands for, if the server lists them.
+    pub values: Vec<JsonValue>,
+}
+
+impl LegendSymbol {
+    pub fn from_json(value: &JsonValue) -> LegendSymbol {
+        LegendSymbol {
+            label: value["label"].as_str().unwrap_or("").to_string(),
+            url: value["url"].as_str().unwrap_or("").to_string(),
+            image_data: value["imageData"].as_str().unwrap_or("").to_string(),
+            content_type: value["contentType"].as_str().unwrap_or("").to_string(),
+            width: value["width"].as_u32().unwrap_or(0),
+            height: value["height"].as_u32().unwrap_or(0),
+            values: value["values"].members().cloned().collect(),
+        }
+    }
+}
+
+/// A layer's entry in a map service's legend.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LegendLayer {
+    pub layer_id: u32,
+    pub layer_name: String,
+    pub layer_type: String,
+    /// The scales the layer draws at; 0 means no limit.
+    pub min_scale: f64,
+    pub max_scale: f64,
+    pub symbols: Vec<LegendSymbol>,
+}
+
+impl LegendLayer {
+    pub fn from_json(value: &JsonValue) -> Option<LegendLayer> {
+        Some(LegendLayer {
+            layer_id: value["layerId"].as_u32()?,
+            layer_name: value["layerName"].as_str().unwrap_or("").to_string(),
+            layer_type: value["layerType"].as_str().unwrap_or("").to_string(),
+            min_scale: value["minScale"].as_f64().unwrap_or(0.0),
+            max_scale: value["maxScale"].as_f64().unwrap_or(0.0),
+            symbols: value["legend"].members().map(LegendSymbol::from_json).collect(),
+        })
+    }
+}
+
+impl MapService {
+    pub fn new(client: &Client, url: &str) -> MapService {
+        MapService {
+            client: client.clone(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Returns one of the service's layers or tables by ID, for querying.
+    pub fn layer(&self, id: u32) -> FeatureLayer {
+        FeatureLayer::new(&self.client, &format!("{}/{}", self.url, id))
+    }
+
+    /// Fetches the service's description.
+    pub async fn describe(&self) -> BoxResult<JsonValue> {
+        self.client.get_json(&self.url, &[], &RequestOptions::default()).await
+    }
+
+    /// Describes all of the service's layers and tables in one request.
+    pub async fn layers(&self) -> BoxResult<Vec<LayerInfo>> {
+        let response = self
+            .client
+            .get_json(&format!("{}/layers", self.url), &[], &RequestOptions::default())
+            .await?;
+        Ok(response["layers"]
+            .members()
+            .chain(response["tables"].members())
+            .map(|layer| LayerInfo::from_json(layer.clone()))
+            .collect())
+    }
+
+    /// Fetches the service's legend.
+    pub async fn legend(&self) -> BoxResult<Vec<LegendLayer>> {
+        let response = self
+            .client
+            .get_json(&format!("{}/legend", self.url), &[], &RequestOptions::default())
+            .await?;
+        Ok(response["layers"].members().filter_map(LegendLayer::from_json).collect())
+    }
+
+    /// Draws the map and returns the image's bytes, in the format asked for.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+    /// use quarenta::{Client, Envelope, ExportOptions, MapService, SpatialReference};
+    ///
+    /// let service = MapService::new(
+    ///     &Client::new(),
+    ///     "https://sampleserver6.arcgisonline.com/arcgis/rest/services/USA/MapServer",
+    /// );
+    /// let bbox = Envelope { xmin: -125.0, ymin: 24.0, xmax: -66.0, ymax: 50.0 };
+    /// let options = ExportOptions::new(bbox, 800, 400)
+    ///     .bbox_sr(SpatialReference::wgs84())
+    ///     .format("png32");
+    /// let image = service.export(&options).await?;
+    /// tokio::fs::write("usa.png", image).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn export(&self, options: &ExportOptions) -> BoxResult<Vec<u8>> {
+        let params = self.client.params_with_auth(&options.to_params(), "image");
+        let request = self.client.http().get(&format!("{}/export", self.url)).query(&params);
+        let response = self.client.send(request, &RequestOptions::default()).await?;
+        let status = response.status();
+        let is_json = has_json_body(response.headers());
+        let bytes = response.bytes().await?;
+        if is_json {
+            // Errors come back as JSON even when an image was requested.
+            let value = json::parse(&String::from_utf8_lossy(&bytes))?;
+            ArcGisError::check(value)?;
+            return Err("expected an image but the server returned JSON".into());
+        }
+        if !status.is_success() {
+            return Err(format!("map export failed with HTTP status {}", status).into());
+        }
+        Ok(bytes.to_vec())
+    }
+
+    /// Finds the features at a location on the map, such as where the user clicked.
+    pub async fn identify(&self, options: &IdentifyOptions) -> BoxResult<Vec<MapFeature>> {
+        let response = self
+            .client
+            .post_json(&format!("{}/identify", self.url), &options.to_params(), &RequestOptions::default())
+            .await?;
+        Ok(response["results"].members().filter_map(MapFeature::from_json).collect())
+    }
+
+    /// Finds features whose attributes contain some text.
+    pub async fn find(&self, options: &FindOptions) -> BoxResult<Vec<MapFeature>> {
+        let response = self
+            .client
+            .get_json(&format!("{}/find", self.url), &options.to_params(), &RequestOptions::default())
+            .await?;
+        Ok(response["results"].members().filter_map(MapFeature::from_json).collect())
+    }
+}
+
+fn bbox_param(envelope: &Envelope) -> String {
+    format!("{},{},{},{}", envelope.xmin, envelope.ymin, envelope.xmax, envelope.ymax)
+}
+
+fn id_list(ids: &[u32]) -> String {
+    ids.iter().map(ToString::to_string).collect::<Vec<_>>().join(",")
+}
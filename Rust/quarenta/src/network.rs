@@ -0,0 +1,875 @@
+//! Network analysis: routes, closest facilities and service areas, from a network analysis
+//! (NAServer) layer's `solve` operations or the equivalent geoprocessing tasks.
+
+use std::time::Duration;
+
+use json::JsonValue;
+
+use crate::feature::{Feature, FeatureSet};
+use crate::geometry::{Geometry, Point, Polygon, Polyline, SpatialReference};
+use crate::gp::{GpResults, GpTask};
+use crate::request::{Client, RequestOptions};
+use crate::BoxResult;
+
+/// The ArcGIS World route service.
+pub const WORLD_ROUTE_URL: &str = "https://route.arcgis.com/arcgis/rest/services/World/Route/NAServer/Route_World";
+/// The ArcGIS World closest facility service.
+pub const WORLD_CLOSEST_FACILITY_URL: &str =
+    "https://route.arcgis.com/arcgis/rest/services/World/ClosestFacility/NAServer/ClosestFacility_World";
+/// The ArcGIS World service area service.
+pub const WORLD_SERVICE_AREA_URL: &str =
+    "https://route.arcgis.com/arcgis/rest/services/World/ServiceAreas/NAServer/ServiceArea_World";
+
+/// How often to check on a geoprocessing job.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How long to wait for a geoprocessing job. Large analyses can take many minutes.
+const JOB_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// A stop on a route, an incident or a facility.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stop {
+    pub location: Point,
+    pub name: Option<String>,
+    /// Other attributes the solver understands, such as `CurbApproach` or `Attr_TravelTime`.
+    pub attributes: JsonValue,
+}
+
+impl Stop {
+    pub fn new(location: Point) -> Stop {
+        Stop {
+            location,
+            name: None,
+            attributes: JsonValue::new_object(),
+        }
+    }
+
+    pub fn named(name: &str, location: Point) -> Stop {
+        Stop {
+            name: Some(name.to_string()),
+            ..Stop::new(location)
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut attributes = if self.attributes.is_object() {
+            self.attributes.clone()
+        } else {
+            JsonValue::new_object()
+        };
+        if let Some(name) = &self.name {
+            attributes["Name"] = name.as_str().into();
+        }
+        json::object! {
+            "geometry" => Geometry::Point(self.location).to_json(),
+            "attributes" => attributes
+        }
+    }
+}
+
+/// A travel mode, such as driving time or walking distance, as the service describes it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TravelMode {
+    pub id: String,
+    pub name: String,
+    /// The full travel mode, which is what gets sent to the solver.
+    pub raw: JsonValue,
+}
+
+impl TravelMode {
+    pub fn from_json(value: &JsonValue) -> Option<TravelMode> {
+        Some(TravelMode {
+            id: value["id"].as_str().or_else(|| value["itemId"].as_str())?.to_string(),
+            name: value["name"].as_str().unwrap_or("").to_string(),
+            raw: value.clone(),
+        })
+    }
+}
+
+/// Whether to travel away from facilities or towards them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TravelDirection {
+    FromFacility,
+    ToFacility,
+}
+
+/// A route, with its totals in whichever units the solver reported.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub name: String,
+    pub geometry: Option<Polyline>,
+    pub total_minutes: Option<f64>,
+    pub total_kilometers: Option<f64>,
+    pub total_miles: Option<f64>,
+    /// For closest facility routes, the facility and incident the route joins.
+    pub facility_id: Option<u64>,
+    pub incident_id: Option<u64>,
+    pub attributes: JsonValue,
+}
+
+impl Route {
+    pub fn from_feature(feature: &Feature) -> Route {
+        let attributes = &feature.attributes;
+        let number = |names: &[&str]| names.iter().find_map(|name| attributes[*name].as_f64());
+        Route {
+            name: attributes["Name"].as_str().unwrap_or("").to_string(),
+            geometry: match &feature.geometry {
+                Some(Geometry::Polyline(polyline)) => Some(polyline.clone()),
+                _ => None,
+            },
+            total_minutes: number(&["Total_TravelTime", "Total_Minutes", "Total_Time"]),
+            total_kilometers: number(&["Total_Kilometers"]),
+            total_miles: number(&["Total_Miles"]),
+            facility_id: attributes["FacilityID"].as_u64(),
+            incident_id: attributes["IncidentID"].as_u64(),
+            attributes: attributes.clone(),
+        }
+    }
+}
+
+/// One step of a route's directions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirectionStep {
+    pub text: String,
+    /// The step's length, in the solver's distance units.
+    pub length: f64,
+    /// The step's time, in minutes.
+    pub minutes: f64,
+    /// e.g. `esriDMTTurnLeft`, or a number from a geoprocessing task.
+    pub maneuver_type: String,
+}
+
+impl DirectionStep {
+    /// Parses a step from a solve response or a geoprocessing task's output directions, which
+    /// name the same attributes differently.
+    pub fn from_feature(feature: &Feature) -> DirectionStep {
+        let attributes = &feature.attributes;
+        let maneuver_type = if attributes["maneuverType"].is_null() {
+            &attributes["Type"]
+        } else {
+            &attributes["maneuverType"]
+        };
+        DirectionStep {
+            text: attributes["text"].as_str().or_else(|| attributes["Text"].as_str()).unwrap_or("").to_string(),
+            length: attributes["length"].as_f64().or_else(|| attributes["DriveDistance"].as_f64()).unwrap_or(0.0),
+            minutes: attributes["time"].as_f64().or_else(|| attributes["ElapsedTime"].as_f64()).unwrap_or(0.0),
+            maneuver_type: maneuver_type.as_str().map(String::from).unwrap_or_else(|| maneuver_type.dump()),
+        }
+    }
+}
+
+/// Turn-by-turn directions for one route.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Directions {
+    pub route_name: String,
+    pub steps: Vec<DirectionStep>,
+}
+
+/// The result of solving a route.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteResult {
+    pub routes: Vec<Route>,
+    pub directions: Vec<Directions>,
+    /// The stops, in the order visited if the solver was allowed to reorder them.
+    pub stops: FeatureSet,
+    pub messages: Vec<String>,
+}
+
+/// The result of finding closest facilities.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClosestFacilityResult {
+    /// One route from each incident to each facility found for it.
+    pub routes: Vec<Route>,
+    pub directions: Vec<Directions>,
+    pub messages: Vec<String>,
+}
+
+/// The area reachable from a facility within a break.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceArea {
+    pub facility_id: Option<u64>,
+    pub from_break: f64,
+    pub to_break: f64,
+    pub geometry: Option<Polygon>,
+    pub attributes: JsonValue,
+}
+
+impl ServiceArea {
+    pub fn from_feature(feature: &Feature) -> ServiceArea {
+        ServiceArea {
+            facility_id: feature.attributes["FacilityID"].as_u64(),
+            from_break: feature.attributes["FromBreak"].as_f64().unwrap_or(0.0),
+            to_break: feature.attributes["ToBreak"].as_f64().unwrap_or(0.0),
+            geometry: match &feature.geometry {
+                Some(Geometry::Polygon(polygon)) => Some(polygon.clone()),
+                _ => None,
+            },
+            attributes: feature.attributes.clone(),
+        }
+    }
+}
+
+/// The result of generating service areas.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceAreaResult {
+    pub service_areas: Vec<ServiceArea>,
+    pub messages: Vec<String>,
+}
+
+/// Settings all three solvers share.
+#[derive(Clone, Debug, Default)]
+struct SolveSettings {
+    spatial_reference: Option<SpatialReference>,
+    barriers: Vec<Geometry>,
+    travel_mode: Option<TravelMode>,
+    out_sr: Option<SpatialReference>,
+    return_directions: bool,
+    directions_language: Option<String>,
+}
+
+impl SolveSettings {
+    fn input_sr(&self) -> SpatialReference {
+        self.spatial_reference.clone().unwrap_or_else(SpatialReference::wgs84)
+    }
+
+    fn features_param(&self, features: impl Iterator<Item = JsonValue>) -> String {
+        json::object! {
+            "features" => JsonValue::Array(features.collect()),
+            "spatialReference" => self.input_sr().to_json()
+        }
+        .dump()
+    }
+
+    fn stops_param(&self, stops: &[Stop]) -> String {
+        self.features_param(stops.iter().map(Stop::to_json))
+    }
+
+    fn push_params(&self, params: &mut Vec<(&'static str, String)>, gp: bool) {
+        let mut points = Vec::new();
+        let mut lines = Vec::new();
+        let mut polygons = Vec::new();
+        for barrier in &self.barriers {
+            match barrier {
+                Geometry::Point(_) => points.push(barrier.to_json()),
+                Geometry::Multipoint(multipoint) => {
+                    points.extend(multipoint.points.iter().map(|point| Geometry::Point(*point).to_json()))
+                }
+                Geometry::Polyline(_) => lines.push(barrier.to_json()),
+                Geometry::Polygon(_) => polygons.push(barrier.to_json()),
+                Geometry::Envelope(envelope) => polygons.push(Geometry::Polygon(envelope.to_polygon()).to_json()),
+            }
+        }
+        let names = if gp {
+            ["point_barriers", "line_barriers", "polygon_barriers"]
+        } else {
+            ["barriers", "polylineBarriers", "polygonBarriers"]
+        };
+        for (name, geometries) in names.iter().zip(vec![points, lines, polygons]) {
+            if !geometries.is_empty() {
+                let features = geometries.into_iter().map(|geometry| json::object! { "geometry" => geometry });
+                params.push((name, self.features_param(features)));
+            }
+        }
+        if let Some(travel_mode) = &self.travel_mode {
+            params.push((if gp { "travel_mode" } else { "travelMode" }, travel_mode.raw.dump()));
+        }
+        if let Some(out_sr) = &self.out_sr {
+            params.push((if gp { "env:outSR" } else { "outSR" }, out_sr.to_param()));
+        }
+        params.push((
+            if gp { "populate_directions" } else { "returnDirections" },
+            self.return_directions.to_string(),
+        ));
+        if let Some(directions_language) = &self.directions_language {
+            params.push((
+                if gp { "directions_language" } else { "directionsLanguage" },
+                directions_language.clone(),
+            ));
+        }
+    }
+}
+
+macro_rules! solve_settings {
+    ($options:ident) => {
+        impl $options {
+            /// The spatial reference of the stops and barriers. The default is WGS 1984.
+            pub fn spatial_reference(mut self, spatial_reference: SpatialReference) -> $options {
+                self.settings.spatial_reference = Some(spatial_reference);
+                self
+            }
+
+            /// Points, lines and polygons that can't be traveled through.
+            pub fn barriers(mut self, barriers: Vec<Geometry>) -> $options {
+                self.settings.barriers = barriers;
+                self
+            }
+
+            /// The travel mode, from [`NetworkService::travel_modes`]. The service's default
+            /// is used if this isn't called.
+            pub fn travel_mode(mut self, travel_mode: &TravelMode) -> $options {
+                self.settings.travel_mode = Some(travel_mode.clone());
+                self
+            }
+
+            pub fn out_sr(mut self, out_sr: SpatialReference) -> $options {
+                self.settings.out_sr = Some(out_sr);
+                self
+            }
+
+            pub fn return_directions(mut self, return_directions: bool) -> $options {
+                self.settings.return_directions = return_directions;
+                self
+            }
+
+            /// The language of the directions, e.g. `en` or `pt-PT`.
+            pub fn directions_language(mut self, directions_language: &str) -> $options {
+                self.settings.directions_language = Some(directions_language.to_string());
+                self
+            }
+        }
+    };
+}
+
+/// The parameters of a route.
+#[derive(Clone, Debug)]
+pub struct RouteOptions {
+    stops: Vec<Stop>,
+    find_best_sequence: bool,
+    settings: SolveSettings,
+}
+
+impl RouteOptions {
+    /// Routes through the stops in order.
+    pub fn new(stops: Vec<Stop>) -> RouteOptions {
+        RouteOptions {
+            stops,
+            find_best_sequence: false,
+            settings: SolveSettings::default(),
+        }
+    }
+
+    /// Lets the solver reorder the stops between the first and last to shorten the route.
+    pub fn find_best_sequence(mut self, find_best_sequence: bool) -> RouteOptions {
+        self.find_best_sequence = find_best_sequence;
+        self
+    }
+
+    fn to_params(&self, gp: bool) -> Vec<(&'static str, String)> {
+        let mut params = vec![("stops", self.settings.stops_param(&self.stops))];
+        params.push((
+            if gp { "reorder_stops_to_find_optimal_routes" } else { "findBestSequence" },
+            self.find_best_sequence.to_string(),
+        ));
+        if !gp {
+            params.push(("returnRoutes", String::from("true")));
+            params.push(("returnStops", String::from("true")));
+        }
+        self.settings.push_params(&mut params, gp);
+        params
+    }
+}
+
+solve_settings!(RouteOptions);
+
+/// The parameters of a closest facility analysis.
+#[derive(Clone, Debug)]
+pub struct ClosestFacilityOptions {
+    incidents: Vec<Stop>,
+    facilities: Vec<Stop>,
+    facility_count: Option<u32>,
+    travel_direction: Option<TravelDirection>,
+    cutoff: Option<f64>,
+    settings: SolveSettings,
+}
+
+impl ClosestFacilityOptions {
+    pub fn new(incidents: Vec<Stop>, facilities: Vec<Stop>) -> ClosestFacilityOptions {
+        ClosestFacilityOptions {
+            incidents,
+            facilities,
+            facility_count: None,
+            travel_direction: None,
+            cutoff: None,
+            settings: SolveSettings::default(),
+        }
+    }
+
+    /// How many facilities to find for each incident. The service's default is 1.
+    pub fn facility_count(mut self, facility_count: u32) -> ClosestFacilityOptions {
+        self.facility_count = Some(facility_count);
+        self
+    }
+
+    pub fn travel_direction(mut self, travel_direction: TravelDirection) -> ClosestFacilityOptions {
+        self.travel_direction = Some(travel_direction);
+        self
+    }
+
+    /// Ignores facilities further than this, in the travel mode's units.
+    pub fn cutoff(mut self, cutoff: f64) -> ClosestFacilityOptions {
+        self.cutoff = Some(cutoff);
+        self
+    }
+
+    fn to_params(&self, gp: bool) -> Vec<(&'static str, String)> {
+        let mut params = vec![
+            ("incidents", self.settings.stops_param(&self.incidents)),
+            ("facilities", self.settings.stops_param(&self.facilities)),
+        ];
+        if let Some(facility_count) = self.facility_count {
+            params.push((
+                if gp { "number_of_facilities_to_find" } else { "defaultTargetFacilityCount" },
+                facility_count.to_string(),
+            ));
+        }
+        if let Some(travel_direction) = self.travel_direction {
+            params.push(match (gp, travel_direction) {
+                (true, TravelDirection::ToFacility) => ("travel_direction", String::from("Incident to Facility")),
+                (true, TravelDirection::FromFacility) => ("travel_direction", String::from("Facility to Incident")),
+                (false, TravelDirection::ToFacility) => ("travelDirection", String::from("esriNATravelDirectionToFacility")),
+                (false, TravelDirection::FromFacility) => {
+                    ("travelDirection", String::from("esriNATravelDirectionFromFacility"))
+                }
+            });
+        }
+        if let Some(cutoff) = self.cutoff {
+            params.push((if gp { "cutoff" } else { "defaultCutoff" }, cutoff.to_string()));
+        }
+        if !gp {
+            params.push(("returnCFRoutes", String::from("true")));
+        }
+        self.settings.push_params(&mut params, gp);
+        params
+    }
+}
+
+solve_settings!(ClosestFacilityOptions);
+
+/// The parameters of a service area analysis.
+#[derive(Clone, Debug)]
+pub struct ServiceAreaOptions {
+    facilities: Vec<Stop>,
+    breaks: Vec<f64>,
+    travel_direction: Option<TravelDirection>,
+    settings: SolveSettings,
+}
+
+impl ServiceAreaOptions {
+    /// Finds the areas reachable from each facility within each break, in the travel mode's
+    /// units, e.g. `&[5.0, 10.0, 15.0]` minutes.
+    pub fn new(facilities: Vec<Stop>, breaks: &[f64]) -> ServiceAreaOptions {
+        ServiceAreaOptions {
+            facilities,
+            breaks: breaks.to_vec(),
+            travel_direction: None,
+            settings: SolveSettings::default(),
+        }
+    }
+
+    pub fn travel_direction(mut self, travel_direction: TravelDirection) -> ServiceAreaOptions {
+        self.travel_direction = Some(travel_direction);
+        self
+    }
+
+    fn to_params(&self, gp: bool) -> Vec<(&'static str, String)> {
+        let breaks: Vec<String> = self.breaks.iter().map(ToString::to_string).collect();
+        let mut params = vec![("facilities", self.settings.stops_param(&self.facilities))];
+        if gp {
+            params.push(("break_values", breaks.join(" ")));
+        } else {
+            params.push(("defaultBreaks", breaks.join(",")));
+        }
+        if let Some(travel_direction) = self.travel_direction {
+            params.push(match (gp, travel_direction) {
+                (true, TravelDirection::FromFacility) => ("travel_direction", String::from("Away From Facility")),
+                (true, TravelDirection::ToFacility) => ("travel_direction", String::from("Towards Facility")),
+                (false, TravelDirection::FromFacility) => {
+                    ("travelDirection", String::from("esriNATravelDirectionFromFacility"))
+                }
+                (false, TravelDirection::ToFacility) => ("travelDirection", String::from("esriNATravelDirectionToFacility")),
+            });
+        }
+        self.settings.push_params(&mut params, gp);
+        params
+    }
+}
+
+solve_settings!(ServiceAreaOptions);
+
+/// A network analysis service: either an NAServer layer such as [`WORLD_ROUTE_URL`], which
+/// solves in one request, or a geoprocessing task such as
+/// `https://logistics.arcgis.com/arcgis/rest/services/World/Route/GPServer/FindRoutes`, which
+/// may run as a job. The same options and results work with both.
+///
+/// # Examples
+///
+/// ```no_run
+/// # async fn example(client: &quarenta::Client) -> Result<(), Box<dyn std::error::Error>> {
+/// use quarenta::{NetworkService, Point, RouteOptions, Stop, WORLD_ROUTE_URL};
+///
+/// let service = NetworkService::new(client, WORLD_ROUTE_URL);
+/// let options = RouteOptions::new(vec![
+///     Stop::named("Lisbon", Point::new(-9.14, 38.72)),
+///     Stop::named("Porto", Point::new(-8.61, 41.15)),
+/// ])
+/// .return_directions(true);
+/// let result = service.solve_route(&options).await?;
+/// for route in &result.routes {
+///     println!("{}: {:?} km, {:?} minutes", route.name, route.total_kilometers, route.total_minutes);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct NetworkService {
+    client: Client,
+    url: String,
+}
+
+impl NetworkService {
+    pub fn new(client: &Client, url: &str) -> NetworkService {
+        NetworkService {
+            client: client.clone(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Whether the service is a geoprocessing task rather than an NAServer layer.
+    pub fn is_geoprocessing(&self) -> bool {
+        self.url.contains("/GPServer/")
+    }
+
+    /// Lists the travel modes an NAServer layer supports.
+    pub async fn travel_modes(&self) -> BoxResult<Vec<TravelMode>> {
+        let response = self
+            .client
+            .get_json(&format!("{}/retrieveTravelModes", self.url), &[], &RequestOptions::default())
+            .await?;
+        Ok(response["supportedTravelModes"].members().filter_map(TravelMode::from_json).collect())
+    }
+
+    pub async fn solve_route(&self, options: &RouteOptions) -> BoxResult<RouteResult> {
+        if self.is_geoprocessing() {
+            let results = self.run_task(&options.to_params(true)).await?;
+            return Ok(RouteResult {
+                routes: routes(&result_features(&results, "output_routes")),
+                directions: gp_directions(&result_features(&results, "output_directions")),
+                stops: result_features(&results, "output_stops"),
+                messages: results.messages,
+            });
+        }
+        let response = self.solve("solve", &options.to_params(false)).await?;
+        Ok(RouteResult {
+            routes: routes(&FeatureSet::from_json(&response["routes"])),
+            directions: directions(&response["directions"]),
+            stops: FeatureSet::from_json(&response["stops"]),
+            messages: messages(&response),
+        })
+    }
+
+    pub async fn solve_closest_facility(&self, options: &ClosestFacilityOptions) -> BoxResult<ClosestFacilityResult> {
+        if self.is_geoprocessing() {
+            let results = self.run_task(&options.to_params(true)).await?;
+            return Ok(ClosestFacilityResult {
+                routes: routes(&result_features(&results, "output_routes")),
+                directions: gp_directions(&result_features(&results, "output_directions")),
+                messages: results.messages,
+            });
+        }
+        let response = self.solve("solveClosestFacility", &options.to_params(false)).await?;
+        Ok(ClosestFacilityResult {
+            routes: routes(&FeatureSet::from_json(&response["routes"])),
+            directions: directions(&response["directions"]),
+            messages: messages(&response),
+        })
+    }
+
+    pub async fn solve_service_area(&self, options: &ServiceAreaOptions) -> BoxResult<ServiceAreaResult> {
+        let (service_areas, messages) = if self.is_geoprocessing() {
+            let results = self.run_task(&options.to_params(true)).await?;
+            (result_features(&results, "output_service_areas"), results.messages)
+        } else {
+            let response = self.solve("solveServiceArea", &options.to_params(false)).await?;
+            (FeatureSet::from_json(&response["saPolygons"]), messages(&response))
+        };
+        Ok(ServiceAreaResult {
+            service_areas: service_areas.features.iter().map(ServiceArea::from_feature).collect(),
+            messages,
+        })
+    }
+
+    async fn solve(&self, operation: &str, params: &[(&str, String)]) -> BoxResult<JsonValue> {
+        self.client
+            .post_json(&format!("{}/{}", self.url, operation), params, &RequestOptions::default())
+            .await
+    }
+
+    async fn run_task(&self, params: &[(&str, String)]) -> BoxResult<GpResults> {
+        GpTask::new(&self.client, &self.url).run(params, POLL_INTERVAL, JOB_TIMEOUT).await
+    }
+}
+
+fn result_features(results: &GpResults, name: &str) -> FeatureSet {
+    results
+        .values
+        .get(name)
+        .map(FeatureSet::from_json)
+        .unwrap_or_default()
+}
+
+fn routes(feature_set: &FeatureSet) -> Vec<Route> {
+    feature_set.features.iter().map(Route::from_feature).collect()
+}
+
+fn directions(value: &JsonValue) -> Vec<Directions> {
+    value
+        .members()
+        .map(|directions| Directions {
+            route_name: directions["routeName"].as_str().unwrap_or("").to_string(),
+            steps: directions["features"]
+                .members()
+                .map(|step| DirectionStep::from_feature(&Feature::from_json(step)))
+                .collect(),
+        })
+        .collect()
+}
+
+/// Groups a geoprocessing task's output directions, one feature per step, by route.
+fn gp_directions(feature_set: &FeatureSet) -> Vec<Directions> {
+    let mut all_directions: Vec<Directions> = Vec::new();
+    for feature in &feature_set.features {
+        let route_name = feature.attributes["RouteName"].as_str().unwrap_or("");
+        let step = DirectionStep::from_feature(feature);
+        match all_directions.iter_mut().find(|directions| directions.route_name == route_name) {
+            Some(directions) => directions.steps.push(step),
+            None => all_directions.push(Directions {
+                route_name: route_name.to_string(),
+                steps: vec![step],
+            }),
+        }
+    }
+    all_directions
+}
+
+fn messages(response: &JsonValue) -> Vec<String> {
+    response["messages"]
+        .members()
+        .filter_map(|message| message["description"].as_str())
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn param<'a>(params: &'a [(&str, String)], name: &str) -> Option<&'a str> {
+        params.iter().find(|(key, _)| *key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn names(params: &[(&'static str, String)]) -> Vec<&'static str> {
+        params.iter().map(|(name, _)| *name).collect()
+    }
+
+    fn stops() -> Vec<Stop> {
+        vec![Stop::named("Lisbon", Point::new(-9.14, 38.72)), Stop::new(Point::new(-8.61, 41.15))]
+    }
+
+    #[test]
+    fn builds_route_params() {
+        let options = RouteOptions::new(stops()).find_best_sequence(true).return_directions(true);
+        let params = options.to_params(false);
+        assert_eq!(
+            names(&params),
+            vec!["stops", "findBestSequence", "returnRoutes", "returnStops", "returnDirections"]
+        );
+        let stops = json::parse(param(&params, "stops").unwrap()).unwrap();
+        assert_eq!(stops["spatialReference"]["wkid"], 4326);
+        assert_eq!(stops["features"][0]["attributes"]["Name"], "Lisbon");
+        assert_eq!(stops["features"][0]["geometry"]["x"], -9.14);
+        assert_eq!(stops["features"][1]["attributes"].len(), 0);
+        assert_eq!(param(&params, "findBestSequence"), Some("true"));
+
+        let params = options.to_params(true);
+        assert_eq!(names(&params), vec!["stops", "reorder_stops_to_find_optimal_routes", "populate_directions"]);
+    }
+
+    #[test]
+    fn builds_shared_solve_params() {
+        let travel_mode = TravelMode::from_json(&json::object! { "id" => "FEgifRtFndKNcJMJ", "name" => "Driving Time" }).unwrap();
+        let barrier = Polyline {
+            paths: vec![vec![Point::new(0.0, 0.0), Point::new(1.0, 1.0)]],
+        };
+        let options = RouteOptions::new(stops())
+            .spatial_reference(SpatialReference::from_wkid(3857))
+            .barriers(vec![
+                Geometry::Point(Point::new(0.5, 0.5)),
+                Geometry::Multipoint(crate::geometry::Multipoint {
+                    points: vec![Point::new(1.0, 1.0), Point::new(2.0, 2.0)],
+                }),
+                Geometry::Polyline(barrier),
+            ])
+            .travel_mode(&travel_mode)
+            .out_sr(SpatialReference::wgs84())
+            .directions_language("pt-PT");
+
+        let params = options.to_params(false);
+        let points = json::parse(param(&params, "barriers").unwrap()).unwrap();
+        assert_eq!(points["features"].len(), 3);
+        assert_eq!(points["spatialReference"]["wkid"], 3857);
+        assert_eq!(json::parse(param(&params, "polylineBarriers").unwrap()).unwrap()["features"].len(), 1);
+        assert_eq!(param(&params, "polygonBarriers"), None);
+        assert_eq!(json::parse(param(&params, "travelMode").unwrap()).unwrap()["name"], "Driving Time");
+        assert_eq!(param(&params, "outSR"), Some("4326"));
+        assert_eq!(param(&params, "returnDirections"), Some("false"));
+        assert_eq!(param(&params, "directionsLanguage"), Some("pt-PT"));
+
+        let params = options.to_params(true);
+        assert!(param(&params, "point_barriers").is_some());
+        assert!(param(&params, "line_barriers").is_some());
+        assert!(param(&params, "travel_mode").is_some());
+        assert_eq!(param(&params, "env:outSR"), Some("4326"));
+        assert_eq!(param(&params, "directions_language"), Some("pt-PT"));
+    }
+
+    #[test]
+    fn builds_closest_facility_params() {
+        let options = ClosestFacilityOptions::new(stops(), stops())
+            .facility_count(2)
+            .travel_direction(TravelDirection::ToFacility)
+            .cutoff(15.0);
+        let params = options.to_params(false);
+        assert_eq!(param(&params, "defaultTargetFacilityCount"), Some("2"));
+        assert_eq!(param(&params, "travelDirection"), Some("esriNATravelDirectionToFacility"));
+        assert_eq!(param(&params, "defaultCutoff"), Some("15"));
+        assert_eq!(param(&params, "returnCFRoutes"), Some("true"));
+        assert!(param(&params, "incidents").is_some() && param(&params, "facilities").is_some());
+
+        let params = options.travel_direction(TravelDirection::FromFacility).to_params(true);
+        assert_eq!(param(&params, "number_of_facilities_to_find"), Some("2"));
+        assert_eq!(param(&params, "travel_direction"), Some("Facility to Incident"));
+        assert_eq!(param(&params, "cutoff"), Some("15"));
+        assert_eq!(param(&params, "returnCFRoutes"), None);
+    }
+
+    #[test]
+    fn builds_service_area_params() {
+        let options = ServiceAreaOptions::new(stops(), &[5.0, 10.0, 15.5]).travel_direction(TravelDirection::ToFacility);
+        let params = options.to_params(false);
+        assert_eq!(param(&params, "defaultBreaks"), Some("5,10,15.5"));
+        assert_eq!(param(&params, "travelDirection"), Some("esriNATravelDirectionToFacility"));
+        let params = options.to_params(true);
+        assert_eq!(param(&params, "break_values"), Some("5 10 15.5"));
+        assert_eq!(param(&params, "travel_direction"), Some("Towards Facility"));
+    }
+
+    #[test]
+    fn reads_routes() {
+        let feature_set = FeatureSet::from_json(&json::parse(r#"{
+            "geometryType": "esriGeometryPolyline",
+            "features": [
+                {
+                    "attributes": { "Name": "Lisbon - Porto", "Total_TravelTime": 187.5, "Total_Kilometers": 313.2, "Total_Miles": 194.6 },
+                    "geometry": { "paths": [[[-9.14, 38.72], [-8.61, 41.15]]] }
+                },
+                {
+                    "attributes": { "Name": "Incident 1 - Facility 2", "Total_Minutes": 4.25, "FacilityID": 2, "IncidentID": 1 }
+                }
+            ]
+        }"#).unwrap());
+        let routes = routes(&feature_set);
+        assert_eq!(routes[0].name, "Lisbon - Porto");
+        assert_eq!(routes[0].total_minutes, Some(187.5));
+        assert_eq!(routes[0].total_kilometers, Some(313.2));
+        assert_eq!(routes[0].total_miles, Some(194.6));
+        assert_eq!(routes[0].geometry.as_ref().map(|polyline| polyline.paths[0].len()), Some(2));
+        assert_eq!(routes[0].facility_id, None);
+        assert_eq!(routes[1].total_minutes, Some(4.25));
+        assert_eq!((routes[1].facility_id, routes[1].incident_id), (Some(2), Some(1)));
+        assert_eq!(routes[1].geometry, None);
+    }
+
+    #[test]
+    fn reads_solve_directions() {
+        let directions = directions(&json::parse(r#"[
+            {
+                "routeId": 1,
+                "routeName": "Lisbon - Porto",
+                "features": [
+                    { "attributes": { "length": 0, "time": 0, "text": "Start at Lisbon", "maneuverType": "esriDMTDepart" } },
+                    { "attributes": { "length": 1.5, "time": 2.25, "text": "Turn left on Avenida da Liberdade", "maneuverType": "esriDMTTurnLeft" } }
+                ]
+            }
+        ]"#).unwrap());
+        assert_eq!(directions.len(), 1);
+        assert_eq!(directions[0].route_name, "Lisbon - Porto");
+        assert_eq!(
+            directions[0].steps[1],
+            DirectionStep {
+                text: String::from("Turn left on Avenida da Liberdade"),
+                length: 1.5,
+                minutes: 2.25,
+                maneuver_type: String::from("esriDMTTurnLeft"),
+            }
+        );
+    }
+
+    #[test]
+    fn groups_task_directions_by_route() {
+        let feature_set = FeatureSet::from_json(&json::parse(r#"{
+            "features": [
+                { "attributes": { "RouteName": "A - B", "Text": "Start at A", "Type": 18, "DriveDistance": 0, "ElapsedTime": 0 } },
+                { "attributes": { "RouteName": "C - D", "Text": "Start at C", "Type": 18 } },
+                { "attributes": { "RouteName": "A - B", "Text": "Finish at B", "Type": 19, "DriveDistance": 2.5, "ElapsedTime": 3.75 } },
+                { "attributes": { "Text": "Unnamed", "Type": 1 } }
+            ]
+        }"#).unwrap());
+        let directions = gp_directions(&feature_set);
+        let routes: Vec<(&str, Vec<&str>)> = directions
+            .iter()
+            .map(|directions| {
+                (
+                    directions.route_name.as_str(),
+                    directions.steps.iter().map(|step| step.text.as_str()).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            routes,
+            vec![("A - B", vec!["Start at A", "Finish at B"]), ("C - D", vec!["Start at C"]), ("", vec!["Unnamed"])]
+        );
+        let finish = &directions[0].steps[1];
+        assert_eq!((finish.length, finish.minutes), (2.5, 3.75));
+        assert_eq!(finish.maneuver_type, "19");
+        assert_eq!(directions[1].steps[0].length, 0.0);
+    }
+
+    #[test]
+    fn reads_service_areas() {
+        let feature = Feature::from_json(&json::parse(r#"{
+            "attributes": { "FacilityID": 1, "FromBreak": 5, "ToBreak": 10, "Name": "Facility 1 : 5 - 10" },
+            "geometry": { "rings": [[[0, 0], [0, 1], [1, 1], [0, 0]]] }
+        }"#).unwrap());
+        let service_area = ServiceArea::from_feature(&feature);
+        assert_eq!(service_area.facility_id, Some(1));
+        assert_eq!((service_area.from_break, service_area.to_break), (5.0, 10.0));
+        assert!(service_area.geometry.is_some());
+        assert_eq!(service_area.attributes["Name"], "Facility 1 : 5 - 10");
+    }
+
+    #[test]
+    fn reads_travel_modes() {
+        let item = TravelMode::from_json(&json::object! { "itemId" => "abc", "name" => "Walking Time" }).unwrap();
+        assert_eq!(item.id, "abc");
+        assert_eq!(TravelMode::from_json(&json::object! { "name" => "No ID" }), None);
+        assert!(NetworkService::new(&Client::new(), "https://example.com/arcgis/rest/services/Route/GPServer/FindRoutes/").is_geoprocessing());
+        assert!(!NetworkService::new(&Client::new(), WORLD_ROUTE_URL).is_geoprocessing());
+    }
+}
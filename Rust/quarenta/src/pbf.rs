@@ -0,0 +1,594 @@
+//! Decoding of `f=pbf` query responses.
+//!
+//! The messages below mirror the parts of Esri's `FeatureCollection.proto` (package
+//! `esriPBuffer`) that a query returns. They're written out by hand so building quarenta
+//! doesn't need `protoc`.
+
+use json::JsonValue;
+use prost::Message;
+
+use crate::feature::{Feature, FeatureSet};
+use crate::field::{Field, FieldType};
+use crate::geometry::{Geometry, GeometryType, Multipoint, Point, Polygon, Polyline, SpatialReference};
+use crate::BoxResult;
+
+#[derive(Clone, PartialEq, Message)]
+struct FeatureCollectionPBuffer {
+    #[prost(string, tag = "1")]
+    version: String,
+    #[prost(message, optional, tag = "2")]
+    query_result: Option<QueryResult>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct QueryResult {
+    #[prost(oneof = "Results", tags = "1, 2, 3")]
+    results: Option<Results>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum Results {
+    #[prost(message, tag = "1")]
+    Features(FeatureResult),
+    #[prost(message, tag = "2")]
+    Count(CountResult),
+    #[prost(message, tag = "3")]
+    Ids(ObjectIdsResult),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct CountResult {
+    #[prost(uint64, tag = "1")]
+    count: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ObjectIdsResult {
+    #[prost(string, tag = "1")]
+    object_id_field_name: String,
+    #[prost(uint64, repeated, tag = "3")]
+    object_ids: Vec<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct FeatureResult {
+    #[prost(string, tag = "1")]
+    object_id_field_name: String,
+    #[prost(string, tag = "3")]
+    global_id_field_name: String,
+    #[prost(enumeration = "PbfGeometryType", tag = "7")]
+    geometry_type: i32,
+    #[prost(message, optional, tag = "8")]
+    spatial_reference: Option<PbfSpatialReference>,
+    #[prost(bool, tag = "9")]
+    exceeded_transfer_limit: bool,
+    #[prost(bool, tag = "10")]
+    has_z: bool,
+    #[prost(bool, tag = "11")]
+    has_m: bool,
+    #[prost(message, optional, tag = "12")]
+    transform: Option<Transform>,
+    #[prost(message, repeated, tag = "13")]
+    fields: Vec<PbfField>,
+    #[prost(message, repeated, tag = "15")]
+    features: Vec<PbfFeature>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbfSpatialReference {
+    #[prost(uint32, tag = "1")]
+    wkid: u32,
+    #[prost(uint32, tag = "2")]
+    lastest_wkid: u32,
+    #[prost(string, tag = "5")]
+    wkt: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbfField {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(enumeration = "PbfFieldType", tag = "2")]
+    field_type: i32,
+    #[prost(string, tag = "3")]
+    alias: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbfFeature {
+    #[prost(message, repeated, tag = "1")]
+    attributes: Vec<Value>,
+    #[prost(message, optional, tag = "2")]
+    geometry: Option<PbfGeometry>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PbfGeometry {
+    #[prost(uint32, repeated, tag = "2")]
+    lengths: Vec<u32>,
+    #[prost(sint64, repeated, tag = "3")]
+    coords: Vec<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Value {
+    #[prost(oneof = "ValueType", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9")]
+    value_type: Option<ValueType>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum ValueType {
+    #[prost(string, tag = "1")]
+    String(String),
+    #[prost(float, tag = "2")]
+    Float(f32),
+    #[prost(double, tag = "3")]
+    Double(f64),
+    #[prost(sint32, tag = "4")]
+    Sint(i32),
+    #[prost(uint32, tag = "5")]
+    Uint(u32),
+    #[prost(int64, tag = "6")]
+    Int64(i64),
+    #[prost(uint64, tag = "7")]
+    Uint64(u64),
+    #[prost(sint64, tag = "8")]
+    Sint64(i64),
+    #[prost(bool, tag = "9")]
+    Bool(bool),
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Transform {
+    #[prost(enumeration = "QuantizeOriginPosition", tag = "1")]
+    quantize_origin_position: i32,
+    #[prost(message, optional, tag = "2")]
+    scale: Option<Scale>,
+    #[prost(message, optional, tag = "3")]
+    translate: Option<Translate>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Scale {
+    #[prost(double, tag = "1")]
+    x_scale: f64,
+    #[prost(double, tag = "2")]
+    y_scale: f64,
+    #[prost(double, tag = "3")]
+    m_scale: f64,
+    #[prost(double, tag = "4")]
+    z_scale: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Translate {
+    #[prost(double, tag = "1")]
+    x_translate: f64,
+    #[prost(double, tag = "2")]
+    y_translate: f64,
+    #[prost(double, tag = "3")]
+    m_translate: f64,
+    #[prost(double, tag = "4")]
+    z_translate: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+enum PbfGeometryType {
+    Point = 0,
+    Multipoint = 1,
+    Polyline = 2,
+    Polygon = 3,
+    Multipatch = 4,
+    None = 127,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+enum PbfFieldType {
+    SmallInteger = 0,
+    Integer = 1,
+    Single = 2,
+    Double = 3,
+    String = 4,
+    Date = 5,
+    Oid = 6,
+    Geometry = 7,
+    Blob = 8,
+    Raster = 9,
+    Guid = 10,
+    GlobalId = 11,
+    Xml = 12,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+enum QuantizeOriginPosition {
+    UpperLeft = 0,
+    LowerLeft = 1,
+}
+
+/// Decodes a PBF query response into the same `FeatureSet` a JSON query would produce.
+pub(crate) fn decode_feature_set(bytes: &[u8]) -> BoxResult<FeatureSet> {
+    let collection = FeatureCollectionPBuffer::decode(bytes)?;
+    let result = match collection.query_result.and_then(|query_result| query_result.results) {
+        Some(Results::Features(result)) => result,
+        Some(_) => return Err("PBF response is a count or ID result, not features".into()),
+        None => return Err("PBF response has no query result".into()),
+    };
+
+    let fields: Vec<Field> = result.fields.iter().map(field_from_pbf).collect();
+    let geometry_type = match PbfGeometryType::from_i32(result.geometry_type) {
+        Some(PbfGeometryType::Point) => Some(GeometryType::Point),
+        Some(PbfGeometryType::Multipoint) => Some(GeometryType::Multipoint),
+        Some(PbfGeometryType::Polyline) => Some(GeometryType::Polyline),
+        Some(PbfGeometryType::Polygon) => Some(GeometryType::Polygon),
+        _ => None,
+    };
+    let decoder = CoordinateDecoder::new(result.transform.as_ref(), result.has_z, result.has_m);
+    let features = result
+        .features
+        .iter()
+        .map(|feature| {
+            let mut attributes = JsonValue::new_object();
+            for (field, value) in fields.iter().zip(feature.attributes.iter()) {
+                attributes[field.name.as_str()] = value_to_json(value);
+            }
+            let geometry = match (geometry_type, &feature.geometry) {
+                (Some(geometry_type), Some(geometry)) => decoder.geometry(geometry_type, geometry),
+                _ => None,
+            };
+            Feature::new(attributes, geometry)
+        })
+        .collect();
+
+    Ok(FeatureSet {
+        object_id_field_name: non_empty(result.object_id_field_name),
+        global_id_field_name: non_empty(result.global_id_field_name),
+        geometry_type,
+        spatial_reference: result.spatial_reference.map(|spatial_reference| SpatialReference {
+            wkid: non_zero(spatial_reference.wkid),
+            latest_wkid: non_zero(spatial_reference.lastest_wkid),
+            wkt: non_empty(spatial_reference.wkt),
+        }),
+        has_z: result.has_z,
+        has_m: result.has_m,
+        fields,
+        features,
+        exceeded_transfer_limit: result.exceeded_transfer_limit,
+    })
+}
+
+/// Undoes the quantization and delta encoding of PBF coordinates.
+///
+/// Each geometry's coordinates are integers, each one the difference from the same dimension
+/// of the previous vertex, across all parts. Multiplying the running total by the transform's
+/// scale and adding its translation gives the real coordinate, with y flipped when the
+/// quantization origin is the upper left.
+struct CoordinateDecoder {
+    scale: [f64; 4],
+    translate: [f64; 4],
+    flip_y: bool,
+    has_z: bool,
+    has_m: bool,
+}
+
+impl CoordinateDecoder {
+    fn new(transform: Option<&Transform>, has_z: bool, has_m: bool) -> CoordinateDecoder {
+        let mut decoder = CoordinateDecoder {
+            scale: [1.0; 4],
+            translate: [0.0; 4],
+            flip_y: false,
+            has_z,
+            has_m,
+        };
+        if let Some(transform) = transform {
+            decoder.flip_y = Some(QuantizeOriginPosition::UpperLeft)
+                == QuantizeOriginPosition::from_i32(transform.quantize_origin_position);
+            if let Some(scale) = &transform.scale {
+                decoder.scale = [scale.x_scale, scale.y_scale, scale.z_scale, scale.m_scale];
+            }
+            if let Some(translate) = &transform.translate {
+                decoder.translate = [
+                    translate.x_translate,
+                    translate.y_translate,
+                    translate.z_translate,
+                    translate.m_translate,
+                ];
+            }
+        }
+        decoder
+    }
+
+    fn dimensions(&self) -> usize {
+        2 + self.has_z as usize + self.has_m as usize
+    }
+
+    fn geometry(&self, geometry_type: GeometryType, geometry: &PbfGeometry) -> Option<Geometry> {
+        let points = self.vertices(&geometry.coords);
+        if points.is_empty() {
+            return None;
+        }
+        match geometry_type {
+            GeometryType::Point => Some(Geometry::Point(points[0])),
+            GeometryType::Multipoint => Some(Geometry::Multipoint(Multipoint { points })),
+            GeometryType::Polyline => Some(Geometry::Polyline(Polyline {
+                paths: split_parts(points, &geometry.lengths),
+            })),
+            GeometryType::Polygon => Some(Geometry::Polygon(Polygon {
+                rings: split_parts(points, &geometry.lengths),
+            })),
+            GeometryType::Envelope => None,
+        }
+    }
+
+    fn vertices(&self, coords: &[i64]) -> Vec<Point> {
+        let dimensions = self.dimensions();
+        let mut running = [0i64; 4];
+        coords
+            .chunks(dimensions)
+            .filter(|vertex| vertex.len() == dimensions)
+            .map(|vertex| {
+                for (total, delta) in running.iter_mut().zip(vertex.iter()) {
+                    *total += delta;
+                }
+                let x = self.translate[0] + running[0] as f64 * self.scale[0];
+                let y = if self.flip_y {
+                    self.translate[1] - running[1] as f64 * self.scale[1]
+                } else {
+                    self.translate[1] + running[1] as f64 * self.scale[1]
+                };
+                let mut point = Point::new(x, y);
+                let mut next = 2;
+                if self.has_z {
+                    point.z = Some(self.translate[2] + running[next] as f64 * self.scale[2]);
+                    next += 1;
+                }
+                if self.has_m {
+                    point.m = Some(self.translate[3] + running[next] as f64 * self.scale[3]);
+                }
+                point
+            })
+            .collect()
+    }
+}
+
+fn split_parts(mut points: Vec<Point>, lengths: &[u32]) -> Vec<Vec<Point>> {
+    if lengths.is_empty() {
+        return vec![points];
+    }
+    let mut parts = Vec::with_capacity(lengths.len());
+    for length in lengths {
+        let rest = points.split_off((*length as usize).min(points.len()));
+        parts.push(points);
+        points = rest;
+    }
+    parts
+}
+
+fn field_from_pbf(field: &PbfField) -> Field {
+    let field_type = match PbfFieldType::from_i32(field.field_type) {
+        Some(PbfFieldType::SmallInteger) => FieldType::SmallInteger,
+        Some(PbfFieldType::Integer) => FieldType::Integer,
+        Some(PbfFieldType::Single) => FieldType::Single,
+        Some(PbfFieldType::Double) => FieldType::Double,
+        Some(PbfFieldType::String) => FieldType::String,
+        Some(PbfFieldType::Date) => FieldType::Date,
+        Some(PbfFieldType::Oid) => FieldType::Oid,
+        Some(PbfFieldType::Geometry) => FieldType::Geometry,
+        Some(PbfFieldType::Blob) => FieldType::Blob,
+        Some(PbfFieldType::Raster) => FieldType::Raster,
+        Some(PbfFieldType::Guid) => FieldType::Guid,
+        Some(PbfFieldType::GlobalId) => FieldType::GlobalId,
+        Some(PbfFieldType::Xml) => FieldType::Xml,
+        None => FieldType::Other(field.field_type.to_string()),
+    };
+    let mut decoded = Field::new(&field.name, field_type);
+    decoded.alias = non_empty(field.alias.clone());
+    decoded
+}
+
+fn value_to_json(value: &Value) -> JsonValue {
+    match &value.value_type {
+        Some(ValueType::String(value)) => value.as_str().into(),
+        Some(ValueType::Float(value)) => (*value as f64).into(),
+        Some(ValueType::Double(value)) => (*value).into(),
+        Some(ValueType::Sint(value)) => (*value).into(),
+        Some(ValueType::Uint(value)) => (*value).into(),
+        Some(ValueType::Int64(value)) => (*value).into(),
+        Some(ValueType::Uint64(value)) => (*value).into(),
+        Some(ValueType::Sint64(value)) => (*value).into(),
+        Some(ValueType::Bool(value)) => (*value).into(),
+        None => JsonValue::Null,
+    }
+}
+
+fn non_empty(value: String) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+fn non_zero(value: u32) -> Option<u32> {
+    if 0 == value {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Protocol buffer wire encoding, written out by hand so the tests don't depend on the
+    // messages above being right.
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn uint(tag: u64, value: u64, out: &mut Vec<u8>) {
+        varint(tag << 3, out);
+        varint(value, out);
+    }
+
+    fn double(tag: u64, value: f64, out: &mut Vec<u8>) {
+        varint(tag << 3 | 1, out);
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(tag: u64, value: &[u8], out: &mut Vec<u8>) {
+        varint(tag << 3 | 2, out);
+        varint(value.len() as u64, out);
+        out.extend_from_slice(value);
+    }
+
+    fn message(tag: u64, build: impl FnOnce(&mut Vec<u8>), out: &mut Vec<u8>) {
+        let mut inner = Vec::new();
+        build(&mut inner);
+        bytes(tag, &inner, out);
+    }
+
+    fn packed_sint(tag: u64, values: &[i64], out: &mut Vec<u8>) {
+        let mut inner = Vec::new();
+        for value in values {
+            varint(((value << 1) ^ (value >> 63)) as u64, &mut inner);
+        }
+        bytes(tag, &inner, out);
+    }
+
+    fn packed_uint(tag: u64, values: &[u64], out: &mut Vec<u8>) {
+        let mut inner = Vec::new();
+        for value in values {
+            varint(*value, &mut inner);
+        }
+        bytes(tag, &inner, out);
+    }
+
+    fn collection(build: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut out = Vec::new();
+        bytes(1, b"3.0", &mut out);
+        message(2, |query_result| message(1, build, query_result), &mut out);
+        out
+    }
+
+    #[test]
+    fn decodes_a_quantized_delta_encoded_polyline() {
+        let bytes = collection(|result| {
+            bytes(1, b"OBJECTID", result);
+            uint(7, 2, result);
+            message(8, |spatial_reference| uint(1, 102100, spatial_reference), result);
+            uint(9, 1, result);
+            message(
+                12,
+                |transform| {
+                    uint(1, 0, transform);
+                    message(2, |scale| {
+                        double(1, 0.5, scale);
+                        double(2, 0.5, scale);
+                    }, transform);
+                    message(3, |translate| {
+                        double(1, 100.0, translate);
+                        double(2, 200.0, translate);
+                    }, transform);
+                },
+                result,
+            );
+            message(13, |field| {
+                bytes(1, b"OBJECTID", field);
+                uint(2, 6, field);
+            }, result);
+            message(13, |field| {
+                bytes(1, b"NAME", field);
+                uint(2, 4, field);
+                bytes(3, b"Street name", field);
+            }, result);
+            message(
+                15,
+                |feature| {
+                    message(1, |value| uint(5, 7, value), feature);
+                    message(1, |value| bytes(1, b"Rua Augusta", value), feature);
+                    message(2, |geometry| {
+                        packed_uint(2, &[2, 1], geometry);
+                        packed_sint(3, &[2, 4, 2, -2, -6, 0], geometry);
+                    }, feature);
+                },
+                result,
+            );
+        });
+
+        let feature_set = decode_feature_set(&bytes).unwrap();
+        assert_eq!(Some("OBJECTID"), feature_set.object_id_field_name.as_deref());
+        assert_eq!(Some(GeometryType::Polyline), feature_set.geometry_type);
+        assert_eq!(Some(102100), feature_set.spatial_reference.unwrap().wkid);
+        assert!(feature_set.exceeded_transfer_limit);
+        assert_eq!(FieldType::Oid, feature_set.fields[0].field_type);
+        assert_eq!(Some("Street name"), feature_set.fields[1].alias.as_deref());
+
+        let feature = &feature_set.features[0];
+        assert_eq!(7, feature.attributes["OBJECTID"]);
+        assert_eq!("Rua Augusta", feature.attributes["NAME"]);
+        // The origin is the upper left, so y counts down from the translation.
+        let expected = Polyline {
+            paths: vec![
+                vec![Point::new(101.0, 198.0), Point::new(102.0, 199.0)],
+                vec![Point::new(99.0, 199.0)],
+            ],
+        };
+        assert_eq!(Some(Geometry::Polyline(expected)), feature.geometry);
+    }
+
+    #[test]
+    fn decodes_z_and_m_from_the_lower_left() {
+        let bytes = collection(|result| {
+            uint(7, 0, result);
+            uint(10, 1, result);
+            uint(11, 1, result);
+            message(
+                12,
+                |transform| {
+                    uint(1, 1, transform);
+                    message(2, |scale| {
+                        double(1, 0.1, scale);
+                        double(2, 0.1, scale);
+                        double(3, 2.0, scale);
+                        double(4, 0.25, scale);
+                    }, transform);
+                    message(3, |translate| {
+                        double(1, -10.0, translate);
+                        double(2, 30.0, translate);
+                        double(3, 1.0, translate);
+                        double(4, 50.0, translate);
+                    }, transform);
+                },
+                result,
+            );
+            message(15, |feature| {
+                message(2, |geometry| packed_sint(3, &[5, 20, 8, 3], geometry), feature);
+            }, result);
+        });
+
+        let feature_set = decode_feature_set(&bytes).unwrap();
+        assert!(feature_set.has_z && feature_set.has_m);
+        let point = match &feature_set.features[0].geometry {
+            Some(Geometry::Point(point)) => *point,
+            other => panic!("expected a point, got {:?}", other),
+        };
+        assert!((point.x - -9.5).abs() < 1e-9);
+        assert!((point.y - 32.0).abs() < 1e-9);
+        // Scale and Translate list m before z, though the coordinates come as x, y, z, m.
+        assert_eq!(Some(52.0), point.z);
+        assert_eq!(Some(7.0), point.m);
+    }
+
+    #[test]
+    fn rejects_a_count_result() {
+        let mut bytes = Vec::new();
+        message(2, |query_result| message(2, |count| uint(1, 42, count), query_result), &mut bytes);
+        assert!(decode_feature_set(&bytes).is_err());
+    }
+}
@@ -0,0 +1,620 @@
+//! An ArcGIS portal's description of itself: the organization, the signed-in user, and the
+//! helper services that the other clients can be pointed at.
+
+use chrono::{DateTime, TimeZone, Utc};
+use json::JsonValue;
+
+use crate::geocode::Geocoder;
+use crate::geometry_service::GeometryService;
+use crate::gp::GpTask;
+use crate::network::NetworkService;
+use crate::request::{Client, RequestOptions};
+use crate::BoxResult;
+
+/// The sharing API of ArcGIS Online.
+pub const ARCGIS_ONLINE_URL: &str = "https://www.arcgis.com/sharing/rest";
+
+/// The user a token belongs to, from `user` in the portal's description.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortalUser {
+    pub username: String,
+    pub full_name: Option<String>,
+    pub email: Option<String>,
+    /// e.g. `org_admin`, `org_publisher` or `org_user`.
+    pub role: Option<String>,
+    /// The custom role's ID, if the user has one.
+    pub role_id: Option<String>,
+    pub privileges: Vec<String>,
+    pub org_id: Option<String>,
+    /// `english` or `metric`, if the user has overridden the organization's units.
+    pub units: Option<String>,
+    pub region: Option<String>,
+    pub culture: Option<String>,
+}
+
+impl PortalUser {
+    pub fn from_json(value: &JsonValue) -> Option<PortalUser> {
+        Some(PortalUser {
+            username: value["username"].as_str()?.to_string(),
+            full_name: value["fullName"].as_str().map(String::from),
+            email: value["email"].as_str().map(String::from),
+            role: value["role"].as_str().map(String::from),
+            role_id: value["roleId"].as_str().map(String::from),
+            privileges: strings(&value["privileges"]),
+            org_id: value["orgId"].as_str().map(String::from),
+            units: value["units"].as_str().map(String::from),
+            region: value["region"].as_str().map(String::from),
+            culture: value["culture"].as_str().map(String::from),
+        })
+    }
+}
+
+/// An ArcGIS Online organization's subscription, from `subscriptionInfo`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubscriptionInfo {
+    pub id: Option<String>,
+    /// e.g. `In House`, `Trial` or `Organization`.
+    pub subscription_type: Option<String>,
+    /// e.g. `active` or `expired`.
+    pub state: Option<String>,
+    pub expiration: Option<DateTime<Utc>>,
+    pub max_users: Option<u32>,
+    pub available_credits: Option<f64>,
+}
+
+impl SubscriptionInfo {
+    pub fn from_json(value: &JsonValue) -> Option<SubscriptionInfo> {
+        if !value.is_object() {
+            return None;
+        }
+        Some(SubscriptionInfo {
+            id: value["id"].as_str().map(String::from),
+            subscription_type: value["type"].as_str().map(String::from),
+            state: value["state"].as_str().map(String::from),
+            expiration: value["expDate"].as_i64().and_then(|millis| Utc.timestamp_millis_opt(millis).single()),
+            max_users: value["maxUsers"].as_u32(),
+            available_credits: value["availableCredits"].as_f64(),
+        })
+    }
+}
+
+/// A layer of a basemap.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BasemapLayer {
+    pub id: Option<String>,
+    pub title: Option<String>,
+    /// e.g. `ArcGISTiledMapServiceLayer` or `VectorTileLayer`.
+    pub layer_type: Option<String>,
+    pub url: Option<String>,
+    /// The style of a vector tile layer.
+    pub style_url: Option<String>,
+    /// Whether the layer is drawn on top of the map, as labels usually are.
+    pub is_reference: bool,
+}
+
+/// A basemap, such as the portal's `defaultBasemap`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Basemap {
+    pub id: Option<String>,
+    pub title: String,
+    pub layers: Vec<BasemapLayer>,
+}
+
+impl Basemap {
+    pub fn from_json(value: &JsonValue) -> Option<Basemap> {
+        if !value.is_object() {
+            return None;
+        }
+        Some(Basemap {
+            id: value["id"].as_str().map(String::from),
+            title: value["title"].as_str().unwrap_or("").to_string(),
+            layers: value["baseMapLayers"]
+                .members()
+                .map(|layer| BasemapLayer {
+                    id: layer["id"].as_str().map(String::from),
+                    title: layer["title"].as_str().map(String::from),
+                    layer_type: layer["layerType"].as_str().map(String::from),
+                    url: layer["url"].as_str().map(String::from),
+                    style_url: layer["styleUrl"].as_str().map(String::from),
+                    is_reference: layer["isReference"].as_bool().unwrap_or(false),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// A helper service the portal is configured to use.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HelperService {
+    pub url: String,
+    /// For routing services, the ID of the travel mode to use unless told otherwise.
+    pub default_travel_mode: Option<String>,
+}
+
+impl HelperService {
+    pub fn from_json(value: &JsonValue) -> Option<HelperService> {
+        Some(HelperService {
+            url: value["url"].as_str()?.trim_end_matches('/').to_string(),
+            default_travel_mode: value["defaultTravelMode"].as_str().map(String::from),
+        })
+    }
+
+    /// A task of the service, for helper services that are geoprocessing services, e.g.
+    /// `Profile` of `elevation` or `Watershed` of `hydrology`.
+    pub fn task(&self, client: &Client, name: &str) -> GpTask {
+        GpTask::new(client, &format!("{}/{}", self.url, name))
+    }
+}
+
+/// A geocoding service the portal is configured to use, from `helperServices.geocode`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeocodeService {
+    pub url: String,
+    pub name: Option<String>,
+    /// Whether the portal uses it for batch geocoding.
+    pub batch: bool,
+    /// Whether the portal uses it to find places and addresses.
+    pub placefinding: bool,
+    /// Whether it supports `suggest`.
+    pub suggest: bool,
+    pub single_line_field_name: Option<String>,
+}
+
+impl GeocodeService {
+    pub fn from_json(value: &JsonValue) -> Option<GeocodeService> {
+        Some(GeocodeService {
+            url: value["url"].as_str()?.trim_end_matches('/').to_string(),
+            name: value["name"].as_str().map(String::from),
+            batch: value["batch"].as_bool().unwrap_or(false),
+            placefinding: value["placefinding"].as_bool().unwrap_or(true),
+            suggest: value["suggest"].as_bool().unwrap_or(false),
+            single_line_field_name: value["singleLineFieldName"].as_str().map(String::from),
+        })
+    }
+}
+
+/// The services a portal is configured to use, from `helperServices`. A service the portal
+/// doesn't have, or that the user isn't licensed for, is `None`.
+#[derive(Clone, Debug)]
+pub struct HelperServices {
+    pub analysis: Option<HelperService>,
+    pub async_closest_facility: Option<HelperService>,
+    pub async_location_allocation: Option<HelperService>,
+    pub async_od_cost_matrix: Option<HelperService>,
+    pub async_route: Option<HelperService>,
+    pub async_service_area: Option<HelperService>,
+    pub async_vrp: Option<HelperService>,
+    pub closest_facility: Option<HelperService>,
+    pub elevation: Option<HelperService>,
+    pub elevation_sync: Option<HelperService>,
+    /// The portal's geocoders, in the order it prefers them.
+    pub geocode: Vec<GeocodeService>,
+    pub geoenrichment: Option<HelperService>,
+    pub geometry: Option<HelperService>,
+    pub hydrology: Option<HelperService>,
+    pub od_cost_matrix: Option<HelperService>,
+    pub orthomosaic: Option<HelperService>,
+    pub packaging: Option<HelperService>,
+    /// The `Export Web Map` task itself, rather than its service.
+    pub print_task: Option<HelperService>,
+    pub route: Option<HelperService>,
+    pub routing_utilities: Option<HelperService>,
+    pub service_area: Option<HelperService>,
+    pub symbols: Option<HelperService>,
+    pub sync_vrp: Option<HelperService>,
+    pub traffic: Option<HelperService>,
+    /// The full `helperServices` object, for services quarenta doesn't model.
+    pub raw: JsonValue,
+}
+
+impl HelperServices {
+    pub fn from_json(value: &JsonValue) -> HelperServices {
+        let service = |name: &str| HelperService::from_json(&value[name]);
+        HelperServices {
+            analysis: service("analysis"),
+            async_closest_facility: service("asyncClosestFacility"),
+            async_location_allocation: service("asyncLocationAllocation"),
+            async_od_cost_matrix: service("asyncODCostMatrix"),
+            async_route: service("asyncRoute"),
+            async_service_area: service("asyncServiceArea"),
+            async_vrp: service("asyncVRP"),
+            closest_facility: service("closestFacility"),
+            elevation: service("elevation"),
+            elevation_sync: service("elevationSync"),
+            geocode: value["geocode"].members().filter_map(GeocodeService::from_json).collect(),
+            geoenrichment: service("geoenrichment"),
+            geometry: service("geometry"),
+            hydrology: service("hydrology"),
+            od_cost_matrix: service("odCostMatrix"),
+            orthomosaic: service("orthomosaic"),
+            packaging: service("packaging"),
+            print_task: service("printTask"),
+            route: service("route"),
+            routing_utilities: service("routingUtilities"),
+            service_area: service("serviceArea"),
+            symbols: service("symbols"),
+            sync_vrp: service("syncVRP"),
+            traffic: service("traffic"),
+            raw: value.clone(),
+        }
+    }
+
+    /// Any helper service by its name in `helperServices`, e.g. `trafficData`.
+    pub fn get(&self, name: &str) -> Option<HelperService> {
+        HelperService::from_json(&self.raw[name])
+    }
+}
+
+/// A portal's description of itself, from `portals/self`.
+#[derive(Clone, Debug)]
+pub struct PortalSelf {
+    /// The organization's ID, which is absent without a token on ArcGIS Online.
+    pub id: Option<String>,
+    pub name: Option<String>,
+    /// The organization's short name, as in `<url_key>.maps.arcgis.com`.
+    pub url_key: Option<String>,
+    pub custom_base_url: Option<String>,
+    pub portal_hostname: Option<String>,
+    /// Whether this is ArcGIS Enterprise rather than ArcGIS Online.
+    pub is_portal: bool,
+    /// The user the token belongs to, if there is one.
+    pub user: Option<PortalUser>,
+    pub subscription_info: Option<SubscriptionInfo>,
+    /// `english` or `metric`.
+    pub units: Option<String>,
+    pub default_basemap: Option<Basemap>,
+    pub default_vector_basemap: Option<Basemap>,
+    pub use_vector_basemaps: bool,
+    /// e.g. `WO` for the world or `US`.
+    pub region: Option<String>,
+    pub culture: Option<String>,
+    pub helper_services: HelperServices,
+    /// The full response, for properties quarenta doesn't model.
+    pub raw: JsonValue,
+}
+
+impl PortalSelf {
+    pub fn from_json(value: JsonValue) -> PortalSelf {
+        PortalSelf {
+            id: value["id"].as_str().map(String::from),
+            name: value["name"].as_str().map(String::from),
+            url_key: value["urlKey"].as_str().map(String::from),
+            custom_base_url: value["customBaseUrl"].as_str().map(String::from),
+            portal_hostname: value["portalHostname"].as_str().map(String::from),
+            is_portal: value["isPortal"].as_bool().unwrap_or(false),
+            user: PortalUser::from_json(&value["user"]),
+            subscription_info: SubscriptionInfo::from_json(&value["subscriptionInfo"]),
+            units: value["units"].as_str().map(String::from),
+            default_basemap: Basemap::from_json(&value["defaultBasemap"]),
+            default_vector_basemap: Basemap::from_json(&value["defaultVectorBasemap"]),
+            use_vector_basemaps: value["useVectorBasemaps"].as_bool().unwrap_or(false),
+            region: value["region"].as_str().map(String::from),
+            culture: value["culture"].as_str().map(String::from),
+            helper_services: HelperServices::from_json(&value["helperServices"]),
+            raw: value,
+        }
+    }
+
+    /// The user's units if they've chosen some, otherwise the organization's.
+    pub fn effective_units(&self) -> Option<&str> {
+        self.user
+            .as_ref()
+            .and_then(|user| user.units.as_deref())
+            .or(self.units.as_deref())
+    }
+
+    /// The portal's preferred geocoder for finding places and addresses.
+    pub fn geocoder(&self, client: &Client) -> Option<Geocoder> {
+        self.helper_services
+            .geocode
+            .iter()
+            .find(|service| service.placefinding)
+            .map(|service| Geocoder::new(client, &service.url))
+    }
+
+    /// The portal's geometry service, or a local one if it hasn't got one.
+    pub fn geometry_service(&self, client: &Client) -> GeometryService {
+        match &self.helper_services.geometry {
+            Some(service) => GeometryService::new(client, &service.url),
+            None => GeometryService::local(client),
+        }
+    }
+
+    /// The portal's routing service.
+    pub fn route_service(&self, client: &Client) -> Option<NetworkService> {
+        network_service(client, self.helper_services.route.as_ref())
+    }
+
+    /// The portal's closest facility service.
+    pub fn closest_facility_service(&self, client: &Client) -> Option<NetworkService> {
+        network_service(client, self.helper_services.closest_facility.as_ref())
+    }
+
+    /// The portal's service area service.
+    pub fn service_area_service(&self, client: &Client) -> Option<NetworkService> {
+        network_service(client, self.helper_services.service_area.as_ref())
+    }
+
+    /// The portal's `Export Web Map` task.
+    pub fn print_task(&self, client: &Client) -> Option<GpTask> {
+        self.helper_services
+            .print_task
+            .as_ref()
+            .map(|service| GpTask::new(client, &service.url))
+    }
+}
+
+/// An ArcGIS Online organization or ArcGIS Enterprise portal, by the URL of its sharing API,
+/// e.g. `https://gis.example.com/portal/sharing/rest`.
+///
+/// ```no_run
+/// # use quarenta::{Client, FindAddressOptions, Portal};
+/// # async fn example(token: &str, referrer: &str) -> Result<(), Box<dyn std::error::Error>> {
+/// let client = Client::new().with_token(token, referrer);
+/// let portal_self = Portal::arcgis_online(&client).portal_self().await?;
+/// if let Some(geocoder) = portal_self.geocoder(&client) {
+///     let candidates = geocoder.find_address_candidates(&FindAddressOptions::new("Lisbon")).await?;
+///     println!("{} candidates from {}", candidates.len(), geocoder.url());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Portal {
+    client: Client,
+    url: String,
+}
+
+impl Portal {
+    pub fn new(client: &Client, url: &str) -> Portal {
+        Portal {
+            client: client.clone(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// ArcGIS Online. With a token, `portal_self` describes the user's organization.
+    pub fn arcgis_online(client: &Client) -> Portal {
+        Portal::new(client, ARCGIS_ONLINE_URL)
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Signs in to the portal. The response has `token` and `expires`, or an `error`; see
+    /// [`crate::login`].
+    pub async fn generate_token(&self, username: &str, password: &str, referrer: &str) -> BoxResult<JsonValue> {
+        let request = self
+            .client
+            .http()
+            .post(&format!("{}/generateToken", self.url))
+            .form(&[
+                ("username", username),
+                ("password", password),
+                ("referer", referrer),
+                ("f", "json"),
+            ]);
+        self.client.send_json(request, &RequestOptions::default()).await
+    }
+
+    /// Fetches the portal's description of itself, as seen by the client's token.
+    pub async fn portal_self(&self) -> BoxResult<PortalSelf> {
+        let value = self
+            .client
+            .get_json(&format!("{}/portals/self", self.url), &[], &RequestOptions::default())
+            .await?;
+        Ok(PortalSelf::from_json(value))
+    }
+}
+
+fn network_service(client: &Client, service: Option<&HelperService>) -> Option<NetworkService> {
+    service.map(|service| NetworkService::new(client, &service.url))
+}
+
+fn strings(value: &JsonValue) -> Vec<String> {
+    value.members().filter_map(|item| item.as_str()).map(String::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An abridged `portals/self` response from an ArcGIS Online organization.
+    const PORTAL_SELF: &str = r#"{
+        "id": "0123456789ABCDEF",
+        "name": "City of Example",
+        "urlKey": "example",
+        "customBaseUrl": "maps.arcgis.com",
+        "portalHostname": "www.arcgis.com",
+        "isPortal": false,
+        "units": "english",
+        "region": "US",
+        "culture": "en",
+        "useVectorBasemaps": true,
+        "subscriptionInfo": {
+            "id": "1234567",
+            "type": "In House",
+            "state": "active",
+            "expDate": 1893456000000,
+            "maxUsers": 50,
+            "availableCredits": 1234.5
+        },
+        "defaultBasemap": {
+            "id": "basemap",
+            "title": "Topographic",
+            "baseMapLayers": [
+                {
+                    "id": "World_Topo_Map",
+                    "layerType": "ArcGISTiledMapServiceLayer",
+                    "url": "https://services.arcgisonline.com/ArcGIS/rest/services/World_Topo_Map/MapServer"
+                }
+            ]
+        },
+        "defaultVectorBasemap": {
+            "title": "Topographic",
+            "baseMapLayers": [
+                {
+                    "id": "topo-base",
+                    "title": "World Topographic Map",
+                    "layerType": "VectorTileLayer",
+                    "styleUrl": "https://cdn.arcgis.com/sharing/rest/content/items/7dc6cea0b1764a1f9af2e679f642f0f5/resources/styles/root.json"
+                },
+                {
+                    "id": "hillshade",
+                    "layerType": "ArcGISTiledMapServiceLayer",
+                    "url": "https://services.arcgisonline.com/arcgis/rest/services/Elevation/World_Hillshade/MapServer",
+                    "isReference": true
+                }
+            ]
+        },
+        "user": {
+            "username": "jdoe_example",
+            "fullName": "Jo Doe",
+            "email": "jdoe@example.com",
+            "role": "org_publisher",
+            "privileges": ["features:user:edit", "portal:user:createItem"],
+            "orgId": "0123456789ABCDEF",
+            "units": "metric"
+        },
+        "helperServices": {
+            "analysis": { "url": "https://analysis7.arcgis.com/arcgis/rest/services/tasks/GPServer" },
+            "geometry": { "url": "https://utility.arcgisonline.com/arcgis/rest/services/Geometry/GeometryServer/" },
+            "route": {
+                "url": "https://route-api.arcgis.com/arcgis/rest/services/World/Route/NAServer/Route_World",
+                "defaultTravelMode": "FEgifRtFndKNcJMJ"
+            },
+            "printTask": { "url": "https://utility.arcgisonline.com/arcgis/rest/services/Utilities/PrintingTools/GPServer/Export%20Web%20Map%20Task" },
+            "elevation": { "url": "https://elevation.arcgis.com/arcgis/rest/services/Tools/Elevation/GPServer" },
+            "trafficData": { "url": "https://traffic.arcgis.com/arcgis/rest/services/World/Traffic/MapServer" },
+            "geocode": [
+                {
+                    "url": "https://geocode.arcgis.com/arcgis/rest/services/World/GeocodeServer",
+                    "northLat": "Ymax",
+                    "southLat": "Ymin",
+                    "name": "ArcGIS World Geocoding Service",
+                    "suggest": true,
+                    "placefinding": true,
+                    "batch": false
+                },
+                {
+                    "url": "https://gis.example.com/arcgis/rest/services/Parcels/GeocodeServer",
+                    "name": "Parcels",
+                    "placefinding": false,
+                    "batch": true,
+                    "singleLineFieldName": "SingleLine"
+                }
+            ]
+        }
+    }"#;
+
+    fn portal_self() -> PortalSelf {
+        PortalSelf::from_json(json::parse(PORTAL_SELF).unwrap())
+    }
+
+    #[test]
+    fn parses_the_organization() {
+        let portal_self = portal_self();
+        assert_eq!(portal_self.id.as_deref(), Some("0123456789ABCDEF"));
+        assert_eq!(portal_self.name.as_deref(), Some("City of Example"));
+        assert_eq!(portal_self.url_key.as_deref(), Some("example"));
+        assert!(!portal_self.is_portal);
+        assert!(portal_self.use_vector_basemaps);
+        assert_eq!(portal_self.region.as_deref(), Some("US"));
+        // The user's units win over the organization's.
+        assert_eq!(portal_self.units.as_deref(), Some("english"));
+        assert_eq!(portal_self.effective_units(), Some("metric"));
+    }
+
+    #[test]
+    fn parses_the_user() {
+        let user = portal_self().user.unwrap();
+        assert_eq!(user.username, "jdoe_example");
+        assert_eq!(user.full_name.as_deref(), Some("Jo Doe"));
+        assert_eq!(user.role.as_deref(), Some("org_publisher"));
+        assert_eq!(user.role_id, None);
+        assert_eq!(user.privileges, vec!["features:user:edit", "portal:user:createItem"]);
+        assert_eq!(PortalUser::from_json(&json::object! { "fullName" => "Anonymous" }), None);
+    }
+
+    #[test]
+    fn parses_the_subscription() {
+        let subscription = portal_self().subscription_info.unwrap();
+        assert_eq!(subscription.subscription_type.as_deref(), Some("In House"));
+        assert_eq!(subscription.state.as_deref(), Some("active"));
+        assert_eq!(subscription.expiration, Utc.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).single());
+        assert_eq!(subscription.max_users, Some(50));
+        assert_eq!(subscription.available_credits, Some(1234.5));
+        assert_eq!(SubscriptionInfo::from_json(&JsonValue::Null), None);
+    }
+
+    #[test]
+    fn parses_basemaps() {
+        let portal_self = portal_self();
+        let basemap = portal_self.default_basemap.unwrap();
+        assert_eq!(basemap.id.as_deref(), Some("basemap"));
+        assert_eq!(basemap.title, "Topographic");
+        assert_eq!(basemap.layers.len(), 1);
+        assert_eq!(basemap.layers[0].layer_type.as_deref(), Some("ArcGISTiledMapServiceLayer"));
+
+        let vector = portal_self.default_vector_basemap.unwrap();
+        assert_eq!(vector.id, None);
+        assert_eq!(vector.layers[0].layer_type.as_deref(), Some("VectorTileLayer"));
+        assert!(vector.layers[0].style_url.as_deref().unwrap().ends_with("/root.json"));
+        assert_eq!(vector.layers[0].url, None);
+        assert!(!vector.layers[0].is_reference);
+        assert!(vector.layers[1].is_reference);
+        assert_eq!(Basemap::from_json(&JsonValue::Null), None);
+    }
+
+    #[test]
+    fn parses_helper_services() {
+        let portal_self = portal_self();
+        let helper_services = &portal_self.helper_services;
+        assert_eq!(
+            helper_services.geometry.as_ref().map(|service| service.url.as_str()),
+            Some("https://utility.arcgisonline.com/arcgis/rest/services/Geometry/GeometryServer")
+        );
+        assert_eq!(
+            helper_services.route.as_ref().and_then(|service| service.default_travel_mode.as_deref()),
+            Some("FEgifRtFndKNcJMJ")
+        );
+        assert!(helper_services.analysis.is_some());
+        assert!(helper_services.closest_facility.is_none());
+        assert!(helper_services.hydrology.is_none());
+        assert!(helper_services.get("trafficData").unwrap().url.ends_with("/Traffic/MapServer"));
+        assert_eq!(helper_services.get("nothing"), None);
+
+        assert_eq!(helper_services.geocode.len(), 2);
+        let world = &helper_services.geocode[0];
+        assert!(world.suggest && world.placefinding && !world.batch);
+        let parcels = &helper_services.geocode[1];
+        assert_eq!(parcels.name.as_deref(), Some("Parcels"));
+        assert!(!parcels.placefinding && parcels.batch);
+        assert_eq!(parcels.single_line_field_name.as_deref(), Some("SingleLine"));
+    }
+
+    #[test]
+    fn points_clients_at_helper_services() {
+        let client = Client::new();
+        let portal_self = portal_self();
+        assert_eq!(
+            portal_self.geocoder(&client).unwrap().url(),
+            "https://geocode.arcgis.com/arcgis/rest/services/World/GeocodeServer"
+        );
+        assert!(portal_self.geometry_service(&client).url().is_some());
+        assert!(portal_self.route_service(&client).is_some());
+        assert!(portal_self.service_area_service(&client).is_none());
+        assert!(portal_self.print_task(&client).is_some());
+
+        let bare = PortalSelf::from_json(json::object! {});
+        assert!(bare.geocoder(&client).is_none());
+        assert!(bare.geometry_service(&client).url().is_none());
+        assert!(bare.user.is_none());
+        assert_eq!(bare.effective_units(), None);
+    }
+}
@@ -0,0 +1,285 @@
+//! Projecting geometries between spatial references locally, without a geometry service.
+//!
+//! WGS 1984 and Web Mercator are converted with their exact formulas. Other spatial
+//! references are looked up by EPSG well-known ID and projected with `proj4rs`, a pure-Rust
+//! port of PROJ.4; Esri-only WKIDs (such as the `102xxx` state planes) and spatial references
+//! given as WKT aren't supported.
+
+use std::convert::TryFrom;
+use std::f64::consts::PI;
+
+use proj4rs::adaptors::transform_xy;
+use proj4rs::Proj;
+
+use crate::geometry::{Envelope, Geometry, Multipoint, Point, Polygon, Polyline, SpatialReference};
+use crate::BoxResult;
+
+/// The semimajor axis of the WGS 1984 ellipsoid, which Web Mercator uses as a sphere.
+const EARTH_RADIUS: f64 = 6_378_137.0;
+
+/// The latitude where Web Mercator's square world ends.
+const MAX_MERCATOR_LATITUDE: f64 = 85.051_128_779_806_59;
+
+/// The number of pieces each side of an envelope is cut into to find its projected extent.
+const ENVELOPE_SIDE_STEPS: usize = 16;
+
+/// WKIDs for Web Mercator, old and new.
+pub(crate) const WEB_MERCATOR_WKIDS: &[u32] = &[3857, 102100, 102113, 900913];
+
+/// A coordinate system a [`Projection`] converts between.
+#[derive(Debug)]
+enum Crs {
+    Wgs84,
+    WebMercator,
+    /// A coordinate system from `proj4rs`, with WGS 1984 to convert to and from.
+    Proj { proj: Box<Proj>, wgs84: Box<Proj> },
+}
+
+impl Crs {
+    fn from_spatial_reference(spatial_reference: &SpatialReference) -> BoxResult<Crs> {
+        let wkid = match spatial_reference.effective_wkid() {
+            Some(wkid) => wkid,
+            None => return Err("can't project locally without a WKID".into()),
+        };
+        if 4326 == wkid {
+            return Ok(Crs::Wgs84);
+        }
+        if WEB_MERCATOR_WKIDS.contains(&wkid) {
+            return Ok(Crs::WebMercator);
+        }
+        let code = match u16::try_from(wkid) {
+            Ok(code) if wkid < 100_000 => code,
+            _ => return Err(format!("can't project {} locally; only EPSG WKIDs are supported", wkid).into()),
+        };
+        let proj = Proj::from_epsg_code(code).map_err(|err| format!("can't project {} locally: {}", wkid, err))?;
+        let wgs84 = Proj::from_proj_string("+proj=longlat +ellps=WGS84 +datum=WGS84 +no_defs")?;
+        Ok(Crs::Proj {
+            proj: Box::new(proj),
+            wgs84: Box::new(wgs84),
+        })
+    }
+
+    /// Converts a point in this coordinate system to longitude and latitude in degrees.
+    fn inverse(&self, point: &Point) -> BoxResult<Point> {
+        match self {
+            Crs::Wgs84 => Ok(*point),
+            Crs::WebMercator => Ok(Point {
+                x: (point.x / EARTH_RADIUS).to_degrees(),
+                y: (2.0 * (point.y / EARTH_RADIUS).exp().atan() - PI / 2.0).to_degrees(),
+                ..*point
+            }),
+            Crs::Proj { proj, wgs84 } => {
+                // proj4rs takes and returns angles in radians.
+                let (x, y) = if proj.is_latlong() {
+                    (point.x.to_radians(), point.y.to_radians())
+                } else {
+                    (point.x, point.y)
+                };
+                let (x, y) = transform_xy(proj, wgs84, x, y)?;
+                Ok(Point {
+                    x: x.to_degrees(),
+                    y: y.to_degrees(),
+                    ..*point
+                })
+            }
+        }
+    }
+
+    /// Converts longitude and latitude in degrees to a point in this coordinate system.
+    fn forward(&self, point: &Point) -> BoxResult<Point> {
+        match self {
+            Crs::Wgs84 => Ok(*point),
+            Crs::WebMercator => {
+                let latitude = point.y.clamp(-MAX_MERCATOR_LATITUDE, MAX_MERCATOR_LATITUDE).to_radians();
+                Ok(Point {
+                    x: EARTH_RADIUS * point.x.to_radians(),
+                    y: EARTH_RADIUS * (PI / 4.0 + latitude / 2.0).tan().ln(),
+                    ..*point
+                })
+            }
+            Crs::Proj { proj, wgs84 } => {
+                let (x, y) = transform_xy(wgs84, proj, point.x.to_radians(), point.y.to_radians())?;
+                if proj.is_latlong() {
+                    Ok(Point {
+                        x: x.to_degrees(),
+                        y: y.to_degrees(),
+                        ..*point
+                    })
+                } else {
+                    Ok(Point { x, y, ..*point })
+                }
+            }
+        }
+    }
+}
+
+/// Converts coordinates from one spatial reference to another.
+///
+/// ```no_run
+/// # use quarenta::{Point, Projection, SpatialReference};
+/// # fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let projection = Projection::new(&SpatialReference::wgs84(), &SpatialReference::from_wkid(3857))?;
+/// let lisbon = projection.point(&Point::new(-9.14, 38.74))?;
+/// println!("{}, {}", lisbon.x, lisbon.y);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Projection {
+    from: Crs,
+    to: Crs,
+}
+
+impl Projection {
+    /// A projection between two spatial references, or an error if either can't be projected
+    /// locally.
+    pub fn new(from: &SpatialReference, to: &SpatialReference) -> BoxResult<Projection> {
+        Ok(Projection {
+            from: Crs::from_spatial_reference(from)?,
+            to: Crs::from_spatial_reference(to)?,
+        })
+    }
+
+    /// Projects a point, keeping its Z and M values as they are.
+    pub fn point(&self, point: &Point) -> BoxResult<Point> {
+        match (&self.from, &self.to) {
+            (Crs::Wgs84, Crs::Wgs84) | (Crs::WebMercator, Crs::WebMercator) => Ok(*point),
+            (from, to) => to.forward(&from.inverse(point)?),
+        }
+    }
+
+    /// Projects every vertex of a geometry. An envelope becomes the extent of its projected
+    /// outline, which may be larger than its projected corners.
+    pub fn geometry(&self, geometry: &Geometry) -> BoxResult<Geometry> {
+        Ok(match geometry {
+            Geometry::Point(point) => Geometry::Point(self.point(point)?),
+            Geometry::Multipoint(multipoint) => Geometry::Multipoint(Multipoint {
+                points: self.points(&multipoint.points)?,
+            }),
+            Geometry::Polyline(polyline) => Geometry::Polyline(Polyline {
+                paths: self.parts(&polyline.paths)?,
+            }),
+            Geometry::Polygon(polygon) => Geometry::Polygon(Polygon {
+                rings: self.parts(&polygon.rings)?,
+            }),
+            Geometry::Envelope(envelope) => Geometry::Envelope(self.envelope(envelope)?),
+        })
+    }
+
+    /// Projects an envelope to the extent of its projected outline.
+    pub fn envelope(&self, envelope: &Envelope) -> BoxResult<Envelope> {
+        let corners = [
+            (envelope.xmin, envelope.ymin),
+            (envelope.xmin, envelope.ymax),
+            (envelope.xmax, envelope.ymax),
+            (envelope.xmax, envelope.ymin),
+            (envelope.xmin, envelope.ymin),
+        ];
+        let mut outline = Vec::with_capacity(4 * ENVELOPE_SIDE_STEPS);
+        for side in corners.windows(2) {
+            let ((x0, y0), (x1, y1)) = (side[0], side[1]);
+            for step in 0..ENVELOPE_SIDE_STEPS {
+                let t = step as f64 / ENVELOPE_SIDE_STEPS as f64;
+                outline.push(self.point(&Point::new(x0 + (x1 - x0) * t, y0 + (y1 - y0) * t))?);
+            }
+        }
+        let mut extent = Envelope {
+            xmin: f64::INFINITY,
+            ymin: f64::INFINITY,
+            xmax: f64::NEG_INFINITY,
+            ymax: f64::NEG_INFINITY,
+        };
+        for point in outline {
+            extent.xmin = extent.xmin.min(point.x);
+            extent.ymin = extent.ymin.min(point.y);
+            extent.xmax = extent.xmax.max(point.x);
+            extent.ymax = extent.ymax.max(point.y);
+        }
+        Ok(extent)
+    }
+
+    fn points(&self, points: &[Point]) -> BoxResult<Vec<Point>> {
+        points.iter().map(|point| self.point(point)).collect()
+    }
+
+    pub(crate) fn parts(&self, parts: &[Vec<Point>]) -> BoxResult<Vec<Vec<Point>>> {
+        parts.iter().map(|part| self.points(part)).collect()
+    }
+}
+
+impl Point {
+    /// Projects the point from one spatial reference to another. Use a [`Projection`] to
+    /// project many points between the same two.
+    pub fn project(&self, from: &SpatialReference, to: &SpatialReference) -> BoxResult<Point> {
+        Projection::new(from, to)?.point(self)
+    }
+}
+
+impl Geometry {
+    /// Projects the geometry from one spatial reference to another, locally.
+    pub fn project(&self, from: &SpatialReference, to: &SpatialReference) -> BoxResult<Geometry> {
+        Projection::new(from, to)?.geometry(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(point: Point, from: u32, to: u32) -> Point {
+        point
+            .project(&SpatialReference::from_wkid(from), &SpatialReference::from_wkid(to))
+            .unwrap()
+    }
+
+    fn assert_near(actual: Point, expected: Point, tolerance: f64) {
+        assert!(
+            (actual.x - expected.x).abs() < tolerance && (actual.y - expected.y).abs() < tolerance,
+            "{:?} is not within {} of {:?}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn wgs84_to_web_mercator_and_back() {
+        let mercator = project(Point::new(-9.14, 38.74), 4326, 3857);
+        assert_near(mercator, Point::new(-1_017_460.15, 4_684_496.94), 0.01);
+        assert_near(project(mercator, 3857, 4326), Point::new(-9.14, 38.74), 1e-9);
+        assert_near(project(Point::new(0.0, 0.0), 4326, 102100), Point::new(0.0, 0.0), 1e-9);
+    }
+
+    #[test]
+    fn projected_epsg_round_trip() {
+        // UTM zone 29N, whose central meridian is 9 degrees west.
+        let utm = project(Point::new(-9.0, 0.0), 4326, 32629);
+        assert_near(utm, Point::new(500_000.0, 0.0), 0.01);
+        let lisbon = project(Point::new(-9.14, 38.74), 4326, 32629);
+        assert!(480_000.0 < lisbon.x && lisbon.x < 500_000.0, "{:?}", lisbon);
+        assert_near(project(lisbon, 32629, 4326), Point::new(-9.14, 38.74), 1e-8);
+    }
+
+    #[test]
+    fn geographic_epsg_round_trip() {
+        // NAD83 and ETRS89 are within a meter or two of WGS 1984, so coordinates barely move.
+        for wkid in [4269, 4258] {
+            let point = project(Point::new(-117.19, 34.06), 4326, wkid);
+            assert_near(point, Point::new(-117.19, 34.06), 1e-4);
+            assert_near(project(point, wkid, 4326), Point::new(-117.19, 34.06), 1e-9);
+        }
+        let mercator = project(Point::new(2.35, 48.86), 4258, 3857);
+        assert_near(mercator, project(Point::new(2.35, 48.86), 4326, 3857), 1.0);
+    }
+
+    #[test]
+    fn keeps_z_and_m() {
+        let point = Point {
+            z: Some(12.5),
+            m: Some(3.0),
+            ..Point::new(10.0, 20.0)
+        };
+        let projected = project(point, 4326, 3857);
+        assert_eq!((Some(12.5), Some(3.0)), (projected.z, projected.m));
+    }
+}
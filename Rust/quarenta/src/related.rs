@@ -0,0 +1,164 @@
+//! Relationship classes and `queryRelatedRecords`.
+
+use std::collections::HashMap;
+
+use json::JsonValue;
+
+use crate::feature::FeatureSet;
+use crate::feature_layer::{FeatureLayer, LayerInfo};
+use crate::geometry::SpatialReference;
+use crate::request::RequestOptions;
+use crate::BoxResult;
+
+/// A relationship between a layer and another layer or table, from the layer's description.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Relationship {
+    pub id: u32,
+    pub name: String,
+    /// The layer or table ID at the other end of the relationship.
+    pub related_table_id: u32,
+    /// e.g. `esriRelCardinalityOneToMany`.
+    pub cardinality: String,
+    /// `esriRelRoleOrigin` or `esriRelRoleDestination`.
+    pub role: String,
+    pub key_field: Option<String>,
+    pub composite: bool,
+}
+
+impl Relationship {
+    pub fn from_json(value: &JsonValue) -> Option<Relationship> {
+        Some(Relationship {
+            id: value["id"].as_u32()?,
+            name: value["name"].as_str().unwrap_or("").to_string(),
+            related_table_id: value["relatedTableId"].as_u32()?,
+            cardinality: value["cardinality"].as_str().unwrap_or("").to_string(),
+            role: value["role"].as_str().unwrap_or("").to_string(),
+            key_field: value["keyField"].as_str().map(String::from),
+            composite: value["composite"].as_bool().unwrap_or(false),
+        })
+    }
+}
+
+impl LayerInfo {
+    /// The relationships this layer takes part in.
+    pub fn relationships(&self) -> Vec<Relationship> {
+        self.raw["relationships"]
+            .members()
+            .filter_map(Relationship::from_json)
+            .collect()
+    }
+
+    /// Finds a relationship by name, ignoring case.
+    pub fn relationship(&self, name: &str) -> Option<Relationship> {
+        self.relationships()
+            .into_iter()
+            .find(|relationship| relationship.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// The parameters of a related records query.
+#[derive(Clone, Debug)]
+pub struct RelatedRecordsQuery {
+    relationship_id: u32,
+    object_ids: Vec<u64>,
+    definition_expression: Option<String>,
+    out_fields: Vec<String>,
+    return_geometry: Option<bool>,
+    out_sr: Option<SpatialReference>,
+}
+
+impl RelatedRecordsQuery {
+    pub fn new(relationship_id: u32) -> RelatedRecordsQuery {
+        RelatedRecordsQuery {
+            relationship_id,
+            object_ids: Vec::new(),
+            definition_expression: None,
+            out_fields: Vec::new(),
+            return_geometry: None,
+            out_sr: None,
+        }
+    }
+
+    /// The object IDs of the features whose related records to return.
+    pub fn object_ids(mut self, object_ids: &[u64]) -> RelatedRecordsQuery {
+        self.object_ids = object_ids.to_vec();
+        self
+    }
+
+    /// A where clause applied to the related records.
+    pub fn definition_expression(mut self, definition_expression: &str) -> RelatedRecordsQuery {
+        self.definition_expression = Some(definition_expression.to_string());
+        self
+    }
+
+    /// The fields of the related records to return. All fields are returned if this isn't
+    /// called.
+    pub fn out_fields(mut self, out_fields: &[&str]) -> RelatedRecordsQuery {
+        self.out_fields = out_fields.iter().map(|field| field.to_string()).collect();
+        self
+    }
+
+    pub fn return_geometry(mut self, return_geometry: bool) -> RelatedRecordsQuery {
+        self.return_geometry = Some(return_geometry);
+        self
+    }
+
+    pub fn out_sr(mut self, out_sr: SpatialReference) -> RelatedRecordsQuery {
+        self.out_sr = Some(out_sr);
+        self
+    }
+
+    fn to_params(&self) -> Vec<(&'static str, String)> {
+        let object_ids: Vec<String> = self.object_ids.iter().map(ToString::to_string).collect();
+        let mut params = vec![
+            ("relationshipId", self.relationship_id.to_string()),
+            ("objectIds", object_ids.join(",")),
+            (
+                "outFields",
+                if self.out_fields.is_empty() { String::from("*") } else { self.out_fields.join(",") },
+            ),
+        ];
+        if let Some(definition_expression) = &self.definition_expression {
+            params.push(("definitionExpression", definition_expression.clone()));
+        }
+        if let Some(return_geometry) = self.return_geometry {
+            params.push(("returnGeometry", return_geometry.to_string()));
+        }
+        if let Some(out_sr) = &self.out_sr {
+            params.push(("outSR", out_sr.to_param()));
+        }
+        params
+    }
+}
+
+impl FeatureLayer {
+    /// Queries the records related to the given features and groups them by the object ID of
+    /// the feature they're related to. Features with no related records are left out.
+    pub async fn query_related_records(
+        &self,
+        query: &RelatedRecordsQuery,
+    ) -> BoxResult<HashMap<u64, FeatureSet>> {
+        let response = self
+            .client()
+            .post_json(
+                &format!("{}/queryRelatedRecords", self.url()),
+                &query.to_params(),
+                &RequestOptions::default(),
+            )
+            .await?;
+        // Every group shares the response's fields, geometry type and spatial reference.
+        let template = FeatureSet::from_json(&response);
+        let mut groups = HashMap::new();
+        for group in response["relatedRecordGroups"].members() {
+            if let Some(object_id) = group["objectId"].as_u64() {
+                let mut feature_set = template.clone();
+                feature_set.features = group["relatedRecords"]
+                    .members()
+                    .map(|record| template.feature_from_json(record))
+                    .collect();
+                groups.insert(object_id, feature_set);
+            }
+        }
+        Ok(groups)
+    }
+}
@@ -0,0 +1,498 @@
+//! Sync-enabled replicas: `createReplica`, `synchronizeReplica` and `unregisterReplica`.
+//!
+//! A replica is a server-side record of what a client has downloaded. The server tracks changes
+//! with generation numbers: each sync sends the generation the client last saw and gets back
+//! only what changed since, along with a new generation to send next time.
+
+use std::collections::BTreeMap;
+
+use json::JsonValue;
+
+use crate::edit::{edit_results, EditResult};
+use crate::feature::Feature;
+use crate::feature_service::FeatureService;
+use crate::geometry::{Geometry, SpatialReference};
+use crate::request::{ArcGisError, RequestOptions};
+use crate::BoxResult;
+
+/// How the server tracks generations for a replica.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncModel {
+    /// One generation for the whole replica; every layer syncs together.
+    #[default]
+    PerReplica,
+    /// A generation per layer, so layers can sync separately.
+    PerLayer,
+}
+
+impl SyncModel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SyncModel::PerReplica => "perReplica",
+            SyncModel::PerLayer => "perLayer",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SyncModel> {
+        match name {
+            "perReplica" => Some(SyncModel::PerReplica),
+            "perLayer" => Some(SyncModel::PerLayer),
+            _ => None,
+        }
+    }
+}
+
+/// Which way edits flow in a sync.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncDirection {
+    /// Only fetch the server's changes.
+    Download,
+    /// Only send local edits.
+    Upload,
+    #[default]
+    Bidirectional,
+}
+
+impl SyncDirection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SyncDirection::Download => "download",
+            SyncDirection::Upload => "upload",
+            SyncDirection::Bidirectional => "bidirectional",
+        }
+    }
+}
+
+/// The parameters for creating a replica.
+#[derive(Clone, Debug)]
+pub struct ReplicaOptions {
+    name: String,
+    layers: Vec<u32>,
+    geometry: Option<(Geometry, SpatialReference)>,
+    layer_queries: BTreeMap<u32, String>,
+    sync_model: SyncModel,
+    pub(crate) out_sr: Option<SpatialReference>,
+}
+
+impl ReplicaOptions {
+    /// Options for a replica of the given layers, with all of their features.
+    pub fn new(name: &str, layers: &[u32]) -> ReplicaOptions {
+        ReplicaOptions {
+            name: name.to_string(),
+            layers: layers.to_vec(),
+            geometry: None,
+            layer_queries: BTreeMap::new(),
+            sync_model: SyncModel::default(),
+            out_sr: None,
+        }
+    }
+
+    /// Only replicates features that intersect `geometry`, which is in `in_sr`.
+    pub fn geometry(mut self, geometry: Geometry, in_sr: SpatialReference) -> ReplicaOptions {
+        self.geometry = Some((geometry, in_sr));
+        self
+    }
+
+    /// Only replicates a layer's features that match a where clause.
+    pub fn layer_query(mut self, layer: u32, where_clause: &str) -> ReplicaOptions {
+        self.layer_queries.insert(layer, where_clause.to_string());
+        self
+    }
+
+    pub fn sync_model(mut self, sync_model: SyncModel) -> ReplicaOptions {
+        self.sync_model = sync_model;
+        self
+    }
+
+    /// The spatial reference of the replica's geometries.
+    pub fn out_sr(mut self, out_sr: SpatialReference) -> ReplicaOptions {
+        self.out_sr = Some(out_sr);
+        self
+    }
+
+    fn to_params(&self) -> Vec<(&'static str, String)> {
+        let layers: Vec<String> = self.layers.iter().map(ToString::to_string).collect();
+        let mut params = vec![
+            ("replicaName", self.name.clone()),
+            ("layers", layers.join(",")),
+            ("syncModel", self.sync_model.as_str().to_string()),
+            ("dataFormat", String::from("json")),
+            ("transportType", String::from("esriTransportTypeEmbedded")),
+            ("returnAttachments", String::from("false")),
+            ("async", String::from("false")),
+        ];
+        if let Some((geometry, in_sr)) = &self.geometry {
+            params.push(("geometry", geometry.to_json().dump()));
+            params.push(("geometryType", geometry.geometry_type().as_str().to_string()));
+            params.push(("inSR", in_sr.to_param()));
+        }
+        if !self.layer_queries.is_empty() {
+            let mut layer_queries = JsonValue::new_object();
+            for (layer, where_clause) in &self.layer_queries {
+                layer_queries[layer.to_string()] = json::object! {
+                    "where" => where_clause.as_str(),
+                    "useGeometry" => self.geometry.is_some(),
+                    "queryOption" => "useFilter"
+                };
+            }
+            params.push(("layerQueries", layer_queries.dump()));
+        }
+        if let Some(out_sr) = &self.out_sr {
+            params.push(("replicaSR", out_sr.to_param()));
+        }
+        params
+    }
+}
+
+/// A replica registered with a feature service, and the generations it has synced up to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replica {
+    pub id: String,
+    pub name: String,
+    pub sync_model: SyncModel,
+    pub layers: Vec<u32>,
+    /// The replica's generation, for `SyncModel::PerReplica`.
+    pub server_gen: Option<i64>,
+    /// Each layer's generation, for `SyncModel::PerLayer`.
+    pub layer_server_gens: BTreeMap<u32, i64>,
+}
+
+impl Replica {
+    pub fn from_json(value: &JsonValue) -> Option<Replica> {
+        Some(Replica {
+            id: value["replicaID"].as_str()?.to_string(),
+            name: value["replicaName"].as_str().unwrap_or("").to_string(),
+            sync_model: value["syncModel"]
+                .as_str()
+                .and_then(SyncModel::from_name)
+                .unwrap_or_default(),
+            layers: value["layers"].members().filter_map(JsonValue::as_u32).collect(),
+            server_gen: value["replicaServerGen"].as_i64(),
+            layer_server_gens: layer_server_gens(&value["layerServerGens"]),
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut value = json::object! {
+            "replicaID" => self.id.as_str(),
+            "replicaName" => self.name.as_str(),
+            "syncModel" => self.sync_model.as_str(),
+            "layers" => self.layers.clone()
+        };
+        if let Some(server_gen) = self.server_gen {
+            value["replicaServerGen"] = server_gen.into();
+        }
+        if !self.layer_server_gens.is_empty() {
+            value["layerServerGens"] = JsonValue::Array(
+                self.layer_server_gens
+                    .iter()
+                    .map(|(id, server_gen)| json::object! { "id" => *id, "serverGen" => *server_gen })
+                    .collect(),
+            );
+        }
+        value
+    }
+
+    /// Takes the generations from a sync response, keeping the old ones for anything it
+    /// doesn't mention.
+    fn advance(&mut self, response: &JsonValue) {
+        if let Some(server_gen) = response["replicaServerGen"].as_i64() {
+            self.server_gen = Some(server_gen);
+        }
+        self.layer_server_gens.extend(layer_server_gens(&response["layerServerGens"]));
+    }
+}
+
+pub(crate) fn layer_server_gens(value: &JsonValue) -> BTreeMap<u32, i64> {
+    value
+        .members()
+        .filter_map(|layer| Some((layer["id"].as_u32()?, layer["serverGen"].as_i64()?)))
+        .collect()
+}
+
+/// The changes to one layer, as downloaded from the server. Like [`Feature`], the adds and
+/// updates can be typed; see [`LayerChanges::into_typed`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerChanges<A = JsonValue, G = Geometry> {
+    pub layer_id: u32,
+    pub adds: Vec<Feature<A, G>>,
+    pub updates: Vec<Feature<A, G>>,
+    /// Deleted features identified by object ID.
+    pub delete_ids: Vec<u64>,
+    /// Deleted features identified by global ID, as replicas report them.
+    pub delete_global_ids: Vec<String>,
+}
+
+impl<A, G> Default for LayerChanges<A, G> {
+    fn default() -> Self {
+        LayerChanges {
+            layer_id: 0,
+            adds: Vec::new(),
+            updates: Vec::new(),
+            delete_ids: Vec::new(),
+            delete_global_ids: Vec::new(),
+        }
+    }
+}
+
+impl LayerChanges {
+    /// Parses a layer's element of `edits`, in which the changes are under `features`.
+    pub fn from_json(value: &JsonValue) -> Option<LayerChanges> {
+        let features = &value["features"];
+        let mut changes = LayerChanges {
+            layer_id: value["id"].as_u32()?,
+            adds: features["adds"].members().map(Feature::from_json).collect(),
+            updates: features["updates"].members().map(Feature::from_json).collect(),
+            ..LayerChanges::default()
+        };
+        for delete_id in features["deleteIds"].members() {
+            if let Some(object_id) = delete_id.as_u64() {
+                changes.delete_ids.push(object_id);
+            } else if let Some(global_id) = delete_id.as_str() {
+                changes.delete_global_ids.push(global_id.to_string());
+            }
+        }
+        Some(changes)
+    }
+}
+
+impl<A, G> LayerChanges<A, G> {
+    pub fn is_empty(&self) -> bool {
+        self.adds.is_empty() && self.updates.is_empty() && self.delete_ids.is_empty() && self.delete_global_ids.is_empty()
+    }
+}
+
+/// Local edits to one layer, to upload in a sync. Features are matched by global ID.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LayerEdits {
+    pub layer_id: u32,
+    pub adds: Vec<Feature>,
+    pub updates: Vec<Feature>,
+    /// The global IDs of deleted features.
+    pub deletes: Vec<String>,
+}
+
+impl LayerEdits {
+    pub fn new(layer_id: u32) -> LayerEdits {
+        LayerEdits {
+            layer_id,
+            ..LayerEdits::default()
+        }
+    }
+
+    pub fn from_json(value: &JsonValue) -> Option<LayerEdits> {
+        Some(LayerEdits {
+            layer_id: value["id"].as_u32()?,
+            adds: value["adds"].members().map(Feature::from_json).collect(),
+            updates: value["updates"].members().map(Feature::from_json).collect(),
+            deletes: value["deletes"].members().filter_map(|id| id.as_str().map(String::from)).collect(),
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        json::object! {
+            "id" => self.layer_id,
+            "adds" => JsonValue::Array(self.adds.iter().map(Feature::to_json).collect()),
+            "updates" => JsonValue::Array(self.updates.iter().map(Feature::to_json).collect()),
+            "deletes" => self.deletes.clone()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.adds.is_empty() && self.updates.is_empty() && self.deletes.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.adds.len() + self.updates.len() + self.deletes.len()
+    }
+}
+
+/// The server's results for one layer's uploaded edits.
+#[derive(Clone, Debug, Default)]
+pub struct LayerEditResults {
+    pub layer_id: u32,
+    pub add_results: Vec<EditResult>,
+    pub update_results: Vec<EditResult>,
+    pub delete_results: Vec<EditResult>,
+}
+
+/// An uploaded edit the server didn't apply, usually because the feature changed or was
+/// deleted on the server since the replica last synced.
+#[derive(Clone, Debug)]
+pub struct SyncConflict {
+    pub layer_id: u32,
+    pub global_id: Option<String>,
+    pub object_id: Option<u64>,
+    pub error: Option<ArcGisError>,
+}
+
+/// What a sync did: the replica's new generations, the changes downloaded and the results of
+/// the edits uploaded.
+#[derive(Clone, Debug)]
+pub struct SyncResult {
+    /// The replica, with its generations advanced.
+    pub replica: Replica,
+    pub changes: Vec<LayerChanges>,
+    pub edit_results: Vec<LayerEditResults>,
+}
+
+impl SyncResult {
+    /// The uploaded edits that failed.
+    pub fn conflicts(&self) -> Vec<SyncConflict> {
+        let mut conflicts = Vec::new();
+        for layer in &self.edit_results {
+            let results = layer
+                .add_results
+                .iter()
+                .chain(&layer.update_results)
+                .chain(&layer.delete_results);
+            for result in results.filter(|result| !result.success) {
+                conflicts.push(SyncConflict {
+                    layer_id: layer.layer_id,
+                    global_id: result.global_id.clone(),
+                    object_id: result.object_id,
+                    error: result.error.clone(),
+                });
+            }
+        }
+        conflicts
+    }
+}
+
+impl FeatureService {
+    /// Registers a replica of some of the service's layers and downloads their features. The
+    /// features come back as adds in `SyncResult::changes`.
+    ///
+    /// This is never retried, since a retry after a lost response would register a second
+    /// replica.
+    pub async fn create_replica(&self, options: &ReplicaOptions) -> BoxResult<SyncResult> {
+        let response = self
+            .client()
+            .post_json(
+                &format!("{}/createReplica", self.url()),
+                &options.to_params(),
+                &RequestOptions::no_retry(),
+            )
+            .await?;
+        let mut replica = Replica::from_json(&response).ok_or("createReplica response has no replicaID")?;
+        replica.sync_model = options.sync_model;
+        replica.layers = options.layers.clone();
+        let changes = response["layers"]
+            .members()
+            .filter_map(|layer| {
+                Some(LayerChanges {
+                    layer_id: layer["id"].as_u32()?,
+                    adds: layer["features"].members().map(Feature::from_json).collect(),
+                    ..LayerChanges::default()
+                })
+            })
+            .collect();
+        Ok(SyncResult {
+            replica,
+            changes,
+            edit_results: Vec::new(),
+        })
+    }
+
+    /// Syncs a replica: uploads `edits` (ignored for `SyncDirection::Download`), downloads the
+    /// server's changes since the replica's generation (skipped for `SyncDirection::Upload`),
+    /// and returns the replica with its generations advanced.
+    ///
+    /// With `rollback_on_failure`, the server applies all of the edits or none of them.
+    /// Otherwise, the ones it couldn't apply are reported by `SyncResult::conflicts`.
+    ///
+    /// Only download-only syncs are retried, since uploads aren't idempotent.
+    pub async fn synchronize_replica(
+        &self,
+        replica: &Replica,
+        edits: &[LayerEdits],
+        direction: SyncDirection,
+        rollback_on_failure: bool,
+    ) -> BoxResult<SyncResult> {
+        let edits: Vec<&LayerEdits> = if SyncDirection::Download == direction {
+            Vec::new()
+        } else {
+            edits.iter().filter(|layer| !layer.is_empty()).collect()
+        };
+        let mut params = vec![
+            ("replicaID", replica.id.clone()),
+            ("transportType", String::from("esriTransportTypeEmbedded")),
+            ("dataFormat", String::from("json")),
+            ("syncDirection", direction.as_str().to_string()),
+            ("rollbackOnFailure", rollback_on_failure.to_string()),
+            ("returnIdsForAdds", String::from("false")),
+            ("closeReplica", String::from("false")),
+            ("async", String::from("false")),
+        ];
+        match replica.sync_model {
+            SyncModel::PerReplica => {
+                if let Some(server_gen) = replica.server_gen {
+                    params.push(("replicaServerGen", server_gen.to_string()));
+                }
+            }
+            SyncModel::PerLayer => {
+                let sync_layers: Vec<JsonValue> = replica
+                    .layers
+                    .iter()
+                    .map(|id| {
+                        let mut layer = json::object! { "id" => *id, "syncDirection" => direction.as_str() };
+                        if let Some(server_gen) = replica.layer_server_gens.get(id) {
+                            layer["serverGen"] = (*server_gen).into();
+                        }
+                        layer
+                    })
+                    .collect();
+                params.push(("syncLayers", JsonValue::Array(sync_layers).dump()));
+            }
+        }
+        if !edits.is_empty() {
+            let edits: Vec<JsonValue> = edits.iter().map(|layer| layer.to_json()).collect();
+            params.push(("edits", JsonValue::Array(edits).dump()));
+        }
+        let options = if edits.is_empty() {
+            RequestOptions::default()
+        } else {
+            RequestOptions::no_retry()
+        };
+        let response = self
+            .client()
+            .post_json(&format!("{}/synchronizeReplica", self.url()), &params, &options)
+            .await?;
+
+        let mut replica = replica.clone();
+        replica.advance(&response);
+        let mut result = SyncResult {
+            replica,
+            changes: Vec::new(),
+            edit_results: Vec::new(),
+        };
+        for layer in response["edits"].members() {
+            if let Some(changes) = LayerChanges::from_json(layer) {
+                if !changes.is_empty() {
+                    result.changes.push(changes);
+                }
+            }
+            if layer.has_key("addResults") || layer.has_key("updateResults") || layer.has_key("deleteResults") {
+                result.edit_results.push(LayerEditResults {
+                    layer_id: layer["id"].as_u32().unwrap_or(0),
+                    add_results: edit_results(&layer["addResults"]),
+                    update_results: edit_results(&layer["updateResults"]),
+                    delete_results: edit_results(&layer["deleteResults"]),
+                });
+            }
+        }
+        Ok(result)
+    }
+
+    /// Unregisters a replica so the server stops tracking changes for it.
+    pub async fn unregister_replica(&self, replica_id: &str) -> BoxResult<()> {
+        self.client()
+            .post_json(
+                &format!("{}/unregisterReplica", self.url()),
+                &[("replicaID", replica_id.to_string())],
+                &RequestOptions::default(),
+            )
+            .await?;
+        Ok(())
+    }
+}
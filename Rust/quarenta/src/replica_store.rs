@@ -0,0 +1,357 @@
+//! A local, file-backed copy of a replica's features, with a queue of edits made offline.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use json::JsonValue;
+use uuid::Uuid;
+
+use crate::edit::EditResult;
+use crate::feature::Feature;
+use crate::feature_service::FeatureService;
+use crate::geometry::SpatialReference;
+use crate::replica::{LayerChanges, LayerEditResults, LayerEdits, Replica, ReplicaOptions, SyncDirection, SyncResult};
+use crate::util::write_replacing;
+use crate::values::AttributeValue;
+use crate::BoxResult;
+
+/// One layer's features and pending edits.
+#[derive(Clone, Debug)]
+struct LocalLayer {
+    object_id_field: String,
+    global_id_field: String,
+    features: Vec<Feature>,
+    pending: LayerEdits,
+}
+
+impl LocalLayer {
+    fn global_id<'a>(&self, feature: &'a Feature) -> Option<&'a str> {
+        feature.attributes[self.global_id_field.as_str()].as_str()
+    }
+
+    fn position(&self, global_id: &str) -> Option<usize> {
+        self.features
+            .iter()
+            .position(|feature| self.global_id(feature).is_some_and(|id| id.eq_ignore_ascii_case(global_id)))
+    }
+
+    fn upsert(&mut self, feature: Feature) {
+        match self.global_id(&feature).and_then(|global_id| self.position(global_id)) {
+            Some(index) => self.features[index] = feature,
+            None => self.features.push(feature),
+        }
+    }
+
+    fn apply(&mut self, changes: &LayerChanges) {
+        for feature in changes.adds.iter().chain(&changes.updates) {
+            self.upsert(feature.clone());
+        }
+        let object_id_field = self.object_id_field.as_str();
+        self.features.retain(|feature| {
+            !feature.attributes[object_id_field]
+                .as_u64()
+                .is_some_and(|object_id| changes.delete_ids.contains(&object_id))
+        });
+        for global_id in &changes.delete_global_ids {
+            if let Some(index) = self.position(global_id) {
+                self.features.remove(index);
+            }
+        }
+    }
+
+    /// Puts the queued edits back over the local features, so a sync that downloads the
+    /// server's copy of a feature doesn't hide an edit still waiting to be uploaded.
+    fn reapply_pending(&mut self) {
+        for feature in self.pending.adds.clone().into_iter().chain(self.pending.updates.clone()) {
+            self.upsert(feature);
+        }
+        for global_id in self.pending.deletes.clone() {
+            if let Some(index) = self.position(&global_id) {
+                self.features.remove(index);
+            }
+        }
+    }
+
+    /// The queued edits the server rejected, which stay queued.
+    fn rejected(&self, results: &LayerEditResults) -> LayerEdits {
+        let global_id_field = self.global_id_field.as_str();
+        let feature_id = |feature: &Feature| feature.attributes[global_id_field].as_str().map(String::from);
+        LayerEdits {
+            layer_id: self.pending.layer_id,
+            adds: rejected(&self.pending.adds, &results.add_results, |add| feature_id(add)),
+            updates: rejected(&self.pending.updates, &results.update_results, |update| feature_id(update)),
+            deletes: rejected(&self.pending.deletes, &results.delete_results, |delete| Some(delete.clone())),
+        }
+    }
+
+    fn from_json(value: &JsonValue) -> Option<(u32, LocalLayer)> {
+        let id = value["id"].as_u32()?;
+        let layer = LocalLayer {
+            object_id_field: value["objectIdField"].as_str()?.to_string(),
+            global_id_field: value["globalIdField"].as_str()?.to_string(),
+            features: value["features"].members().map(Feature::from_json).collect(),
+            pending: LayerEdits::from_json(&value["pending"]).unwrap_or_else(|| LayerEdits::new(id)),
+        };
+        Some((id, layer))
+    }
+
+    fn to_json(&self, id: u32) -> JsonValue {
+        json::object! {
+            "id" => id,
+            "objectIdField" => self.object_id_field.as_str(),
+            "globalIdField" => self.global_id_field.as_str(),
+            "features" => JsonValue::Array(self.features.iter().map(Feature::to_json).collect()),
+            "pending" => self.pending.to_json()
+        }
+    }
+}
+
+/// A replica's features stored in a JSON file, so they can be read and edited without a
+/// connection. Edits are applied locally right away and queued until the next
+/// [`ReplicaStore::synchronize`].
+///
+/// Features are identified by global ID, since features added offline don't have an object ID
+/// until the server assigns one.
+pub struct ReplicaStore {
+    path: PathBuf,
+    service_url: String,
+    replica: Replica,
+    spatial_reference: Option<SpatialReference>,
+    layers: BTreeMap<u32, LocalLayer>,
+}
+
+impl ReplicaStore {
+    /// Creates a replica on the server, downloads its features and saves them to `path`.
+    pub async fn create(service: &FeatureService, options: &ReplicaOptions, path: &Path) -> BoxResult<ReplicaStore> {
+        let created = service.create_replica(options).await?;
+        let mut layers = BTreeMap::new();
+        for &id in &created.replica.layers {
+            let info = service.layer(id).describe().await?;
+            let object_id_field = info
+                .object_id_field
+                .clone()
+                .ok_or_else(|| format!("layer {} has no object ID field", id))?;
+            let global_id_field = info
+                .global_id_field
+                .clone()
+                .ok_or_else(|| format!("layer {} has no global ID field, so it can't be synced", id))?;
+            layers.insert(
+                id,
+                LocalLayer {
+                    object_id_field,
+                    global_id_field,
+                    features: Vec::new(),
+                    pending: LayerEdits::new(id),
+                },
+            );
+        }
+        let mut store = ReplicaStore {
+            path: path.to_path_buf(),
+            service_url: service.url().to_string(),
+            replica: created.replica.clone(),
+            spatial_reference: options.out_sr.clone(),
+            layers,
+        };
+        store.apply(&created);
+        store.save().await?;
+        Ok(store)
+    }
+
+    /// Opens a store saved by an earlier [`ReplicaStore::create`].
+    pub async fn open(path: &Path) -> BoxResult<ReplicaStore> {
+        let text = tokio::fs::read_to_string(path).await?;
+        let value = json::parse(&text)?;
+        let replica = Replica::from_json(&value["replica"]).ok_or("replica store has no replica")?;
+        Ok(ReplicaStore {
+            path: path.to_path_buf(),
+            service_url: value["serviceUrl"].as_str().unwrap_or("").to_string(),
+            replica,
+            spatial_reference: SpatialReference::from_json(&value["spatialReference"]),
+            layers: value["layers"].members().filter_map(LocalLayer::from_json).collect(),
+        })
+    }
+
+    /// Writes the store to its file, replacing it only once the new contents are complete.
+    pub async fn save(&self) -> BoxResult<()> {
+        let mut value = json::object! {
+            "serviceUrl" => self.service_url.as_str(),
+            "replica" => self.replica.to_json(),
+            "layers" => JsonValue::Array(self.layers.iter().map(|(id, layer)| layer.to_json(*id)).collect())
+        };
+        if let Some(spatial_reference) = &self.spatial_reference {
+            value["spatialReference"] = spatial_reference.to_json();
+        }
+        write_replacing(&self.path, value.dump().as_bytes()).await
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The URL of the feature service the replica belongs to.
+    pub fn service_url(&self) -> &str {
+        &self.service_url
+    }
+
+    pub fn replica(&self) -> &Replica {
+        &self.replica
+    }
+
+    /// The spatial reference the replica was created in, which is the one its geometries are
+    /// stored in and the one edits must use. `None` means the service's own, for stores
+    /// created without [`ReplicaOptions::out_sr`].
+    pub fn spatial_reference(&self) -> Option<&SpatialReference> {
+        self.spatial_reference.as_ref()
+    }
+
+    /// A layer's local features, including ones added or updated offline.
+    pub fn features(&self, layer_id: u32) -> &[Feature] {
+        self.layers
+            .get(&layer_id)
+            .map(|layer| layer.features.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// The number of edits waiting to be uploaded.
+    pub fn pending_edit_count(&self) -> usize {
+        self.layers.values().map(|layer| layer.pending.len()).sum()
+    }
+
+    /// Adds a feature locally and queues it for upload. A new global ID is assigned if the
+    /// feature doesn't have one. Returns the feature's global ID.
+    pub fn add_feature(&mut self, layer_id: u32, mut feature: Feature) -> BoxResult<String> {
+        let layer = self.layer_mut(layer_id)?;
+        let global_id = match layer.global_id(&feature) {
+            Some(global_id) => global_id.to_string(),
+            None => {
+                let global_id = new_global_id();
+                feature.attributes[layer.global_id_field.as_str()] = global_id.as_str().into();
+                global_id
+            }
+        };
+        layer.upsert(feature.clone());
+        layer.pending.adds.push(feature);
+        Ok(global_id)
+    }
+
+    /// Updates a feature, matched by its global ID, locally and queues the update. Updating a
+    /// feature that was added offline just changes the queued add.
+    pub fn update_feature(&mut self, layer_id: u32, feature: Feature) -> BoxResult<()> {
+        let layer = self.layer_mut(layer_id)?;
+        let global_id = layer
+            .global_id(&feature)
+            .ok_or("feature has no global ID")?
+            .to_string();
+        layer.upsert(feature.clone());
+        let pending_add = layer
+            .pending
+            .adds
+            .iter()
+            .position(|add| layer.global_id(add).is_some_and(|id| id.eq_ignore_ascii_case(&global_id)));
+        let pending_update = layer
+            .pending
+            .updates
+            .iter()
+            .position(|update| layer.global_id(update).is_some_and(|id| id.eq_ignore_ascii_case(&global_id)));
+        match (pending_add, pending_update) {
+            (Some(index), _) => layer.pending.adds[index] = feature,
+            (None, Some(index)) => layer.pending.updates[index] = feature,
+            (None, None) => layer.pending.updates.push(feature),
+        }
+        Ok(())
+    }
+
+    /// Deletes a feature by global ID locally and queues the delete. Deleting a feature that
+    /// was added offline just drops the queued add.
+    pub fn delete_feature(&mut self, layer_id: u32, global_id: &str) -> BoxResult<()> {
+        let layer = self.layer_mut(layer_id)?;
+        if let Some(index) = layer.position(global_id) {
+            layer.features.remove(index);
+        }
+        let matches = |feature: &Feature, global_id_field: &str| {
+            feature.attributes[global_id_field]
+                .as_str()
+                .is_some_and(|id| id.eq_ignore_ascii_case(global_id))
+        };
+        let global_id_field = layer.global_id_field.clone();
+        let queued_adds = layer.pending.adds.len();
+        layer.pending.adds.retain(|add| !matches(add, &global_id_field));
+        layer.pending.updates.retain(|update| !matches(update, &global_id_field));
+        if queued_adds == layer.pending.adds.len() {
+            layer.pending.deletes.push(global_id.to_string());
+        }
+        Ok(())
+    }
+
+    /// Syncs with the server: uploads the queued edits, applies the server's changes to the
+    /// local features, advances the replica's generations and saves the store.
+    ///
+    /// Once the server has answered, the edits it applied are taken off the queue. The ones
+    /// it rejected are in [`SyncResult::conflicts`] and stay queued, and stay applied locally,
+    /// to be retried by the next sync; fix them with [`ReplicaStore::update_feature`] or give
+    /// them up with [`ReplicaStore::delete_feature`]. If the sync fails outright, nothing
+    /// changes and all the edits stay queued.
+    pub async fn synchronize(&mut self, service: &FeatureService, direction: SyncDirection) -> BoxResult<SyncResult> {
+        let edits: Vec<LayerEdits> = self.layers.values().map(|layer| layer.pending.clone()).collect();
+        let result = service
+            .synchronize_replica(&self.replica, &edits, direction, false)
+            .await?;
+        if SyncDirection::Download != direction {
+            for (id, layer) in self.layers.iter_mut() {
+                layer.pending = match result.edit_results.iter().find(|results| results.layer_id == *id) {
+                    Some(results) => layer.rejected(results),
+                    None => LayerEdits::new(*id),
+                };
+            }
+        }
+        self.replica = result.replica.clone();
+        self.apply(&result);
+        for layer in self.layers.values_mut() {
+            layer.reapply_pending();
+        }
+        self.save().await?;
+        Ok(result)
+    }
+
+    fn apply(&mut self, result: &SyncResult) {
+        for changes in &result.changes {
+            if let Some(layer) = self.layers.get_mut(&changes.layer_id) {
+                layer.apply(changes);
+            }
+        }
+    }
+
+    fn layer_mut(&mut self, layer_id: u32) -> BoxResult<&mut LocalLayer> {
+        self.layers
+            .get_mut(&layer_id)
+            .ok_or_else(|| format!("layer {} is not in the replica", layer_id).into())
+    }
+}
+
+/// The edits whose results say they failed. Results are matched to edits by global ID, or by
+/// position when a result doesn't have one.
+fn rejected<T: Clone>(edits: &[T], results: &[EditResult], global_id: impl Fn(&T) -> Option<String>) -> Vec<T> {
+    edits
+        .iter()
+        .enumerate()
+        .filter(|(index, edit)| {
+            let by_id = global_id(edit).and_then(|global_id| {
+                results.iter().find(|result| {
+                    result
+                        .global_id
+                        .as_deref()
+                        .is_some_and(|id| id.eq_ignore_ascii_case(&global_id))
+                })
+            });
+            by_id
+                .or_else(|| results.get(*index).filter(|result| result.global_id.is_none()))
+                .is_some_and(|result| !result.success)
+        })
+        .map(|(_, edit)| edit.clone())
+        .collect()
+}
+
+/// Generates a random GUID in the braced, upper-case form ArcGIS uses.
+fn new_global_id() -> String {
+    Uuid::new_v4().to_attribute().as_str().unwrap_or_default().to_string()
+}
@@ -0,0 +1,633 @@
+//! The request pipeline that every quarenta call goes through.
+//!
+//! ArcGIS services fail transiently more often than one would like: a 5xx from a busy server,
+//! a 429 when a rate limit kicks in, or a 200 whose body is `{"error":{"code":500,"message":
+//! "Unable to complete operation."}}`. [`Client`] retries those with exponential backoff and
+//! jitter, honors `Retry-After`, and caps the number of requests in flight to any one host.
+//! Each request runs inside an `arcgis_request` `tracing` span with the operation, redacted URL,
+//! status, duration, attempts, record count and ArcGIS error code.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use json::JsonValue;
+use rand::Rng;
+use reqwest::header::{HeaderMap, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::{Request, RequestBuilder, Response, StatusCode};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::Instrument;
+
+use crate::portal::Portal;
+use crate::trace;
+use crate::BoxResult;
+
+const DEFAULT_MAX_CONCURRENT_PER_HOST: usize = 6;
+
+/// How many times, and how patiently, a failed request is retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// The number of retries after the first attempt. Zero disables retrying.
+    pub max_retries: u32,
+    /// The backoff ceiling for the first retry. Each later retry doubles it.
+    pub base_delay: Duration,
+    /// The largest backoff ceiling, no matter how many retries have happened. A server that
+    /// asks for a longer wait with `Retry-After` isn't retried.
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Returns how long to wait before retry number `attempt` (starting at zero), using "full
+    /// jitter": a random duration between zero and the exponential ceiling.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self.ceiling(attempt);
+        let ceiling_ms = ceiling.as_millis() as u64;
+        if 0 == ceiling_ms {
+            return ceiling;
+        }
+        Duration::from_millis(rand::thread_rng().gen_range(0, ceiling_ms + 1))
+    }
+
+    /// The most [`RetryPolicy::backoff`] waits before retry number `attempt`: the base delay,
+    /// doubled for each earlier retry, up to the maximum.
+    fn ceiling(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .checked_mul(1 << attempt.min(16))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// Returns how long to wait before retry number `attempt`: the backoff, or the server's
+    /// `Retry-After` if that's longer. `None` if the server asks for a longer wait than
+    /// `max_delay`, in which case the request isn't retried.
+    fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        let backoff = self.backoff(attempt);
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after.max(backoff)),
+            None => Some(backoff),
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Per-call options for a request sent through a [`Client`].
+#[derive(Clone, Debug)]
+pub struct RequestOptions {
+    /// Whether this call may be retried. Turn this off for calls that are not idempotent, such
+    /// as `addFeatures`, where a retry after a lost response could add the same features twice.
+    pub retry: bool,
+}
+
+impl RequestOptions {
+    /// Options for a call that must be sent at most once.
+    pub fn no_retry() -> RequestOptions {
+        RequestOptions { retry: false }
+    }
+}
+
+impl Default for RequestOptions {
+    fn default() -> RequestOptions {
+        RequestOptions { retry: true }
+    }
+}
+
+/// An error object returned in the body of an ArcGIS REST response.
+#[derive(Clone, Debug)]
+pub struct ArcGisError {
+    pub code: i32,
+    pub message: String,
+    pub details: Vec<String>,
+}
+
+impl ArcGisError {
+    /// Returns the error in a response body, if the body contains an `error` object.
+    pub fn from_json(value: &JsonValue) -> Option<ArcGisError> {
+        let error = &value["error"];
+        if !error.is_object() {
+            return None;
+        }
+        Some(ArcGisError {
+            code: error["code"].as_i32().unwrap_or(0),
+            message: error["message"].as_str().unwrap_or("").to_string(),
+            details: error["details"]
+                .members()
+                .filter_map(|detail| detail.as_str())
+                .map(String::from)
+                .collect(),
+        })
+    }
+
+    /// Returns `Err` if the response body contains an `error` object, or the body otherwise.
+    pub fn check(value: JsonValue) -> BoxResult<JsonValue> {
+        match ArcGisError::from_json(&value) {
+            Some(err) => Err(Box::new(err)),
+            None => Ok(value),
+        }
+    }
+
+    /// Whether trying again later might succeed.
+    pub fn is_transient(&self) -> bool {
+        is_retryable_status(self.code as u16)
+            || self.message.contains("Unable to complete operation")
+    }
+}
+
+impl fmt::Display for ArcGisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ArcGIS error {}: {}", self.code, self.message)?;
+        if !self.details.is_empty() {
+            write!(f, " ({})", self.details.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ArcGisError {}
+
+/// An HTTP client for ArcGIS REST services.
+///
+/// Cloning a `Client` is cheap, and clones share the underlying connection pool and per-host
+/// limits, so create one and pass it around.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use quarenta::{Client, RequestOptions, RetryPolicy};
+/// # async fn example(url: &str) -> Result<(), Box<dyn std::error::Error>> {
+/// let client = Client::new()
+///     .retry_policy(RetryPolicy { max_retries: 5, ..RetryPolicy::default() })
+///     .max_concurrent_per_host(4);
+/// let response = client.send_json(
+///     client.http().get(url).query(&[("f", "json")]),
+///     &RequestOptions::default(),
+/// ).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    retry_policy: RetryPolicy,
+    max_concurrent_per_host: usize,
+    host_permits: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+    credentials: Option<Credentials>,
+}
+
+#[derive(Clone)]
+struct Credentials {
+    token: String,
+    referrer: String,
+}
+
+/// A response from [`Client::send`]. It counts against its host's limit on requests in flight
+/// until it's dropped, so reading its body is limited along with the rest of the request.
+///
+/// It dereferences to the `reqwest` response for `status`, `headers`, `chunk` and the like.
+#[derive(Debug)]
+pub struct LimitedResponse {
+    response: Response,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl LimitedResponse {
+    /// Reads the whole body.
+    pub async fn bytes(self) -> reqwest::Result<Bytes> {
+        self.response.bytes().await
+    }
+
+    /// Reads the whole body as text.
+    pub async fn text(self) -> reqwest::Result<String> {
+        self.response.text().await
+    }
+
+    /// The `reqwest` response, which no longer counts against the limit.
+    pub fn into_inner(self) -> Response {
+        self.response
+    }
+}
+
+impl Deref for LimitedResponse {
+    type Target = Response;
+
+    fn deref(&self) -> &Response {
+        &self.response
+    }
+}
+
+impl DerefMut for LimitedResponse {
+    fn deref_mut(&mut self) -> &mut Response {
+        &mut self.response
+    }
+}
+
+impl Client {
+    /// Creates a client with a new `reqwest` client and the default retry policy.
+    pub fn new() -> Client {
+        Client::from(reqwest::Client::new())
+    }
+
+    /// Replaces the retry policy.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Client {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets the maximum number of requests in flight to any one host. This applies to requests
+    /// created after the call, so set it before sharing the client.
+    pub fn max_concurrent_per_host(mut self, max_concurrent_per_host: usize) -> Client {
+        self.max_concurrent_per_host = max_concurrent_per_host.max(1);
+        self.host_permits = Arc::new(Mutex::new(HashMap::new()));
+        self
+    }
+
+    /// Sends `token` and `referer` with every request made by quarenta's service clients, such
+    /// as [`crate::FeatureLayer`]. Requests built by hand and passed to [`Client::send`] are
+    /// left alone.
+    pub fn with_token(mut self, token: &str, referrer: &str) -> Client {
+        self.credentials = Some(Credentials {
+            token: token.to_string(),
+            referrer: referrer.to_string(),
+        });
+        self
+    }
+
+    /// The underlying `reqwest` client, for building requests to pass to [`Client::send`] and
+    /// [`Client::send_json`].
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    /// Sends a request, retrying on connection errors and retryable HTTP statuses.
+    ///
+    /// Use this for responses that aren't JSON, such as images or attachments. The body is not
+    /// inspected, so ArcGIS errors reported with a 200 status are not retried. The request
+    /// counts against the host's limit until the response is dropped.
+    pub async fn send(&self, request: RequestBuilder, options: &RequestOptions) -> BoxResult<LimitedResponse> {
+        self.execute(request, options, |response| async move { Ok(response) }).await
+    }
+
+    /// Sends a request and parses the response body as JSON, retrying on connection errors,
+    /// retryable HTTP statuses, and transient ArcGIS errors in the body.
+    ///
+    /// If the body contains a non-transient ArcGIS error, or retries run out, the body is
+    /// returned as-is so the caller can inspect the `error` object. Use [`ArcGisError::check`]
+    /// to turn it into an `Err`.
+    pub async fn send_json(&self, request: RequestBuilder, options: &RequestOptions) -> BoxResult<JsonValue> {
+        self.execute(request, options, read_json).await
+    }
+
+    /// Attempts to login to ArcGIS Online. See [`crate::login`], and [`Portal::generate_token`]
+    /// for other portals.
+    pub async fn login(&self, username: &str, password: &str, referrer: &str) -> BoxResult<JsonValue> {
+        Portal::arcgis_online(self).generate_token(username, password, referrer).await
+    }
+
+    /// Appends `f` and, if the client has a token, `token` and `referer` to the parameters.
+    pub(crate) fn params_with_auth<'a>(&'a self, params: &[(&'a str, String)], format: &str) -> Vec<(&'a str, String)> {
+        let mut params = params.to_vec();
+        params.push(("f", format.to_string()));
+        params.extend(self.auth_params());
+        params
+    }
+
+    /// The `token` and `referer` parameters, if the client has a token.
+    pub(crate) fn auth_params(&self) -> Vec<(&'static str, String)> {
+        match &self.credentials {
+            Some(credentials) => vec![
+                ("token", credentials.token.clone()),
+                ("referer", credentials.referrer.clone()),
+            ],
+            None => Vec::new(),
+        }
+    }
+
+    /// GETs a URL with `f=json` and the client's token, and returns the body, turning an ArcGIS
+    /// error into an `Err`.
+    pub(crate) async fn get_json(&self, url: &str, params: &[(&str, String)], options: &RequestOptions) -> BoxResult<JsonValue> {
+        let request = self.http.get(url).query(&self.params_with_auth(params, "json"));
+        ArcGisError::check(self.send_json(request, options).await?)
+    }
+
+    /// Like [`Client::get_json`], but POSTs the parameters as a form. Use this when parameters
+    /// such as geometries or features might be too long for a URL.
+    pub(crate) async fn post_json(&self, url: &str, params: &[(&str, String)], options: &RequestOptions) -> BoxResult<JsonValue> {
+        let request = self.http.post(url).form(&self.params_with_auth(params, "json"));
+        ArcGisError::check(self.send_json(request, options).await?)
+    }
+
+    /// Sends a request with retries, reading each response that doesn't fail outright with
+    /// `read`. When retries run out on a failure that carries a reply, such as a body with a
+    /// transient ArcGIS error, that reply is returned.
+    async fn execute<T, R, F>(&self, request: RequestBuilder, options: &RequestOptions, read: R) -> BoxResult<T>
+    where
+        R: Fn(LimitedResponse) -> F,
+        F: Future<Output = Result<T, Failure<T>>>,
+    {
+        let request = request.build()?;
+        let span = trace::request_span(&request);
+        let started = Instant::now();
+        let result = self
+            .execute_with_retries(request, options, read)
+            .instrument(span.clone())
+            .await;
+        span.record("duration_ms", started.elapsed().as_millis() as u64);
+        if let Err(err) = &result {
+            tracing::warn!(parent: &span, error = %trace::redact_text(&err.to_string()), "ArcGIS request failed");
+        }
+        result
+    }
+
+    async fn execute_with_retries<T, R, F>(&self, request: Request, options: &RequestOptions, read: R) -> BoxResult<T>
+    where
+        R: Fn(LimitedResponse) -> F,
+        F: Future<Output = Result<T, Failure<T>>>,
+    {
+        let permits = self.permits_for(&request);
+        let max_retries = if options.retry { self.retry_policy.max_retries } else { 0 };
+        let mut attempt = 0;
+        loop {
+            tracing::Span::current().record("attempts", attempt + 1);
+            let this_request = match request.try_clone() {
+                Some(this_request) => this_request,
+                // A streaming body can only be sent once.
+                None => {
+                    let response = self.execute_once::<T>(request, &permits).await.map_err(|failure| failure.error)?;
+                    return read(response).await.map_err(|failure| failure.error);
+                }
+            };
+            let result = match self.execute_once(this_request, &permits).await {
+                Ok(response) => read(response).await,
+                Err(failure) => Err(failure),
+            };
+            match result {
+                Ok(reply) => return Ok(reply),
+                Err(failure) => {
+                    if !failure.retryable || attempt >= max_retries {
+                        return match failure.last_reply {
+                            Some(reply) => Ok(reply),
+                            None => Err(failure.error),
+                        };
+                    }
+                    let delay = match self.retry_policy.delay(attempt, failure.retry_after) {
+                        Some(delay) => delay,
+                        None => {
+                            let message = format!(
+                                "{}; the server asked to retry after {} seconds, longer than the retry policy allows",
+                                failure.error,
+                                failure.retry_after.unwrap_or_default().as_secs()
+                            );
+                            return Err(message.into());
+                        }
+                    };
+                    tracing::info!(
+                        error = %trace::redact_text(&failure.error.to_string()),
+                        delay_ms = delay.as_millis() as u64,
+                        "retrying ArcGIS request"
+                    );
+                    tokio::time::delay_for(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Sends a request once. Retryable HTTP statuses are failures; other responses are
+    /// returned unread.
+    async fn execute_once<T>(&self, request: Request, permits: &Arc<Semaphore>) -> Result<LimitedResponse, Failure<T>> {
+        let permit = permits.clone().acquire_owned().await;
+        let response = match self.http.execute(request).await {
+            Ok(response) => response,
+            Err(err) => {
+                let retryable = !err.is_builder() && !err.is_redirect();
+                return Err(Failure::new(Box::new(err), retryable));
+            }
+        };
+        let status = response.status();
+        tracing::Span::current().record("status", status.as_u16());
+        if is_retryable_status(status.as_u16()) {
+            let retry_after = retry_after(response.headers());
+            let mut failure = Failure::new(format!("HTTP status {}", status).into(), true);
+            failure.retry_after = retry_after;
+            return Err(failure);
+        }
+        Ok(LimitedResponse {
+            response,
+            _permit: permit,
+        })
+    }
+
+    fn permits_for(&self, request: &Request) -> Arc<Semaphore> {
+        let host = request.url().host_str().unwrap_or("").to_string();
+        let mut host_permits = self.host_permits.lock().unwrap();
+        host_permits
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_concurrent_per_host)))
+            .clone()
+    }
+}
+
+impl Default for Client {
+    fn default() -> Client {
+        Client::new()
+    }
+}
+
+impl From<reqwest::Client> for Client {
+    fn from(http: reqwest::Client) -> Client {
+        Client {
+            http,
+            retry_policy: RetryPolicy::default(),
+            max_concurrent_per_host: DEFAULT_MAX_CONCURRENT_PER_HOST,
+            host_permits: Arc::new(Mutex::new(HashMap::new())),
+            credentials: None,
+        }
+    }
+}
+
+/// Why an attempt failed, and whether to try again. `last_reply` is what to return instead of
+/// the error if retries run out.
+struct Failure<T> {
+    error: Box<dyn Error>,
+    retryable: bool,
+    retry_after: Option<Duration>,
+    last_reply: Option<T>,
+}
+
+impl<T> Failure<T> {
+    fn new(error: Box<dyn Error>, retryable: bool) -> Failure<T> {
+        Failure {
+            error,
+            retryable,
+            retry_after: None,
+            last_reply: None,
+        }
+    }
+}
+
+/// Reads a response body as JSON. A transient ArcGIS error in the body is a retryable failure
+/// that keeps the body, so the caller can still inspect it once retries run out.
+async fn read_json(response: LimitedResponse) -> Result<JsonValue, Failure<JsonValue>> {
+    let status = response.status();
+    let text = match response.text().await {
+        Ok(text) => text,
+        Err(err) => return Err(Failure::new(Box::new(err), true)),
+    };
+    let value = match json::parse(text.as_str()) {
+        Ok(value) => value,
+        Err(err) => return Err(Failure::new(Box::new(err), !status.is_success())),
+    };
+    trace::record_json(&value);
+    match ArcGisError::from_json(&value) {
+        Some(err) if err.is_transient() => {
+            let mut failure = Failure::new(Box::new(err), true);
+            failure.last_reply = Some(value);
+            Err(failure)
+        }
+        _ => Ok(value),
+    }
+}
+
+fn is_retryable_status(status: u16) -> bool {
+    matches!(
+        StatusCode::from_u16(status),
+        Ok(StatusCode::TOO_MANY_REQUESTS)
+            | Ok(StatusCode::INTERNAL_SERVER_ERROR)
+            | Ok(StatusCode::BAD_GATEWAY)
+            | Ok(StatusCode::SERVICE_UNAVAILABLE)
+            | Ok(StatusCode::GATEWAY_TIMEOUT)
+    )
+}
+
+/// Whether a response to a request for an image, tile or PBF has a JSON body instead, which
+/// is how ArcGIS reports errors for those. Some servers label the JSON as plain text.
+pub(crate) fn has_json_body(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.contains("json") || content_type.contains("text"))
+}
+
+/// Reads a `Retry-After` header given in seconds. The HTTP-date form is rare from ArcGIS and is
+/// ignored, which falls back to the normal backoff.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use reqwest::header::HeaderValue;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn doubles_the_backoff_ceiling_up_to_the_maximum() {
+        let policy = policy();
+        let ceilings: Vec<u128> = (0..6).map(|attempt| policy.ceiling(attempt).as_millis()).collect();
+        assert_eq!(ceilings, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.ceiling(40), Duration::from_secs(1));
+        assert_eq!(RetryPolicy::none().ceiling(0), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn jitters_within_the_ceiling() {
+        let policy = policy();
+        for attempt in 0..6 {
+            for _ in 0..100 {
+                assert!(policy.backoff(attempt) <= policy.ceiling(attempt));
+            }
+        }
+        let no_delay = RetryPolicy {
+            base_delay: Duration::from_millis(0),
+            ..policy
+        };
+        assert_eq!(no_delay.backoff(2), Duration::from_millis(0));
+    }
+
+    #[test]
+    fn waits_as_long_as_the_server_asks() {
+        let policy = policy();
+        assert_eq!(policy.delay(0, Some(Duration::from_secs(1))), Some(Duration::from_secs(1)));
+        // The backoff still applies when the server asks for less.
+        let delay = policy.delay(3, Some(Duration::from_millis(1))).unwrap();
+        assert!(Duration::from_millis(1) <= delay && delay <= Duration::from_millis(800));
+        assert!(policy.delay(1, None).unwrap() <= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn gives_up_when_the_server_asks_for_too_long() {
+        assert_eq!(policy().delay(0, Some(Duration::from_secs(120))), None);
+    }
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static(" 120 "));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn recognizes_json_bodies() {
+        let mut headers = HeaderMap::new();
+        assert!(!has_json_body(&headers));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+        assert!(has_json_body(&headers));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        assert!(has_json_body(&headers));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        assert!(!has_json_body(&headers));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-protobuf"));
+        assert!(!has_json_body(&headers));
+    }
+
+    #[test]
+    fn retries_transient_errors_only() {
+        assert!(is_retryable_status(429));
+        assert!(is_retryable_status(503));
+        assert!(!is_retryable_status(404));
+        let busy = ArcGisError::from_json(&json::parse(r#"{"error":{"code":400,"message":"Unable to complete operation."}}"#).unwrap());
+        assert!(busy.unwrap().is_transient());
+        let invalid = ArcGisError::from_json(&json::parse(r#"{"error":{"code":498,"message":"Invalid token."}}"#).unwrap());
+        assert!(!invalid.unwrap().is_transient());
+    }
+}
@@ -0,0 +1,296 @@
+//! An in-memory R-tree over downloaded features, for answering repeated spatial questions
+//! without going back to the server.
+//!
+//! Features are indexed by their extents in WGS 1984, projecting them first if the feature
+//! set is in another spatial reference. Distances and bearings are geodesic, measured to a
+//! feature's nearest vertex: exact for points, and a close upper bound for lines and polygons
+//! with short segments.
+
+use json::JsonValue;
+use rstar::primitives::{GeomWithData, Rectangle};
+use rstar::{RTree, AABB};
+
+use crate::feature::{Feature, FeatureSet};
+use crate::geodesic::{geodesic_bearing, geodesic_distance};
+use crate::geometry::{Envelope, Geometry, Point, SpatialReference};
+use crate::projection::Projection;
+use crate::typed::GeometryValue;
+use crate::BoxResult;
+
+/// The earth's polar radius, the smallest, so angles worked out from it are never too small.
+const POLAR_RADIUS: f64 = 6_356_752.3;
+
+/// A feature's extent in the tree, with its position in the index's features.
+type Entry = GeomWithData<Rectangle<[f64; 2]>, usize>;
+
+/// Features with an R-tree over their locations.
+///
+/// ```no_run
+/// # use quarenta::{Client, FeatureLayer, Point, Query, SpatialIndex, WhereClause};
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let layer = FeatureLayer::new(&Client::new(), "https://example.com/arcgis/rest/services/Cities/FeatureServer/0");
+/// let cities = layer.query(&Query::new().filter(WhereClause::all())).await?;
+/// let index = SpatialIndex::new(cities)?;
+/// for (city, meters) in index.nearest(&Point::new(-9.14, 38.74), 3) {
+///     println!("{} is {:.0} km away", city.attributes["CITY_NAME"], meters / 1000.0);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct SpatialIndex<A = JsonValue, G = Geometry> {
+    features: Vec<Feature<A, G>>,
+    /// Each feature's vertices in WGS 1984, by its position in `features`.
+    vertices: Vec<Vec<Point>>,
+    tree: RTree<Entry>,
+}
+
+impl<A, G: GeometryValue> SpatialIndex<A, G> {
+    /// Indexes a feature set's features. Features without geometries are kept but never
+    /// found by a spatial query. It's an error if the set's spatial reference can't be
+    /// projected to WGS 1984 locally; a set with no spatial reference is taken to be in it.
+    pub fn new(feature_set: FeatureSet<A, G>) -> BoxResult<SpatialIndex<A, G>> {
+        let wgs84 = SpatialReference::wgs84();
+        let projection = Projection::new(feature_set.spatial_reference.as_ref().unwrap_or(&wgs84), &wgs84)?;
+        let mut vertices = Vec::with_capacity(feature_set.features.len());
+        let mut entries = Vec::with_capacity(feature_set.features.len());
+        for (index, feature) in feature_set.features.iter().enumerate() {
+            let geometry = match &feature.geometry {
+                Some(geometry) => Some(projection.geometry(&geometry.to_geometry())?),
+                None => None,
+            };
+            if let Some(extent) = geometry.as_ref().and_then(Geometry::envelope) {
+                let rectangle = Rectangle::from_corners([extent.xmin, extent.ymin], [extent.xmax, extent.ymax]);
+                entries.push(GeomWithData::new(rectangle, index));
+            }
+            vertices.push(geometry.map(|geometry| geometry_vertices(&geometry)).unwrap_or_default());
+        }
+        Ok(SpatialIndex {
+            features: feature_set.features,
+            vertices,
+            tree: RTree::bulk_load(entries),
+        })
+    }
+
+    /// All the features, in their original order.
+    pub fn features(&self) -> &[Feature<A, G>] {
+        &self.features
+    }
+
+    pub fn len(&self) -> usize {
+        self.features.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    /// The features whose extents intersect an envelope in WGS 1984. An envelope whose `xmin`
+    /// is greater than its `xmax` crosses the antimeridian.
+    pub fn in_envelope(&self, envelope: &Envelope) -> Vec<&Feature<A, G>> {
+        self.locate(envelope)
+            .into_iter()
+            .map(|index| &self.features[index])
+            .collect()
+    }
+
+    /// The features within `distance` meters of a point, nearest first, with their
+    /// distances.
+    pub fn within_distance(&self, origin: &Point, distance: f64) -> Vec<(&Feature<A, G>, f64)> {
+        let mut found: Vec<(usize, f64)> = self
+            .candidates(origin, distance)
+            .into_iter()
+            .filter_map(|index| {
+                let meters = self.distance_to(index, origin)?;
+                if meters <= distance {
+                    Some((index, meters))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        found.sort_by(|a, b| a.1.total_cmp(&b.1));
+        found.into_iter().map(|(index, meters)| (&self.features[index], meters)).collect()
+    }
+
+    /// The `count` features nearest a point, nearest first, with their distances in meters.
+    pub fn nearest(&self, origin: &Point, count: usize) -> Vec<(&Feature<A, G>, f64)> {
+        if 0 == count {
+            return Vec::new();
+        }
+        // The nearest in degrees aren't necessarily the nearest on the ellipsoid, but the
+        // farthest of them bounds how far away the true nearest can be.
+        let reach = self
+            .tree
+            .nearest_neighbor_iter(&[origin.x, origin.y])
+            .take(count)
+            .filter_map(|entry| self.distance_to(entry.data, origin))
+            .fold(0.0, f64::max);
+        let mut nearest = self.within_distance(origin, reach);
+        nearest.truncate(count);
+        nearest
+    }
+
+    /// The features in a direction from a point, nearest first: those whose bearing from it
+    /// is between `start` and `end`, in degrees clockwise from north. The sector goes
+    /// clockwise from `start` to `end`, so `(315.0, 45.0)` is north. With `max_distance`, only
+    /// features within that many meters are included.
+    pub fn in_sector(&self, origin: &Point, start: f64, end: f64, max_distance: Option<f64>) -> Vec<(&Feature<A, G>, f64)> {
+        let candidates = match max_distance {
+            Some(max_distance) => self.candidates(origin, max_distance),
+            None => (0..self.features.len()).collect(),
+        };
+        let mut found: Vec<(usize, f64)> = candidates
+            .into_iter()
+            .filter_map(|index| {
+                let (vertex, meters) = self.nearest_vertex(index, origin)?;
+                let in_reach = max_distance.is_none_or(|max_distance| meters <= max_distance);
+                if in_reach && in_bearing_range(geodesic_bearing(origin, vertex), start, end) {
+                    Some((index, meters))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        found.sort_by(|a, b| a.1.total_cmp(&b.1));
+        found.into_iter().map(|(index, meters)| (&self.features[index], meters)).collect()
+    }
+
+    /// The positions of the features whose extents intersect an envelope, in order.
+    fn locate(&self, envelope: &Envelope) -> Vec<usize> {
+        let boxes = if envelope.xmin <= envelope.xmax {
+            vec![([envelope.xmin, envelope.ymin], [envelope.xmax, envelope.ymax])]
+        } else {
+            vec![
+                ([envelope.xmin, envelope.ymin], [180.0, envelope.ymax]),
+                ([-180.0, envelope.ymin], [envelope.xmax, envelope.ymax]),
+            ]
+        };
+        let mut indexes: Vec<usize> = boxes
+            .into_iter()
+            .flat_map(|(lower, upper)| {
+                self.tree
+                    .locate_in_envelope_intersecting(&AABB::from_corners(lower, upper))
+                    .map(|entry| entry.data)
+            })
+            .collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        indexes
+    }
+
+    /// The features whose extents come within a box sure to hold every point `distance`
+    /// meters or less from `origin`.
+    fn candidates(&self, origin: &Point, distance: f64) -> Vec<usize> {
+        // On a sphere with the polar radius, with a little to spare for the ellipsoid.
+        let angle = distance * 1.005 / POLAR_RADIUS;
+        let delta_latitude = angle.to_degrees();
+        let (ymin, ymax) = (origin.y - delta_latitude, origin.y + delta_latitude);
+        let spread = angle.sin() / origin.y.to_radians().cos();
+        let (xmin, xmax) = if ymin <= -90.0 || 90.0 <= ymax || 1.0 <= spread || std::f64::consts::FRAC_PI_2 <= angle {
+            (-180.0, 180.0)
+        } else {
+            let delta_longitude = spread.asin().to_degrees();
+            (wrap_longitude(origin.x - delta_longitude), wrap_longitude(origin.x + delta_longitude))
+        };
+        self.locate(&Envelope {
+            xmin,
+            ymin: ymin.max(-90.0),
+            xmax,
+            ymax: ymax.min(90.0),
+        })
+    }
+
+    /// A feature's vertex nearest a point, and its distance in meters.
+    fn nearest_vertex(&self, index: usize, origin: &Point) -> Option<(&Point, f64)> {
+        self.vertices[index]
+            .iter()
+            .map(|vertex| (vertex, geodesic_distance(origin, vertex)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    fn distance_to(&self, index: usize, origin: &Point) -> Option<f64> {
+        self.nearest_vertex(index, origin).map(|(_, meters)| meters)
+    }
+}
+
+/// Whether a bearing is in the sector going clockwise from `start` to `end`.
+fn in_bearing_range(bearing: f64, start: f64, end: f64) -> bool {
+    let (bearing, start, end) = (bearing.rem_euclid(360.0), start.rem_euclid(360.0), end.rem_euclid(360.0));
+    if start <= end {
+        start <= bearing && bearing <= end
+    } else {
+        start <= bearing || bearing <= end
+    }
+}
+
+fn wrap_longitude(longitude: f64) -> f64 {
+    (longitude + 180.0).rem_euclid(360.0) - 180.0
+}
+
+fn geometry_vertices(geometry: &Geometry) -> Vec<Point> {
+    match geometry {
+        Geometry::Point(point) => vec![*point],
+        Geometry::Multipoint(multipoint) => multipoint.points.clone(),
+        Geometry::Polyline(polyline) => polyline.paths.iter().flatten().copied().collect(),
+        Geometry::Polygon(polygon) => polygon.rings.iter().flatten().copied().collect(),
+        Geometry::Envelope(envelope) => envelope.to_polygon().rings.remove(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Places on both sides of the antimeridian around Fiji, and one far away.
+    fn places() -> SpatialIndex {
+        let place = |name: &str, x: f64, y: f64| {
+            Feature::new(json::object! { "name" => name }, Some(Geometry::Point(Point::new(x, y))))
+        };
+        SpatialIndex::new(FeatureSet {
+            features: vec![
+                place("west of the line", 179.5, -17.0),
+                place("east of the line", -179.5, -17.0),
+                place("Greenwich", 0.0, 51.48),
+            ],
+            ..FeatureSet::default()
+        })
+        .unwrap()
+    }
+
+    fn names<'a>(features: impl IntoIterator<Item = &'a Feature>) -> Vec<String> {
+        features.into_iter().map(|feature| feature.attributes["name"].to_string()).collect()
+    }
+
+    #[test]
+    fn splits_an_envelope_across_the_antimeridian() {
+        let index = places();
+        let across = Envelope { xmin: 179.0, ymin: -18.0, xmax: -179.0, ymax: -16.0 };
+        assert_eq!(vec!["west of the line", "east of the line"], names(index.in_envelope(&across)));
+        let not_across = Envelope { xmin: -179.0, ymin: -18.0, xmax: 179.0, ymax: -16.0 };
+        assert!(index.in_envelope(&not_across).is_empty());
+    }
+
+    #[test]
+    fn measures_across_the_antimeridian() {
+        let index = places();
+        let origin = Point::new(179.9, -17.0);
+        let found = index.within_distance(&origin, 100_000.0);
+        assert_eq!(vec!["west of the line", "east of the line"], names(found.iter().map(|(feature, _)| *feature)));
+        assert!((found[0].1 - 42_600.0).abs() < 500.0, "{}", found[0].1);
+        assert!((found[1].1 - 63_900.0).abs() < 500.0, "{}", found[1].1);
+
+        let origin = Point::new(-179.9, -17.0);
+        let nearest = index.nearest(&origin, 1);
+        assert_eq!(vec!["east of the line"], names(nearest.iter().map(|(feature, _)| *feature)));
+    }
+
+    #[test]
+    fn finds_features_in_a_sector() {
+        let index = places();
+        let origin = Point::new(179.9, -17.0);
+        let east = index.in_sector(&origin, 45.0, 135.0, Some(500_000.0));
+        assert_eq!(vec!["east of the line"], names(east.iter().map(|(feature, _)| *feature)));
+        let north = index.in_sector(&origin, 315.0, 45.0, None);
+        assert_eq!(vec!["Greenwich"], names(north.iter().map(|(feature, _)| *feature)));
+    }
+}
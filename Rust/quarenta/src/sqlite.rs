@@ -0,0 +1,93 @@
+//! Helpers shared by the SQLite files quarenta writes: GeoPackages and feature caches.
+
+use json::JsonValue;
+use rusqlite::types::Value;
+
+use crate::field::{Field, FieldType};
+use crate::values::date_text;
+
+pub(crate) fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// The GeoPackage column type for a field.
+pub(crate) fn sqlite_type(field: &Field) -> String {
+    match field.field_type {
+        FieldType::SmallInteger => String::from("SMALLINT"),
+        FieldType::Integer => String::from("MEDIUMINT"),
+        FieldType::BigInteger | FieldType::Oid => String::from("INTEGER"),
+        FieldType::Single => String::from("FLOAT"),
+        FieldType::Double => String::from("DOUBLE"),
+        FieldType::Date => String::from("DATETIME"),
+        FieldType::DateOnly => String::from("DATE"),
+        FieldType::String => match field.length {
+            Some(length) if 0 < length => format!("TEXT({})", length),
+            _ => String::from("TEXT"),
+        },
+        _ => String::from("TEXT"),
+    }
+}
+
+/// An attribute value as the SQLite value for its field type.
+pub(crate) fn sqlite_value(value: &JsonValue, field_type: &FieldType) -> Value {
+    match (value, field_type) {
+        (JsonValue::Null, _) => Value::Null,
+        (_, FieldType::Date) => date_text(value).map(Value::Text).unwrap_or(Value::Null),
+        (JsonValue::Boolean(boolean), _) => Value::Integer(*boolean as i64),
+        (JsonValue::Number(_), FieldType::Single) | (JsonValue::Number(_), FieldType::Double) => {
+            value.as_f64().map(Value::Real).unwrap_or(Value::Null)
+        }
+        (JsonValue::Number(_), _) => match value.as_i64() {
+            Some(integer) if value.as_f64() == Some(integer as f64) => Value::Integer(integer),
+            _ => value.as_f64().map(Value::Real).unwrap_or(Value::Null),
+        },
+        _ => match value.as_str() {
+            Some(text) => Value::Text(text.to_string()),
+            None => Value::Text(value.dump()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_identifiers() {
+        assert_eq!("\"NAME\"", quote_identifier("NAME"));
+        assert_eq!("\"my field\"", quote_identifier("my field"));
+        assert_eq!("\"say \"\"hi\"\"\"", quote_identifier("say \"hi\""));
+    }
+
+    #[test]
+    fn converts_values_for_their_field_types() {
+        let value = |value: JsonValue, field_type: FieldType| sqlite_value(&value, &field_type);
+        assert_eq!(Value::Null, value(JsonValue::Null, FieldType::Integer));
+        assert_eq!(Value::Integer(42), value(42.into(), FieldType::Integer));
+        assert_eq!(Value::Integer(42), value(42.into(), FieldType::Oid));
+        // Whole numbers in floating-point fields stay real.
+        assert_eq!(Value::Real(42.0), value(42.into(), FieldType::Double));
+        assert_eq!(Value::Real(2.5), value(2.5.into(), FieldType::Single));
+        // A fraction in an integer field isn't truncated.
+        assert_eq!(Value::Real(2.5), value(2.5.into(), FieldType::Integer));
+        assert_eq!(Value::Integer(1), value(true.into(), FieldType::SmallInteger));
+        assert_eq!(Value::Text(String::from("Lisbon")), value("Lisbon".into(), FieldType::String));
+        assert_eq!(
+            Value::Text(String::from("2020-01-01T00:00:00.000Z")),
+            value(1_577_836_800_000i64.into(), FieldType::Date)
+        );
+        assert_eq!(Value::Null, value("not a date".into(), FieldType::Date));
+        assert_eq!(Value::Text(String::from("[1,2]")), value(json::array![1, 2], FieldType::String));
+    }
+
+    #[test]
+    fn picks_column_types() {
+        let mut name = Field::new("NAME", FieldType::String);
+        assert_eq!("TEXT", sqlite_type(&name));
+        name.length = Some(50);
+        assert_eq!("TEXT(50)", sqlite_type(&name));
+        assert_eq!("INTEGER", sqlite_type(&Field::new("OBJECTID", FieldType::Oid)));
+        assert_eq!("DATETIME", sqlite_type(&Field::new("FOUNDED", FieldType::Date)));
+        assert_eq!("TEXT", sqlite_type(&Field::new("GlobalID", FieldType::GlobalId)));
+    }
+}
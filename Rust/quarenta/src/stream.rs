@@ -0,0 +1,362 @@
+//! Incremental parsing of JSON query responses.
+//!
+//! A query response is one JSON object whose `features` array can run to many megabytes.
+//! Rather than reading it into a `String` and parsing the whole document, [`FeatureStream`]
+//! scans the bytes as they arrive, cuts each element of `features` out as soon as it's
+//! complete, and parses just that element. Memory use is bounded by the largest single feature
+//! rather than the whole response.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::feature::{Feature, FeatureSet};
+use crate::feature_layer::LayerInfo;
+use crate::request::{ArcGisError, LimitedResponse};
+use crate::BoxResult;
+
+/// Features from a query, parsed as the response downloads.
+///
+/// # Examples
+///
+/// ```no_run
+/// # async fn example(layer: quarenta::FeatureLayer) -> Result<(), Box<dyn std::error::Error>> {
+/// let mut features = layer.query_stream(&quarenta::Query::new()).await?;
+/// while let Some(feature) = features.next().await {
+///     println!("{}", feature?.attributes["CITY_NAME"]);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct FeatureStream {
+    response: LimitedResponse,
+    parser: FeatureStreamParser,
+    pending: VecDeque<Feature>,
+    finished: bool,
+    /// Set when coded values are to be replaced with their labels.
+    decode: Option<Arc<LayerInfo>>,
+}
+
+impl FeatureStream {
+    pub(crate) fn new(response: LimitedResponse) -> FeatureStream {
+        FeatureStream {
+            response,
+            parser: FeatureStreamParser::new(),
+            pending: VecDeque::new(),
+            finished: false,
+            decode: None,
+        }
+    }
+
+    pub(crate) fn decode_with(&mut self, info: Arc<LayerInfo>) {
+        self.decode = Some(info);
+    }
+
+    /// Returns the next feature, or `None` once the response is exhausted. An ArcGIS error in
+    /// the response is returned as the last item.
+    pub async fn next(&mut self) -> Option<BoxResult<Feature>> {
+        loop {
+            if let Some(mut feature) = self.pending.pop_front() {
+                if let Some(info) = &self.decode {
+                    info.decode(&mut feature);
+                }
+                return Some(Ok(feature));
+            }
+            if self.finished {
+                return None;
+            }
+            let result = match self.response.chunk().await {
+                Ok(Some(chunk)) => self.parser.push(&chunk),
+                Ok(None) => {
+                    self.finished = true;
+                    self.parser.finish()
+                }
+                Err(err) => {
+                    self.finished = true;
+                    Err(Box::new(err) as Box<dyn std::error::Error>)
+                }
+            };
+            match result {
+                Ok(features) => self.pending.extend(features),
+                Err(err) => {
+                    self.finished = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+
+    /// The feature set's metadata (fields, geometry type, spatial reference and so on) without
+    /// its features. This is complete once `next` has returned `None`; before that, properties
+    /// that follow `features` in the response, such as `exceededTransferLimit`, may be missing.
+    pub fn metadata(&self) -> FeatureSet {
+        self.parser.metadata()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// Before the opening `{` of the response.
+    Start,
+    /// Inside the top-level object, waiting for a key or its end.
+    ExpectKey,
+    /// Inside a top-level key.
+    Key,
+    ExpectColon,
+    ExpectValue,
+    /// Inside a top-level value other than `features`, which is copied to the header.
+    Value,
+    /// Inside the `features` array, between elements.
+    Features,
+    /// Inside an element of `features`.
+    Feature,
+    /// After the closing `}` of the response.
+    Done,
+}
+
+/// A push parser that splits a query response into its features and everything else.
+pub(crate) struct FeatureStreamParser {
+    state: State,
+    /// How deeply nested the scanner is inside the current value or feature.
+    depth: u32,
+    in_string: bool,
+    escaped: bool,
+    key: Vec<u8>,
+    /// Every top-level property except `features`, as the text of a JSON object.
+    header: Vec<u8>,
+    /// The header parsed when `features` started, used to interpret each feature.
+    feature_set: FeatureSet,
+    feature: Vec<u8>,
+}
+
+impl FeatureStreamParser {
+    pub(crate) fn new() -> FeatureStreamParser {
+        FeatureStreamParser {
+            state: State::Start,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            key: Vec::new(),
+            header: vec![b'{'],
+            feature_set: FeatureSet::default(),
+            feature: Vec::new(),
+        }
+    }
+
+    /// Consumes the next chunk of the response and returns any features it completed.
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> BoxResult<Vec<Feature>> {
+        let mut features = Vec::new();
+        for &byte in chunk {
+            if let Some(feature) = self.scan(byte)? {
+                features.push(feature);
+            }
+        }
+        Ok(features)
+    }
+
+    /// Checks that the response ended cleanly and wasn't an ArcGIS error.
+    pub(crate) fn finish(&mut self) -> BoxResult<Vec<Feature>> {
+        let header = self.header_json()?;
+        ArcGisError::check(header)?;
+        if State::Done != self.state {
+            return Err("query response ended before it was complete".into());
+        }
+        Ok(Vec::new())
+    }
+
+    pub(crate) fn metadata(&self) -> FeatureSet {
+        match self.header_json() {
+            Ok(header) => FeatureSet::from_json(&header),
+            Err(_) => self.feature_set.clone(),
+        }
+    }
+
+    fn header_json(&self) -> BoxResult<json::JsonValue> {
+        let mut header = self.header.clone();
+        if b',' == *header.last().unwrap() {
+            header.pop();
+        }
+        header.push(b'}');
+        Ok(json::parse(std::str::from_utf8(&header)?)?)
+    }
+
+    /// Tracks strings and escapes, returning whether the byte is structural (outside a string).
+    fn track_string(&mut self, byte: u8) -> bool {
+        if self.in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if b'\\' == byte {
+                self.escaped = true;
+            } else if b'"' == byte {
+                self.in_string = false;
+            }
+            return false;
+        }
+        if b'"' == byte {
+            self.in_string = true;
+            return false;
+        }
+        true
+    }
+
+    fn scan(&mut self, byte: u8) -> BoxResult<Option<Feature>> {
+        match self.state {
+            State::Start => {
+                if b'{' == byte {
+                    self.state = State::ExpectKey;
+                } else if !byte.is_ascii_whitespace() {
+                    return Err("query response is not a JSON object".into());
+                }
+            }
+            State::ExpectKey => {
+                if b'"' == byte {
+                    self.key.clear();
+                    self.in_string = true;
+                    self.state = State::Key;
+                } else if b'}' == byte {
+                    self.state = State::Done;
+                }
+            }
+            State::Key => {
+                self.track_string(byte);
+                if self.in_string {
+                    self.key.push(byte);
+                } else {
+                    self.state = State::ExpectColon;
+                }
+            }
+            State::ExpectColon => {
+                if b':' == byte {
+                    self.state = State::ExpectValue;
+                }
+            }
+            State::ExpectValue => {
+                if byte.is_ascii_whitespace() {
+                    return Ok(None);
+                }
+                if b"features" == self.key.as_slice() && b'[' == byte {
+                    self.feature_set = FeatureSet::from_json(&self.header_json()?);
+                    self.state = State::Features;
+                    return Ok(None);
+                }
+                self.header.push(b'"');
+                self.header.extend_from_slice(&self.key);
+                self.header.extend_from_slice(b"\":");
+                self.state = State::Value;
+                self.depth = 0;
+                return self.scan(byte);
+            }
+            State::Value => {
+                if self.track_string(byte) {
+                    match byte {
+                        b'{' | b'[' => self.depth += 1,
+                        b'}' | b']' if 0 == self.depth => {
+                            self.header.push(b',');
+                            self.state = State::Done;
+                            return Ok(None);
+                        }
+                        b'}' | b']' => self.depth -= 1,
+                        b',' if 0 == self.depth => {
+                            self.header.push(b',');
+                            self.state = State::ExpectKey;
+                            return Ok(None);
+                        }
+                        _ => {}
+                    }
+                }
+                self.header.push(byte);
+            }
+            State::Features => match byte {
+                b'{' => {
+                    self.feature.clear();
+                    self.feature.push(byte);
+                    self.depth = 1;
+                    self.state = State::Feature;
+                }
+                b']' => self.state = State::ExpectKey,
+                _ => {}
+            },
+            State::Feature => {
+                self.feature.push(byte);
+                if self.track_string(byte) {
+                    match byte {
+                        b'{' | b'[' => self.depth += 1,
+                        b'}' | b']' => {
+                            self.depth -= 1;
+                            if 0 == self.depth {
+                                self.state = State::Features;
+                                let value = json::parse(std::str::from_utf8(&self.feature)?)?;
+                                return Ok(Some(self.feature_set.feature_from_json(&value)));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            State::Done => {}
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Geometry, Point};
+
+    const RESPONSE: &str = r#"{
+        "objectIdFieldName": "OBJECTID",
+        "geometryType": "esriGeometryPoint",
+        "spatialReference": {"wkid": 4326},
+        "fields": [{"name": "OBJECTID", "type": "esriFieldTypeOID"}, {"name": "NAME", "type": "esriFieldTypeString"}],
+        "features": [
+            {"attributes": {"OBJECTID": 1, "NAME": "Praça do \"Comércio\" {1}"}, "geometry": {"x": -9.1366, "y": 38.7075}},
+            {"attributes": {"OBJECTID": 2, "NAME": "São Bento \\ [2]"}, "geometry": {"x": -9.1478, "y": 38.7131}}
+        ],
+        "exceededTransferLimit": true
+    }"#;
+
+    fn parse_in_chunks(text: &str, chunk_size: usize) -> (Vec<Feature>, FeatureSet) {
+        let mut parser = FeatureStreamParser::new();
+        let mut features = Vec::new();
+        for chunk in text.as_bytes().chunks(chunk_size) {
+            features.extend(parser.push(chunk).unwrap());
+        }
+        features.extend(parser.finish().unwrap());
+        (features, parser.metadata())
+    }
+
+    #[test]
+    fn splits_features_whatever_the_chunk_boundaries() {
+        let (whole, _) = parse_in_chunks(RESPONSE, RESPONSE.len());
+        assert_eq!(2, whole.len());
+        assert_eq!("Praça do \"Comércio\" {1}", whole[0].attributes["NAME"]);
+        assert_eq!("São Bento \\ [2]", whole[1].attributes["NAME"]);
+        assert_eq!(Some(Geometry::Point(Point::new(-9.1478, 38.7131))), whole[1].geometry);
+        // Chunks of one byte split every token, escape and multi-byte character.
+        for chunk_size in 1..=13 {
+            let (features, metadata) = parse_in_chunks(RESPONSE, chunk_size);
+            assert_eq!(whole, features, "chunks of {} bytes", chunk_size);
+            assert!(metadata.exceeded_transfer_limit);
+            assert_eq!(Some("OBJECTID"), metadata.object_id_field_name.as_deref());
+            assert!(metadata.features.is_empty());
+        }
+    }
+
+    #[test]
+    fn returns_an_arcgis_error() {
+        let mut parser = FeatureStreamParser::new();
+        let text = r#"{"error": {"code": 400, "message": "Invalid query", "details": []}}"#;
+        for chunk in text.as_bytes().chunks(5) {
+            assert!(parser.push(chunk).unwrap().is_empty());
+        }
+        assert!(parser.finish().is_err());
+    }
+
+    #[test]
+    fn rejects_a_truncated_response() {
+        let mut parser = FeatureStreamParser::new();
+        let cut = RESPONSE.find("São").unwrap() + 1;
+        assert_eq!(1, parser.push(&RESPONSE.as_bytes()[..cut]).unwrap().len());
+        assert!(parser.finish().is_err());
+    }
+}
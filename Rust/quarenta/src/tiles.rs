@@ -0,0 +1,580 @@
+//! Tiled map, image and vector tile services: tiling schemes, fetching tiles, and an on-disk
+//! tile cache.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::stream::{self, StreamExt};
+use json::JsonValue;
+use reqwest::StatusCode;
+
+use crate::geometry::{Envelope, Point, SpatialReference};
+use crate::request::{has_json_body, ArcGisError, Client, RequestOptions};
+use crate::util::write_replacing;
+use crate::BoxResult;
+
+/// A level of detail in a tiling scheme.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lod {
+    pub level: u32,
+    /// Map units per pixel.
+    pub resolution: f64,
+    pub scale: f64,
+}
+
+/// A tile, by level, row and column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TileKey {
+    pub level: u32,
+    pub row: u32,
+    pub col: u32,
+}
+
+/// The tiles at one level that cover an extent, inclusive of both ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TileRange {
+    pub level: u32,
+    pub min_row: u32,
+    pub max_row: u32,
+    pub min_col: u32,
+    pub max_col: u32,
+}
+
+impl TileRange {
+    /// The number of tiles in the range.
+    pub fn len(&self) -> usize {
+        ((self.max_row - self.min_row + 1) as usize) * ((self.max_col - self.min_col + 1) as usize)
+    }
+
+    /// Always `false`: a range has at least one tile.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// The tiles in the range, a row at a time.
+    pub fn tiles(&self) -> impl Iterator<Item = TileKey> {
+        let range = *self;
+        (range.min_row..=range.max_row)
+            .flat_map(move |row| (range.min_col..=range.max_col).map(move |col| TileKey { level: range.level, row, col }))
+    }
+}
+
+/// A service's tiling scheme, from `tileInfo` in its description.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TileInfo {
+    /// The tile height in pixels.
+    pub rows: u32,
+    /// The tile width in pixels.
+    pub cols: u32,
+    pub dpi: Option<u32>,
+    /// e.g. `PNG32`, `JPEG`, `MIXED` or `pbf`.
+    pub format: Option<String>,
+    /// The top left corner of tile row 0, column 0.
+    pub origin: Point,
+    pub spatial_reference: Option<SpatialReference>,
+    pub lods: Vec<Lod>,
+}
+
+impl TileInfo {
+    pub fn from_json(value: &JsonValue) -> Option<TileInfo> {
+        Some(TileInfo {
+            rows: value["rows"].as_u32()?,
+            cols: value["cols"].as_u32()?,
+            dpi: value["dpi"].as_u32(),
+            format: value["format"].as_str().map(String::from),
+            origin: Point::new(value["origin"]["x"].as_f64()?, value["origin"]["y"].as_f64()?),
+            spatial_reference: SpatialReference::from_json(&value["spatialReference"]),
+            lods: value["lods"]
+                .members()
+                .filter_map(|lod| {
+                    Some(Lod {
+                        level: lod["level"].as_u32()?,
+                        resolution: lod["resolution"].as_f64()?,
+                        scale: lod["scale"].as_f64().unwrap_or(0.0),
+                    })
+                })
+                .collect(),
+        })
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut value = json::object! {
+            "rows" => self.rows,
+            "cols" => self.cols,
+            "origin" => json::object! { "x" => self.origin.x, "y" => self.origin.y },
+            "lods" => JsonValue::Array(
+                self.lods
+                    .iter()
+                    .map(|lod| json::object! {
+                        "level" => lod.level,
+                        "resolution" => lod.resolution,
+                        "scale" => lod.scale
+                    })
+                    .collect()
+            )
+        };
+        if let Some(dpi) = self.dpi {
+            value["dpi"] = dpi.into();
+        }
+        if let Some(format) = &self.format {
+            value["format"] = format.as_str().into();
+        }
+        if let Some(spatial_reference) = &self.spatial_reference {
+            value["spatialReference"] = spatial_reference.to_json();
+        }
+        value
+    }
+
+    pub fn lod(&self, level: u32) -> Option<&Lod> {
+        self.lods.iter().find(|lod| lod.level == level)
+    }
+
+    /// The most detailed level whose resolution is no finer than `resolution`, e.g. to fill a
+    /// map of a given size without fetching more tiles than it can show. If every level is
+    /// finer, this is the least detailed one.
+    pub fn level_for_resolution(&self, resolution: f64) -> Option<u32> {
+        self.lods
+            .iter()
+            .filter(|lod| lod.resolution >= resolution)
+            .min_by(|a, b| a.resolution.partial_cmp(&b.resolution).unwrap())
+            .or_else(|| self.lods.iter().max_by(|a, b| a.resolution.partial_cmp(&b.resolution).unwrap()))
+            .map(|lod| lod.level)
+    }
+
+    /// The tile containing a point, in the tiling scheme's spatial reference.
+    pub fn tile_at(&self, point: &Point, level: u32) -> Option<TileKey> {
+        let lod = self.lod(level)?;
+        let col = ((point.x - self.origin.x) / (lod.resolution * f64::from(self.cols))).floor();
+        let row = ((self.origin.y - point.y) / (lod.resolution * f64::from(self.rows))).floor();
+        if col < 0.0 || row < 0.0 {
+            return None;
+        }
+        Some(TileKey { level, row: row as u32, col: col as u32 })
+    }
+
+    /// The tiles that cover an extent, in the tiling scheme's spatial reference. Parts of the
+    /// extent above or left of the origin are left out; returns `None` if that's all of it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let tile_info = quarenta::TileInfo::from_json(&json::parse(r#"{
+    ///     "rows": 256, "cols": 256,
+    ///     "origin": { "x": -20037508.342787, "y": 20037508.342787 },
+    ///     "lods": [{ "level": 1, "resolution": 78271.516964, "scale": 295828763.795777 }]
+    /// }"#).unwrap()).unwrap();
+    /// // A small area around 0, 0 touches all four tiles at level 1.
+    /// let extent = quarenta::Envelope { xmin: -1000.0, ymin: -1000.0, xmax: 1000.0, ymax: 1000.0 };
+    /// let range = tile_info.tile_range(&extent, 1).unwrap();
+    /// assert_eq!((0, 1, 0, 1), (range.min_row, range.max_row, range.min_col, range.max_col));
+    /// ```
+    pub fn tile_range(&self, extent: &Envelope, level: u32) -> Option<TileRange> {
+        let lod = self.lod(level)?;
+        let tile_width = lod.resolution * f64::from(self.cols);
+        let tile_height = lod.resolution * f64::from(self.rows);
+        let min_col = ((extent.xmin - self.origin.x) / tile_width).floor().max(0.0);
+        // An edge that falls exactly on a tile boundary doesn't need the next tile.
+        let max_col = ((extent.xmax - self.origin.x) / tile_width).ceil() - 1.0;
+        let min_row = ((self.origin.y - extent.ymax) / tile_height).floor().max(0.0);
+        let max_row = ((self.origin.y - extent.ymin) / tile_height).ceil() - 1.0;
+        if max_col < min_col || max_row < min_row {
+            return None;
+        }
+        Some(TileRange {
+            level,
+            min_row: min_row as u32,
+            max_row: max_row as u32,
+            min_col: min_col as u32,
+            max_col: max_col as u32,
+        })
+    }
+
+    /// The area a tile covers, in the tiling scheme's spatial reference.
+    pub fn tile_extent(&self, key: &TileKey) -> Option<Envelope> {
+        let lod = self.lod(key.level)?;
+        let tile_width = lod.resolution * f64::from(self.cols);
+        let tile_height = lod.resolution * f64::from(self.rows);
+        let xmin = self.origin.x + f64::from(key.col) * tile_width;
+        let ymax = self.origin.y - f64::from(key.row) * tile_height;
+        Some(Envelope {
+            xmin,
+            ymin: ymax - tile_height,
+            xmax: xmin + tile_width,
+            ymax,
+        })
+    }
+}
+
+/// How a [`TileCache`] lays out its files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TileLayout {
+    /// ArcGIS's exploded cache folders: `L05/R0000000b/C00000012.png`, with the level in
+    /// decimal and the row and column in hex.
+    #[default]
+    Exploded,
+    /// `level/col/row.png`, as most web map libraries expect.
+    Xyz,
+}
+
+/// Tiles stored in folders on disk, for reuse without a connection.
+///
+/// The file extension comes from the tile's contents: `.png` or `.jpg` for images, and `.pbf`
+/// for anything else, which is what vector tiles are.
+#[derive(Clone, Debug)]
+pub struct TileCache {
+    root: PathBuf,
+    layout: TileLayout,
+}
+
+const TILE_EXTENSIONS: &[&str] = &["png", "jpg", "pbf"];
+const TILE_INFO_FILE: &str = "tileInfo.json";
+
+impl TileCache {
+    pub fn new(root: &Path, layout: TileLayout) -> TileCache {
+        TileCache {
+            root: root.to_path_buf(),
+            layout,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where a tile with the given extension is stored.
+    pub fn tile_path(&self, key: &TileKey, extension: &str) -> PathBuf {
+        match self.layout {
+            TileLayout::Exploded => self
+                .root
+                .join(format!("L{:02}", key.level))
+                .join(format!("R{:08x}", key.row))
+                .join(format!("C{:08x}.{}", key.col, extension)),
+            TileLayout::Xyz => self
+                .root
+                .join(key.level.to_string())
+                .join(key.col.to_string())
+                .join(format!("{}.{}", key.row, extension)),
+        }
+    }
+
+    /// The path of a cached tile, if it's in the cache.
+    pub fn find(&self, key: &TileKey) -> Option<PathBuf> {
+        TILE_EXTENSIONS
+            .iter()
+            .map(|extension| self.tile_path(key, extension))
+            .find(|path| path.is_file())
+    }
+
+    pub fn contains(&self, key: &TileKey) -> bool {
+        self.find(key).is_some()
+    }
+
+    /// Reads a tile from the cache, or returns `None` if it isn't there.
+    pub async fn get(&self, key: &TileKey) -> BoxResult<Option<Vec<u8>>> {
+        match self.find(key) {
+            Some(path) => Ok(Some(tokio::fs::read(path).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes a tile to the cache, replacing the file only once it's complete. Returns the
+    /// tile's path.
+    pub async fn put(&self, key: &TileKey, bytes: &[u8]) -> BoxResult<PathBuf> {
+        let path = self.tile_path(key, tile_extension(bytes));
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        write_replacing(&path, bytes).await?;
+        Ok(path)
+    }
+
+    /// Saves the tiling scheme next to the tiles, so the cache can be used on its own.
+    pub async fn save_tile_info(&self, tile_info: &TileInfo) -> BoxResult<()> {
+        tokio::fs::create_dir_all(&self.root).await?;
+        write_replacing(&self.root.join(TILE_INFO_FILE), tile_info.to_json().dump().as_bytes()).await
+    }
+
+    /// Reads the tiling scheme saved by [`TileCache::save_tile_info`].
+    pub async fn tile_info(&self) -> BoxResult<TileInfo> {
+        let text = tokio::fs::read_to_string(self.root.join(TILE_INFO_FILE)).await?;
+        TileInfo::from_json(&json::parse(&text)?).ok_or_else(|| "the cache's tile info is incomplete".into())
+    }
+}
+
+fn tile_extension(bytes: &[u8]) -> &'static str {
+    if bytes.starts_with(b"\x89PNG") {
+        "png"
+    } else if bytes.starts_with(b"\xff\xd8") {
+        "jpg"
+    } else {
+        "pbf"
+    }
+}
+
+/// What [`TileService::cache_tiles`] did with each tile.
+#[derive(Debug, Default)]
+pub struct CacheSummary {
+    /// Tiles that were already in the cache.
+    pub cached: Vec<TileKey>,
+    pub downloaded: Vec<TileKey>,
+    /// Tiles the service doesn't have, usually because they're outside its data.
+    pub missing: Vec<TileKey>,
+    pub failed: Vec<(TileKey, String)>,
+}
+
+#[derive(Debug)]
+struct Description {
+    tile_info: Arc<TileInfo>,
+    /// The tile URL relative to the service, with `{z}`, `{y}` and `{x}` for level, row and
+    /// column.
+    tile_template: String,
+}
+
+/// A tiled map service, image service or vector tile service, identified by its REST URL, e.g.
+/// `https://services.arcgisonline.com/ArcGIS/rest/services/World_Street_Map/MapServer`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::path::Path;
+/// use quarenta::{Client, Envelope, TileCache, TileLayout, TileService};
+///
+/// let service = TileService::new(
+///     &Client::new(),
+///     "https://services.arcgisonline.com/ArcGIS/rest/services/World_Street_Map/MapServer",
+/// );
+/// let tile_info = service.tile_info().await?;
+/// // Around Lisbon, in Web Mercator.
+/// let extent = Envelope { xmin: -1_030_000.0, ymin: 4_670_000.0, xmax: -990_000.0, ymax: 4_700_000.0 };
+/// let range = tile_info.tile_range(&extent, 12).ok_or("extent is outside the tiles")?;
+/// let keys: Vec<_> = range.tiles().collect();
+/// let cache = TileCache::new(Path::new("tiles"), TileLayout::Exploded);
+/// let summary = service.cache_tiles(&cache, &keys, 8).await?;
+/// println!("{} downloaded, {} already cached", summary.downloaded.len(), summary.cached.len());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct TileService {
+    client: Client,
+    url: String,
+    description: Arc<Mutex<Option<Arc<Description>>>>,
+}
+
+impl TileService {
+    pub fn new(client: &Client, url: &str) -> TileService {
+        TileService {
+            client: client.clone(),
+            url: url.trim_end_matches('/').to_string(),
+            description: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Fetches the service's tiling scheme. The result is cached.
+    pub async fn tile_info(&self) -> BoxResult<Arc<TileInfo>> {
+        Ok(self.describe().await?.tile_info.clone())
+    }
+
+    async fn describe(&self) -> BoxResult<Arc<Description>> {
+        if let Some(description) = self.description.lock().unwrap().as_ref() {
+            return Ok(description.clone());
+        }
+        let value = self.client.get_json(&self.url, &[], &RequestOptions::default()).await?;
+        let tile_info = TileInfo::from_json(&value["tileInfo"]).ok_or("the service isn't tiled")?;
+        // Vector tile services give their tile URL; map and image services all use the same.
+        let tile_template = value["tiles"][0].as_str().unwrap_or("tile/{z}/{y}/{x}").to_string();
+        let description = Arc::new(Description {
+            tile_info: Arc::new(tile_info),
+            tile_template,
+        });
+        *self.description.lock().unwrap() = Some(description.clone());
+        Ok(description)
+    }
+
+    /// The URL of a tile.
+    pub async fn tile_url(&self, key: &TileKey) -> BoxResult<String> {
+        let template = &self.describe().await?.tile_template;
+        let path = template
+            .replace("{z}", &key.level.to_string())
+            .replace("{y}", &key.row.to_string())
+            .replace("{x}", &key.col.to_string());
+        Ok(format!("{}/{}", self.url, path))
+    }
+
+    /// Fetches a tile. Returns `None` if the service has no tile there.
+    pub async fn fetch_tile(&self, key: &TileKey) -> BoxResult<Option<Vec<u8>>> {
+        let url = self.tile_url(key).await?;
+        let request = self.client.http().get(&url).query(&self.client.auth_params());
+        let response = self.client.send(request, &RequestOptions::default()).await?;
+        let status = response.status();
+        if StatusCode::NOT_FOUND == status {
+            return Ok(None);
+        }
+        let is_json = has_json_body(response.headers());
+        let bytes = response.bytes().await?;
+        if is_json {
+            // Errors come back as JSON, sometimes with a 200 status.
+            let value = json::parse(&String::from_utf8_lossy(&bytes))?;
+            return match ArcGisError::from_json(&value) {
+                Some(error) if 404 == error.code => Ok(None),
+                Some(error) => Err(error.into()),
+                None => Err("expected a tile but the server returned JSON".into()),
+            };
+        }
+        if !status.is_success() {
+            return Err(format!("tile request failed with HTTP status {}", status).into());
+        }
+        Ok(Some(bytes.to_vec()))
+    }
+
+    /// Fetches tiles, at most `concurrency` at a time, and returns them in the order they
+    /// arrive. The client's per-host limit applies as well.
+    pub async fn fetch_tiles(&self, keys: &[TileKey], concurrency: usize) -> Vec<(TileKey, BoxResult<Option<Vec<u8>>>)> {
+        stream::iter(keys.iter().copied())
+            .map(|key| async move { (key, self.fetch_tile(&key).await) })
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// Downloads the tiles that aren't already in the cache, at most `concurrency` at a time,
+    /// and saves the tiling scheme with them. Each tile is written as soon as it arrives, so
+    /// only the tiles in flight are held in memory. A tile that fails doesn't stop the others;
+    /// see [`CacheSummary::failed`].
+    pub async fn cache_tiles(&self, cache: &TileCache, keys: &[TileKey], concurrency: usize) -> BoxResult<CacheSummary> {
+        cache.save_tile_info(&*self.tile_info().await?).await?;
+        let (cached, uncached): (Vec<TileKey>, Vec<TileKey>) = keys.iter().partition(|key| cache.contains(key));
+        let summary = Mutex::new(CacheSummary {
+            cached,
+            ..CacheSummary::default()
+        });
+        stream::iter(uncached)
+            .for_each_concurrent(concurrency.max(1), |key| {
+                let summary = &summary;
+                async move {
+                    let result = match self.fetch_tile(&key).await {
+                        Ok(Some(bytes)) => cache.put(&key, &bytes).await.map(|_| true),
+                        Ok(None) => Ok(false),
+                        Err(err) => Err(err),
+                    };
+                    let mut summary = summary.lock().unwrap();
+                    match result {
+                        Ok(true) => summary.downloaded.push(key),
+                        Ok(false) => summary.missing.push(key),
+                        Err(err) => summary.failed.push((key, err.to_string())),
+                    }
+                }
+            })
+            .await;
+        Ok(summary.into_inner().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: f64 = 20_037_508.342787;
+
+    /// Web Mercator's scheme at levels 0 to 2, as ArcGIS Online basemaps describe it.
+    fn web_mercator() -> TileInfo {
+        TileInfo {
+            rows: 256,
+            cols: 256,
+            dpi: Some(96),
+            format: Some(String::from("PNG")),
+            origin: Point::new(-ORIGIN, ORIGIN),
+            spatial_reference: Some(SpatialReference::from_wkid(3857)),
+            lods: (0..3)
+                .map(|level| Lod {
+                    level,
+                    resolution: 156_543.033928 / f64::from(1 << level),
+                    scale: 591_657_527.591555 / f64::from(1 << level),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn finds_the_tile_at_a_point() {
+        let tile_info = web_mercator();
+        assert_eq!(Some(TileKey { level: 0, row: 0, col: 0 }), tile_info.tile_at(&Point::new(0.0, 0.0), 0));
+        // Lisbon is just west of the meridian and north of the equator.
+        let lisbon = Point::new(-1_017_460.15, 4_684_496.94);
+        assert_eq!(Some(TileKey { level: 2, row: 1, col: 1 }), tile_info.tile_at(&lisbon, 2));
+        // Sydney is east and south.
+        let sydney = Point::new(16_832_259.0, -4_011_415.0);
+        assert_eq!(Some(TileKey { level: 2, row: 2, col: 3 }), tile_info.tile_at(&sydney, 2));
+        assert_eq!(None, tile_info.tile_at(&Point::new(-ORIGIN - 1.0, 0.0), 2));
+        assert_eq!(None, tile_info.tile_at(&lisbon, 3));
+    }
+
+    #[test]
+    fn covers_an_extent_without_spilling_over_edges() {
+        // Tiles 256 units across, with round numbers so edges fall exactly on boundaries.
+        let tile_info = TileInfo {
+            origin: Point::new(0.0, 1024.0),
+            spatial_reference: None,
+            lods: vec![Lod { level: 0, resolution: 1.0, scale: 1.0 }],
+            ..web_mercator()
+        };
+        let extent = Envelope { xmin: 256.0, ymin: 512.0, xmax: 512.0, ymax: 768.0 };
+        let range = tile_info.tile_range(&extent, 0).unwrap();
+        assert_eq!((1, 1, 1, 1), (range.min_row, range.max_row, range.min_col, range.max_col));
+        assert_eq!(1, range.len());
+
+        let extent = Envelope { xmin: 255.0, ymin: 511.0, xmax: 513.0, ymax: 769.0 };
+        let range = tile_info.tile_range(&extent, 0).unwrap();
+        assert_eq!((0, 2, 0, 2), (range.min_row, range.max_row, range.min_col, range.max_col));
+        let keys: Vec<TileKey> = range.tiles().collect();
+        assert_eq!(9, keys.len());
+        assert_eq!(TileKey { level: 0, row: 0, col: 1 }, keys[1]);
+        assert_eq!(TileKey { level: 0, row: 1, col: 0 }, keys[3]);
+
+        // Anything above or left of the origin is left out.
+        let extent = Envelope { xmin: -100.0, ymin: 1000.0, xmax: 100.0, ymax: 1100.0 };
+        let range = tile_info.tile_range(&extent, 0).unwrap();
+        assert_eq!((0, 0, 0, 0), (range.min_row, range.max_row, range.min_col, range.max_col));
+        let extent = Envelope { xmin: -100.0, ymin: 1100.0, xmax: -1.0, ymax: 1200.0 };
+        assert_eq!(None, tile_info.tile_range(&extent, 0));
+    }
+
+    #[test]
+    fn tile_extent_matches_tile_at() {
+        let tile_info = web_mercator();
+        let key = TileKey { level: 2, row: 3, col: 2 };
+        let extent = tile_info.tile_extent(&key).unwrap();
+        assert!((extent.xmax - extent.xmin - ORIGIN / 2.0).abs() < 0.01);
+        assert!((extent.ymin - -ORIGIN).abs() < 0.01);
+        let center = Point::new((extent.xmin + extent.xmax) / 2.0, (extent.ymin + extent.ymax) / 2.0);
+        assert_eq!(Some(key), tile_info.tile_at(&center, 2));
+    }
+
+    #[test]
+    fn picks_a_level_for_a_resolution() {
+        let tile_info = web_mercator();
+        assert_eq!(Some(1), tile_info.level_for_resolution(50_000.0));
+        // Coarser than the first level gets the first level, and finer than the last gets the
+        // last.
+        assert_eq!(Some(0), tile_info.level_for_resolution(1_000_000.0));
+        assert_eq!(Some(2), tile_info.level_for_resolution(1.0));
+    }
+
+    #[test]
+    fn lays_out_cache_paths() {
+        let key = TileKey { level: 5, row: 11, col: 18 };
+        let exploded = TileCache::new(Path::new("tiles"), TileLayout::Exploded);
+        assert_eq!(Path::new("tiles/L05/R0000000b/C00000012.png"), exploded.tile_path(&key, "png"));
+        let xyz = TileCache::new(Path::new("tiles"), TileLayout::Xyz);
+        assert_eq!(Path::new("tiles/5/18/11.pbf"), xyz.tile_path(&key, "pbf"));
+    }
+}
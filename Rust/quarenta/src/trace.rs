@@ -0,0 +1,170 @@
+//! `tracing` instrumentation for the request pipeline.
+//!
+//! Every request sent through [`crate::Client`] runs inside an `arcgis_request` span that
+//! records the operation, redacted URL, HTTP status, duration, attempt count, record count,
+//! and ArcGIS error code. Install any `tracing` subscriber to see them.
+//!
+//! Credentials never reach a span or event: URL parameters that carry secrets are replaced
+//! with `REDACTED`, form bodies are never recorded, and error messages are scrubbed the same
+//! way before they are logged.
+
+use json::JsonValue;
+use reqwest::{Request, Url};
+use tracing::field::Empty;
+use tracing::Span;
+
+const REDACTED: &str = "REDACTED";
+
+/// URL and form parameters whose values must never be logged.
+const SECRET_PARAMETERS: &[&str] = &[
+    "token",
+    "password",
+    "client_secret",
+    "access_token",
+    "refresh_token",
+    "code",
+];
+
+/// Creates the span for one logical request, which covers all of its retries.
+pub(crate) fn request_span(request: &Request) -> Span {
+    tracing::info_span!(
+        "arcgis_request",
+        operation = %operation(request.url()),
+        method = %request.method(),
+        url = %redact_url(request.url()),
+        status = Empty,
+        duration_ms = Empty,
+        attempts = Empty,
+        record_count = Empty,
+        error_code = Empty,
+    )
+}
+
+/// Records what can be learned from a JSON response body on the current span.
+pub(crate) fn record_json(value: &JsonValue) {
+    let span = Span::current();
+    if let Some(count) = record_count(value) {
+        span.record("record_count", count as u64);
+    }
+    if let Some(code) = value["error"]["code"].as_i64() {
+        span.record("error_code", code);
+    }
+}
+
+/// The name of the REST operation, which is the last path segment, e.g. `query`,
+/// `applyEdits`, or `generateToken`.
+pub(crate) fn operation(url: &Url) -> String {
+    url.path_segments()
+        .and_then(|mut segments| segments.rfind(|segment| !segment.is_empty()))
+        .unwrap_or("")
+        .to_string()
+}
+
+/// Returns the URL as a string with the values of secret query parameters replaced.
+pub(crate) fn redact_url(url: &Url) -> String {
+    if url.query().is_none() {
+        return url.to_string();
+    }
+    let mut redacted = url.clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(name, value)| {
+            let value = if is_secret(&name) { REDACTED.to_string() } else { value.into_owned() };
+            (name.into_owned(), value)
+        })
+        .collect();
+    redacted.query_pairs_mut().clear().extend_pairs(pairs);
+    redacted.to_string()
+}
+
+/// Scrubs `name=value` secrets from free text, such as a `reqwest` error message that embeds
+/// the request URL.
+pub(crate) fn redact_text(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(equals) = rest.find('=') {
+        let (before, after) = rest.split_at(equals + 1);
+        let name = before[..equals]
+            .rsplit(|c: char| !(c.is_ascii_alphanumeric() || '_' == c))
+            .next()
+            .unwrap_or("");
+        redacted.push_str(before);
+        rest = after;
+        if is_secret(name) {
+            let value_end = rest
+                .find(|c: char| '&' == c || ')' == c || c.is_whitespace())
+                .unwrap_or(rest.len());
+            redacted.push_str(REDACTED);
+            rest = &rest[value_end..];
+        }
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+fn is_secret(name: &str) -> bool {
+    SECRET_PARAMETERS.iter().any(|secret| secret.eq_ignore_ascii_case(name))
+}
+
+/// The number of records in a response: features from a query, the count from a count-only
+/// query, or edit results from `addFeatures`, `updateFeatures`, `deleteFeatures` and
+/// `applyEdits`.
+fn record_count(value: &JsonValue) -> Option<usize> {
+    if value["features"].is_array() {
+        return Some(value["features"].len());
+    }
+    if let Some(count) = value["count"].as_usize() {
+        return Some(count);
+    }
+    let edit_results = ["addResults", "updateResults", "deleteResults"];
+    if edit_results.iter().any(|key| value[*key].is_array()) {
+        return Some(edit_results.iter().map(|key| value[*key].len()).sum());
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_secret_query_parameters() {
+        let url = Url::parse("https://example.com/arcgis/rest/services/Parcels/FeatureServer/0/query?where=1%3D1&TOKEN=abc123&f=json&Client_Secret=s3cr3t").unwrap();
+        assert_eq!(
+            redact_url(&url),
+            "https://example.com/arcgis/rest/services/Parcels/FeatureServer/0/query?where=1%3D1&TOKEN=REDACTED&f=json&Client_Secret=REDACTED"
+        );
+    }
+
+    #[test]
+    fn leaves_urls_without_secrets_alone() {
+        let url = Url::parse("https://example.com/arcgis/rest/services?f=json&tokenized=yes").unwrap();
+        assert_eq!(redact_url(&url), "https://example.com/arcgis/rest/services?f=json&tokenized=yes");
+        let url = Url::parse("https://example.com/arcgis/rest/info").unwrap();
+        assert_eq!(redact_url(&url), "https://example.com/arcgis/rest/info");
+    }
+
+    #[test]
+    fn redacts_secrets_in_form_bodies_and_messages() {
+        assert_eq!(
+            redact_text("username=jo&Password=hunter2&referer=app&f=json"),
+            "username=jo&Password=REDACTED&referer=app&f=json"
+        );
+        assert_eq!(
+            redact_text("error sending request for url (https://example.com/query?f=json&token=abc.def): timed out"),
+            "error sending request for url (https://example.com/query?f=json&token=REDACTED): timed out"
+        );
+        assert_eq!(redact_text("refresh_token=xyz"), "refresh_token=REDACTED");
+        assert_eq!(redact_text("where=code = 'A'&outFields=*"), "where=code = 'A'&outFields=*");
+        assert_eq!(redact_text("no parameters here"), "no parameters here");
+    }
+
+    #[test]
+    fn knows_secrets_regardless_of_case() {
+        assert!(is_secret("token"));
+        assert!(is_secret("ACCESS_TOKEN"));
+        assert!(is_secret("Code"));
+        assert!(!is_secret("tokens"));
+        assert!(!is_secret("username"));
+    }
+}
@@ -0,0 +1,316 @@
+//! Typed features: attributes as the caller's `serde` structs and geometries as specific
+//! geometry types.
+//!
+//! Responses are parsed into the untyped [`Feature`] and [`FeatureSet`] first, whichever
+//! format the server used, and then converted. Attributes go through `serde_json::Value`, so
+//! any struct that derives `Deserialize` (for queries) or `Serialize` (for edits) works,
+//! including `#[serde(rename = "...")]` for field names that aren't Rust names.
+
+use json::JsonValue;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::edit::EditResult;
+use crate::feature::{Feature, FeatureSet};
+use crate::feature_layer::{FeatureLayer, Query};
+use crate::geometry::{Envelope, Geometry, Multipoint, Point, Polygon, Polyline, SpatialReference};
+use crate::replica::LayerChanges;
+use crate::BoxResult;
+
+/// 2^53, above which not every integer is an `f64`.
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// A geometry type a typed feature can hold: [`Geometry`] for any geometry, or one of the
+/// specific types when a layer only has one.
+pub trait GeometryValue: Sized {
+    /// Converts from a parsed geometry, or returns `None` if it's a different type.
+    fn from_geometry(geometry: Geometry) -> Option<Self>;
+    fn to_geometry(&self) -> Geometry;
+}
+
+impl GeometryValue for Geometry {
+    fn from_geometry(geometry: Geometry) -> Option<Self> {
+        Some(geometry)
+    }
+
+    fn to_geometry(&self) -> Geometry {
+        self.clone()
+    }
+}
+
+macro_rules! geometry_value {
+    ($type:ident) => {
+        impl GeometryValue for $type {
+            fn from_geometry(geometry: Geometry) -> Option<Self> {
+                match geometry {
+                    Geometry::$type(geometry) => Some(geometry),
+                    _ => None,
+                }
+            }
+
+            fn to_geometry(&self) -> Geometry {
+                Geometry::$type(self.clone())
+            }
+        }
+    };
+}
+
+geometry_value!(Point);
+geometry_value!(Multipoint);
+geometry_value!(Polyline);
+geometry_value!(Polygon);
+geometry_value!(Envelope);
+
+pub(crate) fn to_serde(value: &JsonValue) -> serde_json::Value {
+    match value {
+        JsonValue::Null => serde_json::Value::Null,
+        JsonValue::Boolean(value) => serde_json::Value::Bool(*value),
+        JsonValue::Number(_) => match (value.as_i64(), value.as_u64()) {
+            (Some(number), _) if value.as_f64() == Some(number as f64) => number.into(),
+            (_, Some(number)) if value.as_f64() == Some(number as f64) => number.into(),
+            // `json` reads `3.0` with an exponent that `as_i64` ignores, so whole numbers written
+            // with a fraction end up here, and are integers for serde as long as they're exact.
+            _ => match value.as_f64().unwrap_or(0.0) {
+                number if 0.0 == number.fract() && number.abs() < MAX_EXACT_INTEGER => (number as i64).into(),
+                number => serde_json::Number::from_f64(number)
+                    .map(serde_json::Value::Number)
+                    .unwrap_or(serde_json::Value::Null),
+            },
+        },
+        JsonValue::Short(_) | JsonValue::String(_) => serde_json::Value::String(value.as_str().unwrap_or("").to_string()),
+        JsonValue::Array(values) => serde_json::Value::Array(values.iter().map(to_serde).collect()),
+        JsonValue::Object(object) => serde_json::Value::Object(
+            object
+                .iter()
+                .map(|(key, value)| (key.to_string(), to_serde(value)))
+                .collect(),
+        ),
+    }
+}
+
+pub(crate) fn from_serde(value: serde_json::Value) -> JsonValue {
+    match value {
+        serde_json::Value::Null => JsonValue::Null,
+        serde_json::Value::Bool(value) => value.into(),
+        serde_json::Value::Number(number) => match (number.as_i64(), number.as_u64()) {
+            (Some(number), _) => number.into(),
+            (_, Some(number)) => number.into(),
+            _ => number.as_f64().unwrap_or(0.0).into(),
+        },
+        serde_json::Value::String(value) => value.into(),
+        serde_json::Value::Array(values) => JsonValue::Array(values.into_iter().map(from_serde).collect()),
+        serde_json::Value::Object(object) => {
+            let mut value = JsonValue::new_object();
+            for (key, member) in object {
+                value[key.as_str()] = from_serde(member);
+            }
+            value
+        }
+    }
+}
+
+impl Feature {
+    /// Converts to a typed feature, deserializing the attributes into `A` and checking that the
+    /// geometry, if any, is a `G`.
+    pub fn into_typed<A: DeserializeOwned, G: GeometryValue>(self) -> BoxResult<Feature<A, G>> {
+        let attributes = serde_json::from_value(to_serde(&self.attributes))?;
+        let geometry = match self.geometry {
+            Some(geometry) => {
+                let geometry_type = geometry.geometry_type();
+                Some(G::from_geometry(geometry).ok_or_else(|| {
+                    format!("a {} doesn't fit the feature's geometry type", geometry_type.as_str())
+                })?)
+            }
+            None => None,
+        };
+        Ok(Feature { attributes, geometry })
+    }
+}
+
+impl<A: Serialize, G: GeometryValue> Feature<A, G> {
+    /// Converts a typed feature back to JSON attributes and a [`Geometry`], as edits send it.
+    pub fn to_untyped(&self) -> BoxResult<Feature> {
+        Ok(Feature {
+            attributes: from_serde(serde_json::to_value(&self.attributes)?),
+            geometry: self.geometry.as_ref().map(G::to_geometry),
+        })
+    }
+}
+
+impl FeatureSet {
+    /// Converts every feature with [`Feature::into_typed`], keeping the metadata.
+    pub fn into_typed<A: DeserializeOwned, G: GeometryValue>(self) -> BoxResult<FeatureSet<A, G>> {
+        let features = self
+            .features
+            .into_iter()
+            .map(Feature::into_typed)
+            .collect::<BoxResult<Vec<_>>>()?;
+        Ok(FeatureSet {
+            object_id_field_name: self.object_id_field_name,
+            global_id_field_name: self.global_id_field_name,
+            geometry_type: self.geometry_type,
+            spatial_reference: self.spatial_reference,
+            has_z: self.has_z,
+            has_m: self.has_m,
+            fields: self.fields,
+            features,
+            exceeded_transfer_limit: self.exceeded_transfer_limit,
+        })
+    }
+}
+
+impl LayerChanges {
+    /// Converts the adds and updates with [`Feature::into_typed`].
+    pub fn into_typed<A: DeserializeOwned, G: GeometryValue>(self) -> BoxResult<LayerChanges<A, G>> {
+        Ok(LayerChanges {
+            layer_id: self.layer_id,
+            adds: self.adds.into_iter().map(Feature::into_typed).collect::<BoxResult<_>>()?,
+            updates: self.updates.into_iter().map(Feature::into_typed).collect::<BoxResult<_>>()?,
+            delete_ids: self.delete_ids,
+            delete_global_ids: self.delete_global_ids,
+        })
+    }
+}
+
+fn untyped<A: Serialize, G: GeometryValue>(features: &[Feature<A, G>]) -> BoxResult<Vec<Feature>> {
+    features.iter().map(Feature::to_untyped).collect()
+}
+
+impl FeatureLayer {
+    /// Like [`FeatureLayer::query`], with the features as `Feature<A, G>`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn example(layer: quarenta::FeatureLayer) -> Result<(), Box<dyn std::error::Error>> {
+    /// #[derive(serde::Deserialize)]
+    /// struct City {
+    ///     city: String,
+    ///     population: Option<u32>,
+    /// }
+    ///
+    /// let cities = layer
+    ///     .query_as::<City, quarenta::Point>(&quarenta::Query::new().where_clause("population > 1000000"))
+    ///     .await?;
+    /// for feature in cities.features {
+    ///     println!("{} has {:?} people", feature.attributes.city, feature.attributes.population);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn query_as<A: DeserializeOwned, G: GeometryValue>(&self, query: &Query) -> BoxResult<FeatureSet<A, G>> {
+        self.query(query).await?.into_typed()
+    }
+
+    /// Like [`FeatureLayer::add_features`], with the features as `Feature<A, G>`.
+    pub async fn add_features_as<A: Serialize, G: GeometryValue>(
+        &self,
+        features: &[Feature<A, G>],
+        spatial_reference: Option<&SpatialReference>,
+    ) -> BoxResult<Vec<EditResult>> {
+        self.add_features(&untyped(features)?, spatial_reference).await
+    }
+
+    /// Like [`FeatureLayer::update_features`], with the features as `Feature<A, G>`.
+    pub async fn update_features_as<A: Serialize, G: GeometryValue>(
+        &self,
+        features: &[Feature<A, G>],
+        spatial_reference: Option<&SpatialReference>,
+    ) -> BoxResult<Vec<EditResult>> {
+        self.update_features(&untyped(features)?, spatial_reference).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct City {
+        #[serde(rename = "CITY_NAME")]
+        name: String,
+        population: Option<u32>,
+        capital: bool,
+    }
+
+    fn city_feature() -> Feature {
+        Feature::new(
+            json::object! { "CITY_NAME" => "Lisboa", "population" => 545_000, "capital" => true },
+            Some(Geometry::Point(Point::new(-9.14, 38.72))),
+        )
+    }
+
+    #[test]
+    fn converts_to_serde_values() {
+        let value = json::parse(r#"{ "a": 1, "b": -2, "c": 2.5, "d": 18446744073709551615, "e": null, "f": [true, "x"], "g": 3.0 }"#).unwrap();
+        let converted = to_serde(&value);
+        assert_eq!(
+            converted,
+            serde_json::json!({ "a": 1, "b": -2, "c": 2.5, "d": 18_446_744_073_709_551_615u64, "e": null, "f": [true, "x"], "g": 3 })
+        );
+        assert!(converted["a"].is_i64());
+        assert!(converted["c"].is_f64());
+        assert_eq!(to_serde(&JsonValue::from("short")), serde_json::json!("short"));
+        assert_eq!(to_serde(&JsonValue::from("a string long enough not to be a short one")), serde_json::json!("a string long enough not to be a short one"));
+    }
+
+    #[test]
+    fn converts_from_serde_values() {
+        let value = serde_json::json!({ "a": 1, "b": -2, "c": 2.5, "d": 18_446_744_073_709_551_615u64, "e": null, "f": [true, "x"] });
+        let converted = from_serde(value.clone());
+        assert_eq!(converted["a"], 1);
+        assert_eq!(converted["b"], -2);
+        assert_eq!(converted["c"], 2.5);
+        assert_eq!(converted["d"].as_u64(), Some(u64::MAX));
+        assert!(converted["e"].is_null());
+        assert_eq!(converted["f"], json::array![true, "x"]);
+        assert_eq!(to_serde(&converted), value);
+    }
+
+    #[test]
+    fn converts_features_to_typed_features() {
+        let typed = city_feature().into_typed::<City, Point>().unwrap();
+        assert_eq!(
+            typed.attributes,
+            City {
+                name: String::from("Lisboa"),
+                population: Some(545_000),
+                capital: true,
+            }
+        );
+        assert_eq!(typed.geometry, Some(Point::new(-9.14, 38.72)));
+        assert_eq!(typed.to_untyped().unwrap(), city_feature());
+
+        let any = city_feature().into_typed::<City, Geometry>().unwrap();
+        assert_eq!(any.geometry, city_feature().geometry);
+        let none = Feature::new(city_feature().attributes, None).into_typed::<City, Polygon>().unwrap();
+        assert_eq!(none.geometry, None);
+    }
+
+    #[test]
+    fn refuses_features_that_do_not_fit() {
+        let err = city_feature().into_typed::<City, Polygon>().unwrap_err();
+        assert_eq!(err.to_string(), "a esriGeometryPoint doesn't fit the feature's geometry type");
+        let mut feature = city_feature();
+        feature.attributes["population"] = "many".into();
+        assert!(feature.into_typed::<City, Point>().is_err());
+    }
+
+    #[test]
+    fn converts_feature_sets_keeping_their_metadata() {
+        let mut feature_set = FeatureSet::from_json(&json::object! {
+            "objectIdFieldName" => "OBJECTID",
+            "geometryType" => "esriGeometryPoint",
+            "spatialReference" => json::object! { "wkid" => 4326 },
+            "exceededTransferLimit" => true
+        });
+        feature_set.features = vec![city_feature(), city_feature()];
+        let typed = feature_set.into_typed::<City, Point>().unwrap();
+        assert_eq!(typed.features.len(), 2);
+        assert_eq!(typed.object_id_field_name.as_deref(), Some("OBJECTID"));
+        assert_eq!(typed.spatial_reference.and_then(|spatial_reference| spatial_reference.wkid), Some(4326));
+        assert!(typed.exceeded_transfer_limit);
+    }
+}
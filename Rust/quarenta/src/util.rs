@@ -0,0 +1,15 @@
+//! Small helpers shared by modules that have nothing else in common.
+
+use std::path::Path;
+
+use crate::BoxResult;
+
+/// Writes a file by writing a temporary file beside it and renaming that over it, so a reader
+/// never sees it half written and a failed write leaves the old contents.
+pub(crate) async fn write_replacing(path: &Path, bytes: &[u8]) -> BoxResult<()> {
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+    tokio::fs::write(&temporary, bytes).await?;
+    tokio::fs::rename(&temporary, path).await?;
+    Ok(())
+}
@@ -0,0 +1,325 @@
+//! Typed attribute values: dates, times, timestamps and GUIDs as ArcGIS encodes them in JSON.
+
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveTime, SecondsFormat, TimeZone, Utc};
+use json::JsonValue;
+use uuid::Uuid;
+
+use crate::feature::Feature;
+
+/// A Rust type an attribute value converts to and from.
+///
+/// | Field type | Rust type | JSON |
+/// |---|---|---|
+/// | `esriFieldTypeDate` | `DateTime<Utc>` | epoch milliseconds |
+/// | `esriFieldTypeDateOnly` | `NaiveDate` | `"2024-01-31"` |
+/// | `esriFieldTypeTimeOnly` | `NaiveTime` | `"13:45:00"` |
+/// | `esriFieldTypeTimestampOffset` | `DateTime<FixedOffset>` | `"2024-01-31T13:45:00.000-05:00"` |
+/// | `esriFieldTypeGUID`, `esriFieldTypeGlobalID` | `Uuid` | `"{8D8C...}"` |
+pub trait AttributeValue: Sized {
+    fn from_attribute(value: &JsonValue) -> Option<Self>;
+    fn to_attribute(&self) -> JsonValue;
+}
+
+impl AttributeValue for DateTime<Utc> {
+    fn from_attribute(value: &JsonValue) -> Option<Self> {
+        match value.as_i64() {
+            Some(millis) => Utc.timestamp_millis_opt(millis).single(),
+            None => DateTime::parse_from_rfc3339(value.as_str()?)
+                .ok()
+                .map(|date| date.with_timezone(&Utc)),
+        }
+    }
+
+    fn to_attribute(&self) -> JsonValue {
+        self.timestamp_millis().into()
+    }
+}
+
+impl AttributeValue for NaiveDate {
+    fn from_attribute(value: &JsonValue) -> Option<Self> {
+        NaiveDate::parse_from_str(value.as_str()?, "%Y-%m-%d").ok()
+    }
+
+    fn to_attribute(&self) -> JsonValue {
+        self.format("%Y-%m-%d").to_string().into()
+    }
+}
+
+impl AttributeValue for NaiveTime {
+    fn from_attribute(value: &JsonValue) -> Option<Self> {
+        NaiveTime::parse_from_str(value.as_str()?, "%H:%M:%S%.f").ok()
+    }
+
+    /// Fractional seconds are kept, and left out when there are none.
+    fn to_attribute(&self) -> JsonValue {
+        self.format("%H:%M:%S%.f").to_string().into()
+    }
+}
+
+impl AttributeValue for DateTime<FixedOffset> {
+    fn from_attribute(value: &JsonValue) -> Option<Self> {
+        DateTime::parse_from_rfc3339(value.as_str()?).ok()
+    }
+
+    fn to_attribute(&self) -> JsonValue {
+        self.to_rfc3339_opts(SecondsFormat::Millis, false).into()
+    }
+}
+
+impl AttributeValue for Uuid {
+    fn from_attribute(value: &JsonValue) -> Option<Self> {
+        let text = value.as_str()?.trim_start_matches('{').trim_end_matches('}');
+        Uuid::parse_str(text).ok()
+    }
+
+    /// ArcGIS writes GUIDs braced and in upper case.
+    fn to_attribute(&self) -> JsonValue {
+        format!("{{{}}}", self.to_hyphenated()).to_uppercase().into()
+    }
+}
+
+impl AttributeValue for String {
+    fn from_attribute(value: &JsonValue) -> Option<Self> {
+        value.as_str().map(String::from)
+    }
+
+    fn to_attribute(&self) -> JsonValue {
+        self.as_str().into()
+    }
+}
+
+impl AttributeValue for i64 {
+    fn from_attribute(value: &JsonValue) -> Option<Self> {
+        value.as_i64()
+    }
+
+    fn to_attribute(&self) -> JsonValue {
+        (*self).into()
+    }
+}
+
+impl AttributeValue for f64 {
+    fn from_attribute(value: &JsonValue) -> Option<Self> {
+        value.as_f64()
+    }
+
+    fn to_attribute(&self) -> JsonValue {
+        (*self).into()
+    }
+}
+
+impl AttributeValue for bool {
+    fn from_attribute(value: &JsonValue) -> Option<Self> {
+        value.as_bool()
+    }
+
+    fn to_attribute(&self) -> JsonValue {
+        (*self).into()
+    }
+}
+
+impl Feature {
+    /// Reads an attribute as a typed value, matching the field name exactly or else ignoring
+    /// case. Returns `None` if the attribute is missing, null or doesn't convert.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chrono::{DateTime, Utc};
+    /// use quarenta::Feature;
+    ///
+    /// let mut attributes = json::JsonValue::new_object();
+    /// attributes["Reported"] = 1_577_836_800_000i64.into();
+    /// let feature = Feature::new(attributes, None);
+    /// let reported: DateTime<Utc> = feature.attribute("reported").unwrap();
+    /// assert_eq!("2020-01-01T00:00:00+00:00", reported.to_rfc3339());
+    /// ```
+    pub fn attribute<T: AttributeValue>(&self, field: &str) -> Option<T> {
+        let value = if self.attributes.has_key(field) {
+            &self.attributes[field]
+        } else {
+            self.attributes
+                .entries()
+                .find(|(name, _)| name.eq_ignore_ascii_case(field))
+                .map(|(_, value)| value)?
+        };
+        T::from_attribute(value)
+    }
+
+    /// Sets an attribute from a typed value, encoded the way ArcGIS expects.
+    pub fn set_attribute<T: AttributeValue>(&mut self, field: &str, value: &T) {
+        if !self.attributes.is_object() {
+            self.attributes = JsonValue::new_object();
+        }
+        self.attributes[field] = value.to_attribute();
+    }
+}
+
+/// `serde` support for struct members of [`AttributeValue`] types, encoded the way ArcGIS
+/// encodes them, for use with `#[serde(with = "quarenta::as_attribute")]`. Use
+/// `quarenta::as_attribute::option` for `Option` members, along with `#[serde(default)]`.
+///
+/// # Examples
+///
+/// ```
+/// use chrono::{DateTime, Utc};
+///
+/// #[derive(serde::Deserialize)]
+/// struct Inspection {
+///     #[serde(with = "quarenta::as_attribute")]
+///     inspected: DateTime<Utc>,
+///     #[serde(default, with = "quarenta::as_attribute::option")]
+///     global_id: Option<uuid::Uuid>,
+/// }
+///
+/// let attributes = json::object! { "inspected" => 1_577_836_800_000i64, "global_id" => json::Null };
+/// let feature = quarenta::Feature::new(attributes, None).into_typed::<Inspection, quarenta::Geometry>().unwrap();
+/// assert_eq!("2020-01-01T00:00:00+00:00", feature.attributes.inspected.to_rfc3339());
+/// assert_eq!(None, feature.attributes.global_id);
+/// ```
+pub mod as_attribute {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::AttributeValue;
+    use crate::typed::{from_serde, to_serde};
+
+    pub fn serialize<T: AttributeValue, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        to_serde(&value.to_attribute()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: AttributeValue, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        let value = from_serde(serde_json::Value::deserialize(deserializer)?);
+        T::from_attribute(&value).ok_or_else(|| D::Error::custom(format!("{} isn't a valid value", value)))
+    }
+
+    /// Like [`as_attribute`](self), for `Option` members. Nulls are `None`.
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<T: AttributeValue, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+            match value {
+                Some(value) => super::serialize(value, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, T: AttributeValue, D: Deserializer<'de>>(deserializer: D) -> Result<Option<T>, D::Error> {
+            let value = from_serde(serde_json::Value::deserialize(deserializer)?);
+            if value.is_null() {
+                return Ok(None);
+            }
+            T::from_attribute(&value)
+                .map(Some)
+                .ok_or_else(|| D::Error::custom(format!("{} isn't a valid value", value)))
+        }
+    }
+}
+
+/// An epoch-milliseconds date as an ISO 8601 timestamp in UTC.
+pub(crate) fn date_text(value: &JsonValue) -> Option<String> {
+    DateTime::<Utc>::from_attribute(value).map(|date| date.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+/// Encodes a `time` parameter: a single instant, or an extent with either end open.
+pub(crate) fn time_param(start: Option<&DateTime<Utc>>, end: Option<&DateTime<Utc>>) -> String {
+    let millis = |time: Option<&DateTime<Utc>>| {
+        time.map(|time| time.timestamp_millis().to_string())
+            .unwrap_or_else(|| String::from("null"))
+    };
+    format!("{},{}", millis(start), millis(end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: AttributeValue + PartialEq + std::fmt::Debug>(value: T, attribute: JsonValue) {
+        assert_eq!(value.to_attribute(), attribute);
+        assert_eq!(T::from_attribute(&attribute), Some(value));
+    }
+
+    #[test]
+    fn converts_dates() {
+        round_trip(Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap(), 1_577_836_800_000i64.into());
+        round_trip(NaiveDate::from_ymd_opt(2024, 1, 31).unwrap(), "2024-01-31".into());
+        assert_eq!(DateTime::<Utc>::from_attribute(&"2020-01-01".into()), None);
+        assert_eq!(NaiveDate::from_attribute(&"31/01/2024".into()), None);
+        assert_eq!(date_text(&1_577_836_800_123i64.into()).as_deref(), Some("2020-01-01T00:00:00.123Z"));
+        assert_eq!(date_text(&JsonValue::Null), None);
+    }
+
+    #[test]
+    fn converts_times() {
+        round_trip(NaiveTime::from_hms_opt(13, 45, 0).unwrap(), "13:45:00".into());
+        round_trip(NaiveTime::from_hms_milli_opt(13, 45, 0, 250).unwrap(), "13:45:00.250".into());
+        assert_eq!(NaiveTime::from_attribute(&"1:45 PM".into()), None);
+    }
+
+    #[test]
+    fn converts_timestamps_with_offsets() {
+        let timestamp = FixedOffset::west_opt(5 * 3600)
+            .unwrap()
+            .with_ymd_and_hms(2024, 1, 31, 13, 45, 0)
+            .unwrap();
+        round_trip(timestamp, "2024-01-31T13:45:00.000-05:00".into());
+    }
+
+    #[test]
+    fn converts_guids() {
+        let guid = Uuid::parse_str("8d8c1c2e-8c1a-4c4b-9f0e-0c1f2b3a4d5e").unwrap();
+        round_trip(guid, "{8D8C1C2E-8C1A-4C4B-9F0E-0C1F2B3A4D5E}".into());
+        assert_eq!(Uuid::from_attribute(&"8d8c1c2e-8c1a-4c4b-9f0e-0c1f2b3a4d5e".into()), Some(guid));
+        assert_eq!(Uuid::from_attribute(&"{not a guid}".into()), None);
+    }
+
+    #[test]
+    fn converts_plain_values() {
+        round_trip(String::from("Lisboa"), "Lisboa".into());
+        round_trip(42i64, 42.into());
+        round_trip(2.5, 2.5.into());
+        round_trip(true, true.into());
+        assert_eq!(i64::from_attribute(&"42".into()), None);
+    }
+
+    #[test]
+    fn reads_and_writes_feature_attributes() {
+        let mut feature = Feature::new(JsonValue::Null, None);
+        let day = NaiveDate::from_ymd_opt(2024, 2, 29).unwrap();
+        feature.set_attribute("Surveyed", &day);
+        assert_eq!(feature.attributes["Surveyed"], "2024-02-29");
+        assert_eq!(feature.attribute::<NaiveDate>("surveyed"), Some(day));
+        assert_eq!(feature.attribute::<NaiveDate>("missing"), None);
+        assert_eq!(feature.attribute::<i64>("Surveyed"), None);
+    }
+
+    #[test]
+    fn encodes_time_params() {
+        let start = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(time_param(Some(&start), Some(&start)), "1577836800000,1577836800000");
+        assert_eq!(time_param(Some(&start), None), "1577836800000,null");
+        assert_eq!(time_param(None, Some(&start)), "null,1577836800000");
+    }
+
+    #[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+    struct Inspection {
+        #[serde(with = "as_attribute")]
+        inspected: DateTime<Utc>,
+        #[serde(default, with = "as_attribute::option")]
+        due: Option<NaiveDate>,
+    }
+
+    #[test]
+    fn serializes_struct_members_as_attributes() {
+        let attributes = json::object! { "inspected" => 1_577_836_800_000i64, "due" => "2020-02-01" };
+        let feature = Feature::new(attributes.clone(), None);
+        let typed = feature.into_typed::<Inspection, crate::Geometry>().unwrap();
+        assert_eq!(typed.attributes.due, NaiveDate::from_ymd_opt(2020, 2, 1));
+        assert_eq!(typed.to_untyped().unwrap().attributes, attributes);
+
+        let missing = Feature::new(json::object! { "inspected" => 0 }, None);
+        assert_eq!(missing.into_typed::<Inspection, crate::Geometry>().unwrap().attributes.due, None);
+        let invalid = Feature::new(json::object! { "inspected" => "yesterday" }, None);
+        assert!(invalid.into_typed::<Inspection, crate::Geometry>().is_err());
+    }
+}
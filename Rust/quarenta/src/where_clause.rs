@@ -0,0 +1,514 @@
+//! Building where clauses without string formatting.
+//!
+//! Values are written as SQL literals with quotes escaped, so user input can't end a string
+//! early. Field names that aren't plain identifiers are quoted, and all of them are checked
+//! against the layer before a query is sent.
+
+use std::fmt;
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::feature_layer::LayerInfo;
+use crate::BoxResult;
+
+/// A value in a where clause.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    Integer(i64),
+    /// An integer too large for `Integer`, such as a 64-bit object ID.
+    Unsigned(u64),
+    /// SQL has no literal for NaN or infinity, so those are written as `NULL`, which matches
+    /// nothing, and [`WhereClause::check`] rejects them.
+    Number(f64),
+    Text(String),
+    /// Written as `timestamp 'YYYY-MM-DD HH:MM:SS'`, in UTC.
+    Timestamp(DateTime<Utc>),
+    /// Written as `date 'YYYY-MM-DD'`.
+    Date(NaiveDate),
+}
+
+impl SqlValue {
+    pub fn to_sql(&self) -> String {
+        match self {
+            SqlValue::Integer(value) => value.to_string(),
+            SqlValue::Unsigned(value) => value.to_string(),
+            SqlValue::Number(value) if value.is_finite() => value.to_string(),
+            SqlValue::Number(_) => String::from("NULL"),
+            SqlValue::Text(value) => quote(value),
+            SqlValue::Timestamp(value) => format!("timestamp '{}'", value.format("%Y-%m-%d %H:%M:%S")),
+            SqlValue::Date(value) => format!("date '{}'", value.format("%Y-%m-%d")),
+        }
+    }
+}
+
+/// Quotes a string literal, doubling any single quotes in it.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Writes a field name as it is if it's a plain identifier, such as `POP_2020` or
+/// `owner.parcels.APN`, and otherwise as a quoted identifier with any double quotes doubled, so
+/// a field name can't change the meaning of the SQL either.
+fn identifier(name: &str) -> String {
+    let is_plain = |part: &str| {
+        let mut chars = part.chars();
+        match chars.next() {
+            Some(first) => {
+                (first.is_ascii_alphabetic() || '_' == first) && chars.all(|c| c.is_ascii_alphanumeric() || '_' == c)
+            }
+            None => false,
+        }
+    };
+    if name.split('.').all(is_plain) {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+macro_rules! sql_integer {
+    ($($type:ty),*) => {
+        $(impl From<$type> for SqlValue {
+            fn from(value: $type) -> SqlValue {
+                SqlValue::Integer(value as i64)
+            }
+        })*
+    };
+}
+
+sql_integer!(i16, i32, i64, u16, u32);
+
+impl From<u64> for SqlValue {
+    fn from(value: u64) -> SqlValue {
+        SqlValue::Unsigned(value)
+    }
+}
+
+impl From<f32> for SqlValue {
+    fn from(value: f32) -> SqlValue {
+        SqlValue::Number(f64::from(value))
+    }
+}
+
+impl From<f64> for SqlValue {
+    fn from(value: f64) -> SqlValue {
+        SqlValue::Number(value)
+    }
+}
+
+impl From<&str> for SqlValue {
+    fn from(value: &str) -> SqlValue {
+        SqlValue::Text(value.to_string())
+    }
+}
+
+impl From<String> for SqlValue {
+    fn from(value: String) -> SqlValue {
+        SqlValue::Text(value)
+    }
+}
+
+impl From<&String> for SqlValue {
+    fn from(value: &String) -> SqlValue {
+        SqlValue::Text(value.clone())
+    }
+}
+
+impl From<DateTime<Utc>> for SqlValue {
+    fn from(value: DateTime<Utc>) -> SqlValue {
+        SqlValue::Timestamp(value)
+    }
+}
+
+impl From<NaiveDate> for SqlValue {
+    fn from(value: NaiveDate) -> SqlValue {
+        SqlValue::Date(value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Condition {
+    All,
+    Compare { field: String, operator: &'static str, value: SqlValue },
+    In { field: String, values: Vec<SqlValue>, negated: bool },
+    Like { field: String, pattern: String, escaped: bool, negated: bool },
+    IsNull { field: String, negated: bool },
+    And(Vec<WhereClause>),
+    Or(Vec<WhereClause>),
+    Not(Box<WhereClause>),
+}
+
+/// A where clause, built from field conditions combined with AND, OR and NOT.
+///
+/// # Examples
+///
+/// ```
+/// use quarenta::WhereClause;
+///
+/// let clause = WhereClause::field("population").ge(1_000_000)
+///     .and(WhereClause::field("country").is_in(&["Côte d'Ivoire", "Ghana"]))
+///     .and(WhereClause::field("city").starts_with("San"));
+/// assert_eq!(
+///     r"population >= 1000000 AND country IN ('Côte d''Ivoire', 'Ghana') AND city LIKE 'San%' ESCAPE '\'",
+///     clause.to_sql()
+/// );
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct WhereClause {
+    condition: Condition,
+}
+
+/// A field to build a condition on; see [`WhereClause::field`].
+#[derive(Clone, Debug)]
+pub struct FieldCondition {
+    field: String,
+}
+
+impl WhereClause {
+    /// Matches every feature (`1=1`).
+    pub fn all() -> WhereClause {
+        WhereClause { condition: Condition::All }
+    }
+
+    /// Starts a condition on a field.
+    pub fn field(name: &str) -> FieldCondition {
+        FieldCondition { field: name.to_string() }
+    }
+
+    /// Matches features that match this clause and `other`.
+    pub fn and(self, other: WhereClause) -> WhereClause {
+        match self.condition {
+            Condition::And(mut clauses) => {
+                clauses.push(other);
+                WhereClause { condition: Condition::And(clauses) }
+            }
+            condition => WhereClause {
+                condition: Condition::And(vec![WhereClause { condition }, other]),
+            },
+        }
+    }
+
+    /// Matches features that match this clause or `other`.
+    pub fn or(self, other: WhereClause) -> WhereClause {
+        match self.condition {
+            Condition::Or(mut clauses) => {
+                clauses.push(other);
+                WhereClause { condition: Condition::Or(clauses) }
+            }
+            condition => WhereClause {
+                condition: Condition::Or(vec![WhereClause { condition }, other]),
+            },
+        }
+    }
+
+    /// Matches features that don't match this clause.
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> WhereClause {
+        WhereClause {
+            condition: Condition::Not(Box::new(self)),
+        }
+    }
+
+    pub fn to_sql(&self) -> String {
+        match &self.condition {
+            Condition::All => String::from("1=1"),
+            Condition::Compare { field, operator, value } => {
+                format!("{} {} {}", identifier(field), operator, value.to_sql())
+            }
+            Condition::In { field, values, negated } => {
+                if values.is_empty() {
+                    // IN () isn't valid SQL; an empty list matches nothing.
+                    return String::from(if *negated { "1=1" } else { "1=0" });
+                }
+                let values: Vec<String> = values.iter().map(SqlValue::to_sql).collect();
+                format!("{} {}IN ({})", identifier(field), if *negated { "NOT " } else { "" }, values.join(", "))
+            }
+            Condition::Like { field, pattern, escaped, negated } => format!(
+                "{} {}LIKE {}{}",
+                identifier(field),
+                if *negated { "NOT " } else { "" },
+                quote(pattern),
+                if *escaped { r" ESCAPE '\'" } else { "" }
+            ),
+            Condition::IsNull { field, negated } => {
+                format!("{} IS {}NULL", identifier(field), if *negated { "NOT " } else { "" })
+            }
+            Condition::And(clauses) => join(clauses, " AND "),
+            Condition::Or(clauses) => join(clauses, " OR "),
+            Condition::Not(clause) => format!("NOT ({})", clause.to_sql()),
+        }
+    }
+
+    /// The names of the fields the clause uses.
+    pub fn fields(&self) -> Vec<&str> {
+        match &self.condition {
+            Condition::All => Vec::new(),
+            Condition::Compare { field, .. }
+            | Condition::In { field, .. }
+            | Condition::Like { field, .. }
+            | Condition::IsNull { field, .. } => vec![field.as_str()],
+            Condition::And(clauses) | Condition::Or(clauses) => clauses.iter().flat_map(WhereClause::fields).collect(),
+            Condition::Not(clause) => clause.fields(),
+        }
+    }
+
+    /// Checks that every number in the clause is finite.
+    pub fn check(&self) -> BoxResult<()> {
+        match self.values().into_iter().find(|value| matches!(value, SqlValue::Number(number) if !number.is_finite())) {
+            Some(SqlValue::Number(number)) => Err(format!("{} can't be used in a where clause", number).into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Like [`WhereClause::check`], and also checks that every field the clause uses is one of
+    /// the layer's fields.
+    pub fn validate(&self, info: &LayerInfo) -> BoxResult<()> {
+        self.check()?;
+        let unknown: Vec<&str> = self
+            .fields()
+            .into_iter()
+            .filter(|field| info.field(field).is_none())
+            .collect();
+        if unknown.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("{} has no field named {}", info.name, unknown.join(", ")).into())
+        }
+    }
+
+    fn values(&self) -> Vec<&SqlValue> {
+        match &self.condition {
+            Condition::Compare { value, .. } => vec![value],
+            Condition::In { values, .. } => values.iter().collect(),
+            Condition::And(clauses) | Condition::Or(clauses) => clauses.iter().flat_map(WhereClause::values).collect(),
+            Condition::Not(clause) => clause.values(),
+            Condition::All | Condition::Like { .. } | Condition::IsNull { .. } => Vec::new(),
+        }
+    }
+
+    /// Whether the clause needs parentheses when combined with others.
+    fn is_compound(&self) -> bool {
+        matches!(self.condition, Condition::And(_) | Condition::Or(_))
+    }
+}
+
+fn join(clauses: &[WhereClause], separator: &str) -> String {
+    let clauses: Vec<String> = clauses
+        .iter()
+        .map(|clause| {
+            if clause.is_compound() {
+                format!("({})", clause.to_sql())
+            } else {
+                clause.to_sql()
+            }
+        })
+        .collect();
+    clauses.join(separator)
+}
+
+impl fmt::Display for WhereClause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_sql())
+    }
+}
+
+/// Escapes LIKE wildcards and the escape character itself, for use with `ESCAPE '\'`.
+fn escape_like(text: &str) -> String {
+    text.replace('\\', r"\\").replace('%', r"\%").replace('_', r"\_")
+}
+
+impl FieldCondition {
+    fn compare(self, operator: &'static str, value: SqlValue) -> WhereClause {
+        WhereClause {
+            condition: Condition::Compare {
+                field: self.field,
+                operator,
+                value,
+            },
+        }
+    }
+
+    pub fn eq<V: Into<SqlValue>>(self, value: V) -> WhereClause {
+        self.compare("=", value.into())
+    }
+
+    pub fn ne<V: Into<SqlValue>>(self, value: V) -> WhereClause {
+        self.compare("<>", value.into())
+    }
+
+    pub fn lt<V: Into<SqlValue>>(self, value: V) -> WhereClause {
+        self.compare("<", value.into())
+    }
+
+    pub fn le<V: Into<SqlValue>>(self, value: V) -> WhereClause {
+        self.compare("<=", value.into())
+    }
+
+    pub fn gt<V: Into<SqlValue>>(self, value: V) -> WhereClause {
+        self.compare(">", value.into())
+    }
+
+    pub fn ge<V: Into<SqlValue>>(self, value: V) -> WhereClause {
+        self.compare(">=", value.into())
+    }
+
+    /// Matches any of the values. An empty list matches nothing.
+    pub fn is_in<V: Clone + Into<SqlValue>>(self, values: &[V]) -> WhereClause {
+        self.in_list(values, false)
+    }
+
+    /// Matches none of the values. An empty list matches everything.
+    pub fn not_in<V: Clone + Into<SqlValue>>(self, values: &[V]) -> WhereClause {
+        self.in_list(values, true)
+    }
+
+    fn in_list<V: Clone + Into<SqlValue>>(self, values: &[V], negated: bool) -> WhereClause {
+        WhereClause {
+            condition: Condition::In {
+                field: self.field,
+                values: values.iter().cloned().map(Into::into).collect(),
+                negated,
+            },
+        }
+    }
+
+    /// Matches a LIKE pattern, in which `%` and `_` are wildcards. Quotes are escaped, but the
+    /// wildcards aren't; use [`FieldCondition::starts_with`] or [`FieldCondition::contains`]
+    /// to match user input literally.
+    pub fn like(self, pattern: &str) -> WhereClause {
+        self.like_pattern(pattern.to_string(), false, false)
+    }
+
+    pub fn not_like(self, pattern: &str) -> WhereClause {
+        self.like_pattern(pattern.to_string(), false, true)
+    }
+
+    /// Matches values that start with `text`, taken literally.
+    pub fn starts_with(self, text: &str) -> WhereClause {
+        self.like_pattern(format!("{}%", escape_like(text)), true, false)
+    }
+
+    /// Matches values that contain `text`, taken literally.
+    pub fn contains(self, text: &str) -> WhereClause {
+        self.like_pattern(format!("%{}%", escape_like(text)), true, false)
+    }
+
+    fn like_pattern(self, pattern: String, escaped: bool, negated: bool) -> WhereClause {
+        WhereClause {
+            condition: Condition::Like {
+                field: self.field,
+                pattern,
+                escaped,
+                negated,
+            },
+        }
+    }
+
+    pub fn is_null(self) -> WhereClause {
+        WhereClause {
+            condition: Condition::IsNull {
+                field: self.field,
+                negated: false,
+            },
+        }
+    }
+
+    pub fn is_not_null(self) -> WhereClause {
+        WhereClause {
+            condition: Condition::IsNull {
+                field: self.field,
+                negated: true,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn doubles_single_quotes() {
+        assert_eq!("surname = 'O''Brien'", WhereClause::field("surname").eq("O'Brien").to_sql());
+        assert_eq!(
+            "surname = ''' OR ''1''=''1'",
+            WhereClause::field("surname").eq("' OR '1'='1").to_sql()
+        );
+        assert_eq!(
+            r"surname LIKE 'O''Br%' ESCAPE '\'",
+            WhereClause::field("surname").starts_with("O'Br").to_sql()
+        );
+    }
+
+    #[test]
+    fn escapes_like_wildcards() {
+        assert_eq!(
+            r"code LIKE '%50\%\_off\\%' ESCAPE '\'",
+            WhereClause::field("code").contains(r"50%_off\").to_sql()
+        );
+        assert_eq!("code LIKE 'A_%'", WhereClause::field("code").like("A_%").to_sql());
+    }
+
+    #[test]
+    fn writes_in_lists() {
+        assert_eq!(
+            "surname IN ('O''Brien', 'Silva')",
+            WhereClause::field("surname").is_in(&["O'Brien", "Silva"]).to_sql()
+        );
+        assert_eq!("FID NOT IN (1, 2, 3)", WhereClause::field("FID").not_in(&[1, 2, 3]).to_sql());
+        assert_eq!("1=0", WhereClause::field("FID").is_in::<i32>(&[]).to_sql());
+        assert_eq!("1=1", WhereClause::field("FID").not_in::<i32>(&[]).to_sql());
+    }
+
+    #[test]
+    fn parenthesizes_nested_clauses() {
+        let clause = WhereClause::field("a")
+            .eq(1)
+            .or(WhereClause::field("b").eq(2))
+            .and(WhereClause::field("c").is_null().not());
+        assert_eq!("(a = 1 OR b = 2) AND NOT (c IS NULL)", clause.to_sql());
+        assert_eq!(vec!["a", "b", "c"], clause.fields());
+    }
+
+    #[test]
+    fn keeps_large_integers_exact() {
+        assert_eq!(
+            "OBJECTID = 18446744073709551615",
+            WhereClause::field("OBJECTID").eq(u64::MAX).to_sql()
+        );
+        assert_eq!("id > 9007199254740993", WhereClause::field("id").gt((1u64 << 53) + 1).to_sql());
+    }
+
+    #[test]
+    fn rejects_non_finite_numbers() {
+        let clause = WhereClause::field("depth").lt(f64::NAN).or(WhereClause::field("depth").gt(f64::INFINITY));
+        assert_eq!("depth < NULL OR depth > NULL", clause.to_sql());
+        assert!(clause.check().is_err());
+        assert!(WhereClause::field("depth").is_in(&[1.5, -0.25]).check().is_ok());
+    }
+
+    #[test]
+    fn quotes_unusual_field_names() {
+        assert_eq!("owner.parcels.APN = 1", WhereClause::field("owner.parcels.APN").eq(1).to_sql());
+        assert_eq!(
+            r#""1=1 OR x" IS NULL"#,
+            WhereClause::field("1=1 OR x").is_null().to_sql()
+        );
+        assert_eq!(
+            r#""a""b" LIKE 'c%' ESCAPE '\'"#,
+            WhereClause::field("a\"b").starts_with("c").to_sql()
+        );
+    }
+
+    #[test]
+    fn writes_dates_and_times() {
+        let date = NaiveDate::from_ymd_opt(2020, 4, 25).unwrap();
+        assert_eq!("day = date '2020-04-25'", WhereClause::field("day").eq(date).to_sql());
+        let time = Utc.from_utc_datetime(&date.and_hms_opt(12, 30, 0).unwrap());
+        assert_eq!(
+            "updated > timestamp '2020-04-25 12:30:00'",
+            WhereClause::field("updated").gt(time).to_sql()
+        );
+    }
+}
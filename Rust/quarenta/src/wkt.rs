@@ -0,0 +1,894 @@
+//! Well-known text and well-known binary, including PostGIS's extended forms with a SRID, for
+//! exchanging geometries with other GIS tools and databases.
+//!
+//! OGC geometries map onto Esri JSON as you'd expect, except that line strings become
+//! polylines with one path and polygons and multipolygons become polygons, with their rings
+//! turned to Esri's orientation: outer rings clockwise and holes counterclockwise. Geometry
+//! collections, curves and empty points have no Esri JSON equivalent and can't be read.
+
+use std::convert::TryInto;
+use std::fmt::Write;
+
+use crate::geometry::{signed_area, Geometry, Multipoint, Point, Polygon, Polyline, SpatialReference};
+use crate::BoxResult;
+
+/// Whether any of the points has a Z or an M value.
+fn dimensions<'a>(points: impl Iterator<Item = &'a Point> + Clone) -> (bool, bool) {
+    (
+        points.clone().any(|point| point.z.is_some()),
+        points.into_iter().any(|point| point.m.is_some()),
+    )
+}
+
+fn all_points(geometry: &Geometry) -> Vec<&Point> {
+    match geometry {
+        Geometry::Point(point) => vec![point],
+        Geometry::Multipoint(multipoint) => multipoint.points.iter().collect(),
+        Geometry::Polyline(polyline) => polyline.paths.iter().flatten().collect(),
+        Geometry::Polygon(polygon) => polygon.rings.iter().flatten().collect(),
+        Geometry::Envelope(_) => Vec::new(),
+    }
+}
+
+/// Writes a geometry as WKT. Polylines with one path become `LINESTRING`s and polygons with
+/// one outer ring become `POLYGON`s; otherwise they're `MULTI` geometries. Envelopes become
+/// polygons.
+pub(crate) fn to_wkt(geometry: &Geometry) -> String {
+    if let Geometry::Envelope(envelope) = geometry {
+        return to_wkt(&Geometry::Polygon(envelope.to_polygon()));
+    }
+    let points = all_points(geometry);
+    let (has_z, has_m) = dimensions(points.iter().copied());
+    let suffix = match (has_z, has_m) {
+        (true, true) => " ZM",
+        (true, false) => " Z",
+        (false, true) => " M",
+        (false, false) => "",
+    };
+    let coordinates = |point: &Point| {
+        let mut text = format!("{} {}", point.x, point.y);
+        if has_z {
+            write!(text, " {}", point.z.unwrap_or(0.0)).unwrap();
+        }
+        if has_m {
+            write!(text, " {}", point.m.map(|m| m.to_string()).unwrap_or_else(|| String::from("NaN"))).unwrap();
+        }
+        text
+    };
+    let sequence = |points: &[Point]| format!("({})", points.iter().map(coordinates).collect::<Vec<_>>().join(", "));
+    let (name, body) = match geometry {
+        Geometry::Point(point) => ("POINT", format!("({})", coordinates(point))),
+        Geometry::Multipoint(multipoint) => (
+            "MULTIPOINT",
+            format!(
+                "({})",
+                multipoint
+                    .points
+                    .iter()
+                    .map(|point| format!("({})", coordinates(point)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ),
+        Geometry::Polyline(polyline) if 1 == polyline.paths.len() => ("LINESTRING", sequence(&polyline.paths[0])),
+        Geometry::Polyline(polyline) => (
+            "MULTILINESTRING",
+            format!("({})", polyline.paths.iter().map(|path| sequence(path)).collect::<Vec<_>>().join(", ")),
+        ),
+        Geometry::Polygon(polygon) => {
+            let parts: Vec<String> = polygon
+                .parts()
+                .iter()
+                .map(|rings| format!("({})", rings.iter().map(|ring| sequence(ring)).collect::<Vec<_>>().join(", ")))
+                .collect();
+            if 1 == parts.len() {
+                ("POLYGON", parts[0].clone())
+            } else {
+                ("MULTIPOLYGON", format!("({})", parts.join(", ")))
+            }
+        }
+        Geometry::Envelope(_) => unreachable!(),
+    };
+    if points.is_empty() {
+        format!("{} EMPTY", name)
+    } else {
+        format!("{}{} {}", name, suffix, body)
+    }
+}
+
+const WKB_POINT: u32 = 1;
+const WKB_LINESTRING: u32 = 2;
+const WKB_POLYGON: u32 = 3;
+const WKB_MULTIPOINT: u32 = 4;
+const WKB_MULTILINESTRING: u32 = 5;
+const WKB_MULTIPOLYGON: u32 = 6;
+
+/// The flags EWKB adds to a geometry type for Z, M and a SRID.
+const EWKB_Z: u32 = 0x8000_0000;
+const EWKB_M: u32 = 0x4000_0000;
+const EWKB_SRID: u32 = 0x2000_0000;
+
+/// Writes geometries as little-endian WKB: ISO WKB, where Z adds 1000 to the type and M 2000,
+/// or with `ewkb`, PostGIS's EWKB, where they're flags.
+struct WkbWriter {
+    bytes: Vec<u8>,
+    has_z: bool,
+    has_m: bool,
+    ewkb: bool,
+    /// The SRID to write in the next header, which is the outermost one.
+    srid: Option<u32>,
+}
+
+impl WkbWriter {
+    fn header(&mut self, geometry_type: u32) {
+        let mut geometry_type = geometry_type;
+        if self.ewkb {
+            if self.has_z {
+                geometry_type |= EWKB_Z;
+            }
+            if self.has_m {
+                geometry_type |= EWKB_M;
+            }
+            if self.srid.is_some() {
+                geometry_type |= EWKB_SRID;
+            }
+        } else {
+            if self.has_z {
+                geometry_type += 1000;
+            }
+            if self.has_m {
+                geometry_type += 2000;
+            }
+        }
+        self.bytes.push(1);
+        self.bytes.extend_from_slice(&geometry_type.to_le_bytes());
+        if let Some(srid) = self.srid.take() {
+            self.bytes.extend_from_slice(&srid.to_le_bytes());
+        }
+    }
+
+    fn count(&mut self, count: usize) {
+        self.bytes.extend_from_slice(&(count as u32).to_le_bytes());
+    }
+
+    fn coordinates(&mut self, point: &Point) {
+        self.bytes.extend_from_slice(&point.x.to_le_bytes());
+        self.bytes.extend_from_slice(&point.y.to_le_bytes());
+        if self.has_z {
+            self.bytes.extend_from_slice(&point.z.unwrap_or(0.0).to_le_bytes());
+        }
+        if self.has_m {
+            self.bytes.extend_from_slice(&point.m.unwrap_or(f64::NAN).to_le_bytes());
+        }
+    }
+
+    fn point(&mut self, point: &Point) {
+        self.header(WKB_POINT);
+        self.coordinates(point);
+    }
+
+    fn line_string(&mut self, points: &[Point]) {
+        self.header(WKB_LINESTRING);
+        self.count(points.len());
+        for point in points {
+            self.coordinates(point);
+        }
+    }
+
+    fn polygon(&mut self, rings: &[&[Point]]) {
+        self.header(WKB_POLYGON);
+        self.count(rings.len());
+        for ring in rings {
+            self.count(ring.len());
+            for point in ring.iter() {
+                self.coordinates(point);
+            }
+        }
+    }
+}
+
+/// Writes a geometry as WKB. With `multi`, polylines and polygons are always written as
+/// `MultiLineString`s and `MultiPolygon`s, for formats that want one type per column.
+pub(crate) fn to_wkb(geometry: &Geometry, multi: bool) -> Vec<u8> {
+    write_wkb(geometry, multi, false, None)
+}
+
+/// Writes a geometry as EWKB, with a SRID if one is given.
+pub(crate) fn to_ewkb(geometry: &Geometry, srid: Option<u32>) -> Vec<u8> {
+    write_wkb(geometry, false, true, srid)
+}
+
+fn write_wkb(geometry: &Geometry, multi: bool, ewkb: bool, srid: Option<u32>) -> Vec<u8> {
+    if let Geometry::Envelope(envelope) = geometry {
+        return write_wkb(&Geometry::Polygon(envelope.to_polygon()), multi, ewkb, srid);
+    }
+    let (has_z, has_m) = dimensions(all_points(geometry).into_iter());
+    let mut writer = WkbWriter {
+        bytes: Vec::new(),
+        has_z,
+        has_m,
+        ewkb,
+        srid,
+    };
+    match geometry {
+        Geometry::Point(point) => writer.point(point),
+        Geometry::Multipoint(multipoint) => {
+            writer.header(WKB_MULTIPOINT);
+            writer.count(multipoint.points.len());
+            for point in &multipoint.points {
+                writer.point(point);
+            }
+        }
+        Geometry::Polyline(polyline) if 1 == polyline.paths.len() && !multi => writer.line_string(&polyline.paths[0]),
+        Geometry::Polyline(polyline) => {
+            writer.header(WKB_MULTILINESTRING);
+            writer.count(polyline.paths.len());
+            for path in &polyline.paths {
+                writer.line_string(path);
+            }
+        }
+        Geometry::Polygon(polygon) => {
+            let parts = polygon.parts();
+            if 1 == parts.len() && !multi {
+                writer.polygon(&parts[0]);
+            } else {
+                writer.header(WKB_MULTIPOLYGON);
+                writer.count(parts.len());
+                for part in &parts {
+                    writer.polygon(part);
+                }
+            }
+        }
+        Geometry::Envelope(_) => unreachable!(),
+    }
+    writer.bytes
+}
+
+/// A geometry as WKT or WKB describe it, before it becomes Esri JSON.
+enum Shape {
+    /// A point, or `None` for `POINT EMPTY`.
+    Point(Option<Point>),
+    LineString(Vec<Point>),
+    Polygon(Vec<Vec<Point>>),
+    MultiPoint(Vec<Point>),
+    MultiLineString(Vec<Vec<Point>>),
+    MultiPolygon(Vec<Vec<Vec<Point>>>),
+}
+
+impl Shape {
+    fn into_geometry(self) -> BoxResult<Geometry> {
+        Ok(match self {
+            Shape::Point(Some(point)) => Geometry::Point(point),
+            Shape::Point(None) => return Err("an empty point has no Esri JSON equivalent".into()),
+            Shape::LineString(points) if points.is_empty() => Geometry::Polyline(Polyline::default()),
+            Shape::LineString(points) => Geometry::Polyline(Polyline { paths: vec![points] }),
+            Shape::Polygon(rings) => Geometry::Polygon(Polygon {
+                rings: esri_rings(rings),
+            }),
+            Shape::MultiPoint(points) => Geometry::Multipoint(Multipoint { points }),
+            Shape::MultiLineString(paths) => Geometry::Polyline(Polyline { paths }),
+            Shape::MultiPolygon(polygons) => Geometry::Polygon(Polygon {
+                rings: polygons.into_iter().flat_map(esri_rings).collect(),
+            }),
+        })
+    }
+}
+
+/// Turns a polygon's rings so the first, its outer ring, is clockwise and the rest, its holes,
+/// are counterclockwise.
+fn esri_rings(rings: Vec<Vec<Point>>) -> Vec<Vec<Point>> {
+    rings
+        .into_iter()
+        .enumerate()
+        .map(|(index, mut ring)| {
+            let clockwise = signed_area(&ring) < 0.0;
+            if clockwise != (0 == index) {
+                ring.reverse();
+            }
+            ring
+        })
+        .collect()
+}
+
+/// A point whose NaN Z or M, as WKT and WKB write a missing value, is `None`.
+fn point_from_coordinates(x: f64, y: f64, z: Option<f64>, m: Option<f64>) -> Point {
+    Point {
+        x,
+        y,
+        z: z.filter(|z| !z.is_nan()),
+        m: m.filter(|m| !m.is_nan()),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Number(f64),
+    Open,
+    Close,
+    Comma,
+}
+
+fn tokenize(text: &str) -> BoxResult<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '(' | ')' | ',' => {
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    _ => Token::Comma,
+                });
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            c if c.is_ascii_alphanumeric() || '-' == c || '+' == c || '.' == c => {
+                let mut atom = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || '-' == c || '+' == c || '.' == c {
+                        atom.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                // NaN, which WKT writers use for a missing M, is a number too.
+                tokens.push(match atom.parse() {
+                    Ok(number) => Token::Number(number),
+                    Err(_) => Token::Word(atom.to_ascii_uppercase()),
+                });
+            }
+            c => return Err(format!("unexpected {:?} in WKT", c).into()),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Reads WKT, one token at a time.
+struct WktReader {
+    tokens: Vec<Token>,
+    position: usize,
+    /// Whether three coordinates are X, Y and M rather than X, Y and Z.
+    measured: bool,
+}
+
+impl WktReader {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> BoxResult<Token> {
+        let token = self.peek().cloned().ok_or("the WKT ends too soon")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token) -> BoxResult<()> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("expected {:?} in WKT but found {:?}", expected, token).into()),
+        }
+    }
+
+    /// Whether the next token is `token`, consuming it if it is.
+    fn accept(&mut self, token: &Token) -> bool {
+        if Some(token) == self.peek() {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn shape(&mut self) -> BoxResult<Shape> {
+        let word = match self.next()? {
+            Token::Word(word) => word,
+            token => return Err(format!("expected a geometry type in WKT but found {:?}", token).into()),
+        };
+        // PostGIS writes the dimensions onto the type, as in POINTM.
+        let name = ["ZM", "Z", "M"]
+            .iter()
+            .find_map(|suffix| word.strip_suffix(suffix).filter(|name| is_geometry_name(name)))
+            .unwrap_or(word.as_str())
+            .to_string();
+        let mut dimensions = word[name.len()..].to_string();
+        if let Some(Token::Word(suffix)) = self.peek() {
+            if ["ZM", "Z", "M"].contains(&suffix.as_str()) {
+                dimensions = suffix.clone();
+                self.position += 1;
+            }
+        }
+        self.measured = "M" == dimensions;
+        if self.accept(&Token::Word(String::from("EMPTY"))) {
+            return match name.as_str() {
+                "POINT" => Ok(Shape::Point(None)),
+                "LINESTRING" => Ok(Shape::LineString(Vec::new())),
+                "POLYGON" => Ok(Shape::Polygon(Vec::new())),
+                "MULTIPOINT" => Ok(Shape::MultiPoint(Vec::new())),
+                "MULTILINESTRING" => Ok(Shape::MultiLineString(Vec::new())),
+                "MULTIPOLYGON" => Ok(Shape::MultiPolygon(Vec::new())),
+                _ => Err(format!("{} isn't supported", name).into()),
+            };
+        }
+        match name.as_str() {
+            "POINT" => {
+                self.expect(Token::Open)?;
+                let point = self.coordinates()?;
+                self.expect(Token::Close)?;
+                Ok(Shape::Point(Some(point)))
+            }
+            "LINESTRING" => Ok(Shape::LineString(self.points()?)),
+            "POLYGON" => Ok(Shape::Polygon(self.list(WktReader::points)?)),
+            // Points in a MULTIPOINT may or may not have their own parentheses.
+            "MULTIPOINT" => Ok(Shape::MultiPoint(self.list(|reader| {
+                if reader.accept(&Token::Open) {
+                    let point = reader.coordinates()?;
+                    reader.expect(Token::Close)?;
+                    Ok(point)
+                } else {
+                    reader.coordinates()
+                }
+            })?)),
+            "MULTILINESTRING" => Ok(Shape::MultiLineString(self.list(WktReader::points)?)),
+            "MULTIPOLYGON" => Ok(Shape::MultiPolygon(self.list(|reader| reader.list(WktReader::points))?)),
+            _ => Err(format!("{} isn't supported", name).into()),
+        }
+    }
+
+    /// A parenthesized, comma-separated list.
+    fn list<T>(&mut self, mut item: impl FnMut(&mut WktReader) -> BoxResult<T>) -> BoxResult<Vec<T>> {
+        self.expect(Token::Open)?;
+        let mut items = vec![item(self)?];
+        while self.accept(&Token::Comma) {
+            items.push(item(self)?);
+        }
+        self.expect(Token::Close)?;
+        Ok(items)
+    }
+
+    fn points(&mut self) -> BoxResult<Vec<Point>> {
+        self.list(WktReader::coordinates)
+    }
+
+    /// A vertex of two to four numbers.
+    fn coordinates(&mut self) -> BoxResult<Point> {
+        let mut numbers = Vec::with_capacity(4);
+        while let Some(Token::Number(number)) = self.peek() {
+            numbers.push(*number);
+            self.position += 1;
+        }
+        match (numbers.as_slice(), self.measured) {
+            ([x, y], _) => Ok(point_from_coordinates(*x, *y, None, None)),
+            ([x, y, m], true) => Ok(point_from_coordinates(*x, *y, None, Some(*m))),
+            ([x, y, z], false) => Ok(point_from_coordinates(*x, *y, Some(*z), None)),
+            ([x, y, z, m], _) => Ok(point_from_coordinates(*x, *y, Some(*z), Some(*m))),
+            _ => Err(format!("a WKT vertex has {} coordinates", numbers.len()).into()),
+        }
+    }
+}
+
+fn is_geometry_name(name: &str) -> bool {
+    [
+        "POINT",
+        "LINESTRING",
+        "POLYGON",
+        "MULTIPOINT",
+        "MULTILINESTRING",
+        "MULTIPOLYGON",
+        "GEOMETRYCOLLECTION",
+    ]
+    .contains(&name)
+}
+
+/// Splits the `SRID=4326;` off the front of EWKT.
+fn split_srid(text: &str) -> BoxResult<(Option<u32>, &str)> {
+    let text = text.trim();
+    match text.get(..5) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("SRID=") => {
+            let (srid, rest) = text[5..].split_once(';').ok_or("the EWKT SRID isn't followed by ';'")?;
+            let srid = srid.trim().parse().map_err(|_| format!("{} isn't a SRID", srid))?;
+            Ok((Some(srid), rest))
+        }
+        _ => Ok((None, text)),
+    }
+}
+
+/// Reads WKB and EWKB, in either byte order.
+struct WkbReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    srid: Option<u32>,
+}
+
+impl WkbReader<'_> {
+    fn take(&mut self, count: usize) -> BoxResult<&[u8]> {
+        let end = self.position + count;
+        let bytes = self.bytes.get(self.position..end).ok_or("the WKB ends too soon")?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self, little_endian: bool) -> BoxResult<u32> {
+        let bytes: [u8; 4] = self.take(4)?.try_into()?;
+        Ok(if little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    }
+
+    fn f64(&mut self, little_endian: bool) -> BoxResult<f64> {
+        let bytes: [u8; 8] = self.take(8)?.try_into()?;
+        Ok(if little_endian { f64::from_le_bytes(bytes) } else { f64::from_be_bytes(bytes) })
+    }
+
+    /// Reads a byte order, type code and EWKB SRID.
+    fn header(&mut self) -> BoxResult<WkbHeader> {
+        let little_endian = match self.take(1)?[0] {
+            0 => false,
+            1 => true,
+            order => return Err(format!("{} isn't a WKB byte order", order).into()),
+        };
+        let code = self.u32(little_endian)?;
+        let (mut has_z, mut has_m) = (0 != code & EWKB_Z, 0 != code & EWKB_M);
+        if 0 != code & EWKB_SRID {
+            let srid = self.u32(little_endian)?;
+            self.srid.get_or_insert(srid);
+        }
+        let code = code & 0x0fff_ffff;
+        match code / 1000 {
+            0 => {}
+            1 => has_z = true,
+            2 => has_m = true,
+            3 => {
+                has_z = true;
+                has_m = true;
+            }
+            _ => return Err(format!("{} isn't a WKB geometry type", code).into()),
+        }
+        Ok(WkbHeader {
+            little_endian,
+            geometry_type: code % 1000,
+            has_z,
+            has_m,
+        })
+    }
+
+    fn shape(&mut self) -> BoxResult<Shape> {
+        let header = self.header()?;
+        match header.geometry_type {
+            WKB_MULTIPOINT | WKB_MULTILINESTRING | WKB_MULTIPOLYGON => {
+                let count = self.u32(header.little_endian)?;
+                // Parts are read without recursing, so a multi-geometry can't nest another.
+                let parts = (0..count)
+                    .map(|_| {
+                        let part = self.header()?;
+                        self.single_shape(&part)
+                    })
+                    .collect::<BoxResult<Vec<_>>>()?;
+                multi_shape(header.geometry_type, parts)
+            }
+            _ => self.single_shape(&header),
+        }
+    }
+
+    /// Reads a point, line string or polygon after its header.
+    fn single_shape(&mut self, header: &WkbHeader) -> BoxResult<Shape> {
+        let little_endian = header.little_endian;
+        let coordinates = |reader: &mut Self| -> BoxResult<Point> {
+            let (x, y) = (reader.f64(little_endian)?, reader.f64(little_endian)?);
+            let z = if header.has_z { Some(reader.f64(little_endian)?) } else { None };
+            let m = if header.has_m { Some(reader.f64(little_endian)?) } else { None };
+            Ok(point_from_coordinates(x, y, z, m))
+        };
+        let points = |reader: &mut Self| -> BoxResult<Vec<Point>> {
+            let count = reader.u32(little_endian)?;
+            (0..count).map(|_| coordinates(reader)).collect()
+        };
+        match header.geometry_type {
+            WKB_POINT => {
+                let point = coordinates(self)?;
+                Ok(Shape::Point(if point.x.is_nan() && point.y.is_nan() { None } else { Some(point) }))
+            }
+            WKB_LINESTRING => Ok(Shape::LineString(points(self)?)),
+            WKB_POLYGON => {
+                let count = self.u32(little_endian)?;
+                Ok(Shape::Polygon((0..count).map(|_| points(self)).collect::<BoxResult<_>>()?))
+            }
+            WKB_MULTIPOINT | WKB_MULTILINESTRING | WKB_MULTIPOLYGON => {
+                Err("a WKB multi-geometry can't be part of another".into())
+            }
+            other => Err(format!("WKB geometry type {} isn't supported", other).into()),
+        }
+    }
+}
+
+struct WkbHeader {
+    little_endian: bool,
+    /// The type without its Z and M thousands, e.g. `WKB_POLYGON`.
+    geometry_type: u32,
+    has_z: bool,
+    has_m: bool,
+}
+
+/// Gathers the parts of a WKB multi-geometry, which are geometries of their own.
+fn multi_shape(geometry_type: u32, parts: Vec<Shape>) -> BoxResult<Shape> {
+    let wrong_part = || format!("a WKB multi-geometry of type {} has a part of another type", geometry_type);
+    match geometry_type {
+        WKB_MULTIPOINT => parts
+            .into_iter()
+            .map(|part| match part {
+                Shape::Point(Some(point)) => Ok(point),
+                _ => Err(wrong_part().into()),
+            })
+            .collect::<BoxResult<_>>()
+            .map(Shape::MultiPoint),
+        WKB_MULTILINESTRING => parts
+            .into_iter()
+            .map(|part| match part {
+                Shape::LineString(points) => Ok(points),
+                _ => Err(wrong_part().into()),
+            })
+            .collect::<BoxResult<_>>()
+            .map(Shape::MultiLineString),
+        _ => parts
+            .into_iter()
+            .map(|part| match part {
+                Shape::Polygon(rings) => Ok(rings),
+                _ => Err(wrong_part().into()),
+            })
+            .collect::<BoxResult<_>>()
+            .map(Shape::MultiPolygon),
+    }
+}
+
+impl Geometry {
+    /// Writes the geometry as WKT. Polylines with one path become `LINESTRING`s and polygons
+    /// with one outer ring become `POLYGON`s; otherwise they're `MULTI` geometries. Envelopes
+    /// become polygons.
+    pub fn to_wkt(&self) -> String {
+        to_wkt(self)
+    }
+
+    /// Writes the geometry as EWKT: WKT with a `SRID=4326;` prefix if the spatial reference
+    /// has a WKID.
+    pub fn to_ewkt(&self, spatial_reference: &SpatialReference) -> String {
+        match spatial_reference.effective_wkid() {
+            Some(wkid) => format!("SRID={};{}", wkid, to_wkt(self)),
+            None => to_wkt(self),
+        }
+    }
+
+    /// Parses WKT. EWKT is accepted too, but its SRID is ignored; use [`Geometry::from_ewkt`]
+    /// to keep it.
+    pub fn from_wkt(text: &str) -> BoxResult<Geometry> {
+        Geometry::from_ewkt(text).map(|(geometry, _)| geometry)
+    }
+
+    /// Parses EWKT, or plain WKT, with the spatial reference its SRID gives.
+    pub fn from_ewkt(text: &str) -> BoxResult<(Geometry, Option<SpatialReference>)> {
+        let (srid, text) = split_srid(text)?;
+        let mut reader = WktReader {
+            tokens: tokenize(text)?,
+            position: 0,
+            measured: false,
+        };
+        let shape = reader.shape()?;
+        if let Some(token) = reader.peek() {
+            return Err(format!("unexpected {:?} after the WKT geometry", token).into());
+        }
+        Ok((shape.into_geometry()?, srid.map(SpatialReference::from_wkid)))
+    }
+
+    /// Writes the geometry as little-endian ISO WKB.
+    pub fn to_wkb(&self) -> Vec<u8> {
+        to_wkb(self, false)
+    }
+
+    /// Writes the geometry as PostGIS EWKB, with the spatial reference's WKID as its SRID.
+    pub fn to_ewkb(&self, spatial_reference: Option<&SpatialReference>) -> Vec<u8> {
+        to_ewkb(self, spatial_reference.and_then(SpatialReference::effective_wkid))
+    }
+
+    /// Parses ISO WKB or EWKB, in either byte order. An EWKB SRID is ignored; use
+    /// [`Geometry::from_ewkb`] to keep it.
+    pub fn from_wkb(bytes: &[u8]) -> BoxResult<Geometry> {
+        Geometry::from_ewkb(bytes).map(|(geometry, _)| geometry)
+    }
+
+    /// Parses EWKB, or ISO WKB, with the spatial reference its SRID gives.
+    pub fn from_ewkb(bytes: &[u8]) -> BoxResult<(Geometry, Option<SpatialReference>)> {
+        let mut reader = WkbReader {
+            bytes,
+            position: 0,
+            srid: None,
+        };
+        let shape = reader.shape()?;
+        if reader.position != bytes.len() {
+            return Err(format!("{} bytes follow the WKB geometry", bytes.len() - reader.position).into());
+        }
+        Ok((shape.into_geometry()?, reader.srid.map(SpatialReference::from_wkid)))
+    }
+
+    /// Parses EWKB or ISO WKB written as hex, as PostGIS and [`CsvGeometry::Ewkb`] write it,
+    /// with the spatial reference its SRID gives.
+    ///
+    /// [`CsvGeometry::Ewkb`]: crate::CsvGeometry::Ewkb
+    pub fn from_hex_ewkb(text: &str) -> BoxResult<(Geometry, Option<SpatialReference>)> {
+        Geometry::from_ewkb(&from_hex(text)?)
+    }
+}
+
+/// Bytes as uppercase hex, as PostGIS writes WKB in text.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> BoxResult<Vec<u8>> {
+    let text = text.trim();
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err("not an even number of hex digits".into());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| Ok(u8::from_str_radix(&text[index..index + 2], 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zm(x: f64, y: f64, z: Option<f64>, m: Option<f64>) -> Point {
+        Point { x, y, z, m }
+    }
+
+    /// A square with a square hole, in Esri's orientation, and a second square.
+    fn two_polygons() -> Geometry {
+        let ring = |points: &[(f64, f64)]| points.iter().map(|(x, y)| Point::new(*x, *y)).collect::<Vec<_>>();
+        Geometry::Polygon(Polygon {
+            rings: vec![
+                ring(&[(0.0, 0.0), (0.0, 10.0), (10.0, 10.0), (10.0, 0.0), (0.0, 0.0)]),
+                ring(&[(2.0, 2.0), (8.0, 2.0), (8.0, 8.0), (2.0, 8.0), (2.0, 2.0)]),
+                ring(&[(20.0, 0.0), (20.0, 5.0), (25.0, 5.0), (25.0, 0.0), (20.0, 0.0)]),
+            ],
+        })
+    }
+
+    fn geometries() -> Vec<Geometry> {
+        vec![
+            Geometry::Point(Point::new(-9.14, 38.71)),
+            Geometry::Point(zm(1.0, 2.0, Some(3.0), None)),
+            Geometry::Point(zm(1.0, 2.0, None, Some(4.0))),
+            Geometry::Point(zm(1.0, 2.0, Some(3.0), Some(4.0))),
+            Geometry::Multipoint(Multipoint {
+                points: vec![Point::new(1.0, 2.0), Point::new(3.0, 4.0)],
+            }),
+            Geometry::Polyline(Polyline {
+                paths: vec![vec![zm(0.0, 0.0, Some(1.0), None), zm(1.0, 1.0, Some(2.0), None)]],
+            }),
+            Geometry::Polyline(Polyline {
+                paths: vec![
+                    vec![Point::new(0.0, 0.0), Point::new(1.0, 1.0)],
+                    vec![Point::new(2.0, 2.0), Point::new(3.0, 3.0), Point::new(4.0, 2.0)],
+                ],
+            }),
+            two_polygons(),
+        ]
+    }
+
+    #[test]
+    fn round_trips_wkt() {
+        for geometry in geometries() {
+            let wkt = geometry.to_wkt();
+            assert_eq!(geometry, Geometry::from_wkt(&wkt).unwrap(), "{}", wkt);
+        }
+    }
+
+    #[test]
+    fn writes_wkt() {
+        assert_eq!("POINT ZM (1 2 3 4)", Geometry::Point(zm(1.0, 2.0, Some(3.0), Some(4.0))).to_wkt());
+        assert_eq!(
+            "MULTIPOLYGON (((0 0, 0 10, 10 10, 10 0, 0 0), (2 2, 8 2, 8 8, 2 8, 2 2)), ((20 0, 20 5, 25 5, 25 0, 20 0)))",
+            two_polygons().to_wkt()
+        );
+        assert_eq!("LINESTRING EMPTY", Geometry::Polyline(Polyline { paths: vec![vec![]] }).to_wkt());
+    }
+
+    #[test]
+    fn reads_wkt_variants() {
+        assert_eq!(
+            Geometry::Point(zm(1.0, 2.0, None, Some(4.0))),
+            Geometry::from_wkt("pointm(1 2 4)").unwrap()
+        );
+        assert_eq!(
+            Geometry::Point(zm(1.0, 2.0, None, Some(4.0))),
+            Geometry::from_wkt("POINT M (1 2 4)").unwrap()
+        );
+        assert_eq!(
+            Geometry::from_wkt("MULTIPOINT ((1 2), (3 4))").unwrap(),
+            Geometry::from_wkt("MULTIPOINT (1 2, 3 4)").unwrap()
+        );
+        // OGC outer rings are counterclockwise; Esri's are clockwise.
+        let polygon = Geometry::from_wkt("POLYGON ((0 0, 10 0, 10 10, 0 10, 0 0), (2 2, 2 8, 8 8, 8 2, 2 2))").unwrap();
+        match polygon {
+            Geometry::Polygon(polygon) => {
+                assert!(signed_area(&polygon.rings[0]) < 0.0);
+                assert!(signed_area(&polygon.rings[1]) > 0.0);
+            }
+            other => panic!("expected a polygon, got {:?}", other),
+        }
+        let (geometry, spatial_reference) = Geometry::from_ewkt("SRID=3857;POINT(1 2)").unwrap();
+        assert_eq!(Geometry::Point(Point::new(1.0, 2.0)), geometry);
+        assert_eq!(Some(3857), spatial_reference.unwrap().wkid);
+        assert!(Geometry::from_wkt("POINT EMPTY").is_err());
+        assert!(Geometry::from_wkt("POINT (1 2) x").is_err());
+        assert!(Geometry::from_wkt("CIRCULARSTRING (0 0, 1 1, 2 0)").is_err());
+    }
+
+    #[test]
+    fn round_trips_wkb_and_ewkb() {
+        let spatial_reference = SpatialReference::from_wkid(4326);
+        for geometry in geometries() {
+            assert_eq!(geometry, Geometry::from_wkb(&geometry.to_wkb()).unwrap());
+            let (read, read_spatial_reference) = Geometry::from_ewkb(&geometry.to_ewkb(Some(&spatial_reference))).unwrap();
+            assert_eq!(geometry, read);
+            assert_eq!(Some(4326), read_spatial_reference.unwrap().wkid);
+        }
+    }
+
+    #[test]
+    fn writes_ewkb_hex_with_z_and_m() {
+        let point = Geometry::Point(zm(1.0, 2.0, Some(3.0), Some(4.0)));
+        // As PostGIS writes SRID=4326;POINT ZM (1 2 3 4).
+        assert_eq!(
+            "01010000E0E6100000000000000000F03F000000000000004000000000000008400000000000001040",
+            to_hex(&point.to_ewkb(Some(&SpatialReference::wgs84())))
+        );
+        let point = Geometry::Point(zm(1.0, 2.0, Some(3.0), None));
+        assert_eq!(
+            "0101000080000000000000F03F00000000000000400000000000000840",
+            to_hex(&point.to_ewkb(None))
+        );
+        // ISO WKB adds 3000 to the type for ZM.
+        assert_eq!(&[1, 0xb9, 0x0b, 0, 0], &Geometry::Point(zm(1.0, 2.0, Some(3.0), Some(4.0))).to_wkb()[..5]);
+    }
+
+    #[test]
+    fn reads_big_endian_wkb() {
+        let bytes = [
+            0, 0, 0, 0, 1, 0x3f, 0xf0, 0, 0, 0, 0, 0, 0, 0x40, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(Geometry::Point(Point::new(1.0, 2.0)), Geometry::from_wkb(&bytes).unwrap());
+        assert!(Geometry::from_wkb(&bytes[..20]).is_err());
+        let mut trailing = bytes.to_vec();
+        trailing.push(0);
+        assert!(Geometry::from_wkb(&trailing).is_err());
+    }
+
+    #[test]
+    fn rejects_nested_multi_geometries() {
+        let inner = Geometry::from_wkt("MULTIPOINT ((1 2))").unwrap().to_wkb();
+        // A multipoint whose one part is itself a multipoint.
+        let mut bytes = vec![1, 4, 0, 0, 0, 1, 0, 0, 0];
+        bytes.extend(&inner);
+        assert!(Geometry::from_wkb(&bytes).is_err());
+        // A deep nest fails the same way rather than overflowing the stack.
+        let mut deep = Vec::new();
+        for _ in 0..100_000 {
+            deep.extend(&[1, 4, 0, 0, 0, 1, 0, 0, 0]);
+        }
+        deep.extend(&inner);
+        assert!(Geometry::from_wkb(&deep).is_err());
+    }
+
+    #[test]
+    fn reads_hex_ewkb() {
+        let point = Geometry::Point(Point::new(1.0, 2.0));
+        let hex = to_hex(&point.to_ewkb(Some(&SpatialReference::from_wkid(3857))));
+        let (geometry, spatial_reference) = Geometry::from_hex_ewkb(&hex.to_lowercase()).unwrap();
+        assert_eq!(point, geometry);
+        assert_eq!(Some(3857), spatial_reference.and_then(|spatial_reference| spatial_reference.effective_wkid()));
+        assert!(Geometry::from_hex_ewkb("0101").is_err());
+        assert!(Geometry::from_hex_ewkb("POINT (1 2)").is_err());
+    }
+}
@@ -1,28 +1,35 @@
-extern crate geo;
 #[macro_use]
 extern crate json;
 extern crate rand;
 extern crate rpassword;
 extern crate strfmt;
 
-use geo::algorithm::bearing::Bearing;
-use geo::Point;
 use json::object;
+use quarenta::{
+    geodesic_bearing, CacheSummary, Envelope, Feature, FeatureCache, FeatureLayer, FeatureSet, Geometry, GeometryEngine, GpTask,
+    NetworkService, Portal, PortalSelf, Query, RequestOptions, Route, RouteOptions, SpatialIndex, SpatialReference, Stop, TileCache, TileLayout,
+    TileService, WhereClause, ARCGIS_ONLINE_URL, WORLD_ROUTE_URL,
+};
 use rand::Rng;
-use reqwest::header::CACHE_CONTROL;
-use reqwest::Response;
-use reqwest::Result;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::env;
 use std::io;
-use std::io::{Error, ErrorKind};
-use std::{thread, time};
+use std::time::Duration;
 use strfmt::strfmt;
+use tracing_subscriber::EnvFilter;
 use uuid::Uuid;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-const FEATURE_LAYER_URL: &'static str = "https://services7.arcgis.com/iYTqAIgyDcVSpgzf/arcgis/rest/services/World_Cities/FeatureServer/0";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const FEATURE_LAYER_URL: &str = "https://services7.arcgis.com/iYTqAIgyDcVSpgzf/arcgis/rest/services/World_Cities/FeatureServer/0";
+const BASEMAP_URL: &str = "https://services.arcgisonline.com/ArcGIS/rest/services/World_Street_Map/MapServer";
+const BASEMAP_CACHE_PATH: &str = "wanderer-tiles";
+const BASEMAP_LEVELS: &[u32] = &[6, 8, 10];
+const CITY_CACHE_PATH: &str = "wanderer-cities.sqlite";
+const PORTAL_URL_VAR: &str = "WANDERER_PORTAL_URL";
+const FIND_NEAREST_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const FIND_NEAREST_TIMEOUT: Duration = Duration::from_secs(3 * 60);
 const WELCOME_MESSAGES: &[&str] = &[
     "Though you've just arrived, you look around and immediately realize that you are in {city}.",
     "Something in the air tells you you've just arrived in {city}.",
@@ -30,110 +37,45 @@ const WELCOME_MESSAGES: &[&str] = &[
     "The sunsets in {city} are so beautiful this time of year. If only you had time to linger.",
 ];
 
-#[derive(Deserialize)]
-struct Service {
-    url: String,
-}
-
-#[derive(Deserialize)]
-struct HelperServices {
-    analysis: Service,
-    geometry: Service,
-}
-
-#[derive(Deserialize)]
-struct PortalSelf {
-    #[serde(rename = "helperServices")]
-    helper_services: HelperServices,
-}
-
-#[derive(Deserialize)]
+/// A city's attributes. This is written by hand rather than generated with `codegen`, because
+/// FindNearest returns the FID as `ORIG_FID` and the game only needs a few of the layer's
+/// fields; `codegen <layer URL> City` shows the layer's own types to check these against.
+#[derive(Clone, Deserialize)]
 struct City {
     city: String,
     lat: f64,
     lng: f64,
     country: String,
     admin_name: String,
-    //#[serde(default)]
-    population: u32,
+    // Nullable in the World_Cities layer
+    population: Option<u32>,
     #[serde(alias = "FID", alias = "ORIG_FID")]
     fid: u32,
 }
 
-#[derive(Deserialize)]
-struct CityFeature {
-    #[serde(rename = "attributes")]
-    city: City,
-}
-
-#[derive(Deserialize)]
-struct QueryResponse {
-    features: Vec<CityFeature>,
-}
-
-#[derive(Deserialize)]
-struct NearestLayerValue {
-    #[serde(rename = "featureSet")]
-    feature_set: QueryResponse,
-}
-
-#[derive(Deserialize)]
-struct NearestLayerResponse {
-    value: NearestLayerValue,
-}
-
-async fn get_portal_self(
-    client: &reqwest::Client,
-    token: &String,
-    referrer: &String,
-) -> Result<PortalSelf> {
-    let f_json = String::from("json");
-    let result: Result<Response> = client
-        .get("https://www.arcgis.com/sharing/rest/portals/self")
-        .query(&[("token", token), ("referer", referrer), ("f", &f_json)])
-        .send()
-        .await;
-    match result {
-        Ok(response) => response.json().await,
-        Err(err) => {
-            println!("Couldn't get portal self: {:?}", err);
-            Err(err)
-        }
+/// The portal to play against: the `--portal` argument, or the `WANDERER_PORTAL_URL`
+/// environment variable, or ArcGIS Online. Either the portal's home, such as
+/// `https://gis.example.com/portal`, or its sharing API will do.
+fn portal_url() -> String {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let url = args
+        .iter()
+        .position(|arg| "--portal" == arg)
+        .and_then(|index| args.get(index + 1).cloned())
+        .or_else(|| env::var(PORTAL_URL_VAR).ok())
+        .unwrap_or_else(|| String::from(ARCGIS_ONLINE_URL));
+    let url = url.trim_end_matches('/');
+    if url.ends_with("/sharing/rest") {
+        url.to_string()
+    } else {
+        format!("{}/sharing/rest", url)
     }
 }
 
-async fn get_cities_count(client: &reqwest::Client, token: &String, referrer: &String) -> u32 {
-    let f_json = String::from("json");
-    let result: Result<Response> = client
-        .get(format!("{}/query", FEATURE_LAYER_URL).as_str())
-        .query(&[
-            ("where", "population IS NOT NULL"),
-            ("returnCountOnly", "true"),
-            ("token", token),
-            ("referer", referrer),
-            ("f", &f_json),
-        ])
-        .send()
-        .await;
-    match result {
-        Ok(response) => {
-            let response_result: Result<String> = response.text().await;
-            match response_result {
-                Ok(response_string) => {
-                    match json::parse(response_string.as_str()).unwrap()["count"].as_u32() {
-                        Some(count) => count,
-                        None => {
-                            println!("Count is null (this should never happen)");
-                            0
-                        }
-                    }
-                }
-                Err(err) => {
-                    println!("Couldn't parse response: {:?}", err);
-                    0
-                }
-            }
-        }
+async fn get_cities_count(client: &quarenta::Client) -> u32 {
+    let query = Query::new().filter(WhereClause::field("population").is_not_null());
+    match FeatureLayer::new(client, FEATURE_LAYER_URL).query_count(&query).await {
+        Ok(count) => count as u32,
         Err(err) => {
             println!("Couldn't get city count: {:?}", err);
             0
@@ -141,50 +83,22 @@ async fn get_cities_count(client: &reqwest::Client, token: &String, referrer: &S
     }
 }
 
-async fn get_minimum_population(
-    client: &reqwest::Client,
-    token: &String,
-    referrer: &String,
-    city_count: u32,
-) -> u32 {
-    let f_json = String::from("json");
-    let result: Result<Response> = client
-        .get(format!("{}/query", FEATURE_LAYER_URL).as_str())
-        .query(&[
-            ("outFields", "population"),
-            ("where", "population IS NOT NULL"),
-            ("orderByFields", "population DESC"),
-            ("resultOffset", (city_count - 1).to_string().as_str()),
-            ("resultRecordCount", "1"),
-            ("returnGeometry", "false"),
-            ("token", token),
-            ("referer", referrer),
-            ("f", &f_json),
-        ])
-        .send()
-        .await;
-    match result {
-        Ok(response) => {
-            let response_result: Result<String> = response.text().await;
-            match response_result {
-                Ok(response_string) => {
-                    match json::parse(response_string.as_str()).unwrap()["features"][0]
-                        ["attributes"]["population"]
-                        .as_u32()
-                    {
-                        Some(population) => population,
-                        None => {
-                            println!("Population is null (this should never happen)");
-                            0
-                        }
-                    }
-                }
-                Err(err) => {
-                    println!("Couldn't parse response: {:?}", err);
-                    0
-                }
+async fn get_minimum_population(client: &quarenta::Client, city_count: u32) -> u32 {
+    let query = Query::new()
+        .filter(WhereClause::field("population").is_not_null())
+        .out_fields(&["population"])
+        .order_by("population DESC")
+        .result_offset(u64::from(city_count.saturating_sub(1)))
+        .result_record_count(1)
+        .return_geometry(false);
+    match FeatureLayer::new(client, FEATURE_LAYER_URL).query(&query).await {
+        Ok(feature_set) => match feature_set.features.first().and_then(|feature| feature.attributes["population"].as_u32()) {
+            Some(population) => population,
+            None => {
+                println!("Population is null (this should never happen)");
+                0
             }
-        }
+        },
         Err(err) => {
             println!("Couldn't get minimum population: {:?}", err);
             0
@@ -192,37 +106,11 @@ async fn get_minimum_population(
     }
 }
 
-async fn get_cities(
-    client: &reqwest::Client,
-    token: &String,
-    referrer: &String,
-    fids: Vec<u32>,
-) -> Vec<CityFeature> {
-    let f_json = String::from("json");
-    let fid_strings: Vec<String> = fids.iter().map(ToString::to_string).collect();
-    let result: Result<Response> = client
-        .get(format!("{}/query", FEATURE_LAYER_URL).as_str())
-        .query(&[
-            ("objectIds", &fid_strings.join(",")),
-            ("outFields", &String::from("*")),
-            ("token", token),
-            ("referer", referrer),
-            ("f", &f_json),
-        ])
-        .send()
-        .await;
-    match result {
-        Ok(response) => {
-            //println!("Here it is:\n{}", &response.text().await.unwrap());
-            let response_result: Result<QueryResponse> = response.json().await;
-            match response_result {
-                Ok(query_response) => query_response.features,
-                Err(err) => {
-                    println!("Couldn't parse city query response: {:?}", err);
-                    Vec::new()
-                }
-            }
-        }
+async fn get_cities(client: &quarenta::Client, fids: Vec<u32>) -> Vec<Feature<City>> {
+    let layer = FeatureLayer::new(client, FEATURE_LAYER_URL);
+    let fids: Vec<u64> = fids.iter().map(|fid| u64::from(*fid)).collect();
+    match layer.query_as::<City, Geometry>(&Query::new().object_ids(&fids)).await {
+        Ok(feature_set) => feature_set.features,
         Err(err) => {
             println!("Couldn't get query results: {:?}", err);
             Vec::new()
@@ -230,142 +118,74 @@ async fn get_cities(
     }
 }
 
+/// Gets the lowest or highest FID among cities with at least the minimum population.
+async fn get_fid_bound(
+    client: &quarenta::Client,
+    minimum_population: u32,
+    order: &str,
+) -> std::result::Result<u32, Box<dyn std::error::Error>> {
+    let query = Query::new()
+        .filter(WhereClause::field("population").ge(minimum_population))
+        .out_fields(&["FID"])
+        .order_by(&format!("FID {}", order))
+        .result_record_count(1)
+        .return_geometry(false);
+    let feature_set = FeatureLayer::new(client, FEATURE_LAYER_URL).query(&query).await?;
+    feature_set
+        .features
+        .first()
+        .and_then(|feature| feature.attributes["FID"].as_u32())
+        .ok_or_else(|| "no cities are that big".into())
+}
+
 async fn get_random_city_pair(
-    client: &reqwest::Client,
-    token: &String,
-    referrer: &String,
+    client: &quarenta::Client,
     minimum_population: u32,
-) -> Result<(City, City)> {
+) -> std::result::Result<(City, City), Box<dyn std::error::Error>> {
     println!(
         "Getting a random city pair with minimum population {}",
         minimum_population
     );
-    let f_json = String::from("json");
-    let result: Result<Response> = client
-        .get(format!("{}/query", FEATURE_LAYER_URL).as_str())
-        .query(&[
-            (
-                "outStatistics",
-                r#"
-                [
-                    {
-                        "statisticType": "max",
-                        "onStatisticField": "FID",
-                        "outStatisticFieldName": "max_fid"
-                    },
-                    {
-                        "statisticType": "min",
-                        "onStatisticField": "FID",
-                        "outStatisticFieldName": "min_fid"
-                    }
-                ]
-            "#,
-            ),
-            (
-                "where",
-                format!("population >= {}", minimum_population).as_str(),
-            ),
-            ("token", token),
-            ("referer", referrer),
-            ("f", &f_json),
-        ])
-        .send()
-        .await;
-    match result {
-        Ok(response) => {
-            let response_result: Result<String> = response.text().await;
-            match response_result {
-                Ok(response_string) => {
-                    let json_value = json::parse(response_string.as_str()).unwrap();
-                    let attributes = &json_value["features"][0]["attributes"];
-                    let min_fid = attributes["min_fid"].as_u32().unwrap();
-                    let max_fid = attributes["max_fid"].as_u32().unwrap();
-                    let mut cities: Vec<City> = Vec::new();
-                    let mut tried_fids = HashSet::new();
-                    while 2 > cities.len() {
-                        let mut rng = rand::thread_rng();
-                        let mut fids = Vec::new();
-                        while 2 > fids.len() {
-                            let fid = rng.gen_range(min_fid, max_fid + 1);
-                            if tried_fids.insert(fid) {
-                                fids.push(fid);
-                            }
-                        }
-                        let city_results = get_cities(client, token, referrer, fids).await;
-                        for city_feature in city_results {
-                            if 2 > cities.len()
-                                && city_feature.city.population >= minimum_population
-                            {
-                                cities.push(city_feature.city);
-                            }
-                        }
-                    }
-                    Ok((cities.remove(0), cities.remove(0)))
-                }
-                Err(err) => {
-                    println!("Couldn't parse statistics response: {:?}", err);
-                    Err(err)
-                }
+    let min_fid = get_fid_bound(client, minimum_population, "ASC").await?;
+    let max_fid = get_fid_bound(client, minimum_population, "DESC").await?;
+    let mut cities: Vec<City> = Vec::new();
+    let mut tried_fids = HashSet::new();
+    while 2 > cities.len() {
+        let mut rng = rand::thread_rng();
+        let mut fids = Vec::new();
+        while 2 > fids.len() {
+            let fid = rng.gen_range(min_fid, max_fid + 1);
+            if tried_fids.insert(fid) {
+                fids.push(fid);
             }
         }
-        Err(err) => {
-            println!("Couldn't get statistics: {:?}", err);
-            Err(err)
+        let city_results = get_cities(client, fids).await;
+        for city_feature in city_results {
+            if 2 > cities.len()
+                && city_feature.attributes.population.is_some_and(|population| population >= minimum_population)
+            {
+                cities.push(city_feature.attributes);
+            }
         }
     }
+    Ok((cities.remove(0), cities.remove(0)))
 }
 
-fn point_geometry(x: f64, y: f64) -> json::JsonValue {
-    let mut data = json::JsonValue::new_object();
-    data["geometryType"] = "esriGeometryPoint".into();
-    data["geometry"] = json::JsonValue::new_object().into();
-    data["geometry"]["x"] = x.into();
-    data["geometry"]["y"] = y.into();
-    data
-}
-
+/// Measures the distance in kilometers between two cities, with the portal's geometry service
+/// if it has one and locally otherwise.
 async fn get_distance(
-    client: &reqwest::Client,
+    client: &quarenta::Client,
     portal_self: &PortalSelf,
     cities: &(&City, &City),
 ) -> f64 {
-    let result: Result<Response> = client
-        .get(format!("{}/distance", &portal_self.helper_services.geometry.url).as_str())
-        .query(&[
-            (
-                "geometry1",
-                point_geometry(cities.0.lng, cities.0.lat).dump().as_str(),
-            ),
-            (
-                "geometry2",
-                point_geometry(cities.1.lng, cities.1.lat).dump().as_str(),
-            ),
-            ("sr", "4326"),
-            ("distanceUnit", "9036"), // esriSRUnit_Kilometer
-            ("geodesic", "true"),
-            ("f", "json"),
-        ])
-        .send()
-        .await;
-    match result {
-        Ok(response) => {
-            let response_result: Result<String> = response.text().await;
-            match response_result {
-                Ok(response_string) => {
-                    match json::parse(response_string.as_str()).unwrap()["distance"].as_f64() {
-                        Some(distance) => distance,
-                        None => {
-                            println!("Distance is null (this should never happen)");
-                            std::f64::MAX
-                        }
-                    }
-                }
-                Err(err) => {
-                    println!("Couldn't parse response: {:?}", err);
-                    0.0
-                }
-            }
-        }
+    let service = portal_self.geometry_service(client);
+    let from = Geometry::Point(quarenta::Point::new(cities.0.lng, cities.0.lat));
+    let to = Geometry::Point(quarenta::Point::new(cities.1.lng, cities.1.lat));
+    match service
+        .distance(&from, &to, &SpatialReference::wgs84(), GeometryEngine::Auto)
+        .await
+    {
+        Ok(meters) => meters / 1000.0,
         Err(err) => {
             println!("Couldn't get distance: {:?}", err);
             0.0
@@ -373,102 +193,155 @@ async fn get_distance(
     }
 }
 
-async fn get_job_status(
-    client: &reqwest::Client,
-    token: &String,
-    referrer: &String,
-    portal_self: &PortalSelf,
-    job_id: &String,
-) -> String {
-    match client
-        .get(
-            format!(
-                "{}/FindNearest/jobs/{}",
-                &portal_self.helper_services.analysis.url, job_id
-            )
-            .as_str(),
-        )
-        .header(CACHE_CONTROL, "no-cache")
-        .query(&[
-            ("token", token),
-            ("referer", referrer),
-            ("f", &String::from("json")),
+/// Finds the nearest city in a direction with the FindNearest analysis, for when the cities
+/// couldn't all be downloaded.
+async fn find_nearest_city(
+    client: &quarenta::Client,
+    analysis_url: &str,
+    minimum_population: u32,
+    current_city: &City,
+    direction: &str,
+) -> std::result::Result<City, Box<dyn std::error::Error>> {
+    let extent = directional_extent(current_city, direction);
+    let mut out_sr = json::JsonValue::new_object();
+    out_sr["wkid"] = 4326.into();
+    let mut context = json::JsonValue::new_object();
+    context["extent"] = extent;
+    context["outSR"] = out_sr;
+    let mut analysis_layer = json::JsonValue::new_object();
+    analysis_layer["url"] = FEATURE_LAYER_URL.into();
+    analysis_layer["filter"] = WhereClause::field("population")
+        .ge(minimum_population)
+        .and(WhereClause::field("FID").ne(current_city.fid))
+        .to_sql()
+        .into();
+    let mut near_layer = json::JsonValue::new_object();
+    near_layer["url"] = FEATURE_LAYER_URL.into();
+    near_layer["filter"] = WhereClause::field("FID").eq(current_city.fid).to_sql().into();
+
+    let task = GpTask::new(client, &format!("{}/FindNearest", analysis_url));
+    let job = task
+        .submit_job(&[
+            ("analysisLayer", analysis_layer.dump()),
+            ("nearLayer", near_layer.dump()),
+            ("measurementType", String::from("StraightLine")),
+            ("maxCount", String::from("2")),
+            ("context", context.dump()),
         ])
-        .send()
-        .await
-    {
-        Ok(job_result) => match job_result.text().await {
-            Ok(job_result_string) => {
-                let json_result = json::parse(job_result_string.as_str()).unwrap();
-                json_result["jobStatus"].as_str().unwrap().to_string()
-            }
-            Err(err) => {
-                println!("Problem with job result string: {:?}", err);
-                "unknown status".to_string()
-            }
-        },
-        Err(err) => {
-            println!("Could not get job result: {:?}", err);
-            "unknown status".to_string()
-        }
-    }
+        .await?;
+    println!("Waiting for job {}", job.id);
+    let job = task.wait_for_job(&job.id, FIND_NEAREST_POLL_INTERVAL, FIND_NEAREST_TIMEOUT).await?;
+    let result = task.job_result(&job.id, "nearestLayer").await?;
+    let feature_set: FeatureSet<City> = FeatureSet::from_json(&result["featureSet"]).into_typed()?;
+    feature_set
+        .features
+        .into_iter()
+        .map(|feature| feature.attributes)
+        .find(|city| city.fid != current_city.fid)
+        .ok_or_else(|| format!("Near Features did not find any cities near city {}", current_city.fid).into())
 }
 
-async fn get_next_city(
-    client: &reqwest::Client,
-    token: &String,
-    referrer: &String,
+/// Finds the driving route between two cities with the World route service.
+async fn get_route(
+    client: &quarenta::Client,
     portal_self: &PortalSelf,
-    job_id: &String,
-    current_city_id: u32
-) -> std::result::Result<City, Error> {
-    println!("Job ID is {}", job_id);
-    match client
-        .get(
-            format!(
-                "{}/FindNearest/jobs/{}/results/nearestLayer",
-                &portal_self.helper_services.analysis.url, job_id
-            )
-            .as_str(),
-        )
-        .query(&[
-            ("token", token),
-            ("referer", referrer),
-            ("f", &String::from("json")),
-        ])
-        .send()
-        .await
-    {
-        Ok(result) => match result.json().await {
-            Ok(response) => {
-                let response: NearestLayerResponse = response;
-                let mut features: Vec<CityFeature> = response.value.feature_set.features;
-                let mut ret = Err(Error::new(ErrorKind::NotFound, format!("Near Features did not find any cities near city {}", current_city_id)));
-                while !features.is_empty() {
-                    let city: City = features.remove(0).city;
-                    if city.fid != current_city_id {
-                        println!("Job result says {}", city.city);
-                        ret = Ok(city);
-                        break;
-                    }
-                }
-                ret
-            },
-            Err(err) => {
-                println!("Problem with job result string: {:?}", err);
-                Err(Error::new(ErrorKind::Other, format!("Problem with job result string: {:?}", err)))
-            }
-        },
+    from: &City,
+    to: &City,
+) -> std::result::Result<Route, Box<dyn std::error::Error>> {
+    let service = portal_self
+        .route_service(client)
+        .unwrap_or_else(|| NetworkService::new(client, WORLD_ROUTE_URL));
+    let options = RouteOptions::new(vec![
+        Stop::named(&from.city, quarenta::Point::new(from.lng, from.lat)),
+        Stop::named(&to.city, quarenta::Point::new(to.lng, to.lat)),
+    ]);
+    let result = service.solve_route(&options).await?;
+    result.routes.into_iter().next().ok_or_else(|| "the route service found no route".into())
+}
+
+/// Downloads basemap tiles around a city at a few levels, so its map can be drawn offline.
+async fn cache_basemap(
+    client: &quarenta::Client,
+    city: &City,
+) -> std::result::Result<CacheSummary, Box<dyn std::error::Error>> {
+    let service = TileService::new(client, BASEMAP_URL);
+    let tile_info = service.tile_info().await?;
+    let center = quarenta::Point::new(city.lng, city.lat)
+        .project(&SpatialReference::wgs84(), &SpatialReference::from_wkid(3857))?;
+    let (x, y) = (center.x, center.y);
+    let extent = Envelope { xmin: x - 25_000., ymin: y - 25_000., xmax: x + 25_000., ymax: y + 25_000. };
+    let keys: Vec<_> = BASEMAP_LEVELS
+        .iter()
+        .filter_map(|level| tile_info.tile_range(&extent, *level))
+        .flat_map(|range| range.tiles())
+        .collect();
+    let cache = TileCache::new(std::path::Path::new(BASEMAP_CACHE_PATH), TileLayout::Exploded);
+    service.cache_tiles(&cache, &keys, 8).await
+}
+
+/// Opens the local copy of the cities layer, bringing it up to date if the layer can be
+/// reached, or downloads it the first time.
+async fn open_city_cache(client: &quarenta::Client) -> std::result::Result<FeatureCache, Box<dyn std::error::Error>> {
+    let path = std::path::Path::new(CITY_CACHE_PATH);
+    if !path.exists() {
+        return FeatureCache::create(path, &FeatureLayer::new(client, FEATURE_LAYER_URL)).await;
+    }
+    let mut cache = FeatureCache::open(path)?;
+    if let Err(err) = cache.refresh(client).await {
+        println!("Couldn't refresh the cities, so using the copy from last time: {:?}", err);
+    }
+    Ok(cache)
+}
+
+/// Gets every city in the game once, from the local copy of the layer if there is one, so
+/// moves can be worked out locally. Returns `None` if the layer wouldn't return them all,
+/// and moves use the FindNearest analysis instead.
+async fn get_city_index(client: &quarenta::Client, minimum_population: u32) -> Option<SpatialIndex<City>> {
+    let query = Query::new()
+        .filter(WhereClause::field("population").ge(minimum_population))
+        .out_sr(SpatialReference::wgs84());
+    let feature_set = match open_city_cache(client).await {
+        Ok(cache) => cache.query_as::<City, Geometry>(&query),
         Err(err) => {
-            println!("Could not get job result: {:?}", err);
-            Err(Error::new(ErrorKind::Other, format!("Could not get job result: {:?}", err)))
+            println!("Couldn't cache the cities: {:?}", err);
+            FeatureLayer::new(client, FEATURE_LAYER_URL).query_as::<City, Geometry>(&query).await
+        }
+    };
+    let feature_set = match feature_set {
+        Ok(feature_set) if !feature_set.exceeded_transfer_limit => feature_set,
+        Ok(_) => return None,
+        Err(err) => {
+            println!("Couldn't download the cities: {:?}", err);
+            return None;
+        }
+    };
+    match SpatialIndex::new(feature_set) {
+        Ok(index) => Some(index),
+        Err(err) => {
+            println!("Couldn't index the cities: {:?}", err);
+            None
         }
     }
 }
 
+/// Finds the nearest city in a compass direction from a city, other than the city itself.
+fn next_city_nearby<'a>(index: &'a SpatialIndex<City>, city: &City, direction: &str) -> Option<&'a City> {
+    let (start, end) = match direction {
+        "n" => (315., 45.),
+        "e" => (45., 135.),
+        "s" => (135., 225.),
+        _ => (225., 315.),
+    };
+    index
+        .in_sector(&quarenta::Point::new(city.lng, city.lat), start, end, None)
+        .into_iter()
+        .map(|(feature, _)| &feature.attributes)
+        .find(|next_city| next_city.fid != city.fid)
+}
+
 fn directional_extent(city: &City, direction: &str) -> json::JsonValue {
     let mut extent = json::JsonValue::new_object();
-    extent["spatialReference"] = json::JsonValue::new_object().into();
+    extent["spatialReference"] = json::JsonValue::new_object();
     extent["spatialReference"]["wkid"] = 4326.into();
     match direction {
         "n" => {
@@ -509,15 +382,16 @@ fn directional_extent(city: &City, direction: &str) -> json::JsonValue {
 }
 
 async fn create_game_item(
-    client: &reqwest::Client,
-    token: &String,
-    referrer: &String,
-    username: &String,
+    portal: &Portal,
+    token: &str,
+    referrer: &str,
+    username: &str,
     cities_visited: &[&City]
 ) -> Option<String> {
+    let client = portal.client();
     let mut params = HashMap::new();
-    params.insert("token", token.as_str());
-    params.insert("referer", referrer.as_str());
+    params.insert("token", token);
+    params.insert("referer", referrer);
     params.insert("f", "json");
     params.insert("type", "Color Set");
     let keywords_string = json::stringify(array!["Wanderer game"]);
@@ -529,34 +403,18 @@ async fn create_game_item(
     });
     params.insert("text", text.as_str());
 
-    let result = client
-        .post(format!("https://www.arcgis.com/sharing/rest/content/users/{}/addItem", &username).as_str())
-        .form(&params)
-        .send()
-        .await;
-    match result {
-        Ok(response) => {
-            let response_result: Result<String> = response.text().await;
-            match response_result {
-                Ok(response_string) => {
-                    println!("response string is {}", response_string.as_str());
-                    let response_json = json::parse(response_string.as_str()).unwrap();
-                    match response_json["success"].as_bool() {
-                        Some(success) => {
-                            if success {
-                                Some(String::from(response_json["id"].as_str().unwrap()))
-                            } else {
-                                None
-                            }
-                        },
-                        None => {
-                            println!("Could not add item. No 'success' value in response.");
-                            None
-                        }
-                    }
-                },
-                Err(err) => {
-                    println!("Could not add item: {}", err);
+    let request = client
+        .http()
+        .post(&format!("{}/content/users/{}/addItem", portal.url(), username))
+        .form(&params);
+    match client.send_json(request, &RequestOptions::no_retry()).await {
+        Ok(response_json) => {
+            println!("response string is {}", response_json.dump());
+            match response_json["success"].as_bool() {
+                Some(true) => response_json["id"].as_str().map(String::from),
+                Some(false) => None,
+                None => {
+                    println!("Could not add item. No 'success' value in response.");
                     None
                 }
             }
@@ -568,22 +426,35 @@ async fn create_game_item(
     }
 }
 
-async fn play_game(client: &reqwest::Client, token: &String, referrer: &String, username: &String, city_count: u32) {
+async fn play_game(portal: &Portal, token: &str, referrer: &str, username: &str, city_count: u32) {
+    let client = portal.client();
     println!("Let's play Wanderer with {} cities", city_count);
     // We need the portal self for its URLs
-    let portal_self = get_portal_self(client, token, referrer).await.unwrap();
+    let portal_self = match portal.portal_self().await {
+        Ok(portal_self) => portal_self,
+        Err(err) => {
+            println!("Couldn't get your portal's settings: {}", err);
+            return;
+        }
+    };
+    // Only needed when the city index couldn't be built
+    let analysis_url = portal_self
+        .helper_services
+        .analysis
+        .as_ref()
+        .map(|analysis| analysis.url.as_str());
 
     // Get the minimum population for cities in this game
-    let minimum_population = get_minimum_population(client, token, referrer, city_count).await;
+    let minimum_population = get_minimum_population(client, city_count).await;
     println!("Minimum population: {}", minimum_population);
+    let city_index = get_city_index(client, minimum_population).await;
     // Get a couple of random cities
-    match get_random_city_pair(client, token, referrer, minimum_population).await {
+    match get_random_city_pair(client, minimum_population).await {
         Ok(cities) => {
             println!("Hey, Wanderer! Let's see if you can make it to the secret destination.");
-            let current_city: &City = &cities.0;
-            let target_city: &City = &cities.1;
+            let (mut current_city, target_city) = cities;
 
-            match create_game_item(client, token, referrer, username, &[current_city]).await {
+            match create_game_item(portal, token, referrer, username, &[&current_city]).await {
                 Some(id) => {
                     println!("Successfully created game item {}", id);
                 },
@@ -592,15 +463,15 @@ async fn play_game(client: &reqwest::Client, token: &String, referrer: &String,
                 }
             }
 
-            let distance_to_target: f64 = get_distance(
+            let mut distance_to_target: f64 = get_distance(
                 client,
                 &portal_self,
-                &(current_city, target_city),
+                &(&current_city, &target_city),
             )
             .await;
             let mut rng = rand::thread_rng();
             let mut welcome_vars = HashMap::new();
-            welcome_vars.insert(String::from("city"), &current_city.city);
+            welcome_vars.insert(String::from("city"), current_city.city.clone());
             println!(
                 "{}",
                 strfmt(
@@ -614,7 +485,7 @@ async fn play_game(client: &reqwest::Client, token: &String, referrer: &String,
                     "You are now {:.0}km from your destination.",
                     distance_to_target
                 );
-                println!("What's next, Wanderer? (n, s, e, w, info)");
+                println!("What's next, Wanderer? (n, s, e, w, info, map, route)");
                 let mut cmd = String::new();
                 io::stdin()
                     .read_line(&mut cmd)
@@ -624,110 +495,38 @@ async fn play_game(client: &reqwest::Client, token: &String, referrer: &String,
                 match cmd {
                     "n" | "s" | "e" | "w" => {
                         println!("You decide to travel {}.", cmd);
-                        let extent = directional_extent(&current_city, cmd);
-                        let mut out_sr = json::JsonValue::new_object();
-                        out_sr["wkid"] = 4326.into();
-                        let mut context = json::JsonValue::new_object();
-                        context["extent"] = extent.into();
-                        context["outSR"] = out_sr.into();
-                        let mut analysis_layer = json::JsonValue::new_object();
-                        analysis_layer["url"] = FEATURE_LAYER_URL.into();
-                        analysis_layer["filter"] = format!(
-                            "population >= {} AND FID <> {}",
-                            minimum_population, current_city.fid
-                        )
-                        .into();
-                        let mut near_layer = json::JsonValue::new_object();
-                        near_layer["url"] = FEATURE_LAYER_URL.into();
-                        near_layer["filter"] = format!("FID = {}", current_city.fid).into();
-                        println!("Token is {}", token);
-                        let result: Result<Response> = client
-                            .post(
-                                format!(
-                                    "{}/FindNearest/submitJob",
-                                    &portal_self.helper_services.analysis.url
-                                )
-                                .as_str(),
-                            )
-                            .form(&[
-                                ("analysisLayer", analysis_layer.dump().as_str()),
-                                ("nearLayer", near_layer.dump().as_str()),
-                                ("measurementType", "StraightLine"),
-                                ("maxCount", "2"),
-                                ("context", context.dump().as_str()),
-                                ("token", token),
-                                ("referer", referrer),
-                                ("f", "json"),
-                            ])
-                            .send()
-                            .await;
-                        match result {
-                            Ok(response) => {
-                                let response_result: Result<String> = response.text().await;
-                                match response_result {
-                                    Ok(response_string) => {
-                                        let response_json =
-                                            json::parse(response_string.as_str()).unwrap();
-                                        let mut status = String::from(
-                                            response_json["jobStatus"].as_str().unwrap(),
-                                        );
-                                        let job_id =
-                                            String::from(response_json["jobId"].as_str().unwrap());
-                                        println!("Waiting for job {}", job_id);
-                                        loop {
-                                            match status.as_str() {
-                                                "esriJobSucceeded" => {
-                                                    let next_city_result = get_next_city(
-                                                        client,
-                                                        token,
-                                                        referrer,
-                                                        &portal_self,
-                                                        &job_id,
-                                                        current_city.fid
-                                                    ).await;
-                                                    if next_city_result.is_ok() {
-                                                        let current_city = next_city_result.unwrap();
-                                                        println!("The next city is {}", current_city.city);
-                                                    } else {
-                                                        println!("Could not get next city: {:?}", next_city_result.err());
-                                                    }
-                                                    break;
-                                                }
-                                                "esriJobFailed" | "esriJobTimedOut"
-                                                | "esriJobCancelled" => {
-                                                    println!(
-                                                        "Could not move to a city at this time."
-                                                    );
-                                                    break;
-                                                }
-                                                _ => {
-                                                    thread::sleep(time::Duration::from_millis(
-                                                        5000,
-                                                    ));
-                                                    status = get_job_status(
-                                                        client,
-                                                        token,
-                                                        referrer,
-                                                        &portal_self,
-                                                        &job_id,
-                                                    )
-                                                    .await;
-                                                    println!("{}", status);
-                                                }
-                                            };
-                                        }
-                                    }
-                                    Err(err) => {
-                                        println!("Couldn't parse response: {:?}", err);
-                                    }
+                        let nearby = city_index
+                            .as_ref()
+                            .and_then(|index| next_city_nearby(index, &current_city, cmd))
+                            .cloned();
+                        let next_city = match (nearby, analysis_url) {
+                            (Some(next_city), _) => next_city,
+                            (None, Some(analysis_url)) => match find_nearest_city(client, analysis_url, minimum_population, &current_city, cmd).await {
+                                Ok(next_city) => next_city,
+                                Err(err) => {
+                                    println!("Could not move to a city at this time: {}", err);
+                                    continue;
                                 }
+                            },
+                            (None, None) => {
+                                println!("There's no city that way that we know of, and your organization has no spatial analysis service to look further.");
+                                continue;
                             }
-                            Err(err) => {
-                                println!("Couldn't get distance: {:?}", err);
-                            }
+                        };
+                        println!("The next city is {}", next_city.city);
+                        current_city = next_city;
+                        if current_city.fid == target_city.fid {
+                            println!("You made it to {}, your secret destination! Well done, Wanderer.", target_city.city);
+                            break;
                         }
+                        distance_to_target = get_distance(
+                            client,
+                            &portal_self,
+                            &(&current_city, &target_city),
+                        )
+                        .await;
 
-                        welcome_vars.insert(String::from("city"), &current_city.city);
+                        welcome_vars.insert(String::from("city"), current_city.city.clone());
                         println!(
                             "{}",
                             strfmt(
@@ -742,19 +541,36 @@ async fn play_game(client: &reqwest::Client, token: &String, referrer: &String,
                             "Current location: {}, {}, {}",
                             &current_city.city, &current_city.admin_name, &current_city.country
                         );
-                        let mut bearing = Point::<f64>::new(current_city.lng, current_city.lat)
-                            .bearing(Point::<f64>::new(target_city.lng, target_city.lat));
-                        while bearing > 360.0 {
-                            bearing -= 360.0;
-                        }
-                        while bearing < 0.0 {
-                            bearing += 360.0;
-                        }
+                        let bearing = geodesic_bearing(
+                            &quarenta::Point::new(current_city.lng, current_city.lat),
+                            &quarenta::Point::new(target_city.lng, target_city.lat),
+                        );
                         println!(
                             "Your destination is {:.0}km away at a bearing of {:.0} degrees.",
                             distance_to_target, bearing
                         );
                     }
+                    "route" => match get_route(client, &portal_self, &current_city, &target_city).await {
+                        Ok(route) => match (route.total_kilometers, route.total_minutes) {
+                            (Some(kilometers), Some(minutes)) => println!(
+                                "By road, your destination is {:.0}km away, about {:.1} hours of driving.",
+                                kilometers,
+                                minutes / 60.
+                            ),
+                            _ => println!("There is a road to your destination, but nobody knows how long it is."),
+                        },
+                        Err(err) => println!("You can't drive to your destination from here: {}", err),
+                    },
+                    "map" => match cache_basemap(client, &current_city).await {
+                        Ok(summary) => println!(
+                            "Saved a map of {} to {}: {} tiles downloaded, {} already there.",
+                            &current_city.city,
+                            BASEMAP_CACHE_PATH,
+                            summary.downloaded.len(),
+                            summary.cached.len()
+                        ),
+                        Err(err) => println!("Couldn't get a map of {}: {}", &current_city.city, err),
+                    },
                     _ => {
                         println!("I don't know how to {}", cmd);
                     }
@@ -767,9 +583,14 @@ async fn play_game(client: &reqwest::Client, token: &String, referrer: &String,
 
 #[tokio::main]
 async fn main() {
+    // Set RUST_LOG=quarenta=debug (or info) to see each ArcGIS request.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
     let referrer = format!("Referrer {}", Uuid::new_v4());
+    let portal_url = portal_url();
     println!("Wanderer {}", VERSION);
-    println!("ArcGIS Online username:");
+    println!("Username for {}:", portal_url);
     let mut username = String::new();
     io::stdin()
         .read_line(&mut username)
@@ -777,13 +598,15 @@ async fn main() {
     username = String::from(username.trim());
     let password = rpassword::read_password_from_tty(Some("Password: ")).unwrap();
 
-    let reqwest_client = reqwest::Client::new();
-    let login_result = quarenta::login(&reqwest_client, &username, &password, &referrer).await;
+    let client = quarenta::Client::new();
+    let login_result = Portal::new(&client, &portal_url).generate_token(&username, &password, &referrer).await;
     match login_result {
         Ok(token_response) => {
             match token_response["token"].as_str() {
                 Some(token_str) => {
                     let token = String::from(token_str);
+                    let client = client.with_token(&token, &referrer);
+                    let portal = Portal::new(&client, &portal_url);
                     println!(
                         "Level of difficulty (0 = easy, 1 = medium, 2 = hard, 3 = legendary):"
                     );
@@ -802,14 +625,14 @@ async fn main() {
                         0 => 10,
                         1 => 100,
                         2 => 1000,
-                        3 => get_cities_count(&reqwest_client, &token, &referrer).await,
+                        3 => get_cities_count(&client).await,
                         _ => {
                             println!("Okay, then you get the default of 0 = easy.");
                             10
                         }
                     };
 
-                    play_game(&reqwest_client, &token, &referrer, &username, city_count).await;
+                    play_game(&portal, &token, &referrer, &username, city_count).await;
                 },
                 None => println!("Login returned but was not successful: {}", token_response),
             }